@@ -1,95 +1,133 @@
-use crypto::{ buffer, aes, blockmodes };
-use crypto::symmetriccipher::SymmetricCipherError;
-use crypto::buffer::{ ReadBuffer, WriteBuffer };
-use crypto::digest::Digest;
-use crypto::sha3::Sha3;
 use rand_chacha::ChaCha20Rng;
-use rand_core::{RngCore, SeedableRng};
+use rand_core::{RngCore, SeedableRng, OsRng};
+use aes_gcm::{ Aes128Gcm, Nonce, KeyInit };
+use aes_gcm::aead::{ Aead, Payload };
+use hkdf::Hkdf;
+use hmac::{ Hmac, Mac };
+use sha2::Sha256;
+use crate::error::{ Error, Result };
 
-static mut COUNTER :  u64 = 0;
+pub const NONCE_LEN : usize = 12;
 
-pub fn gen_key()->[u8; 16]{
-    unsafe{
-        // Using unsafe global counter for now (so runs the same every time)
-        // but would use safe from_entropy() once ready for production
-        let mut rng = ChaCha20Rng::seed_from_u64(COUNTER);
-        COUNTER = COUNTER + 1;
+/// A ciphertext together with the label it is stored under, which it was
+/// encrypted with as associated data.
+pub type LabelledCiphertext = (Vec<u8>, Vec<u8>);
+
+/// Source of key material for the clients. `from_entropy` is what every client
+/// uses by default; `seeded` exists so benchmarks can reproduce the same keys
+/// across runs and must be asked for explicitly.
+pub struct KeyRng {
+    rng : ChaCha20Rng,
+}
+
+impl KeyRng {
+    pub fn from_entropy() -> KeyRng {
+        KeyRng {
+            rng : ChaCha20Rng::from_rng(OsRng).expect("Could not read from the OS entropy source")
+        }
+    }
+    
+    pub fn seeded(seed : u64) -> KeyRng {
+        KeyRng {
+            rng : ChaCha20Rng::seed_from_u64(seed)
+        }
+    }
+    
+    pub fn gen_key(&mut self) -> [u8; 16]{
         let mut key = [0u8; 16];
-        rng.fill_bytes(&mut key);
+        self.rng.fill_bytes(&mut key);
+        key
+    }
+    
+    pub fn gen_master_key(&mut self) -> [u8; 32]{
+        let mut key = [0u8; 32];
+        self.rng.fill_bytes(&mut key);
         key
     }
 }
 
-pub fn prf(plaintext_bytes : &[u8], key : &[u8]) -> [u8; 32] {
-    let mut hash_func = Sha3::sha3_256();
-    let ciphertext = fixed_encrypt(plaintext_bytes, key);
-    hash_func.input(&ciphertext[..]);
-    
-    let output = hash_func.result_str();
-    let mut result = [0u8; 32];
-    result.copy_from_slice(&output.as_bytes()[0..32]);
-    result
+/// Derives a 16 byte subkey of the client master key with HKDF-SHA256. Every
+/// use of key material gets its own `info` string, so no two index types or
+/// relations ever share a key.
+pub fn derive_key(master_key : &[u8; 32], info : &str) -> [u8; 16]{
+    let hk = Hkdf::<Sha256>::new(Some(b"encrypted-spj"), &master_key[..]);
+    let mut key = [0u8; 16];
+    hk.expand(info.as_bytes(), &mut key).expect("16 bytes is a valid HKDF-SHA256 output length");
+    key
 }
 
-pub fn fixed_encrypt(plaintext_bytes : &[u8], key : &[u8]) -> Vec<u8>{
-    let iv = &[0u8; 16][..];
-    let mut encryptor = aes::cbc_encryptor(aes::KeySize::KeySize128,
-        key,
-        &iv,
-        blockmodes::PkcsPadding);
-    
-    let mut final_result = Vec::<u8>::new();
-    let mut read_buffer = buffer::RefReadBuffer::new(plaintext_bytes);
-    let mut buffer = [0; 4096];
-    let mut write_buffer = buffer::RefWriteBuffer::new(&mut buffer);
-    
-    loop {
-        let result = encryptor.encrypt(&mut read_buffer, &mut write_buffer, true);
+/// Like `derive_key` but 32 bytes long, to seed the rng that makes a key pair.
+pub fn derive_seed(master_key : &[u8; 32], info : &str) -> [u8; 32]{
+    let hk = Hkdf::<Sha256>::new(Some(b"encrypted-spj"), &master_key[..]);
+    let mut seed = [0u8; 32];
+    hk.expand(info.as_bytes(), &mut seed).expect("32 bytes is a valid HKDF-SHA256 output length");
+    seed
+}
 
-        final_result.extend(write_buffer.take_read_buffer().take_remaining().iter().map(|&i| i));
+/// HMAC-SHA256 under `key`. Used for the labels and per-counter keys of the
+/// indexes, where the same input has to give the same output every time.
+pub fn prf(plaintext_bytes : &[u8], key : &[u8]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes a key of any length");
+    mac.update(plaintext_bytes);
+    mac.finalize().into_bytes().into()
+}
 
-        match result {
-            Ok(_) => break,
-            Err(e) => {
-                match e {
-                    SymmetricCipherError::InvalidLength => println!("Invalid length"),
-                    SymmetricCipherError::InvalidPadding => println!("Invalid padding"),
-                }
-                break;
-            }
-        }
-    }
+/// Randomized authenticated encryption with AES-128-GCM. A fresh nonce is drawn
+/// for every call and prepended to the ciphertext, so equal plaintexts encrypt to
+/// unrelated ciphertexts. `aad` is authenticated but not encrypted: passing the
+/// label a ciphertext is stored under means it cannot be moved to another label.
+pub fn rand_encrypt(plaintext_bytes : &[u8], key : &[u8], aad : &[u8]) -> Vec<u8>{
+    let cipher = Aes128Gcm::new_from_slice(key).expect("AES-128-GCM needs a 16 byte key");
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce_bytes);
+    let nonce = Nonce::from_slice(&nonce_bytes);
+    let ciphertext = cipher.encrypt(nonce, Payload{ msg : plaintext_bytes, aad }).expect("AES-128-GCM encryption failed");
     
+    let mut final_result = nonce_bytes.to_vec();
+    final_result.extend(ciphertext);
     final_result
 }
 
-pub fn fixed_decrypt(ciphertext_bytes: &[u8], key: &[u8]) -> Vec<u8> {
-    let iv = &[0u8; 16][..];
-    let mut decryptor = aes::cbc_decryptor(
-            aes::KeySize::KeySize128,
-            key,
-            &iv,
-            blockmodes::PkcsPadding);
+/// Decrypts a ciphertext made by `rand_encrypt`. Fails if the ciphertext was
+/// truncated, tampered with, encrypted under another key or with other `aad`.
+pub fn rand_decrypt(ciphertext_bytes : &[u8], key : &[u8], aad : &[u8]) -> Result<Vec<u8>>{
+    if ciphertext_bytes.len() < NONCE_LEN {
+        return Err(Error::Crypto("Ciphertext is too short to hold a nonce".to_string()));
+    }
+    let cipher = match Aes128Gcm::new_from_slice(key) {
+        Ok(cipher) => cipher,
+        Err(_) => return Err(Error::Crypto("AES-128-GCM needs a 16 byte key".to_string()))
+    };
+    let (nonce_bytes, ciphertext) = ciphertext_bytes.split_at(NONCE_LEN);
+    match cipher.decrypt(Nonce::from_slice(nonce_bytes), Payload{ msg : ciphertext, aad }) {
+        Ok(plaintext) => Ok(plaintext),
+        Err(_) => Err(Error::Crypto("Ciphertext failed authentication".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let mut final_result = Vec::<u8>::new();
-    let mut read_buffer = buffer::RefReadBuffer::new(ciphertext_bytes);
-    let mut buffer = [0; 4096];
-    let mut write_buffer = buffer::RefWriteBuffer::new(&mut buffer);
+    #[test]
+    fn ciphertexts_are_bound_to_their_label() {
+        let key = KeyRng::seeded(1).gen_key();
+        let ct = rand_encrypt(b"cell", &key, b"label 1");
+        assert_eq!(rand_decrypt(&ct, &key, b"label 1").unwrap(), b"cell".to_vec());
+        assert!(rand_decrypt(&ct, &key, b"label 2").is_err());
+        assert!(rand_decrypt(&ct, &KeyRng::seeded(2).gen_key(), b"label 1").is_err());
+    }
 
-    loop {
-        let result = decryptor.decrypt(&mut read_buffer, &mut write_buffer, true);
-        final_result.extend(write_buffer.take_read_buffer().take_remaining().iter().map(|&i| i));
-        match result {
-            Ok(_) => break,
-            Err(e) => {
-                match e {
-                    SymmetricCipherError::InvalidLength => println!("Invalid length"),
-                    SymmetricCipherError::InvalidPadding => println!("Invalid padding"),
-                }
-                break;
-            }
+    #[test]
+    fn tampered_ciphertexts_are_rejected() {
+        let key = KeyRng::seeded(1).gen_key();
+        let ct = rand_encrypt(b"cell", &key, b"label");
+        for i in 0..ct.len() {
+            let mut tampered = ct.clone();
+            tampered[i] ^= 1;
+            assert!(rand_decrypt(&tampered, &key, b"label").is_err());
         }
+        assert!(rand_decrypt(&ct[..NONCE_LEN - 1], &key, b"label").is_err());
+        assert_ne!(rand_encrypt(b"cell", &key, b"label"), ct);
     }
-
-    final_result
-}
\ No newline at end of file
+}
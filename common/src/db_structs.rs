@@ -1,18 +1,33 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
 use serde::{Deserialize, Serialize};
+use sha2::{ Sha256, Digest };
+use crate::error::{ Error, Result };
+use crate::value::{ Value, ValueType };
+use crate::range::key_bounds;
+use crate::token::BoolQuery;
 
 #[derive(Serialize, Deserialize)]
 pub struct DB {
     rels: HashMap<String, Relation>,
-    pub annotations : HashSet<(String, String)>
+    pub annotations : HashSet<(Vec<String>, Vec<String>)>,
+    pub conjunctions : HashSet<(String, String)>,
+    pub ordered : HashSet<String>,
+    pub ore : HashSet<String>,
+    pub summable : HashSet<String>,
+    pub grouped : HashSet<String>
 }
 
 impl DB {
     pub fn new_db() -> DB{
         DB {
             rels: HashMap::new(),
-            annotations: HashSet::new()
+            annotations: HashSet::new(),
+            conjunctions: HashSet::new(),
+            ordered: HashSet::new(),
+            ore: HashSet::new(),
+            summable: HashSet::new(),
+            grouped: HashSet::new()
         }
     }
     
@@ -36,8 +51,58 @@ impl DB {
         self.rels.insert(id.to_string(), rel);
     }
     
-    pub fn add_annotation(&mut self, attrib1 : &String, attrib2 :&String) {
-        self.annotations.insert((attrib1.to_string(), attrib2.to_string()));
+    /// Declares the columns that identify each row of a relation. Setup labels
+    /// every cell with its row's key, so the key has to be unique and not NULL.
+    pub fn set_primary_key(&mut self, id : &String, key_ats : &Vec<String>) -> Result<()> {
+        match self.rels.get_mut(id) {
+            Some(rel) => rel.set_key(key_ats),
+            None => Err(Error::Schema(format!("Could not find table {} to give it a primary key", id)))
+        }
+    }
+    
+    /// Asks setup to precompute the join of two relations on a pair of
+    /// attributes, or on several pairs at once for a composite key.
+    pub fn add_annotation(&mut self, ats1 : &[String], ats2 : &[String]) {
+        self.annotations.insert((ats1.to_owned(), ats2.to_owned()));
+    }
+    
+    /// The relation all of the attributes belong to, if there is one.
+    pub fn get_id_from_ats(&self, ats : &[String]) -> Option<String>{
+        let ids : HashSet<Option<String>> = ats.iter().map(|at| self.get_id_from_at(at)).collect();
+        match ids.into_iter().collect::<Vec<Option<String>>>().as_slice() {
+            [Some(id)] => Some(id.to_string()),
+            _ => None
+        }
+    }
+    
+    /// Asks setup to index both attributes together so a query selecting on
+    /// both of them leaks only the volume of the rows that match both.
+    pub fn add_conjunction(&mut self, attrib1 : &String, attrib2 :&String) {
+        self.conjunctions.insert((attrib1.to_string(), attrib2.to_string()));
+    }
+    
+    /// Asks setup to build a range index on a numeric or date attribute so it
+    /// can be compared with <, <=, >, >= and BETWEEN.
+    pub fn add_ordered(&mut self, attrib : &String) {
+        self.ordered.insert(attrib.to_string());
+    }
+    
+    /// Asks setup to store order-revealing ciphertexts for a numeric or date
+    /// attribute so the server can sort on it and take its MIN and MAX.
+    pub fn add_ore(&mut self, attrib : &String) {
+        self.ore.insert(attrib.to_string());
+    }
+    
+    /// Asks setup to store Paillier ciphertexts for a numeric attribute so the
+    /// server can total it for SUM and AVG.
+    pub fn add_summable(&mut self, attrib : &String) {
+        self.summable.insert(attrib.to_string());
+    }
+    
+    /// Asks setup to precompute how many rows hold each value of an attribute
+    /// so GROUP BY with COUNT(*) on it is a single lookup.
+    pub fn add_grouped(&mut self, attrib : &String) {
+        self.grouped.insert(attrib.to_string());
     }
     
     pub fn get_id_from_at(&self, at_target : &String) -> Option<String>{
@@ -58,9 +123,20 @@ impl DB {
         result
     }
     
+    /// The type of every attribute in the database.
+    pub fn get_types(&self) -> HashMap<String, ValueType>{
+        let mut result = HashMap::new();
+        for rel in self.rels.values(){
+            for (at, ty) in rel.get_ats().iter().zip(rel.get_types()){
+                result.insert(at.to_string(), ty);
+            }
+        }
+        result
+    }
+    
     pub fn get_all_ats(&self) -> HashSet<String>{
         let mut result = HashSet::new();
-        for (_, rel) in &self.rels{
+        for rel in self.rels.values(){
             for attrib in rel.get_ats().iter(){
                 result.insert(attrib.to_string());
             }
@@ -72,77 +148,310 @@ impl DB {
         println!("Printing schema:");
         for (id, rel) in &self.rels{
             println!("Relation: {}", id);
-            let ats = rel.get_ats().join(", ");
-            println!("    {}", ats);
+            let ats : Vec<String> = rel.get_ats().iter().zip(rel.get_types()).map(|(at, ty)| format!("{} {}", at, ty)).collect();
+            println!("    {}", ats.join(", "));
+            println!("    PRIMARY KEY ({})", rel.get_key().join(", "));
         }
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Relation {
-    pub table: Vec<Vec<String>>,
+    pub table: Vec<Vec<Value>>,
     ats: Vec<String>,
+    types: Vec<ValueType>,
+    // indices of the primary key columns, and whether the last column was
+    // generated to number the rows because nothing else was unique
+    key: Vec<usize>,
+    surrogate: bool,
 }
 
 impl Relation {
     pub fn empty_rel() -> Relation {
         Relation {
             table : Vec::new(),
-            ats: Vec::new()
+            ats: Vec::new(),
+            types: Vec::new(),
+            key: Vec::new(),
+            surrogate: false
         }
     }
     
-    pub fn new_rel(ats : Vec<String>) -> Relation {
+    pub fn new_rel(ats : Vec<String>, types : Vec<ValueType>) -> Relation {
         Relation {
             table : Vec::new(),
-            ats: ats
+            ats,
+            types,
+            key: Vec::new(),
+            surrogate: false
         }
     }
     
-    pub fn add_row(&mut self, row : Vec<String>) {
-        if row.len() == self.ats.len(){
-            self.table.push(row);
-        } else {
-            println!("A row doesn\'t fit into a relation");
+    /// Builds a relation from rows as they were written in a file. Columns with
+    /// no declared type get the narrowest type that fits all their values, then
+    /// every value is normalized to its column's type.
+    pub fn from_strings(ats : Vec<String>, declared : Vec<Option<ValueType>>, rows : Vec<Vec<String>>) -> Result<Relation> {
+        let types = declared.iter().enumerate().map(|(i, ty)| match ty {
+            Some(ty) => *ty,
+            None => ValueType::infer(&rows.iter().filter_map(|row| row.get(i).map(|raw| &raw[..])).collect::<Vec<&str>>())
+        }).collect();
+        let mut rel = Relation::new_rel(ats, types);
+        for row in rows{
+            if row.len() != rel.ats.len() {
+                return Err(Error::Schema(format!("A row with {} values doesn\'t fit into a relation with {} columns", row.len(), rel.ats.len())));
+            }
+            let mut values = Vec::new();
+            for (raw, ty) in row.iter().zip(&rel.types){
+                values.push(Value::parse(raw, ty)?);
+            }
+            rel.add_row(values)?;
+        }
+        Ok(rel)
+    }
+    
+    /// Gives a loaded relation a primary key when none was declared: its first
+    /// column if every value there is unique and not NULL, as with most id
+    /// columns, and otherwise a new column `id.rowid` numbering the rows.
+    pub fn default_key(&mut self, id : &String) {
+        if !self.key.is_empty() || self.ats.is_empty() {
+            return;
+        }
+        if is_unique(&self.table, &[0]) {
+            self.key = vec!(0);
+            return;
+        }
+        self.ats.push(format!("{}.rowid", id));
+        self.types.push(ValueType::Int);
+        for (i, row) in self.table.iter_mut().enumerate() {
+            row.push(Value::Int(i as i64 + 1));
+        }
+        self.key = vec!(self.ats.len() - 1);
+        self.surrogate = true;
+    }
+    
+    /// Makes `key_ats` the primary key. A generated row number is dropped since
+    /// the rows now have a natural key.
+    pub fn set_key(&mut self, key_ats : &Vec<String>) -> Result<()> {
+        let mut key = Vec::new();
+        for key_at in key_ats {
+            match self.ats.iter().position(|at| at == key_at) {
+                Some(i) if !key.contains(&i) => key.push(i),
+                Some(_) => return Err(Error::Schema(format!("{} is listed twice in the primary key", key_at))),
+                None => return Err(Error::Schema(format!("Could not find a column named {} for the primary key", key_at)))
+            }
+        }
+        if key.is_empty() {
+            return Err(Error::Schema("A primary key needs at least one column".to_string()));
+        }
+        if !is_unique(&self.table, &key) {
+            return Err(Error::Schema(format!("({}) cannot be the primary key because two rows share it or it is NULL in a row", key_ats.join(", "))));
+        }
+        if self.surrogate {
+            let rowid = self.ats.len() - 1;
+            if key.contains(&rowid) {
+                return Err(Error::Schema("The generated row number cannot be part of a declared primary key".to_string()));
+            }
+            self.ats.pop();
+            self.types.pop();
+            for row in &mut self.table {
+                row.pop();
+            }
+            self.surrogate = false;
+        }
+        self.key = key;
+        Ok(())
+    }
+    
+    /// The primary key columns, which are empty for relations that are the
+    /// result of a query.
+    pub fn get_key(&self) -> Vec<String> {
+        self.key.iter().map(|i| self.ats[*i].to_string()).collect()
+    }
+    
+    /// The values of a row that identify it. Setup labels every cell with these.
+    pub fn row_key(&self, row : &[Value]) -> Vec<Value> {
+        self.key.iter().map(|i| row[*i].clone()).collect()
+    }
+    
+    /// Builds a new row of the relation from values as they were written, giving
+    /// it the next row number if the relation has a generated key. The row is
+    /// refused if its key is NULL or already belongs to a row.
+    pub fn row_from_strings(&self, raw : &[String]) -> Result<Vec<Value>> {
+        let width = if self.surrogate { self.ats.len() - 1 } else { self.ats.len() };
+        if raw.len() != width {
+            return Err(Error::Schema(format!("A row with {} values doesn\'t fit into a relation with {} columns", raw.len(), width)));
+        }
+        let mut row = Vec::new();
+        for (val, ty) in raw.iter().zip(&self.types){
+            row.push(Value::parse(val, ty)?);
+        }
+        if self.surrogate {
+            let last = self.table.iter().filter_map(|row| match row.last() {
+                Some(Value::Int(rowid)) => Some(*rowid),
+                _ => None
+            }).max().unwrap_or(0);
+            row.push(Value::Int(last + 1));
+        }
+        let row_key = self.row_key(&row);
+        if row_key.iter().any(|value| value.is_null()) {
+            return Err(Error::Schema(format!("The primary key ({}) cannot be NULL", self.get_key().join(", "))));
+        }
+        if self.table.iter().any(|other| self.row_key(other) == row_key) {
+            return Err(Error::Schema(format!("A row with the same primary key ({}) is already there", self.get_key().join(", "))));
+        }
+        Ok(row)
+    }
+    
+    /// The rows `matched` with the columns of `assignments` set to their new
+    /// values, checked to still have unique and non-NULL primary keys among the
+    /// rows that are left as they are.
+    pub fn updated_rows(&self, matched : &Vec<Vec<Value>>, assignments : &Vec<(String, String)>) -> Result<Vec<Vec<Value>>> {
+        let mut columns = Vec::new();
+        for (attrib, val) in assignments{
+            let i = match self.ats.iter().position(|at| at == attrib) {
+                Some(i) => i,
+                None => return Err(Error::Schema(format!("Could not find a column named {}", attrib)))
+            };
+            if self.is_surrogate(attrib) {
+                return Err(Error::Schema(format!("{} is a generated row number and cannot be set", attrib)));
+            }
+            columns.push((i, Value::parse(val, &self.types[i])?));
+        }
+        let mut keys : HashSet<Vec<Value>> = self.without_rows(matched).iter().map(|row| self.row_key(row)).collect();
+        let mut rows = Vec::new();
+        for row in matched{
+            let mut new_row = row.clone();
+            for (i, value) in &columns{
+                new_row[*i] = value.clone();
+            }
+            let row_key = self.row_key(&new_row);
+            if row_key.iter().any(|value| value.is_null()) {
+                return Err(Error::Schema(format!("The primary key ({}) cannot be NULL", self.get_key().join(", "))));
+            }
+            if !keys.insert(row_key) {
+                return Err(Error::Schema(format!("A row with the same primary key ({}) is already there", self.get_key().join(", "))));
+            }
+            rows.push(new_row);
+        }
+        Ok(rows)
+    }
+    
+    /// The same relation, with the rows replaced by `table`.
+    pub fn with_rows(&self, table : Vec<Vec<Value>>) -> Relation {
+        Relation {
+            table,
+            ats : self.ats.clone(),
+            types : self.types.clone(),
+            key : self.key.clone(),
+            surrogate : self.surrogate
         }
     }
     
-    pub fn join(&self, rel : &Relation, at1 : &String, at2 : &String) -> Relation{
+    /// The rows left once every row with the key of one of `removed` is taken out.
+    pub fn without_rows(&self, removed : &[Vec<Value>]) -> Vec<Vec<Value>> {
+        let removed_keys : HashSet<Vec<Value>> = removed.iter().map(|row| self.row_key(row)).collect();
+        self.table.iter().filter(|row| !removed_keys.contains(&self.row_key(row))).cloned().collect()
+    }
+    
+    /// Whether a row satisfies a selection predicate. As in SQL a comparison with
+    /// NULL is unknown, given as `None`, and only `Some(true)` selects the row.
+    pub fn eval_bool(&self, row : &Vec<Value>, b_query : &BoolQuery) -> Result<Option<bool>> {
+        let column = |attrib : &String| match self.ats.iter().position(|at| at == attrib) {
+            Some(i) => Ok(i),
+            None => Err(Error::Schema(format!("Could not find a column named {}", attrib)))
+        };
+        let literal = |i : usize, val : &String| match Value::parse(val, &self.types[i])? {
+            Value::Null => Err(Error::Parse(format!("Nothing equals NULL, so use {} IS NULL to select the rows where it is NULL", self.ats[i]))),
+            value => Ok(value)
+        };
+        match b_query {
+            BoolQuery::Eq(attrib, val) => {
+                let i = column(attrib)?;
+                let value = literal(i, val)?;
+                Ok(if row[i].is_null() { None } else { Some(row[i] == value) })
+            },
+            BoolQuery::In(attrib, vals) => {
+                let i = column(attrib)?;
+                let mut found = false;
+                for val in vals {
+                    found |= row[i] == literal(i, val)?;
+                }
+                Ok(if row[i].is_null() { None } else { Some(found) })
+            },
+            BoolQuery::Range(attrib, lower, upper) => {
+                let i = column(attrib)?;
+                let bounds = key_bounds(lower, upper)?;
+                if row[i].is_null() {
+                    return Ok(None);
+                }
+                let key = row[i].order_key()?;
+                Ok(Some(bounds.is_some_and(|(lo, hi)| lo <= key && key <= hi)))
+            },
+            BoolQuery::IsNull(attrib) => Ok(Some(row[column(attrib)?].is_null())),
+            BoolQuery::Not(sub) => Ok(self.eval_bool(row, sub)?.map(|b| !b)),
+            BoolQuery::And(left, right) => match (self.eval_bool(row, left)?, self.eval_bool(row, right)?) {
+                (Some(false), _) | (_, Some(false)) => Ok(Some(false)),
+                (Some(true), Some(true)) => Ok(Some(true)),
+                _ => Ok(None)
+            },
+            BoolQuery::Or(left, right) => match (self.eval_bool(row, left)?, self.eval_bool(row, right)?) {
+                (Some(true), _) | (_, Some(true)) => Ok(Some(true)),
+                (Some(false), Some(false)) => Ok(Some(false)),
+                _ => Ok(None)
+            },
+            BoolQuery::BadBool(s) => Err(Error::Parse(s.to_string()))
+        }
+    }
+    
+    /// Whether the column is the generated row number.
+    pub fn is_surrogate(&self, at : &String) -> bool {
+        self.surrogate && self.ats.last() == Some(at)
+    }
+    
+    pub fn add_row(&mut self, row : Vec<Value>) -> Result<()> {
+        if row.len() != self.ats.len(){
+            return Err(Error::Schema(format!("A row with {} values doesn\'t fit into a relation with {} columns", row.len(), self.ats.len())));
+        }
+        for ((value, ty), at) in row.iter().zip(&self.types).zip(&self.ats){
+            if !value.fits(ty) {
+                return Err(Error::Schema(format!("{} is not a {} value so it cannot go in {}", value, ty, at)));
+            }
+        }
+        self.table.push(row);
+        Ok(())
+    }
+    
+    /// Joins the rows of the two relations where each attribute of `ats1`
+    /// equals the attribute of `ats2` in the same position.
+    pub fn join(&self, rel : &Relation, ats1 : &[String], ats2 : &[String]) -> Relation{
         let mut result = Relation::empty_rel();
         let mut all_ats = self.get_ats();
         all_ats.extend(rel.get_ats());
         result.set_ats(all_ats);
+        result.types = [&self.types[..], &rel.types[..]].concat();
         
-        let mut i1_opt = None;
-        let mut i2_opt = None;
-        let mut h1 : HashMap<String, Vec<Vec<String>>> = HashMap::new();
-        let mut h2 : HashMap<String, Vec<Vec<String>>> = HashMap::new();
-        for (i, at) in self.ats.iter().enumerate(){
-            if at == at1{
-                i1_opt = Some(i);
-            }
-        }
-        for (i, at) in rel.get_ats().iter().enumerate(){
-            if at == at2{
-                i2_opt = Some(i);
-            }
-        }
-        if let Some(i1) = i1_opt {
-            if let Some(i2) = i2_opt {
+        let mut h1 : HashMap<Vec<Value>, Vec<Vec<Value>>> = HashMap::new();
+        let mut h2 : HashMap<Vec<Value>, Vec<Vec<Value>>> = HashMap::new();
+        if let (Some(cols1), Some(cols2)) = (self.positions(ats1), rel.positions(ats2)) {
+            if cols1.len() == cols2.len() {
                 for row in &self.table {
-                    h1.entry(row[i1].clone()).or_insert(Vec::new()).push(row.clone())
+                    if let Some(value) = join_key(row, &cols1) {
+                        h1.entry(value).or_default().push(row.clone())
+                    }
                 }
                 for row in &rel.table {
-                    h2.entry(row[i2].clone()).or_insert(Vec::new()).push(row.clone())
+                    if let Some(value) = join_key(row, &cols2) {
+                        h2.entry(value).or_default().push(row.clone())
+                    }
                 }
-                let set1: HashSet<String> = h1.keys().cloned().collect();
-                let set2: HashSet<String> = h2.keys().cloned().collect();
+                let set1: HashSet<Vec<Value>> = h1.keys().cloned().collect();
+                let set2: HashSet<Vec<Value>> = h2.keys().cloned().collect();
                 for value in set1.intersection(&set2){
                     for ref1 in h1.get(value).unwrap(){
                         for ref2 in h2.get(value).unwrap(){
                             let row1 = ref1.clone();
                             let row2 = ref2.clone();
-                            result.add_row([&row1[..], &row2[..]].concat());
+                            result.table.push([&row1[..], &row2[..]].concat());
                         }
                     }
                 }
@@ -161,9 +470,41 @@ impl Relation {
             let mut i = 0;
             row.retain(|_| (indices.contains(&i), i += 1).0);
         }
+        let mut i = 0;
+        self.types.retain(|_| (indices.contains(&i), i += 1).0);
         self.ats.retain(|at| cols.contains(&at.to_string()))
     }
     
+    /// Sorts the rows by a numeric or date attribute and keeps the first `limit`.
+    /// Rows where the attribute is NULL go last, as the server orders them.
+    pub fn order_by(&mut self, at_target : &String, desc : bool, limit : Option<usize>) -> Result<()>{
+        let i = match self.ats.iter().position(|at| at == at_target) {
+            Some(i) => i,
+            None => return Err(Error::Schema(format!("Could not find a column named {} to order by", at_target)))
+        };
+        let mut keyed_rows = Vec::new();
+        let mut null_rows = Vec::new();
+        for row in self.table.drain(..){
+            if row[i].is_null() {
+                null_rows.push(row);
+            } else {
+                keyed_rows.push((row[i].order_key()?, row));
+            }
+        }
+        keyed_rows.sort_by(|(key1, _), (key2, _)| if desc { key2.cmp(key1) } else { key1.cmp(key2) });
+        self.table = keyed_rows.into_iter().map(|(_, row)| row).collect();
+        self.table.append(&mut null_rows);
+        if let Some(limit) = limit {
+            self.table.truncate(limit);
+        }
+        Ok(())
+    }
+    
+    /// Where each of the attributes is in a row, if they all belong to the relation.
+    pub fn positions(&self, ats : &[String]) -> Option<Vec<usize>> {
+        ats.iter().map(|at| self.ats.iter().position(|a| a == at)).collect()
+    }
+    
     pub fn set_ats(&mut self, ats : Vec<String>){
         self.ats = ats;
     }
@@ -172,44 +513,76 @@ impl Relation {
         self.ats.clone()
     }
     
+    pub fn get_types(&self) -> Vec<ValueType> {
+        self.types.clone()
+    }
+    
     pub fn print_rel(&self, full : bool){
         println!("Selected {} record(s)", self.table.len());
         for at in &self.ats{
-            print!("{}", format!("{:14}|", get_at_most(14, at)));
+            print!("{:14}|", get_at_most(14, at));
         }
-        println!("");
+        println!();
         for _ in 0..self.ats.len(){
             print!("---------------")
         }
-        println!("");
-        if full || &self.table.len() <= &10 {
+        println!();
+        if full || self.table.len() <= 10 {
             for row in &self.table{
                 for cell in row{
-                    print!("{}", format!("{:14}|", get_at_most(14, cell)));
+                    print!("{:14}|", get_at_most(14, &cell.to_string()));
                 }
-                println!("");
+                println!();
             }
         } else {
             for row in &self.table[0..5]{
                 for cell in row{
-                    print!("{}", format!("{:14}|", get_at_most(14, cell)));
+                    print!("{:14}|", get_at_most(14, &cell.to_string()));
                 }
-                println!("");
+                println!();
             }
             println!("...");
             for row in &self.table[(self.table.len() - 6)..self.table.len()]{
                 for cell in row{
-                    print!("{}", format!("{:14}|", get_at_most(14, cell)));
+                    print!("{:14}|", get_at_most(14, &cell.to_string()));
                 }
-                println!("");
+                println!();
             }
         }
     }
 }
 
-fn get_at_most(length : usize, s : &String) -> &str {
+/// The values a row joins on, or `None` if one of them is NULL, since NULL
+/// equals nothing, not even another NULL, and so never joins.
+pub fn join_key(row : &[Value], cols : &[usize]) -> Option<Vec<Value>> {
+    cols.iter().map(|i| if row[*i].is_null() { None } else { Some(row[*i].clone()) }).collect()
+}
+
+/// A hash of a relation's rows that does not depend on their order, so a client
+/// can tell whether its copy holds the rows an edb was last changed with.
+pub fn rows_digest(table : &[Vec<Value>]) -> [u8; 32] {
+    let mut rows : Vec<Vec<u8>> = table.iter().map(|row| bincode::serialize(row).unwrap()).collect();
+    rows.sort_unstable();
+    let mut hasher = Sha256::new();
+    for row in rows {
+        hasher.update((row.len() as u64).to_be_bytes());
+        hasher.update(&row);
+    }
+    hasher.finalize().into()
+}
+
+// Whether no two rows agree on the columns in `key` and none of them is NULL there
+fn is_unique(table : &[Vec<Value>], key : &[usize]) -> bool {
+    let mut seen = HashSet::new();
+    table.iter().all(|row| {
+        let row_key : Vec<&Value> = key.iter().map(|i| &row[*i]).collect();
+        !row_key.iter().any(|value| value.is_null()) && seen.insert(row_key)
+    })
+}
+
+fn get_at_most(length : usize, s : &str) -> &str {
     if s.len() <= length{
-        &s[..]
+        s
     } else {
         &s[..][..length]
     }
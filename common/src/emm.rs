@@ -1,7 +1,10 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
 use serde::{Deserialize, Serialize};
-use crate::crypto_helper::{ prf, fixed_encrypt, fixed_decrypt, gen_key };
+use crate::crypto_helper::{ prf, rand_encrypt, rand_decrypt, KeyRng, LabelledCiphertext };
 use crate::mm::MM;
+use crate::tdp::{ TdpKey, TdpPublicKey };
+use crate::error::{ Error, Result };
 
 pub struct EMM {
     client: EMMClient,
@@ -29,131 +32,470 @@ impl EMM {
     }
     
     fn search_emm(&mut self, keyword : &Vec<u8>, rr : bool){
-        let (key1, key2) = self.client.tokenize_emm(keyword);
+        let tk = self.client.tokenize_emm(keyword);
         let results;
         if rr {
-            results = self.server.eval_emm_rr(&key1[..], &key2[..]);
+            results = self.server.eval_emm_rr(&tk);
         } else {
-            let enc_results = self.server.eval_emm_rh(&key1[..]);
-            results = self.client.decrypt_emm(enc_results);
-        }
-        
-        if let Ok(keyword_string) = std::str::from_utf8(&keyword[..]) {
-            println!("Identifiers for keyword: {}", keyword_string);
-            for value in results {
-                match std::str::from_utf8(&value[..]) {
-                    Ok(v) => println!("    {}", v),
-                    Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
+            let enc_results = self.server.eval_emm_rh(&tk);
+            match self.client.decrypt_emm(enc_results) {
+                Ok(dec_results) => results = dec_results,
+                Err(e) => {
+                    println!("Error decrypting the emm response: {}", e);
+                    return;
                 }
             }
-        } else {
-            panic!("Invalid UTF-8 sequence");
+        }
+        
+        println!("Identifiers for keyword: {}", String::from_utf8_lossy(&keyword[..]));
+        for value in results {
+            println!("    {}", String::from_utf8_lossy(&value[..]));
         }
     }
 }
 
+/// A search token for one keyword. Setup files a keyword's values under keys
+/// derived from the keyword alone, and a rewrite files them again under keys
+/// derived from the keyword and the list's new generation. Values inserted after a token for the keyword
+/// has been issued start a new epoch whose keys come from a trapdoor permutation
+/// state, so tokens issued earlier cannot find them. From the state of the newest
+/// epoch the server steps back through every older one with the public key.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+pub struct EMMToken {
+    label_key : [u8; 16],
+    // left out of response-hiding tokens so the server cannot read the values
+    value_key : Option<[u8; 16]>,
+    // the state of the newest epoch and how many epochs follow setup's
+    epochs : Option<(Vec<u8>, u64)>,
+}
+
+/// Where each keyword's list ends and which keywords have been searched, so
+/// inserts know where their entries go. It is kept with the keys by `save-keys`.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+pub struct EMMState {
+    lists : HashMap<Vec<u8>, ListState>,
+    // keywords a token was issued for since their newest epoch began
+    searched : HashSet<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+struct ListState {
+    // how many times the list has been rewritten
+    generation : u64,
+    epoch : u64,
+    // values in the newest epoch
+    count : u64,
+    // trapdoor state of the newest epoch, for every epoch after setup's
+    state : Option<Vec<u8>>,
+}
+
+/// Entries made by `EMMClient::update_emm`, with the public key the server
+/// needs to step back through epochs.
+#[derive(Serialize, Deserialize)]
+pub struct EMMUpdate {
+    entries : HashMap<[u8; 32], Vec<u8>>,
+    tdp_key : Option<TdpPublicKey>,
+}
+
+/// Lists made by `EMMClient::rewrite_emm`, with a response-hiding token for
+/// every old list the server has to drop.
+#[derive(Serialize, Deserialize)]
+pub struct EMMRewrite {
+    removals : Vec<EMMToken>,
+    entries : HashMap<[u8; 32], Vec<u8>>,
+}
+
 pub struct EMMClient {
     key: [u8; 16],
-    last_token_key : [u8; 16]
+    last_token : Option<EMMToken>,
+    // only the client that ran the setup, or one restored with its state, can add entries
+    state : Option<EMMState>,
+    pending : HashMap<Vec<u8>, ListState>,
+    // whether the state was restored from saved keys, after which tokens may
+    // have been issued for keywords that have no list yet
+    restored : bool,
+    // made on first use, since finding its primes takes a while
+    tdp_key : Option<TdpKey>,
 }
 
 impl EMMClient {
     pub fn new_emm_client() -> EMMClient {
+        EMMClient::with_rng(KeyRng::from_entropy())
+    }
+    
+    pub fn with_rng(mut rng : KeyRng) -> EMMClient {
+        EMMClient::with_key(rng.gen_key())
+    }
+    
+    pub fn with_key(key : [u8; 16]) -> EMMClient {
         EMMClient {
-            key : [0u8; 16],
-            last_token_key : [0u8; 16]
+            key,
+            last_token : None,
+            state : None,
+            pending : HashMap::new(),
+            restored : false,
+            tdp_key : None
         }
     }
     
     pub fn setup_emm(&mut self, mm : &mut MM)->EMMServer{
         let mut emm_data = HashMap::new();
-        let key = gen_key();
+        let key = self.key;
+        let mut lists = HashMap::new();
         
         for (keyword, v) in &mm.data{
+            lists.insert(keyword.clone(), ListState{ generation : 0, epoch : 0, count : v.len() as u64, state : None });
             let key_pair = prf(&keyword[..], &key[..]);
             let key1 = &key_pair[0..16];
             let key2 = &key_pair[16..];
             
-            let mut c = 0;
+            for (c, id) in (0i32..).zip(v.iter()){
+                let counter_bytes = &bincode::serialize(&c).unwrap()[..];
+                let enc_key = prf(counter_bytes, key1);
+                let enc_value = rand_encrypt(&id[..], key2, &enc_key);
+                emm_data.insert(enc_key, enc_value);
+            }
+        }
+        self.state = Some(EMMState{ lists, searched : HashSet::new() });
+        self.restored = false;
+        self.pending = HashMap::new();
+        EMMServer {
+            data : emm_data,
+            tdp_key : None
+        }
+    }
+    
+    /// Encrypts more values for keywords that may already hold some. A keyword
+    /// nobody has searched since its newest epoch began carries on from that
+    /// epoch's last counter. Any other keyword starts a new epoch, so its old
+    /// tokens reveal nothing about these values. The state only moves on once
+    /// the server has stored the entries and `confirm_update` is called.
+    pub fn update_emm(&mut self, mm : &MM) -> Result<EMMUpdate>{
+        if self.state.is_none() {
+            return Err(Error::Crypto("Only the client that set up the edb, or one restored with its saved keys, knows where to add entries to it".to_string()));
+        }
+        let mut emm_data = HashMap::new();
+        let mut pending = HashMap::new();
+        for (keyword, v) in &mm.data{
+            let (list, searched) = match &self.state {
+                Some(state) => (state.lists.get(keyword).cloned(), state.searched.contains(keyword)),
+                None => (None, false)
+            };
+            let searched = searched || (list.is_none() && self.restored);
+            let mut list = list.unwrap_or(ListState{ generation : 0, epoch : 0, count : 0, state : None });
+            let name = list_name(keyword, list.generation);
+            if searched {
+                let next_state = match &list.state {
+                    Some(epoch_state) => self.tdp_key().invert(epoch_state),
+                    None => self.first_state(&name)
+                };
+                list = ListState{ generation : list.generation, epoch : list.epoch + 1, count : 0, state : Some(next_state) };
+            }
+            let (key1, key2) = self.epoch_keys(&name, &list.state);
             
+            // the same counter type as setup_emm and eval_list, so the labels line up
+            let mut c = list.count as i32;
             for id in v.iter(){
                 let counter_bytes = &bincode::serialize(&c).unwrap()[..];
-                let enc_key = prf(&counter_bytes, key1);
-                let enc_value = fixed_encrypt(&id[..], key2);
+                let enc_key = prf(counter_bytes, &key1);
+                let enc_value = rand_encrypt(&id[..], &key2, &enc_key);
                 emm_data.insert(enc_key, enc_value);
-                c = c + 1;
+                c += 1;
             }
+            list.count = c as u64;
+            pending.insert(keyword.clone(), list);
         }
-        self.key = key;
-        EMMServer {
-            data : emm_data
+        self.pending = pending;
+        Ok(EMMUpdate {
+            entries : emm_data,
+            tdp_key : self.tdp_key.as_ref().map(|tdp_key| tdp_key.public_key())
+        })
+    }
+    
+    /// Replaces the whole lists of `keywords` with the lists `mm` holds for them,
+    /// or with empty ones where it holds none, e.g. once rows are deleted. Each
+    /// list is written again as a new generation, under keys no token issued so
+    /// far knows, and the server gets a token for the old generation with which
+    /// it finds and drops every old entry. Searches made after the rewrite thus
+    /// neither return nor reveal the entries that were taken out. The state only
+    /// moves on once the server has applied the rewrite and `confirm_update` is called.
+    pub fn rewrite_emm(&mut self, keywords : &HashSet<Vec<u8>>, mm : &MM) -> Result<EMMRewrite>{
+        if self.state.is_none() {
+            return Err(Error::Crypto("Only the client that set up the edb, or one restored with its saved keys, knows where to add entries to it".to_string()));
+        }
+        let mut removals = Vec::new();
+        let mut emm_data = HashMap::new();
+        let mut pending = HashMap::new();
+        let empty = Vec::new();
+        for keyword in keywords{
+            let old_list = self.state.as_ref().and_then(|state| state.lists.get(keyword)).cloned();
+            let generation = match old_list {
+                Some(old_list) => {
+                    let (key1, _) = self.epoch_keys(&list_name(keyword, old_list.generation), &None);
+                    removals.push(EMMToken {
+                        label_key : key1,
+                        value_key : None,
+                        epochs : old_list.state.clone().map(|epoch_state| (epoch_state, old_list.epoch))
+                    });
+                    old_list.generation + 1
+                },
+                None => 1
+            };
+            let (key1, key2) = self.epoch_keys(&list_name(keyword, generation), &None);
+            
+            let mut c : i32 = 0;
+            for id in mm.data.get(keyword).unwrap_or(&empty).iter(){
+                let counter_bytes = &bincode::serialize(&c).unwrap()[..];
+                let enc_key = prf(counter_bytes, &key1);
+                let enc_value = rand_encrypt(&id[..], &key2, &enc_key);
+                emm_data.insert(enc_key, enc_value);
+                c += 1;
+            }
+            pending.insert(keyword.clone(), ListState{ generation, epoch : 0, count : c as u64, state : None });
+        }
+        self.pending = pending;
+        Ok(EMMRewrite {
+            removals,
+            entries : emm_data
+        })
+    }
+    
+    /// Records that the server stored the entries from the last `update_emm` or `rewrite_emm`.
+    pub fn confirm_update(&mut self){
+        if let Some(state) = &mut self.state {
+            for (keyword, list) in self.pending.drain() {
+                state.searched.remove(&keyword);
+                state.lists.insert(keyword, list);
+            }
         }
     }
     
-    pub fn tokenize_emm(&mut self, keyword : &Vec<u8>) -> ([u8; 16], [u8; 16]){
-        let key_pair = prf(&keyword[..], &self.key[..]);
-        let key1_slice = &key_pair[0..16];
-        let key2_slice = &key_pair[16..];
+    pub fn get_state(&self) -> Option<EMMState> {
+        self.state.clone()
+    }
+    
+    /// Takes up a state saved with the keys. Tokens issued since it was saved
+    /// were not recorded in it, so every keyword counts as searched: each list
+    /// starts a new epoch at its next insert, as does any list made after this.
+    pub fn restore_state(&mut self, state : Option<EMMState>) {
+        self.restored = state.is_some();
+        self.state = state.map(|mut state| {
+            state.searched = state.lists.keys().cloned().collect();
+            state
+        });
+        self.pending = HashMap::new();
+    }
+    
+    pub fn tokenize_emm(&mut self, keyword : &Vec<u8>) -> EMMToken{
+        let mut generation = 0;
+        let mut epochs = None;
+        if let Some(state) = &mut self.state {
+            state.searched.insert(keyword.clone());
+            if let Some(list) = state.lists.get(keyword) {
+                generation = list.generation;
+                if let Some(epoch_state) = &list.state {
+                    epochs = Some((epoch_state.clone(), list.epoch));
+                }
+            }
+        }
+        let (key1, key2) = self.epoch_keys(&list_name(keyword, generation), &None);
+        let tk = EMMToken {
+            label_key : key1,
+            value_key : Some(key2),
+            epochs
+        };
+        self.last_token = Some(tk.clone());
+        tk
+    }
+    
+    /// A token with which the server finds the values but cannot read them.
+    pub fn tokenize_emm_rh(&mut self, keyword : &Vec<u8>) -> EMMToken{
+        let mut tk = self.tokenize_emm(keyword);
+        tk.value_key = None;
+        tk
+    }
+    
+    pub fn decrypt_emm(&mut self, enc_results : Vec<LabelledCiphertext>) -> Result<Vec<Vec<u8>>>{
+        // the value keys of every epoch the last token reaches
+        let mut keys = Vec::new();
+        if let Some(EMMToken{ value_key : Some(key2), epochs, .. }) = self.last_token.clone() {
+            keys.push(key2);
+            if let Some((mut epoch_state, epoch)) = epochs {
+                let public_key = self.tdp_key().public_key();
+                for _ in 0..epoch {
+                    keys.push(derive_epoch_key(&epoch_state, &key2));
+                    epoch_state = public_key.apply(&epoch_state)?;
+                }
+            }
+        }
+        decrypt_emm_helper(&keys, enc_results)
+    }
+    
+    fn tdp_key(&mut self) -> &TdpKey {
+        let key = self.key;
+        self.tdp_key.get_or_insert_with(|| TdpKey::from_seed(prf(b"emm/tdp", &key[..])))
+    }
+    
+    // The state of a list's first epoch after the one it was written in
+    fn first_state(&mut self, name : &[u8]) -> Vec<u8> {
+        let state_key = prf(b"emm/states", &self.key[..]);
+        let seed = prf(name, &state_key[..16]);
+        self.tdp_key().sample(seed)
+    }
+    
+    // The label and value keys of a list's newest epoch
+    fn epoch_keys(&self, name : &[u8], epoch_state : &Option<Vec<u8>>) -> ([u8; 16], [u8; 16]) {
+        let key_pair = prf(name, &self.key[..]);
         let mut key1 = [0u8; 16];
-        key1.copy_from_slice(key1_slice);
+        key1.copy_from_slice(&key_pair[0..16]);
         let mut key2 = [0u8; 16];
-        key2.copy_from_slice(key2_slice);
-        self.last_token_key = key2;
-        (key1, key2)
+        key2.copy_from_slice(&key_pair[16..]);
+        match epoch_state {
+            Some(epoch_state) => (derive_epoch_key(epoch_state, &key1), derive_epoch_key(epoch_state, &key2)),
+            None => (key1, key2)
+        }
     }
-    
-    pub fn decrypt_emm(&mut self, enc_results : Vec<Vec<u8>>) -> Vec<Vec<u8>>{
-        decrypt_emm_helper(&self.last_token_key[..], enc_results)
+}
+
+// What a keyword's list is keyed by. Setup's generation is keyed by the keyword
+// alone, so the keys of an edb that was never rewritten stay as they were
+fn list_name(keyword : &Vec<u8>, generation : u64) -> Vec<u8> {
+    if generation == 0 {
+        keyword.clone()
+    } else {
+        bincode::serialize(&(keyword, generation)).unwrap()
     }
 }
 
+// A key of an epoch after setup's, from the epoch's state and the keyword's key
+fn derive_epoch_key(epoch_state : &[u8], key : &[u8; 16]) -> [u8; 16] {
+    let mut epoch_key = [0u8; 16];
+    epoch_key.copy_from_slice(&prf(epoch_state, &key[..])[0..16]);
+    epoch_key
+}
+
 #[derive(Serialize, Deserialize)]
 #[derive(Clone)]
 pub struct EMMServer {
     data: HashMap<[u8; 32], Vec<u8>>,
+    tdp_key : Option<TdpPublicKey>,
 }
 
 impl EMMServer {
     pub fn new_emm_server() -> EMMServer {
         EMMServer {
-            data : HashMap::new()
+            data : HashMap::new(),
+            tdp_key : None
         }
     }
     
-    pub fn eval_emm_rh(&mut self, key : &[u8]) -> Vec<Vec<u8>> {
+    /// Stores entries made by `EMMClient::update_emm`. Nothing is stored if any
+    /// of them would replace an entry already there.
+    pub fn add_entries(&mut self, update : EMMUpdate) -> Result<()> {
+        if update.entries.keys().any(|enc_key| self.data.contains_key(enc_key)) {
+            return Err(Error::ServerState("An update tried to overwrite entries of the edb".to_string()));
+        }
+        self.data.extend(update.entries);
+        if update.tdp_key.is_some() {
+            self.tdp_key = update.tdp_key;
+        }
+        Ok(())
+    }
+    
+    /// Applies a rewrite made by `EMMClient::rewrite_emm`: every entry of the old
+    /// lists is dropped and the new lists are stored. Nothing changes if a new
+    /// entry would replace one that is kept.
+    pub fn rewrite_entries(&mut self, rewrite : EMMRewrite) -> Result<()> {
+        let mut removed = HashSet::new();
+        for tk in &rewrite.removals{
+            for (label_key, _) in self.epochs(tk){
+                removed.extend(self.list_labels(&label_key));
+            }
+        }
+        if rewrite.entries.keys().any(|enc_key| self.data.contains_key(enc_key) && !removed.contains(enc_key)) {
+            return Err(Error::ServerState("A rewrite tried to overwrite entries of the edb".to_string()));
+        }
+        for enc_key in removed{
+            self.data.remove(&enc_key);
+        }
+        self.data.extend(rewrite.entries);
+        Ok(())
+    }
+    
+    /// The values of the token's keyword with the labels they are stored under,
+    /// which the client needs to authenticate them.
+    pub fn eval_emm_rh(&self, tk : &EMMToken) -> Vec<LabelledCiphertext> {
+        self.epochs(tk).iter().flat_map(|(label_key, _)| self.eval_list(label_key)).collect()
+    }
+    
+    pub fn eval_emm_rr(&self, tk : &EMMToken) -> Vec<Vec<u8>> {
+        let mut results = Vec::new();
+        for (label_key, value_key) in self.epochs(tk){
+            if let Some(value_key) = value_key {
+                // Values that fail authentication were not written by the client, so they
+                // are dropped rather than handed back as matches
+                results.extend(self.eval_list(&label_key).iter()
+                    .filter_map(|(enc_key, enc_value)| rand_decrypt(&enc_value[..], &value_key, enc_key).ok()));
+            }
+        }
+        results
+    }
+    
+    // The label and value keys of every epoch the token reaches, oldest first
+    fn epochs(&self, tk : &EMMToken) -> Vec<([u8; 16], Option<[u8; 16]>)> {
+        let mut epochs = Vec::new();
+        if let (Some((epoch_state, epoch)), Some(tdp_key)) = (&tk.epochs, &self.tdp_key) {
+            let mut epoch_state = epoch_state.clone();
+            for _ in 0..*epoch {
+                epochs.push((derive_epoch_key(&epoch_state, &tk.label_key),
+                             tk.value_key.map(|value_key| derive_epoch_key(&epoch_state, &value_key))));
+                epoch_state = match tdp_key.apply(&epoch_state) {
+                    Ok(prev_state) => prev_state,
+                    Err(_) => break
+                };
+            }
+        }
+        epochs.push((tk.label_key, tk.value_key));
+        epochs.reverse();
+        epochs
+    }
+    
+    fn eval_list(&self, key : &[u8]) -> Vec<LabelledCiphertext> {
+        self.list_labels(key).iter().map(|enc_key| (enc_key.to_vec(), self.data[enc_key].clone())).collect()
+    }
+    
+    // The labels of a list's entries, in order
+    fn list_labels(&self, key : &[u8]) -> Vec<[u8; 32]> {
         let mut c = 0;
-        let mut enc_results = Vec::new();
+        let mut labels = Vec::new();
         
         loop {
             let counter_bytes = &bincode::serialize(&c).unwrap()[..];
-            let enc_key = prf(&counter_bytes, key);
-            let poss_val = self.data.get(&enc_key);
-            match poss_val {
-                Some(enc_value) => {
-                    let enc_value_clone = enc_value.clone();
-                    enc_results.push(enc_value_clone);
-                },
-                None  => break,
+            let enc_key = prf(counter_bytes, key);
+            if !self.data.contains_key(&enc_key) {
+                break;
             }
-            c = c + 1;
+            labels.push(enc_key);
+            c += 1;
         }
         
-        enc_results
-    }
-    
-    pub fn eval_emm_rr(&mut self, key1 : &[u8], key2 : &[u8]) -> Vec<Vec<u8>> {
-        let enc_results = self.eval_emm_rh(key1);
-        decrypt_emm_helper(key2, enc_results)
+        labels
     }
 }
 
-fn decrypt_emm_helper(key : &[u8], enc_results : Vec<Vec<u8>>) -> Vec<Vec<u8>>{
+// Each value is decrypted with whichever epoch's key authenticates it
+fn decrypt_emm_helper(keys : &[[u8; 16]], enc_results : Vec<LabelledCiphertext>) -> Result<Vec<Vec<u8>>>{
     let mut dec_results = Vec::new();
     
-    for enc_value in enc_results{
-        let dec_value = fixed_decrypt(&enc_value[..], key);
-        dec_results.push(dec_value);
+    for (enc_key, enc_value) in enc_results{
+        match keys.iter().find_map(|key| rand_decrypt(&enc_value[..], &key[..], &enc_key[..]).ok()) {
+            Some(dec_value) => dec_results.push(dec_value),
+            None => return Err(Error::Crypto("A value from the server does not decrypt under the token's keys".to_string()))
+        }
     }
     
-    dec_results
-}
\ No newline at end of file
+    Ok(dec_results)
+}
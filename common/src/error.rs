@@ -0,0 +1,49 @@
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+/// Every way an operation in this crate, the client or the server can fail. The
+/// server sends these back inside `SearchFailure` and `CommandFailure`, so the
+/// client sees the same variant the server produced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub enum Error {
+    /// A query or input file could not be parsed.
+    Parse(String),
+    /// A relation or attribute is missing from the schema, or a row does not fit it.
+    Schema(String),
+    /// A join was asked for on attributes that were not annotated, or an annotation is invalid.
+    Annotation(String),
+    /// A ciphertext failed authentication or a key could not be used or derived.
+    Crypto(String),
+    /// A message, frame or channel handshake was malformed or out of place.
+    Protocol(String),
+    /// The server cannot act on a request given what it holds, e.g. a token
+    /// that does not match its edb or an edb name it does not know.
+    ServerState(String),
+    /// Reading or writing a file or socket failed.
+    Io(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(s) => write!(f, "Parse error: {}", s),
+            Error::Schema(s) => write!(f, "Schema error: {}", s),
+            Error::Annotation(s) => write!(f, "Annotation error: {}", s),
+            Error::Crypto(s) => write!(f, "Crypto error: {}", s),
+            Error::Protocol(s) => write!(f, "Protocol error: {}", s),
+            Error::ServerState(s) => write!(f, "Server error: {}", s),
+            Error::Io(s) => write!(f, "IO error: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e : std::io::Error) -> Error {
+        Error::Io(e.to_string())
+    }
+}
@@ -1,9 +1,14 @@
+use std::collections::HashMap;
 use std::collections::HashSet;
 
 pub struct RefTable {
     pub refs : Vec<RefRow>,
     ids : HashSet<String>,
     save_cols : HashSet<usize>,
+    // the instance of a relation each cell of a row belongs to. A relation
+    // joined with itself has the same columns twice, so the cells of one
+    // instance can only be told apart by where they are
+    layout : Vec<String>,
 }
 
 impl RefTable {
@@ -14,23 +19,25 @@ impl RefTable {
     //     }
     // }
     
-    pub fn from_id(refs : Vec<RefRow>, id: String) -> RefTable{
+    pub fn from_id(refs : Vec<RefRow>, id: String, length : usize) -> RefTable{
         let mut ids = HashSet::new();
-        ids.insert(id);
+        ids.insert(id.to_string());
         let mut save_cols = HashSet::new();
         save_cols.insert(0);
         RefTable {
-            refs : refs,
-            ids : ids,
-            save_cols : save_cols
+            refs,
+            ids,
+            save_cols,
+            layout : vec![id; length]
         }
     }
     
-    fn from_table(refs : Vec<RefRow>, ids: HashSet<String>, save_cols : HashSet<usize>) -> RefTable{
+    fn from_table(refs : Vec<RefRow>, ids: HashSet<String>, save_cols : HashSet<usize>, layout : Vec<String>) -> RefTable{
         RefTable {
-            refs : refs,
-            ids : ids,
-            save_cols : save_cols
+            refs,
+            ids,
+            save_cols,
+            layout
         }
     }
     
@@ -38,51 +45,89 @@ impl RefTable {
         self.ids.contains(id)
     }
     
-    pub fn filter_refs(&mut self, matches : &Vec<Vec<u8>>){
+    /// Where the cells of the instance `id` are in each row.
+    pub fn cells_of(&self, id : &String) -> Vec<usize> {
+        self.layout.iter().enumerate().filter(|(_, owner)| *owner == id).map(|(i, _)| i).collect()
+    }
+    
+    pub fn filter_refs(&mut self, matches : &[Vec<u8>]){
+        let mut kept_cols = None;
         for ref_row in &mut self.refs{
-            (*ref_row).filter_refs(matches, &self.save_cols);
+            let kept = (*ref_row).filter_refs(matches, &self.save_cols);
+            kept_cols.get_or_insert(kept);
+        }
+        // every row has the same columns, so they all keep the same cells
+        if let Some(kept) = kept_cols {
+            self.layout = kept.iter().map(|i| self.layout[*i].to_string()).collect();
         }
     }
     
-    pub fn filter_rows(&mut self, matches : &Vec<Vec<u8>>){
-        &self.refs.retain(|t| matches.iter().any(|match_at| t.has_cell(match_at)));
+    /// Keeps the rows where a cell of the instance `id` is one of `matches`.
+    pub fn filter_rows(&mut self, matches : &[Vec<u8>], id : &String){
+        let cols = self.cells_of(id);
+        self.refs.retain(|t| matches.iter().any(|match_at| t.has_cell(match_at, &cols)));
     }
     
-    pub fn concat_table(&self, other_ref_table : &RefTable, matches : &HashSet<([u8; 32], [u8; 32])>)
-                    -> RefTable{
+    /// Keeps the rows whose cells of the instance `id` satisfy the filter.
+    pub fn filter_rows_by(&mut self, filter : &RowFilter, id : &String){
+        let cols = self.cells_of(id);
+        self.refs.retain(|t| filter.keeps(t, &cols));
+    }
+    
+    /// Joins each row of this table to each row of the other whose handles for
+    /// the instances `id1` and `id2` form one of the matched pairs.
+    pub fn concat_table(&self, other_ref_table : &RefTable, matches : &HashSet<([u8; 32], [u8; 32])>,
+                        id1 : &String, id2 : &String) -> RefTable{
         let mut new_refs = Vec::new();
         for ref_row1 in &self.refs{
             for ref_row2 in &other_ref_table.refs{
-                let mut pairs = HashSet::new();
-                for uk1 in &ref_row1.unique_keys{
-                    for uk2 in &ref_row2.unique_keys{
-                        pairs.insert((uk1.clone(), uk2.clone()));
+                if let (Some(uk1), Some(uk2)) = (ref_row1.unique_keys.get(id1), ref_row2.unique_keys.get(id2)) {
+                    if matches.contains(&(*uk1, *uk2)){
+                        new_refs.push((*ref_row1).concat_row(ref_row2));
                     }
                 }
-                if !pairs.is_disjoint(matches){
-                    new_refs.push((*ref_row1).concat_row(ref_row2));
-                }
             }
         }
-        let new_ids : HashSet<_> = self.ids.union(&other_ref_table.ids).map(|id| id.clone()).collect();
+        let new_ids : HashSet<_> = self.ids.union(&other_ref_table.ids).cloned().collect();
         let mut new_save_cols = self.save_cols.clone();
-        if !self.refs.is_empty() {
-            let offset = self.refs[0].cells.len();
-            for save_col in &other_ref_table.save_cols{
-                new_save_cols.insert(save_col + offset);
-            }
+        let offset = self.layout.len();
+        for save_col in &other_ref_table.save_cols{
+            new_save_cols.insert(save_col + offset);
         }
-        RefTable::from_table(new_refs, new_ids, new_save_cols)
+        let mut new_layout = self.layout.clone();
+        new_layout.extend(other_ref_table.layout.iter().cloned());
+        RefTable::from_table(new_refs, new_ids, new_save_cols, new_layout)
     }
     
     pub fn add_save_col(&mut self, save_col : usize){
-        &self.save_cols.insert(save_col);
+        self.save_cols.insert(save_col);
+    }
+}
+
+/// A selection predicate whose emm lookups the server has already made.
+pub enum RowFilter {
+    Match(HashSet<Vec<u8>>),
+    And(Box<RowFilter>, Box<RowFilter>),
+    Or(Box<RowFilter>, Box<RowFilter>),
+    Not(Box<RowFilter>),
+}
+
+impl RowFilter {
+    // Whether the row passes, looking only at the cells in `cols`
+    fn keeps(&self, ref_row : &RefRow, cols : &Vec<usize>) -> bool {
+        match self {
+            RowFilter::Match(matches) => cols.iter().any(|i| matches.contains(&ref_row.cells[*i])),
+            RowFilter::And(left, right) => left.keeps(ref_row, cols) && right.keeps(ref_row, cols),
+            RowFilter::Or(left, right) => left.keeps(ref_row, cols) || right.keeps(ref_row, cols),
+            RowFilter::Not(sub) => !sub.keeps(ref_row, cols)
+        }
     }
 }
 
 pub struct RefRow {
     pub cells : Vec<Vec<u8>>,
-    unique_keys : HashSet<[u8; 32]>,
+    // the handle of the row of each instance joined into this one
+    unique_keys : HashMap<String, [u8; 32]>,
 }
 
 impl RefRow {
@@ -93,24 +138,25 @@ impl RefRow {
     //     }
     // }
     
-    pub fn from_uk(cells : Vec<Vec<u8>>, unique_key: [u8; 32]) -> RefRow{
-        let mut uks = HashSet::new();
-        uks.insert(unique_key);
+    pub fn from_uk(cells : Vec<Vec<u8>>, unique_key: [u8; 32], id : &String) -> RefRow{
+        let mut uks = HashMap::new();
+        uks.insert(id.to_string(), unique_key);
         RefRow {
-            cells : cells,
+            cells,
             unique_keys : uks
         }
     }
     
-    fn from_row(cells : Vec<Vec<u8>>, unique_keys: HashSet<[u8; 32]>) -> RefRow{
+    fn from_row(cells : Vec<Vec<u8>>, unique_keys: HashMap<String, [u8; 32]>) -> RefRow{
         RefRow {
-            cells : cells,
-            unique_keys : unique_keys
+            cells,
+            unique_keys
         }
     }
     
     fn concat_row(&self, other_ref_row : &RefRow) -> RefRow{
-        let new_uks : HashSet<_> = self.unique_keys.union(&other_ref_row.unique_keys).map(|uk| *uk).collect();
+        let mut new_uks = self.unique_keys.clone();
+        new_uks.extend(other_ref_row.unique_keys.iter().map(|(id, uk)| (id.to_string(), *uk)));
         let mut new_cells = self.cells.clone();
         for cell in &other_ref_row.cells{
             new_cells.push(cell.clone());
@@ -122,12 +168,22 @@ impl RefRow {
     //     self.unique_keys.contains(unique_key)
     // }
     
-    fn has_cell(&self, cell : &Vec<u8>)-> bool {
-        self.cells.iter().any(|c| c == cell)
+    fn has_cell(&self, cell : &Vec<u8>, cols : &[usize])-> bool {
+        cols.iter().any(|i| self.cells[*i] == *cell)
     }
     
-    fn filter_refs(&mut self, matches : &Vec<Vec<u8>>, save_cols : &HashSet<usize>){
+    // Returns where the kept cells were
+    fn filter_refs(&mut self, matches : &[Vec<u8>], save_cols : &HashSet<usize>) -> Vec<usize>{
         let mut i = 0;
-        &self.cells.retain(|at| (matches.iter().any(|match_at| match_at == at || save_cols.contains(&i)), i += 1).0);
+        let mut kept = Vec::new();
+        self.cells.retain(|at| {
+            let keep = matches.iter().any(|match_at| match_at == at || save_cols.contains(&i));
+            if keep {
+                kept.push(i);
+            }
+            i += 1;
+            keep
+        });
+        kept
     }
 }
\ No newline at end of file
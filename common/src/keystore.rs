@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use serde::{Deserialize, Serialize};
+use argon2::Argon2;
+use rand_core::{RngCore, OsRng};
+use crate::crypto_helper::{ rand_encrypt, rand_decrypt };
+use crate::value::ValueType;
+use crate::emm::EMMState;
+use crate::error::{ Error, Result };
+
+/// Everything a client needs to query an edb that was set up in an earlier session.
+#[derive(Serialize, Deserialize)]
+pub struct KeyStore {
+    pub master_key : [u8; 32],
+    pub schema : HashMap<String, Vec<String>>,
+    pub annotations : HashSet<(Vec<String>, Vec<String>)>,
+    pub conjunctions : HashSet<(String, String)>,
+    pub ordered : HashSet<String>,
+    pub ore : HashSet<String>,
+    pub summable : HashSet<String>,
+    pub grouped : HashSet<String>,
+    pub types : HashMap<String, ValueType>,
+    // where inserts go in each index list, absent if the edb was never set up
+    pub emm_state : Option<EMMState>,
+    // a hash of each relation's rows as of the last setup or change, see `rows_digest`
+    pub digests : HashMap<String, [u8; 32]>,
+}
+
+// What actually lands on disk: the salt for the passphrase key and the sealed keystore
+#[derive(Serialize, Deserialize)]
+struct KeyStoreFile {
+    salt : [u8; 16],
+    sealed : Vec<u8>,
+}
+
+impl KeyStore {
+    pub fn save(&self, path : &str, passphrase : &str) -> Result<()> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let key = passphrase_key(passphrase, &salt)?;
+        let plain_bytes = bincode::serialize(self).map_err(|e| Error::Io(format!("Could not serialize the keystore: {}", e)))?;
+        let file = KeyStoreFile {
+            salt,
+            sealed : rand_encrypt(&plain_bytes[..], &key[..], &salt[..])
+        };
+        let file_bytes = bincode::serialize(&file).map_err(|e| Error::Io(format!("Could not serialize the keystore: {}", e)))?;
+        fs::write(path, file_bytes).map_err(|e| Error::Io(format!("Could not write the keystore to {}: {}", path, e)))
+    }
+
+    pub fn load(path : &str, passphrase : &str) -> Result<KeyStore> {
+        let file_bytes = fs::read(path).map_err(|e| Error::Io(format!("Could not read a keystore from {}: {}", path, e)))?;
+        let file : KeyStoreFile = bincode::deserialize(&file_bytes[..])
+            .map_err(|_| Error::Parse(format!("{} is not a keystore file", path)))?;
+        let key = passphrase_key(passphrase, &file.salt)?;
+        let plain_bytes = rand_decrypt(&file.sealed[..], &key[..], &file.salt[..])
+            .map_err(|_| Error::Crypto("Wrong passphrase or the keystore has been modified".to_string()))?;
+        bincode::deserialize(&plain_bytes[..]).map_err(|e| Error::Parse(format!("Could not read the keystore contents: {}", e)))
+    }
+}
+
+fn passphrase_key(passphrase : &str, salt : &[u8; 16]) -> Result<[u8; 16]> {
+    let mut key = [0u8; 16];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), &salt[..], &mut key)
+        .map_err(|e| Error::Crypto(format!("Could not derive a key from the passphrase: {}", e)))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keystore() -> KeyStore {
+        let mut schema = HashMap::new();
+        schema.insert("emp".to_string(), vec!("eid".to_string(), "name".to_string()));
+        let mut types = HashMap::new();
+        types.insert("eid".to_string(), ValueType::Int);
+        types.insert("name".to_string(), ValueType::Text);
+        let mut digests = HashMap::new();
+        digests.insert("emp".to_string(), [3u8; 32]);
+        KeyStore {
+            master_key : [7u8; 32],
+            schema,
+            annotations : HashSet::new(),
+            conjunctions : HashSet::new(),
+            ordered : vec!("eid".to_string()).into_iter().collect(),
+            ore : HashSet::new(),
+            summable : HashSet::new(),
+            grouped : HashSet::new(),
+            types,
+            emm_state : None,
+            digests
+        }
+    }
+
+    fn temp_path(name : &str) -> String {
+        std::env::temp_dir().join(format!("keystore-test-{}-{}", name, std::process::id())).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = temp_path("round-trip");
+        keystore().save(&path, "correct horse").unwrap();
+        let loaded = KeyStore::load(&path, "correct horse");
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.master_key, [7u8; 32]);
+        assert_eq!(loaded.schema, keystore().schema);
+        assert_eq!(loaded.ordered, keystore().ordered);
+        assert_eq!(loaded.types, keystore().types);
+        assert_eq!(loaded.digests, keystore().digests);
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let path = temp_path("passphrase");
+        keystore().save(&path, "correct horse").unwrap();
+        let loaded = KeyStore::load(&path, "battery staple");
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(loaded, Err(Error::Crypto(_))));
+    }
+
+    #[test]
+    fn tampered_or_foreign_files_are_rejected() {
+        let path = temp_path("tampered");
+        keystore().save(&path, "correct horse").unwrap();
+        let mut file_bytes = fs::read(&path).unwrap();
+        // the salt is the passphrase key's and the ciphertext's associated data
+        file_bytes[0] ^= 1;
+        fs::write(&path, &file_bytes).unwrap();
+        let tampered = KeyStore::load(&path, "correct horse");
+        fs::write(&path, b"not a keystore").unwrap();
+        let foreign = KeyStore::load(&path, "correct horse");
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(tampered, Err(Error::Crypto(_))));
+        assert!(matches!(foreign, Err(Error::Parse(_))));
+    }
+}
@@ -1,24 +1,55 @@
 use std::collections::HashMap;
 use std::collections::HashSet;
 // use histogram::Histogram;
-use crate::db_structs::DB;
+use crate::db_structs::{ DB, join_key };
 use crate::token::{ HybQuery, BoolQuery };
-use std::cmp::max;
+use crate::sti::{ pair_conjuncts, ConjunctGroups, COUNT_CIPHERTEXT_BYTES };
+use crate::range::{ range_cover, key_bounds };
+use crate::value::{ Value, ValueType };
+use crate::paillier::CIPHERTEXT_BYTES;
+use crate::error::{ Error, Result };
+use std::cmp::{ min, max };
+
+/// Rows and bytes the server would send back for a query and how many volumes it would learn.
+/// `order_leakage` lists each attribute the server sorts on with how many rows it
+/// learns the order of. `count_leakage` is the number of rows the server counts
+/// for a `COUNT`, `SUM` or `AVG`, which it learns although it sends it back encrypted.
+pub struct Estimate {
+    pub rows : usize,
+    pub bytes : usize,
+    pub volumes : usize,
+    pub order_leakage : Vec<(String, usize)>,
+    pub count_leakage : Option<usize>,
+}
 
 pub struct Statistics{
-    hists : HashMap<String, HashMap<String, usize>>,
+    hists : HashMap<String, HashMap<Value, usize>>,
+    // how many rows hold each combination of values of the attributes of a
+    // side of a composite join annotation
+    joint_hists : HashMap<Vec<String>, HashMap<Vec<Value>, usize>>,
     sizes : HashMap<String, usize>,
     widths : HashMap<String, usize>,
-    schema : HashMap<String, Vec<String>>
+    schema : HashMap<String, Vec<String>>,
+    conjunctions : HashSet<(String, String)>,
+    types : HashMap<String, ValueType>
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Statistics {
     pub fn new()-> Statistics{
         Statistics{
             hists: HashMap::new(),
+            joint_hists: HashMap::new(),
             sizes: HashMap::new(),
             widths: HashMap::new(),
             schema: HashMap::new(),
+            conjunctions: HashSet::new(),
+            types: HashMap::new(),
         }
     }
     
@@ -30,31 +61,77 @@ impl Statistics {
             sizes.insert(id.to_string(), rel.table.len());
             for (i, at) in rel.get_ats().iter().enumerate(){
                 let mut width = 0;
-                let mut h : HashMap<std::string::String, usize> = HashMap::new();
+                let mut h : HashMap<Value, usize> = HashMap::new();
                 for row in &rel.table{
                     // h.increment(row[i]);
                     if h.contains_key(&row[i]){
-                        h.insert(row[i].to_string(), &h.get(&row[i]).unwrap().clone() + 1);
+                        h.insert(row[i].clone(), &h.get(&row[i]).unwrap().clone() + 1);
                     } else {
-                        h.insert(row[i].to_string(), 1);
+                        h.insert(row[i].clone(), 1);
                     }
-                    width = max(width, (row[i].as_bytes().len() + 15) & !15usize);
+                    width = max(width, (row[i].to_string().len() + 15) & !15usize);
                 }
                 widths.insert(at.to_string(), width);
                 hists.insert(at.to_string(), h);
             }
         }
+        let mut joint_hists = HashMap::new();
+        for (ats1, ats2) in &db.annotations{
+            for ats in [ats1, ats2]{
+                let rel = match db.get_id_from_ats(ats).and_then(|id| db.get_rel(&id)) {
+                    Some(rel) if ats.len() > 1 => rel,
+                    _ => continue
+                };
+                let cols = rel.positions(ats).unwrap();
+                let mut h : HashMap<Vec<Value>, usize> = HashMap::new();
+                for row in &rel.table{
+                    if let Some(value) = join_key(row, &cols) {
+                        *h.entry(value).or_insert(0) += 1;
+                    }
+                }
+                joint_hists.insert(ats.clone(), h);
+            }
+        }
         Statistics{
-            hists: hists,
-            sizes: sizes,
-            widths: widths,
+            hists,
+            joint_hists,
+            sizes,
+            widths,
             schema: db.get_schema(),
+            conjunctions: db.conjunctions.clone(),
+            types: db.get_types(),
         }
     }
     
     pub fn estimate_query(&self, qry: HybQuery){
-        let ats_all = self.get_ats_from_qry(&qry).0;
-        let results = self.estimate_query_helper(qry);
+        match self.estimate(qry) {
+            Ok(estimate) => {
+                println!("Estimated bandwidth: {} rows with a size of {} bytes will be sent from the server to the client", estimate.rows, estimate.bytes);
+                println!("Estimated volumes leaked: {} volumes will be leaked to the server", estimate.volumes);
+                for (attrib, rows) in &estimate.order_leakage {
+                    println!("Estimated order leaked: the server will learn the order of {} rows of {}", rows, attrib);
+                }
+                if let Some(count) = estimate.count_leakage {
+                    println!("Estimated count leaked: the server will count {} rows, though the count it sends back is encrypted", count);
+                }
+            },
+            Err(e) => println!("{}", e)
+        }
+    }
+    
+    pub fn estimate(&self, qry: HybQuery) -> Result<Estimate>{
+        if let HybQuery::BadQuery(s) = qry {
+            return Err(Error::Parse(s));
+        }
+        let qry = match qry {
+            HybQuery::Count(sub_query) => return self.estimate_aggregate(*sub_query, COUNT_CIPHERTEXT_BYTES),
+            HybQuery::Sum(_, _, sub_query) => return self.estimate_aggregate(*sub_query, CIPHERTEXT_BYTES + COUNT_CIPHERTEXT_BYTES),
+            HybQuery::Group(attrib, _) => return self.estimate_group(&attrib),
+            qry => qry
+        };
+        let ats_all = self.get_ats_from_qry(&qry)?.0;
+        let order_leakage = self.get_order_leakage(&qry)?;
+        let results = self.estimate_query_helper(qry)?;
         let mut total_rs = 0;
         let mut total_b = 0;
         let mut total_v = 0;
@@ -62,78 +139,166 @@ impl Statistics {
             let ats = &ats_all[i];
             let mut row_width = 0;
             for at in ats{
-                row_width = row_width + self.widths.get(at).unwrap();
+                row_width += self.widths.get(at).unwrap_or(&0);
             }
-            total_rs = total_rs + b;
-            total_b = total_b + (b * row_width);
-            total_v = total_v + v;
+            total_rs += b;
+            total_b += b * row_width;
+            total_v += v;
+        }
+        Ok(Estimate {
+            rows : total_rs,
+            bytes : total_b,
+            volumes : total_v,
+            order_leakage,
+            count_leakage : None
+        })
+    }
+    
+    // Group counts come back from one lookup as an encrypted value and count per group
+    fn estimate_group(&self, attrib: &String) -> Result<Estimate>{
+        let groups = self.get_hist(attrib)?.len();
+        let width = self.widths.get(attrib).unwrap_or(&0);
+        Ok(Estimate {
+            rows : groups,
+            bytes : groups * (width + 16),
+            volumes : 1,
+            order_leakage : Vec::new(),
+            count_leakage : None
+        })
+    }
+    
+    // A count or a sum sends back one number, but the server still learns every
+    // volume. It also counts the rows itself, so it knows the count it encrypts
+    fn estimate_aggregate(&self, sub_query: HybQuery, bytes: usize) -> Result<Estimate>{
+        let sub = self.estimate(sub_query)?;
+        Ok(Estimate {
+            rows : 1,
+            bytes,
+            volumes : sub.volumes,
+            order_leakage : sub.order_leakage,
+            count_leakage : Some(sub.rows)
+        })
+    }
+    
+    // Every ORDER BY in the query with how many rows reach it
+    fn get_order_leakage(&self, qry: &HybQuery) -> Result<Vec<(String, usize)>>{
+        match qry {
+            HybQuery::Order(attrib, _, _, sub_query) => {
+                let mut leakage = self.get_order_leakage(sub_query)?;
+                let id = self.get_id_from_at(attrib)?;
+                let sub = self.estimate_query_helper((**sub_query).clone())?;
+                let rows = sub.iter().find(|(_, _, ids)| ids.contains(&id)).map(|(b, _, _)| *b).unwrap_or(0);
+                leakage.push((attrib.to_string(), rows));
+                Ok(leakage)
+            },
+            HybQuery::Select(_, sub_query) | HybQuery::Project(_, sub_query) | HybQuery::Count(sub_query)
+            | HybQuery::Sum(_, _, sub_query) => self.get_order_leakage(sub_query),
+            HybQuery::FPJoin(_, _, sub_query1, sub_query2) | HybQuery::PPJoin(_, _, sub_query1, sub_query2) => {
+                let mut leakage = self.get_order_leakage(sub_query1)?;
+                leakage.extend(self.get_order_leakage(sub_query2)?);
+                Ok(leakage)
+            },
+            _ => Ok(Vec::new())
         }
-        println!("Estimated bandwidth: {} rows with a size of {} bytes will be sent from the server to the client", total_rs, total_b);
-        println!("Estimated volumes leaked: {} volumes will be leaked to the server", total_v);
     }
     
-    fn estimate_query_helper(&self, qry: HybQuery) -> Vec<(usize, usize, HashSet<String>)>{
+    fn estimate_query_helper(&self, qry: HybQuery) -> Result<Vec<(usize, usize, HashSet<String>)>>{
         match qry {
-            HybQuery::Id(id) => {
+            HybQuery::Id(id, _) => {
                 let mut ids = HashSet::new();
                 match self.sizes.get(&id) {
                     Some(i) => {
                         ids.insert(id);
-                        vec!((*i, 1, ids))
+                        Ok(vec!((*i, 1, ids)))
                     },
-                    None => vec!((0, 0, ids))
+                    None => Err(Error::Schema(format!("Could not find a relation named {} in the statistics", id)))
                 }
             },
-            HybQuery::Select(BoolQuery::Eq(attrib, val), sub_query) => {
-                let mut sub = self.estimate_query_helper(*sub_query);
-                let id = self.get_id_from_at(&attrib).unwrap();
-                let mut new_b = 0;
-                let mut new_v = 0;
-                let mut index = None;
-                for (i, (sub_b, sub_v, sub_ids)) in sub.iter().enumerate(){
-                    if sub_ids.contains(&id){
-                        if let Some(h) = self.hists.get(&attrib){
-                            let id_size = self.sizes.get(&id).unwrap();
-                            let count = h.get(&val).unwrap();
-                            let frac : f64 = (*count as f64) / (*id_size as f64);
-                            index = Some(i);
-                            new_b = ((*sub_b as f64) * frac) as usize;
-                            new_v = sub_v + 1;
+            HybQuery::Select(b_query, sub_query) => {
+                let mut sub = self.estimate_query_helper(*sub_query)?;
+                // the server learns one volume per emm lookup, so an indexed pair
+                // of equalities counts once. Columns are assumed independent
+                let mut groups : ConjunctGroups = Vec::new();
+                for conjunct in b_query.conjuncts()? {
+                    let mut id = None;
+                    for attrib in conjunct.attributes() {
+                        id = Some(self.get_id_from_at(&attrib)?);
+                    }
+                    let id = id.ok_or(Error::Parse("A selection predicate must mention an attribute".to_string()))?;
+                    let group_index = match groups.iter().position(|(group_id, _, _)| *group_id == id) {
+                        Some(i) => i,
+                        None => {
+                            groups.push((id, Vec::new(), Vec::new()));
+                            groups.len() - 1
                         }
+                    };
+                    match conjunct {
+                        BoolQuery::Eq(attrib, val) => groups[group_index].1.push((attrib, val)),
+                        conjunct => groups[group_index].2.push(conjunct)
                     }
                 }
-                let (_, _, ids) = &sub[index.unwrap()];
-                sub[index.unwrap()] = (new_b, new_v, ids.clone());
-                sub
+                for (id, eqs, others) in groups {
+                    let id_size = self.get_size(&id)?;
+                    let index = sub.iter().position(|(_, _, sub_ids)| sub_ids.contains(&id))
+                        .ok_or(Error::Schema(format!("{} is not one of the relations being selected from", id)))?;
+                    for lookup in pair_conjuncts(&eqs, &self.conjunctions) {
+                        let mut frac : f64 = 1.0;
+                        for (attrib, val) in &lookup {
+                            let count = self.get_count(attrib, val)?;
+                            frac *= (count as f64) / (id_size as f64);
+                        }
+                        let (sub_b, sub_v, ids) = &sub[index];
+                        sub[index] = (((*sub_b as f64) * frac) as usize, sub_v + 1, ids.clone());
+                    }
+                    for other in &others {
+                        let (frac, lookups) = self.estimate_bool(other, id_size)?;
+                        let (sub_b, sub_v, ids) = &sub[index];
+                        sub[index] = (((*sub_b as f64) * frac) as usize, sub_v + lookups, ids.clone());
+                    }
+                }
+                Ok(sub)
             },
-            HybQuery::Project(_, sub_query) => {
+            HybQuery::Project(_, sub_query) | HybQuery::Count(sub_query) | HybQuery::Sum(_, _, sub_query) => {
                 self.estimate_query_helper(*sub_query)
             },
-            HybQuery::PPJoin(attrib1, attrib2, sub_query1, sub_query2) => {
-                let mut sub1 = self.estimate_query_helper(*sub_query1);
-                let mut sub2 = self.estimate_query_helper(*sub_query2);
-                let id1 = self.get_id_from_at(&attrib1).unwrap();
-                let id2 = self.get_id_from_at(&attrib2).unwrap();
+            HybQuery::Group(attrib, _) => {
+                let mut ids = HashSet::new();
+                ids.insert(self.get_id_from_at(&attrib)?);
+                Ok(vec!((self.get_hist(&attrib)?.len(), 1, ids)))
+            },
+            HybQuery::Order(_, _, limit, sub_query) => {
+                let mut sub = self.estimate_query_helper(*sub_query)?;
+                // the server only cuts rows when there is a single table left
+                if let (1, Some(limit)) = (sub.len(), limit) {
+                    sub[0].0 = min(sub[0].0, limit);
+                }
+                Ok(sub)
+            },
+            HybQuery::PPJoin(ats1, ats2, sub_query1, sub_query2) => {
+                let mut sub1 = self.estimate_query_helper(*sub_query1)?;
+                let mut sub2 = self.estimate_query_helper(*sub_query2)?;
+                let id1 = self.get_id_from_at(&ats1[0])?;
+                let id2 = self.get_id_from_at(&ats2[0])?;
                 let mut new_b1 = 0;
                 let mut new_v1 = 0;
                 let mut new_b2 = 0;
                 let mut new_v2 = 0;
                 let mut index1 = None;
                 let mut index2 = None;
-                let h1 = self.hists.get(&attrib1).unwrap();
-                let h2 = self.hists.get(&attrib2).unwrap();
-                let rel1_values: HashSet<&String> = h1.keys().collect();
-                let rel2_values: HashSet<&String> = h2.keys().collect();
-                let intersect_values : HashSet<&&String> = rel1_values.intersection(&rel2_values).collect();
+                let h1 = self.get_join_hist(&ats1)?;
+                let h2 = self.get_join_hist(&ats2)?;
+                let rel1_values: HashSet<&Vec<Value>> = h1.keys().collect();
+                let rel2_values: HashSet<&Vec<Value>> = h2.keys().collect();
+                let intersect_values : HashSet<&&Vec<Value>> = rel1_values.intersection(&rel2_values).collect();
                 for (i, (sub_b, sub_v, sub_ids)) in sub1.iter().enumerate(){
                     if sub_ids.contains(&id1){
                         let mut frac : f64 = 0.0;
-                        let id_size = self.sizes.get(&id1).unwrap();
+                        let id_size = self.get_size(&id1)?;
                         for val in &intersect_values{
-                            let count = h1.get(**val).unwrap();
-                            frac = frac + (*count as f64);
+                            let count = h1.get(**val).unwrap_or(&0);
+                            frac += *count as f64;
                         }
-                        frac = frac / (*id_size as f64);
+                        frac /= id_size as f64;
                         index1 = Some(i);
                         new_b1 = ((*sub_b as f64) * frac) as usize;
                         new_v1 = sub_v + 1;
@@ -142,56 +307,57 @@ impl Statistics {
                 for (j, (sub_b, sub_v, sub_ids)) in sub2.iter().enumerate(){
                     if sub_ids.contains(&id2){
                         let mut frac : f64 = 0.0;
-                        let id_size = self.sizes.get(&id2).unwrap();
+                        let id_size = self.get_size(&id2)?;
                         for val in &intersect_values{
-                            let count = h2.get(**val).unwrap();
-                            frac = frac + (*count as f64);
+                            let count = h2.get(**val).unwrap_or(&0);
+                            frac += *count as f64;
                         }
-                        frac = frac / (*id_size as f64);
+                        frac /= id_size as f64;
                         index2 = Some(j);
                         new_b2 = ((*sub_b as f64) * frac) as usize;
                         new_v2 = sub_v + 1;
                     }
                 }
-                let (_, _, ids1) = &sub1[index1.unwrap()];
-                let (_, _, ids2) = &sub2[index2.unwrap()];
-                sub1[index1.unwrap()] = (new_b1, new_v1, ids1.clone());
-                sub2[index2.unwrap()] = (new_b2, new_v2, ids2.clone());
+                let (index1, index2) = join_indices(index1, index2, &ats1, &ats2)?;
+                let (_, _, ids1) = &sub1[index1];
+                let (_, _, ids2) = &sub2[index2];
+                sub1[index1] = (new_b1, new_v1, ids1.clone());
+                sub2[index2] = (new_b2, new_v2, ids2.clone());
                 sub1.append(&mut sub2);
-                sub1
+                Ok(sub1)
             }
-            HybQuery::FPJoin(attrib1, attrib2, sub_query1, sub_query2) => {
-                let mut sub1 = self.estimate_query_helper(*sub_query1);
-                let mut sub2 = self.estimate_query_helper(*sub_query2);
-                let id1 = self.get_id_from_at(&attrib1).unwrap();
-                let id2 = self.get_id_from_at(&attrib2).unwrap();
+            HybQuery::FPJoin(ats1, ats2, sub_query1, sub_query2) => {
+                let mut sub1 = self.estimate_query_helper(*sub_query1)?;
+                let mut sub2 = self.estimate_query_helper(*sub_query2)?;
+                let id1 = self.get_id_from_at(&ats1[0])?;
+                let id2 = self.get_id_from_at(&ats2[0])?;
                 let mut new_b = 0;
                 let mut new_v = 0;
                 let mut index1 = None;
                 let mut index2 = None;
-                let h1 = self.hists.get(&attrib1).unwrap();
-                let h2 = self.hists.get(&attrib2).unwrap();
-                let rel1_values: HashSet<&String> = h1.keys().collect();
-                let rel2_values: HashSet<&String> = h2.keys().collect();
-                let intersect_values : HashSet<&&String> = rel1_values.intersection(&rel2_values).collect();
+                let h1 = self.get_join_hist(&ats1)?;
+                let h2 = self.get_join_hist(&ats2)?;
+                let rel1_values: HashSet<&Vec<Value>> = h1.keys().collect();
+                let rel2_values: HashSet<&Vec<Value>> = h2.keys().collect();
+                let intersect_values : HashSet<&&Vec<Value>> = rel1_values.intersection(&rel2_values).collect();
                 let mut found = false;
                 for (i, (sub_b1, sub_v1, sub_ids1)) in sub1.iter().enumerate(){
                     if sub_ids1.contains(&id1){
                         for (j, (sub_b2, sub_v2, sub_ids2)) in sub2.iter().enumerate(){
                             if sub_ids2.contains(&id2){
                                 let mut frac : f64 = 0.0;
-                                let id_size1 = self.sizes.get(&id1).unwrap();
-                                let id_size2 = self.sizes.get(&id2).unwrap();
+                                let id_size1 = self.get_size(&id1)?;
+                                let id_size2 = self.get_size(&id2)?;
                                 for val in &intersect_values{
-                                    let count1 = h1.get(**val).unwrap();
-                                    let count2 = h2.get(**val).unwrap();
-                                    frac = frac + ((count1 * count2) as f64);
+                                    let count1 = h1.get(**val).unwrap_or(&0);
+                                    let count2 = h2.get(**val).unwrap_or(&0);
+                                    frac += (count1 * count2) as f64;
                                 }
-                                frac = frac / ((*id_size1 as f64) * (*id_size2 as f64));
+                                frac /= (id_size1 as f64) * (id_size2 as f64);
                                 index1 = Some(i);
                                 index2 = Some(j);
                                 new_b = ((*sub_b1 as f64) * (*sub_b2 as f64) * frac) as usize;
-                                new_v = sub_v1 + sub_v2 + &intersect_values.len();
+                                new_v = sub_v1 + sub_v2 + intersect_values.len();
                                 found = true;
                                 break
                             }
@@ -202,21 +368,21 @@ impl Statistics {
                     }
                 }
                 
-                let (_, _, ids1) = &sub1[index1.unwrap()];
-                let (_, _, ids2) = &sub2[index2.unwrap()];
-                ids1.union(ids2);
-                let merged = (new_b, new_v, ids1.clone());
-                sub1.remove(index1.unwrap());
-                sub2.remove(index2.unwrap());
+                let (index1, index2) = join_indices(index1, index2, &ats1, &ats2)?;
+                let (_, _, ids1) = &sub1[index1];
+                let (_, _, ids2) = &sub2[index2];
+                let merged = (new_b, new_v, ids1.union(ids2).cloned().collect());
+                sub1.remove(index1);
+                sub2.remove(index2);
                 sub1.append(&mut sub2);
                 sub1.push(merged);
-                sub1
+                Ok(sub1)
             }
-            _ => vec!((0, 0, HashSet::new()))
+            HybQuery::BadQuery(s) => Err(Error::Parse(s))
         }
     }
     
-    fn get_id_from_at(&self, at_target : &String) -> Option<String>{
+    fn get_id_from_at(&self, at_target : &String) -> Result<String>{
         let mut res = None;
         for (id, ats) in self.schema.iter(){
             for at in ats{
@@ -225,60 +391,190 @@ impl Statistics {
                 }
             }
         }
-        res
+        res.ok_or(Error::Schema(format!("Could not find a column named {} in the statistics", at_target)))
     }
     
-    fn get_ats_from_qry(&self, qry: &HybQuery)-> (Vec<Vec<String>>, Vec<String>){
+    fn get_size(&self, id : &String) -> Result<usize>{
+        match self.sizes.get(id) {
+            Some(size) => Ok(*size),
+            None => Err(Error::Schema(format!("Could not find a relation named {} in the statistics", id)))
+        }
+    }
+    
+    /// The fraction of a relation a predicate keeps and how many emm lookups it
+    /// takes. An `OR` is assumed to match its sides independently.
+    fn estimate_bool(&self, b_query : &BoolQuery, id_size : usize) -> Result<(f64, usize)>{
+        match b_query {
+            BoolQuery::Eq(attrib, val) => {
+                let count = self.get_count(attrib, val)?;
+                Ok(((count as f64) / (id_size as f64), 1))
+            },
+            BoolQuery::In(attrib, vals) => {
+                let h = self.get_hist(attrib)?;
+                let mut distinct = HashSet::new();
+                for val in vals {
+                    distinct.insert(self.parse_literal(attrib, val)?);
+                }
+                let count : usize = distinct.iter().map(|val| h.get(val).unwrap_or(&0)).sum();
+                Ok(((count as f64) / (id_size as f64), vals.len()))
+            },
+            BoolQuery::Range(attrib, lower, upper) => {
+                let (lo, hi) = match key_bounds(lower, upper)? {
+                    Some(bounds) => bounds,
+                    None => return Ok((0.0, 0))
+                };
+                let mut count = 0;
+                for (val, val_count) in self.get_hist(attrib)? {
+                    if let Ok(key) = val.order_key() {
+                        if lo <= key && key <= hi {
+                            count += val_count;
+                        }
+                    }
+                }
+                Ok(((count as f64) / (id_size as f64), range_cover(lo, hi).len()))
+            },
+            BoolQuery::IsNull(attrib) => {
+                Ok((self.get_null_frac(attrib, id_size)?, 1))
+            },
+            BoolQuery::Not(sub) => self.estimate_false(sub, id_size),
+            BoolQuery::And(left, right) => {
+                let (frac1, lookups1) = self.estimate_bool(left, id_size)?;
+                let (frac2, lookups2) = self.estimate_bool(right, id_size)?;
+                Ok((frac1 * frac2, lookups1 + lookups2))
+            },
+            BoolQuery::Or(left, right) => {
+                let (frac1, lookups1) = self.estimate_bool(left, id_size)?;
+                let (frac2, lookups2) = self.estimate_bool(right, id_size)?;
+                Ok((frac1 + frac2 - frac1 * frac2, lookups1 + lookups2))
+            },
+            BoolQuery::BadBool(s) => Err(Error::Parse(s.to_string()))
+        }
+    }
+    
+    // The fraction of a relation where a predicate is false, mirroring how
+    // `STIClient` takes the NULL rows out of a negated comparison
+    fn estimate_false(&self, b_query : &BoolQuery, id_size : usize) -> Result<(f64, usize)>{
+        match b_query {
+            BoolQuery::Eq(attrib, _) | BoolQuery::In(attrib, _) | BoolQuery::Range(attrib, _, _) => {
+                let (frac, lookups) = self.estimate_bool(b_query, id_size)?;
+                let null_frac = self.get_null_frac(attrib, id_size)?;
+                Ok(((1.0 - frac - null_frac).max(0.0), lookups + 1))
+            },
+            BoolQuery::IsNull(attrib) => Ok((1.0 - self.get_null_frac(attrib, id_size)?, 1)),
+            BoolQuery::Not(sub) => self.estimate_bool(sub, id_size),
+            BoolQuery::And(left, right) => {
+                let (frac1, lookups1) = self.estimate_false(left, id_size)?;
+                let (frac2, lookups2) = self.estimate_false(right, id_size)?;
+                Ok((frac1 + frac2 - frac1 * frac2, lookups1 + lookups2))
+            },
+            BoolQuery::Or(left, right) => {
+                let (frac1, lookups1) = self.estimate_false(left, id_size)?;
+                let (frac2, lookups2) = self.estimate_false(right, id_size)?;
+                Ok((frac1 * frac2, lookups1 + lookups2))
+            },
+            BoolQuery::BadBool(s) => Err(Error::Parse(s.to_string()))
+        }
+    }
+    
+    fn get_null_frac(&self, at : &String, id_size : usize) -> Result<f64>{
+        let count = self.get_hist(at)?.get(&Value::Null).unwrap_or(&0);
+        Ok((*count as f64) / (id_size as f64))
+    }
+    
+    fn get_hist(&self, at : &String) -> Result<&HashMap<Value, usize>>{
+        self.hists.get(at).ok_or(Error::Schema(format!("Could not find a column named {} in the statistics", at)))
+    }
+    
+    // How many rows hold each combination of values of the attributes of one side
+    // of a join, leaving out NULL since it never joins. The attributes of a
+    // composite join may be listed in another order than they were annotated in
+    fn get_join_hist(&self, ats : &[String]) -> Result<HashMap<Vec<Value>, usize>>{
+        if ats.len() == 1 {
+            return Ok(self.get_hist(&ats[0])?.iter().filter(|(val, _)| !val.is_null()).map(|(val, count)| (vec!(val.clone()), *count)).collect());
+        }
+        for (key_ats, h) in &self.joint_hists{
+            let order : Option<Vec<usize>> = ats.iter().map(|at| key_ats.iter().position(|a| a == at)).collect();
+            if let (Some(order), true) = (order, key_ats.len() == ats.len()) {
+                return Ok(h.iter().map(|(vals, count)| (order.iter().map(|i| vals[*i].clone()).collect(), *count)).collect());
+            }
+        }
+        Err(Error::Schema(format!("Could not find statistics for joining on {} together since they were not annotated", ats.join(", "))))
+    }
+    
+    // How many rows hold a value written in a query, read as the column's type
+    fn get_count(&self, at : &String, val : &str) -> Result<usize>{
+        let val = self.parse_literal(at, val)?;
+        Ok(*self.get_hist(at)?.get(&val).unwrap_or(&0))
+    }
+    
+    fn parse_literal(&self, at : &String, val : &str) -> Result<Value>{
+        match Value::parse(val, &self.get_type(at))? {
+            Value::Null => Err(Error::Parse(format!("Nothing equals NULL, so use {} IS NULL to select the rows where it is NULL", at))),
+            val => Ok(val)
+        }
+    }
+    
+    fn get_type(&self, at : &String) -> ValueType{
+        self.types.get(at).copied().unwrap_or(ValueType::Text)
+    }
+    
+    fn get_ats_from_qry(&self, qry: &HybQuery)-> Result<(Vec<Vec<String>>, Vec<String>)>{
         let ats_res : (Vec<Vec<String>>, Vec<String>);
         let mut ats_all : Vec<Vec<String>> = Vec::new();
         match qry{
-            HybQuery::Id(id)=> {
-                let ats = self.schema.get(id).unwrap().clone();
-                let primary_key = &ats[0].clone();
-                ats_res = (vec!(ats), vec!(primary_key.to_string()));
+            HybQuery::Id(id, _)=> {
+                let ats = match self.schema.get(id) {
+                    Some(ats) if !ats.is_empty() => ats.clone(),
+                    _ => return Err(Error::Schema(format!("Could not find a relation named {} in the statistics", id)))
+                };
+                // the server never projects away the first cell of a row since it
+                // holds on to the row by it. It need not be the primary key
+                let handle = &ats[0].clone();
+                ats_res = (vec!(ats), vec!(handle.to_string()));
             },
-            HybQuery::Select(_, sub_query)=>{
-                ats_res = self.get_ats_from_qry(sub_query);
+            HybQuery::Select(_, sub_query) | HybQuery::Order(_, _, _, sub_query) | HybQuery::Count(sub_query) | HybQuery::Sum(_, _, sub_query) | HybQuery::Group(_, sub_query)=>{
+                ats_res = self.get_ats_from_qry(sub_query)?;
             },
-            HybQuery::FPJoin(attrib1, attrib2, sub_query1, sub_query2)=>{
-                let (mut sub_ats1, mut save_ats1) = self.get_ats_from_qry(sub_query1);
-                let (mut sub_ats2, mut save_ats2) = self.get_ats_from_qry(sub_query2);
+            HybQuery::FPJoin(ats1, ats2, sub_query1, sub_query2)=>{
+                let (mut sub_ats1, mut save_ats1) = self.get_ats_from_qry(sub_query1)?;
+                let (mut sub_ats2, mut save_ats2) = self.get_ats_from_qry(sub_query2)?;
                 
                 let mut index1 = None;
                 let mut index2 = None;
                 for (i, sub_at) in sub_ats1.iter().enumerate(){
-                    if sub_at.iter().any(|at| at == attrib1){
+                    if sub_at.iter().any(|at| *at == ats1[0]){
                         index1 = Some(i);
                         break;
                     }
                 }
                 for (i, sub_at) in sub_ats2.iter().enumerate(){
-                    if sub_at.iter().any(|at| at == attrib2){
+                    if sub_at.iter().any(|at| *at == ats2[0]){
                         index2 = Some(i);
                         break;
                     }
                 }
-                let mut merged = sub_ats1[index1.unwrap()].clone();
-                merged.append(&mut sub_ats2[index2.unwrap()]);
-                sub_ats1.remove(index1.unwrap());
-                sub_ats2.remove(index2.unwrap());
+                let (index1, index2) = join_indices(index1, index2, ats1, ats2)?;
+                let mut merged = sub_ats1[index1].clone();
+                merged.append(&mut sub_ats2[index2]);
+                sub_ats1.remove(index1);
+                sub_ats2.remove(index2);
                 sub_ats1.append(&mut sub_ats2);
                 sub_ats1.push(merged);
                 save_ats1.append(&mut save_ats2);
                 
                 ats_res = (sub_ats1, save_ats1);
             },
-            HybQuery::PPJoin(attrib1, attrib2, sub_query1, sub_query2)=>{
-                let (mut sub_ats1, mut save_ats1) = self.get_ats_from_qry(sub_query1);
-                let (mut sub_ats2, mut save_ats2) = self.get_ats_from_qry(sub_query2);
+            HybQuery::PPJoin(ats1, ats2, sub_query1, sub_query2)=>{
+                let (mut sub_ats1, mut save_ats1) = self.get_ats_from_qry(sub_query1)?;
+                let (mut sub_ats2, mut save_ats2) = self.get_ats_from_qry(sub_query2)?;
                 sub_ats1.append(&mut sub_ats2);
                 save_ats1.append(&mut save_ats2);
-                save_ats1.push(attrib1.to_string());
-                save_ats1.push(attrib2.to_string());
+                save_ats1.extend(ats1.iter().cloned());
+                save_ats1.extend(ats2.iter().cloned());
                 ats_res = (sub_ats1, save_ats1);
             },
             HybQuery::Project(cols, sub_query)=>{
-                let (sub_ats, save_ats) = self.get_ats_from_qry(sub_query);
+                let (sub_ats, save_ats) = self.get_ats_from_qry(sub_query)?;
                 for t_sub_ats in sub_ats{
                     let filter_ats = t_sub_ats.into_iter()
                         .filter(|at| cols.iter().any(|proj_at| proj_at==at)
@@ -290,6 +586,14 @@ impl Statistics {
             },
             _ => { ats_res = (Vec::new(), Vec::new()) }
         }
-        ats_res
+        Ok(ats_res)
+    }
+}
+
+// Both sides of a join must have been found among the sub-query's tables
+fn join_indices(index1 : Option<usize>, index2 : Option<usize>, ats1 : &[String], ats2 : &[String]) -> Result<(usize, usize)>{
+    match (index1, index2) {
+        (Some(index1), Some(index2)) => Ok((index1, index2)),
+        _ => Err(Error::Schema(format!("Could not find the columns {} and {} in the tables being joined", ats1.join(", "), ats2.join(", "))))
     }
 }
\ No newline at end of file
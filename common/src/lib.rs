@@ -1,8 +1,17 @@
+pub mod error;
 pub mod crypto_helper;
+pub mod keystore;
+pub mod protocol;
+pub mod secure_channel;
 pub mod token;
 pub mod db_structs;
 pub mod emm;
 pub mod mm;
+pub mod range;
+pub mod value;
+pub mod ore;
+pub mod paillier;
+pub mod tdp;
 pub mod ste;
 pub mod sti;
 pub mod eval_references;
@@ -12,7 +12,7 @@ impl MM {
     }
     
     pub fn add_mm(&mut self, identifier : Vec<u8>, keyword : Vec<u8>){
-        self.data.entry(keyword).or_insert(Vec::new()).push(identifier)
+        self.data.entry(keyword).or_default().push(identifier)
     }
     
     pub fn search_mm(&mut self, keyword : &Vec<u8>){
@@ -23,10 +23,7 @@ impl MM {
                 println!("Identifiers for keyword: {}", keyword_string);
                 let v = self.data.get_mut(keyword).unwrap();
                 for id in v.iter(){
-                    match std::str::from_utf8(&id[..]) {
-                        Ok(v) => println!("    {}", v),
-                        Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-                    }
+                    println!("    {}", String::from_utf8_lossy(&id[..]));
                 }
             }
         } else {
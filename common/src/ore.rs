@@ -0,0 +1,198 @@
+use std::cmp::Ordering;
+use serde::{Deserialize, Serialize};
+use hmac::{ Hmac, Mac };
+use sha2::{ Sha256, Digest };
+use rand_chacha::ChaCha20Rng;
+use rand_core::{ RngCore, SeedableRng, OsRng };
+
+// Lewi-Wu order-revealing encryption over u64 plaintexts, split into 8 blocks
+// of one byte each. A ciphertext has a left half and a right half. Comparing the
+// left half of x with the right half of y tells whether x < y, x = y or x > y and
+// the first byte in which they differ, and nothing else. The right half alone is
+// semantically secure.
+const BLOCKS : usize = 8;
+const BLOCK_DOMAIN : usize = 256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub struct OreKey {
+    prf_key : [u8; 16],
+    prp_key : [u8; 16],
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+pub struct OreCiphertext {
+    // per block, the prf of the prefix and permuted byte, then the permuted byte
+    left : Vec<([u8; 32], u8)>,
+    nonce : [u8; 16],
+    // per block and permuted byte, a comparison masked mod 3, four to a byte
+    right : Vec<u8>,
+}
+
+impl OreKey {
+    pub fn new(prf_key : [u8; 16], prp_key : [u8; 16]) -> OreKey {
+        OreKey {
+            prf_key,
+            prp_key,
+        }
+    }
+
+    pub fn encrypt(&self, x : u64) -> OreCiphertext {
+        let bytes = x.to_be_bytes();
+        let mut nonce = [0u8; 16];
+        OsRng.fill_bytes(&mut nonce);
+        let mut left = Vec::new();
+        let mut right = vec![0u8; BLOCKS * BLOCK_DOMAIN / 4];
+        for i in 0..BLOCKS {
+            let prefix = &bytes[..i];
+            let perm = self.permutation(prefix);
+            let permuted = perm[bytes[i] as usize];
+            let prefix_mac = self.prefix_mac(prefix);
+            left.push((prf(&prefix_mac, permuted), permuted));
+
+            let mut inverse = [0u8; BLOCK_DOMAIN];
+            for (j, p) in perm.iter().enumerate() {
+                inverse[*p as usize] = j as u8;
+            }
+            for (j, byte) in inverse.iter().enumerate() {
+                let cmp = compare_byte(*byte, bytes[i]);
+                let masked = (cmp + mask(&prf(&prefix_mac, j as u8), &nonce)) % 3;
+                let index = i * BLOCK_DOMAIN + j;
+                right[index / 4] |= masked << (2 * (index % 4));
+            }
+        }
+        OreCiphertext {
+            left,
+            nonce,
+            right,
+        }
+    }
+
+    // The prf keyed and fed the prefix, ready to be cloned for each byte after it
+    fn prefix_mac(&self, prefix : &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.prf_key[..]).expect("HMAC takes keys of any length");
+        mac.update(prefix);
+        mac
+    }
+
+    // Each prefix gets its own random permutation of the next byte, so the left
+    // halves of two values only line up while their prefixes agree
+    fn permutation(&self, prefix : &[u8]) -> [u8; BLOCK_DOMAIN] {
+        let mut mac = HmacSha256::new_from_slice(&self.prp_key[..]).expect("HMAC takes keys of any length");
+        mac.update(&[prefix.len() as u8]);
+        mac.update(prefix);
+        let seed : [u8; 32] = mac.finalize().into_bytes().into();
+        let mut rng = ChaCha20Rng::from_seed(seed);
+        let mut perm = [0u8; BLOCK_DOMAIN];
+        for (j, p) in perm.iter_mut().enumerate() {
+            *p = j as u8;
+        }
+        for j in (1..BLOCK_DOMAIN).rev() {
+            let k = (rng.next_u32() as usize) % (j + 1);
+            perm.swap(j, k);
+        }
+        perm
+    }
+}
+
+/// Compares the values under two ciphertexts made with the same key. Only the
+/// left half of `x` and the right half of `y` are used.
+pub fn compare(x : &OreCiphertext, y : &OreCiphertext) -> Ordering {
+    for (i, (key, permuted)) in x.left.iter().enumerate() {
+        let index = i * BLOCK_DOMAIN + *permuted as usize;
+        let masked = match y.right.get(index / 4) {
+            Some(packed) => (packed >> (2 * (index % 4))) & 3,
+            None => return Ordering::Equal
+        };
+        match (masked + 3 - mask(key, &y.nonce)) % 3 {
+            1 => return Ordering::Less,
+            2 => return Ordering::Greater,
+            _ => {}
+        }
+    }
+    Ordering::Equal
+}
+
+fn prf(prefix_mac : &HmacSha256, byte : u8) -> [u8; 32] {
+    let mut mac = prefix_mac.clone();
+    mac.update(&[byte]);
+    mac.finalize().into_bytes().into()
+}
+
+fn compare_byte(a : u8, b : u8) -> u8 {
+    match a.cmp(&b) {
+        Ordering::Equal => 0,
+        Ordering::Less => 1,
+        Ordering::Greater => 2,
+    }
+}
+
+// Hashes a prf output with the nonce into a mask mod 3, the random oracle of the
+// Lewi-Wu construction
+fn mask(key : &[u8; 32], nonce : &[u8; 16]) -> u8 {
+    let mut hasher = Sha256::new();
+    hasher.update(&key[..]);
+    hasher.update(&nonce[..]);
+    let out = hasher.finalize();
+    let mut wide = [0u8; 8];
+    wide.copy_from_slice(&out[..8]);
+    (u64::from_be_bytes(wide) % 3) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> OreKey {
+        OreKey::new([1u8; 16], [2u8; 16])
+    }
+
+    #[test]
+    fn compare_orders_boundary_values() {
+        let key = key();
+        let values = [0, 1, 2, 255, 256, 257, 1 << 32, (1 << 56) - 1, 1 << 56, u64::MAX - 1, u64::MAX];
+        let cts : Vec<OreCiphertext> = values.iter().map(|x| key.encrypt(*x)).collect();
+        for (i, x) in cts.iter().enumerate() {
+            for (j, y) in cts.iter().enumerate() {
+                assert_eq!(compare(x, y), values[i].cmp(&values[j]), "comparing {} with {}", values[i], values[j]);
+            }
+        }
+    }
+
+    #[test]
+    fn compare_finds_equal_values_under_fresh_nonces() {
+        let key = key();
+        for x in &[0, 1, 12345, u64::MAX] {
+            let (a, b) = (key.encrypt(*x), key.encrypt(*x));
+            assert_ne!(a.right, b.right);
+            assert_eq!(compare(&a, &b), Ordering::Equal);
+            assert_eq!(compare(&b, &a), Ordering::Equal);
+        }
+    }
+
+    #[test]
+    fn compare_values_first_differing_in_each_block() {
+        let key = key();
+        for block in 0..BLOCKS {
+            // adjacent values where adding one carries into the block, then
+            // values that differ by one in the block and agree elsewhere
+            let carry = ((1u64 << (8 * block)) - 1, 1u64 << (8 * block));
+            let x = 0x1234_5678_9abc_def0u64 & !(0xff << (8 * block));
+            for &(x, y) in &[carry, (x, x + (1 << (8 * block)))] {
+                assert_eq!(compare(&key.encrypt(x), &key.encrypt(y)), Ordering::Less);
+                assert_eq!(compare(&key.encrypt(y), &key.encrypt(x)), Ordering::Greater);
+            }
+        }
+    }
+
+    #[test]
+    fn permutations_are_bijections() {
+        let key = key();
+        for prefix in &[&[][..], &[0u8][..], &[7u8, 200][..]] {
+            let mut perm = key.permutation(prefix).to_vec();
+            perm.sort_unstable();
+            assert!(perm.iter().enumerate().all(|(j, p)| *p as usize == j));
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use serde::{Deserialize, Serialize};
+use num_bigint::BigUint;
+use num_traits::{ Zero, One };
+use rand_chacha::ChaCha20Rng;
+use rand_core::{ RngCore, SeedableRng, OsRng };
+use crate::error::{ Error, Result };
+
+// Paillier encryption with g = n + 1. Multiplying ciphertexts modulo n^2 adds
+// their plaintexts modulo n, so the server can total a column it cannot read.
+const PRIME_BITS : usize = 1024;
+const MILLER_RABIN_ROUNDS : usize = 8;
+// Values are stored as fixed point numbers with this many decimal digits
+const SCALE_DIGITS : usize = 6;
+/// The size of a ciphertext, a number modulo n^2.
+pub const CIPHERTEXT_BYTES : usize = PRIME_BITS / 2;
+
+pub struct PaillierKey {
+    p : BigUint,
+    q : BigUint,
+    p_squared : BigUint,
+    q_squared : BigUint,
+    // p^2 inverted modulo q^2, to put r^n back together from its two halves
+    p_squared_inv : BigUint,
+    n : BigUint,
+    n_squared : BigUint,
+    phi : BigUint,
+    mu : BigUint,
+}
+
+/// The part of a Paillier key the server needs to add ciphertexts.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+pub struct PaillierPublicKey {
+    n_squared : Vec<u8>,
+}
+
+impl PaillierKey {
+    /// Derives the whole key pair from a seed, so a client that keeps only its
+    /// master key can decrypt sums again.
+    pub fn from_seed(seed : [u8; 32]) -> PaillierKey {
+        let mut rng = ChaCha20Rng::from_seed(seed);
+        let p = gen_prime(&mut rng);
+        let mut q = gen_prime(&mut rng);
+        while q == p {
+            q = gen_prime(&mut rng);
+        }
+        let one = BigUint::one();
+        let n = &p * &q;
+        let phi = (&p - &one) * (&q - &one);
+        // with g = n + 1, mu is just phi inverted modulo n
+        let mu = phi.modinv(&n).expect("phi is coprime to n for primes of equal length");
+        let p_squared = &p * &p;
+        let q_squared = &q * &q;
+        let p_squared_inv = p_squared.modinv(&q_squared).expect("distinct primes are coprime");
+        PaillierKey {
+            n_squared : &n * &n,
+            p,
+            q,
+            p_squared,
+            q_squared,
+            p_squared_inv,
+            n,
+            phi,
+            mu,
+        }
+    }
+
+    pub fn public_key(&self) -> PaillierPublicKey {
+        PaillierPublicKey {
+            n_squared : self.n_squared.to_bytes_be()
+        }
+    }
+
+    /// Encrypts a decimal number with at most six digits after the point.
+    pub fn encrypt(&self, val : &str) -> Result<Vec<u8>> {
+        let (negative, magnitude) = parse_fixed(val)?;
+        if magnitude >= &self.n >> 1 {
+            return Err(Error::Crypto(format!("{} is too large to encrypt for a sum", val)));
+        }
+        let m = if negative && !magnitude.is_zero() { &self.n - magnitude } else { magnitude };
+
+        let mut r_bytes = vec![0u8; PRIME_BITS / 4];
+        let r = loop {
+            OsRng.fill_bytes(&mut r_bytes);
+            let r = BigUint::from_bytes_be(&r_bytes) % &self.n;
+            if !r.is_zero() && !(&r % &self.p).is_zero() && !(&r % &self.q).is_zero() {
+                break r;
+            }
+        };
+        // (n + 1)^m is 1 + m n modulo n^2
+        let g_m = (BigUint::one() + m * &self.n) % &self.n_squared;
+        let c = g_m * self.pow_n(&r) % &self.n_squared;
+        Ok(c.to_bytes_be())
+    }
+
+    /// Decrypts a total from the server into a decimal string, divided by `count` when
+    /// `avg` is set.
+    pub fn decrypt_sum(&self, ciphertext : &[u8], count : u64, avg : bool) -> Result<String> {
+        let c = BigUint::from_bytes_be(ciphertext);
+        if c.is_zero() || c >= self.n_squared {
+            return Err(Error::Crypto("The server sent back a malformed sum".to_string()));
+        }
+        let l = (c.modpow(&self.phi, &self.n_squared) - BigUint::one()) / &self.n;
+        let m = l * &self.mu % &self.n;
+        let (negative, mut magnitude) = if m > &self.n >> 1 {
+            (true, &self.n - m)
+        } else {
+            (false, m)
+        };
+        if avg {
+            if count == 0 {
+                return Err(Error::Crypto("Cannot average over no rows".to_string()));
+            }
+            magnitude /= BigUint::from(count);
+        }
+        Ok(format_fixed(negative, magnitude))
+    }
+
+    // r^n modulo n^2, computed modulo p^2 and q^2 since the client knows the factors
+    fn pow_n(&self, r : &BigUint) -> BigUint {
+        let one = BigUint::one();
+        let r_p = r.modpow(&(&self.n % (&self.p * (&self.p - &one))), &self.p_squared);
+        let r_q = r.modpow(&(&self.n % (&self.q * (&self.q - &one))), &self.q_squared);
+        let diff = (&r_q + &self.q_squared - &r_p % &self.q_squared) % &self.q_squared;
+        r_p + &self.p_squared * (diff * &self.p_squared_inv % &self.q_squared)
+    }
+}
+
+impl PaillierPublicKey {
+    /// A ciphertext of 0 with no randomness, the total of no rows.
+    pub fn zero(&self) -> Vec<u8> {
+        vec!(1u8)
+    }
+
+    pub fn add(&self, c1 : &[u8], c2 : &[u8]) -> Vec<u8> {
+        let n_squared = BigUint::from_bytes_be(&self.n_squared);
+        (BigUint::from_bytes_be(c1) * BigUint::from_bytes_be(c2) % n_squared).to_bytes_be()
+    }
+}
+
+pub(crate) fn gen_prime(rng : &mut ChaCha20Rng) -> BigUint {
+    let small_primes = small_primes(1000);
+    let mut bytes = vec![0u8; PRIME_BITS / 8];
+    loop {
+        rng.fill_bytes(&mut bytes);
+        // the top two bits make n exactly twice as long as its primes
+        bytes[0] |= 0xc0;
+        bytes[PRIME_BITS / 8 - 1] |= 1;
+        let candidate = BigUint::from_bytes_be(&bytes);
+        if small_primes.iter().any(|sp| (&candidate % *sp).is_zero()) {
+            continue;
+        }
+        if is_probable_prime(&candidate, rng) {
+            return candidate;
+        }
+    }
+}
+
+fn small_primes(bound : u32) -> Vec<u32> {
+    (3..bound).step_by(2).filter(|k| (3..*k).step_by(2).take_while(|d| d * d <= *k).all(|d| k % d != 0)).collect()
+}
+
+fn is_probable_prime(candidate : &BigUint, rng : &mut ChaCha20Rng) -> bool {
+    let one = BigUint::one();
+    let two = BigUint::from(2u32);
+    let minus_one = candidate - &one;
+    let mut d = minus_one.clone();
+    let mut s = 0;
+    while !d.bit(0) {
+        d >>= 1;
+        s += 1;
+    }
+    let mut bytes = vec![0u8; PRIME_BITS / 8];
+    'witness: for _ in 0..MILLER_RABIN_ROUNDS {
+        rng.fill_bytes(&mut bytes);
+        let a = BigUint::from_bytes_be(&bytes) % (candidate - 3u32) + &two;
+        let mut x = a.modpow(&d, candidate);
+        if x == one || x == minus_one {
+            continue;
+        }
+        for _ in 1..s {
+            x = x.modpow(&two, candidate);
+            if x == minus_one {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+    true
+}
+
+// Reads a plain decimal such as -12.5 into its sign and its value times 10^6
+fn parse_fixed(val : &str) -> Result<(bool, BigUint)> {
+    let not_decimal = || Error::Schema(format!("{} is not a decimal number with at most {} digits after the point so it cannot be summed", val, SCALE_DIGITS));
+    let trimmed = val.trim();
+    let (negative, digits) = match trimmed.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, trimmed.strip_prefix('+').unwrap_or(trimmed))
+    };
+    let (whole, frac) = match digits.split_once('.') {
+        Some((whole, frac)) => (whole, frac),
+        None => (digits, "")
+    };
+    if (whole.is_empty() && frac.is_empty()) || frac.len() > SCALE_DIGITS
+        || !whole.chars().chain(frac.chars()).all(|ch| ch.is_ascii_digit()) {
+        return Err(not_decimal());
+    }
+    let scaled = format!("{}{}{}", whole, frac, "0".repeat(SCALE_DIGITS - frac.len()));
+    match scaled.parse::<BigUint>() {
+        Ok(magnitude) => Ok((negative, magnitude)),
+        Err(_) => Err(not_decimal())
+    }
+}
+
+fn format_fixed(negative : bool, magnitude : BigUint) -> String {
+    let digits = format!("{:0>width$}", magnitude.to_string(), width = SCALE_DIGITS + 1);
+    let (whole, frac) = digits.split_at(digits.len() - SCALE_DIGITS);
+    let frac = frac.trim_end_matches('0');
+    let sign = if negative && !magnitude.is_zero() { "-" } else { "" };
+    if frac.is_empty() {
+        format!("{}{}", sign, whole)
+    } else {
+        format!("{}{}.{}", sign, whole, frac)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+
+    // Making a key takes a while, so every test shares one
+    fn key() -> &'static PaillierKey {
+        static KEY : OnceLock<PaillierKey> = OnceLock::new();
+        KEY.get_or_init(|| PaillierKey::from_seed([7u8; 32]))
+    }
+
+    fn total(vals : &[&str]) -> Vec<u8> {
+        let public = key().public_key();
+        vals.iter().fold(public.zero(), |total, val| public.add(&total, &key().encrypt(val).unwrap()))
+    }
+
+    #[test]
+    fn encryption_round_trips() {
+        for val in &["0", "1", "42", "-7", "3.5", "-0.000001", "123456.789012"] {
+            let ct = key().encrypt(val).unwrap();
+            assert!(ct.len() <= CIPHERTEXT_BYTES);
+            let expected = val.trim_start_matches('-').parse::<f64>().unwrap();
+            let decrypted = key().decrypt_sum(&ct, 1, false).unwrap();
+            assert_eq!(decrypted.parse::<f64>().unwrap(), if val.starts_with('-') { -expected } else { expected });
+        }
+    }
+
+    #[test]
+    fn encryption_is_randomized() {
+        assert_ne!(key().encrypt("5").unwrap(), key().encrypt("5").unwrap());
+    }
+
+    #[test]
+    fn adding_ciphertexts_adds_values() {
+        assert_eq!(key().decrypt_sum(&total(&["1", "2", "3"]), 3, false).unwrap(), "6");
+        assert_eq!(key().decrypt_sum(&total(&["10", "-25"]), 2, false).unwrap(), "-15");
+        assert_eq!(key().decrypt_sum(&total(&["-2.5", "2.5"]), 2, false).unwrap(), "0");
+        assert_eq!(key().decrypt_sum(&total(&["0.1", "0.2"]), 2, false).unwrap(), "0.3");
+        assert_eq!(key().decrypt_sum(&total(&[]), 0, false).unwrap(), "0");
+    }
+
+    #[test]
+    fn averages_divide_by_the_count() {
+        assert_eq!(key().decrypt_sum(&total(&["1", "2"]), 2, true).unwrap(), "1.5");
+        assert_eq!(key().decrypt_sum(&total(&["-3", "-4", "-5"]), 3, true).unwrap(), "-4");
+        assert!(key().decrypt_sum(&total(&[]), 0, true).is_err());
+    }
+
+    #[test]
+    fn fixed_point_parsing() {
+        assert_eq!(format_fixed(false, parse_fixed("12.5").unwrap().1), "12.5");
+        assert_eq!(format_fixed(true, parse_fixed("-.25").unwrap().1), "-0.25");
+        assert_eq!(format_fixed(false, parse_fixed("+7").unwrap().1), "7");
+        assert_eq!(format_fixed(true, BigUint::zero()), "0");
+        for bad in &["", ".", "1.2345678", "1e5", "abc", "--1", "1.2.3"] {
+            assert!(parse_fixed(bad).is_err(), "{} should not parse", bad);
+        }
+        assert!(key().encrypt(&"9".repeat(700)).is_err());
+    }
+
+    #[test]
+    fn malformed_totals_are_rejected() {
+        assert!(key().decrypt_sum(&[0u8], 1, false).is_err());
+        assert!(key().decrypt_sum(&key().n_squared.to_bytes_be(), 1, false).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use std::io::{ Read, Write };
+use crate::token::ServerCommand;
+use crate::error::{ Error, Result };
+
+/// Bumped whenever the frame layout or `ServerCommand` changes incompatibly.
+pub const PROTOCOL_VERSION : u16 = 13;
+
+// Largest frame either side will accept, so a bad length prefix cannot make a peer
+// allocate without bound. Comfortably above the size of the sakila edb.
+const MAX_FRAME_LEN : usize = 1 << 30;
+
+// version (2 bytes) + message type (1 byte) + request id (8 bytes)
+const HEADER_LEN : usize = 11;
+
+// `Hello` is laid out the same in every version, so a peer on another version
+// can still be told which version this side speaks
+const HELLO_TYPE : u8 = 0;
+
+/// One message on the wire:
+///
+/// | length: u32 | version: u16 | message type: u8 | request id: u64 | bincode ServerCommand |
+///
+/// All integers are big endian and the length counts every byte after itself.
+pub struct Frame {
+    pub version : u16,
+    pub msg_type : u8,
+    pub request_id : u64,
+    pub command : ServerCommand,
+}
+
+impl ServerCommand {
+    /// Tag sent in the frame header so a peer can tell what a frame holds before decoding it.
+    pub fn message_type(&self) -> u8 {
+        match self {
+            ServerCommand::Hello(_) => HELLO_TYPE,
+            ServerCommand::Setup(_) => 1,
+            ServerCommand::Search(_) => 2,
+            ServerCommand::SearchResponse(_) => 3,
+            ServerCommand::SearchFailure(_) => 4,
+            ServerCommand::SaveEDB(_) => 5,
+            ServerCommand::LoadEDB(_) => 6,
+            ServerCommand::DeleteEDB(_) => 7,
+            ServerCommand::ListEDBs => 8,
+            ServerCommand::ListResponse(_) => 9,
+            ServerCommand::CommandSuccess(_) => 10,
+            ServerCommand::CommandFailure(_) => 11,
+            ServerCommand::Count(_) => 12,
+            ServerCommand::CountResponse(_) => 13,
+            ServerCommand::Sum(_) => 14,
+            ServerCommand::SumResponse(_, _) => 15,
+            ServerCommand::Group(_) => 16,
+            ServerCommand::GroupResponse(_) => 17,
+            ServerCommand::Update(_) => 18,
+            ServerCommand::Rewrite(_) => 19,
+        }
+    }
+}
+
+pub fn write_frame<W : Write>(writer : &mut W, request_id : u64, command : &ServerCommand) -> Result<()> {
+    let payload = bincode::serialize(command).map_err(|e| Error::Protocol(format!("Could not serialize a message: {}", e)))?;
+    let frame_len = HEADER_LEN + payload.len();
+    if frame_len > MAX_FRAME_LEN {
+        return Err(Error::Protocol(format!("A message of {} bytes is too large to send", frame_len)));
+    }
+    let mut frame = Vec::with_capacity(4 + frame_len);
+    frame.extend_from_slice(&(frame_len as u32).to_be_bytes());
+    frame.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    frame.push(command.message_type());
+    frame.extend_from_slice(&request_id.to_be_bytes());
+    frame.extend(payload);
+    writer.write_all(&frame[..]).map_err(|e| Error::Io(format!("Error writing a message: {}", e)))?;
+    writer.flush().map_err(|e| Error::Io(format!("Error writing a message: {}", e)))
+}
+
+/// Reads the next frame. Returns `Ok(None)` if the peer closed the connection
+/// cleanly between frames. Only a `Hello` is read from a peer on another
+/// protocol version, for the handshake to answer.
+pub fn read_frame<R : Read>(reader : &mut R) -> Result<Option<Frame>> {
+    let mut len_bytes = [0u8; 4];
+    match reader.read_exact(&mut len_bytes) {
+        Ok(_) => {},
+        Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(Error::Io(format!("Error reading a message: {}", e)))
+    }
+    let frame_len = u32::from_be_bytes(len_bytes) as usize;
+    if !(HEADER_LEN..=MAX_FRAME_LEN).contains(&frame_len) {
+        return Err(Error::Protocol(format!("Received a frame with an invalid length of {} bytes", frame_len)));
+    }
+    let mut frame = vec![0u8; frame_len];
+    reader.read_exact(&mut frame[..]).map_err(|e| Error::Io(format!("Error reading a message: {}", e)))?;
+
+    let mut version_bytes = [0u8; 2];
+    version_bytes.copy_from_slice(&frame[0..2]);
+    let version = u16::from_be_bytes(version_bytes);
+    let msg_type = frame[2];
+    if version != PROTOCOL_VERSION && msg_type != HELLO_TYPE {
+        return Err(Error::Protocol(format!("Peer speaks protocol version {} but this build speaks version {}", version, PROTOCOL_VERSION)));
+    }
+    let mut id_bytes = [0u8; 8];
+    id_bytes.copy_from_slice(&frame[3..HEADER_LEN]);
+    let request_id = u64::from_be_bytes(id_bytes);
+
+    let command : ServerCommand = bincode::deserialize(&frame[HEADER_LEN..])
+        .map_err(|e| Error::Protocol(format!("Received a message that could not be decoded: {}", e)))?;
+    if command.message_type() != msg_type {
+        return Err(Error::Protocol("Message type in the frame header does not match its contents".to_string()));
+    }
+    Ok(Some(Frame {
+        version,
+        msg_type,
+        request_id,
+        command
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn frame_bytes(request_id : u64, command : &ServerCommand) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, request_id, command).unwrap();
+        bytes
+    }
+
+    fn read(bytes : Vec<u8>) -> Result<Option<Frame>> {
+        read_frame(&mut Cursor::new(bytes))
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut bytes = frame_bytes(42, &ServerCommand::LoadEDB("sakila".to_string()));
+        bytes.extend(frame_bytes(43, &ServerCommand::ListEDBs));
+        let mut reader = Cursor::new(bytes);
+        let frame = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!((frame.version, frame.msg_type, frame.request_id), (PROTOCOL_VERSION, 6, 42));
+        assert!(matches!(frame.command, ServerCommand::LoadEDB(name) if name == "sakila"));
+        assert!(matches!(read_frame(&mut reader).unwrap().unwrap().command, ServerCommand::ListEDBs));
+        // closing between frames is not an error
+        assert!(read_frame(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn lengths_outside_the_limits_are_rejected() {
+        for frame_len in &[0, HEADER_LEN - 1, MAX_FRAME_LEN + 1, u32::MAX as usize] {
+            let mut bytes = (*frame_len as u32).to_be_bytes().to_vec();
+            bytes.extend(vec![0u8; HEADER_LEN]);
+            assert!(matches!(read(bytes), Err(Error::Protocol(_))), "{}", frame_len);
+        }
+        // a frame cut short is an error rather than a clean close
+        let mut bytes = frame_bytes(1, &ServerCommand::ListEDBs);
+        bytes.pop();
+        assert!(matches!(read(bytes), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn other_versions_are_rejected_except_for_hello() {
+        let mut bytes = frame_bytes(1, &ServerCommand::ListEDBs);
+        bytes[4..6].copy_from_slice(&(PROTOCOL_VERSION - 1).to_be_bytes());
+        assert!(matches!(read(bytes), Err(Error::Protocol(_))));
+
+        let mut bytes = frame_bytes(1, &ServerCommand::Hello(PROTOCOL_VERSION - 1));
+        bytes[4..6].copy_from_slice(&(PROTOCOL_VERSION - 1).to_be_bytes());
+        let frame = read(bytes).unwrap().unwrap();
+        assert_eq!(frame.version, PROTOCOL_VERSION - 1);
+        assert!(matches!(frame.command, ServerCommand::Hello(version) if version == PROTOCOL_VERSION - 1));
+    }
+
+    #[test]
+    fn a_type_that_does_not_match_the_contents_is_rejected() {
+        let mut bytes = frame_bytes(1, &ServerCommand::ListEDBs);
+        bytes[6] = ServerCommand::LoadEDB(String::new()).message_type();
+        assert!(matches!(read(bytes), Err(Error::Protocol(_))));
+        // nor can another message pass as a Hello to skip the version check
+        let mut bytes = frame_bytes(1, &ServerCommand::ListEDBs);
+        bytes[4..6].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+        bytes[6] = HELLO_TYPE;
+        assert!(matches!(read(bytes), Err(Error::Protocol(_))));
+    }
+}
@@ -0,0 +1,229 @@
+use crate::error::{ Error, Result };
+
+// Number of levels in the dyadic tree over the 64 bit order domain. Level 0 is
+// one value wide and level 64 is the whole domain.
+pub const LEVELS : u8 = 65;
+
+/// Maps a value of an ordered column into a u64 whose order matches the value's
+/// order. Numbers compare numerically and dates, written `YYYY-MM-DD` with an
+/// optional `HH:MM:SS`, compare chronologically.
+pub fn order_key(val : &str) -> Result<u64>{
+    let num = match val.trim().parse::<f64>() {
+        Ok(num) if num.is_finite() => num,
+        _ => match parse_date(val.trim()) {
+            Some(seconds) => seconds as f64,
+            None => return Err(Error::Parse(format!("{} is neither a number nor a date so it cannot be compared", val)))
+        }
+    };
+    Ok(float_key(num))
+}
+
+/// The order-preserving u64 of a finite number.
+pub fn float_key(num : f64) -> u64{
+    // -0.0 and 0.0 are the same value
+    let bits = (num + 0.0).to_bits();
+    if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | (1 << 63)
+    }
+}
+
+/// Every dyadic interval that contains `key`, one per level.
+pub fn dyadic_nodes(key : u64) -> Vec<(u8, u64)>{
+    (0..LEVELS).map(|level| (level, key.checked_shr(level as u32).unwrap_or(0))).collect()
+}
+
+/// The fewest dyadic intervals whose union is exactly `[lo, hi]`. There are at
+/// most two per level, so at most 128 in all.
+pub fn range_cover(lo : u64, hi : u64) -> Vec<(u8, u64)>{
+    let mut cover = Vec::new();
+    if lo > hi {
+        return cover;
+    }
+    let mut lo = lo as u128;
+    let hi = hi as u128;
+    while lo <= hi {
+        // grow the interval starting at lo while it stays aligned and inside the range
+        let mut level = 0;
+        while level < (LEVELS - 1) as u32 && lo & ((1u128 << (level + 1)) - 1) == 0 && lo + (1u128 << (level + 1)) - 1 <= hi {
+            level += 1;
+        }
+        cover.push((level as u8, (lo >> level) as u64));
+        lo += 1u128 << level;
+    }
+    cover
+}
+
+/// Turns optional bounds, each a value and whether it is inclusive, into the
+/// inclusive range of order keys they allow. `None` means no key can match.
+pub fn key_bounds(lower : &Option<(String, bool)>, upper : &Option<(String, bool)>) -> Result<Option<(u64, u64)>>{
+    let lo = match lower {
+        Some((val, true)) => order_key(val)?,
+        Some((val, false)) => match order_key(val)?.checked_add(1) {
+            Some(lo) => lo,
+            None => return Ok(None)
+        },
+        None => 0
+    };
+    let hi = match upper {
+        Some((val, true)) => order_key(val)?,
+        Some((val, false)) => match order_key(val)?.checked_sub(1) {
+            Some(hi) => hi,
+            None => return Ok(None)
+        },
+        None => u64::MAX
+    };
+    if lo > hi {
+        Ok(None)
+    } else {
+        Ok(Some((lo, hi)))
+    }
+}
+
+// Seconds since 1970-01-01 of a `YYYY-MM-DD` or `YYYY-MM-DD HH:MM:SS` date
+pub(crate) fn parse_date(val : &str) -> Option<i64>{
+    let mut parts = val.split_whitespace();
+    let date : Vec<i64> = parts.next()?.split('-').map(|s| s.parse::<i64>().ok()).collect::<Option<Vec<i64>>>()?;
+    let time : Vec<i64> = match parts.next() {
+        Some(time) => time.split(':').map(|s| s.parse::<i64>().ok()).collect::<Option<Vec<i64>>>()?,
+        None => vec!(0, 0, 0)
+    };
+    if parts.next().is_some() || date.len() != 3 || time.len() != 3 {
+        return None;
+    }
+    let (year, month, day) = (date[0], date[1], date[2]);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+        return None;
+    }
+    // days from civil, counting years from March so leap days fall last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    Some(days * 86400 + time[0] * 3600 + time[1] * 60 + time[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn width(level : u8) -> u128 {
+        1u128 << level
+    }
+
+    // Checks that the cover holds every key of [lo, hi] once and nothing else
+    fn assert_exact(lo : u64, hi : u64, cover : &[(u8, u64)]) {
+        let mut next = lo as u128;
+        for (level, node) in cover {
+            let start = (*node as u128) << level;
+            assert_eq!(start, next, "cover of [{}, {}] has a gap or an overlap", lo, hi);
+            next = start + width(*level);
+        }
+        assert_eq!(next, hi as u128 + 1, "cover of [{}, {}] does not end at the upper bound", lo, hi);
+    }
+
+    // The fewest dyadic intervals covering [lo, hi], found by trying every split
+    fn fewest(lo : u64, hi : u64) -> usize {
+        let mut best = vec![usize::MAX; (hi - lo + 2) as usize];
+        best[(hi - lo + 1) as usize] = 0;
+        for start in (lo..=hi).rev() {
+            for level in 0..LEVELS {
+                let end = start as u128 + width(level);
+                if start as u128 & (width(level) - 1) != 0 || end > hi as u128 + 1 {
+                    break;
+                }
+                let rest = best[(end - lo as u128) as usize];
+                if rest != usize::MAX {
+                    let i = (start - lo) as usize;
+                    best[i] = std::cmp::min(best[i], rest + 1);
+                }
+            }
+        }
+        best[0]
+    }
+
+    #[test]
+    fn cover_of_one_key() {
+        assert_eq!(range_cover(0, 0), vec![(0, 0)]);
+        assert_eq!(range_cover(u64::MAX, u64::MAX), vec![(0, u64::MAX)]);
+    }
+
+    #[test]
+    fn cover_of_whole_domain() {
+        assert_eq!(range_cover(0, u64::MAX), vec![(64, 0)]);
+        assert_eq!(range_cover(1, u64::MAX).len(), 64);
+    }
+
+    #[test]
+    fn cover_is_exact_and_minimal() {
+        assert_eq!(range_cover(1, 6), vec![(0, 1), (1, 1), (1, 2), (0, 6)]);
+        for lo in 0..70 {
+            for hi in lo..70 {
+                let cover = range_cover(lo, hi);
+                assert_exact(lo, hi, &cover);
+                assert_eq!(cover.len(), fewest(lo, hi), "cover of [{}, {}] is not minimal", lo, hi);
+            }
+        }
+        for &(lo, hi) in &[(3, u64::MAX - 2), (1 << 40 | 1, (1 << 41) + 7), (u64::MAX / 3, u64::MAX / 3 * 2)] {
+            let cover = range_cover(lo, hi);
+            assert_exact(lo, hi, &cover);
+            assert!(cover.len() <= 128);
+        }
+        assert!(range_cover(5, 4).is_empty());
+    }
+
+    #[test]
+    fn nodes_are_in_every_cover_holding_the_key() {
+        for &key in &[0, 1, 6, 1 << 63, u64::MAX] {
+            let nodes = dyadic_nodes(key);
+            assert_eq!(nodes.len(), LEVELS as usize);
+            assert_eq!(nodes[0], (0, key));
+            assert_eq!(nodes[64], (64, 0));
+            let cover = range_cover(key.saturating_sub(3), key.saturating_add(3));
+            assert_eq!(cover.iter().filter(|node| nodes.contains(node)).count(), 1);
+        }
+    }
+
+    #[test]
+    fn keys_keep_the_order_of_values() {
+        let values = ["-1e300", "-2.5", "-1", "-0.5", "0", "0.25", "1", "3", "1e300"];
+        let keys : Vec<u64> = values.iter().map(|val| order_key(val).unwrap()).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(order_key("-0").unwrap(), order_key("0").unwrap());
+        assert!(order_key("1999-12-31 23:59:59").unwrap() < order_key("2000-01-01").unwrap());
+        assert!(order_key("abc").is_err());
+    }
+
+    #[test]
+    fn bounds_leave_out_open_ends() {
+        let bound = |val : &str, inclusive : bool| Some((val.to_string(), inclusive));
+        let two = order_key("2").unwrap();
+        assert_eq!(key_bounds(&bound("2", true), &bound("2", true)).unwrap(), Some((two, two)));
+        assert_eq!(key_bounds(&bound("2", false), &bound("2", true)).unwrap(), None);
+        assert_eq!(key_bounds(&None, &None).unwrap(), Some((0, u64::MAX)));
+        assert_eq!(key_bounds(&bound("2", false), &None).unwrap(), Some((two + 1, u64::MAX)));
+    }
+
+    #[test]
+    fn dates_count_seconds_from_1970() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("1970-01-02 00:00:01"), Some(86401));
+        assert_eq!(parse_date("2000-03-01"), Some(951868800));
+        assert_eq!(parse_date("1969-12-31 23:59:59"), Some(-1));
+    }
+
+    #[test]
+    fn dates_out_of_range_are_rejected() {
+        assert_eq!(parse_date("2021-13-01"), None);
+        assert_eq!(parse_date("2021-00-10"), None);
+        assert_eq!(parse_date("2021-05-00"), None);
+        assert_eq!(parse_date("2021-05-32"), None);
+        assert_eq!(parse_date("2021-05-10 24:00:00"), None);
+        assert_eq!(parse_date("2021-05"), None);
+        assert_eq!(parse_date("2021-05-10 12:00"), None);
+        assert_eq!(parse_date("2021-05-10 12:00:00 extra"), None);
+    }
+}
@@ -0,0 +1,320 @@
+use std::fs::{ self, OpenOptions };
+use std::io::{ Read, Write };
+use std::net::{ Shutdown, TcpStream };
+use snow::{ Builder, TransportState };
+use crate::error::{ Error, Result };
+
+// Noise IK: the client knows the server's static key before connecting and sends
+// its own static key encrypted in the first message, so both ends are
+// authenticated against keys they pinned ahead of time.
+const NOISE_PARAMS : &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+
+const MAX_NOISE_MSG : usize = 65535;
+const TAG_LEN : usize = 16;
+
+/// A static X25519 key pair identifying a client or server.
+pub struct ChannelKeys {
+    pub private : Vec<u8>,
+    pub public : Vec<u8>,
+}
+
+impl ChannelKeys {
+    pub fn generate() -> Result<ChannelKeys> {
+        let keypair = Builder::new(noise_params()?)
+            .generate_keypair()
+            .map_err(|e| Error::Crypto(format!("Could not generate channel keys: {}", e)))?;
+        Ok(ChannelKeys {
+            private : keypair.private,
+            public : keypair.public
+        })
+    }
+
+    /// Writes the private key to `{path}.key` and the public key to `{path}.pub`, both hex encoded.
+    /// Only the owner may read the private key file.
+    pub fn save(&self, path : &str) -> Result<()> {
+        write_private(&format!("{}.key", path), to_hex(&self.private[..]).as_bytes())
+            .map_err(|e| Error::Io(format!("Could not write {}.key: {}", path, e)))?;
+        fs::write(format!("{}.pub", path), to_hex(&self.public[..]))
+            .map_err(|e| Error::Io(format!("Could not write {}.pub: {}", path, e)))
+    }
+
+    pub fn load(path : &str) -> Result<ChannelKeys> {
+        Ok(ChannelKeys {
+            private : load_key(&format!("{}.key", path))?,
+            public : load_key(&format!("{}.pub", path))?
+        })
+    }
+}
+
+/// Reads one hex encoded key from a file, e.g. a pinned `.pub` file.
+pub fn load_key(path : &str) -> Result<Vec<u8>> {
+    let contents = fs::read_to_string(path).map_err(|e| Error::Io(format!("Could not read key file {}: {}", path, e)))?;
+    from_hex(contents.trim()).ok_or(Error::Parse(format!("{} does not hold a hex encoded key", path)))
+}
+
+/// Reads a list of hex encoded public keys, one per line. Blank lines and lines
+/// starting with # are skipped.
+pub fn load_key_list(path : &str) -> Result<Vec<Vec<u8>>> {
+    let contents = fs::read_to_string(path).map_err(|e| Error::Io(format!("Could not read key list {}: {}", path, e)))?;
+    let mut keys = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match from_hex(line) {
+            Some(key) => keys.push(key),
+            None => return Err(Error::Parse(format!("{} holds a line that is not a hex encoded key", path)))
+        }
+    }
+    Ok(keys)
+}
+
+/// A TCP stream wrapped in an authenticated Noise session. Implements `Read` and
+/// `Write` so the frame protocol runs over it unchanged.
+pub struct SecureStream {
+    stream : TcpStream,
+    noise : TransportState,
+    read_buf : Vec<u8>,
+    read_pos : usize,
+}
+
+impl SecureStream {
+    /// Runs the client side of the handshake, failing unless the server proves it
+    /// holds the private key for `server_public`.
+    pub fn connect(mut stream : TcpStream, keys : &ChannelKeys, server_public : &[u8]) -> Result<SecureStream> {
+        let mut handshake = Builder::new(noise_params()?)
+            .local_private_key(&keys.private[..])
+            .remote_public_key(server_public)
+            .build_initiator()
+            .map_err(|e| Error::Crypto(format!("Could not start the channel handshake: {}", e)))?;
+        let mut buf = vec![0u8; MAX_NOISE_MSG];
+
+        let len = handshake.write_message(&[], &mut buf).map_err(|e| Error::Protocol(format!("Channel handshake failed: {}", e)))?;
+        send_noise_msg(&mut stream, &buf[..len])?;
+        let msg = recv_noise_msg(&mut stream)?
+            .ok_or(Error::Protocol("The server closed the connection during the channel handshake".to_string()))?;
+        handshake.read_message(&msg[..], &mut buf)
+            .map_err(|_| Error::Crypto("The server could not prove it holds the pinned server key".to_string()))?;
+
+        let noise = handshake.into_transport_mode().map_err(|e| Error::Protocol(format!("Channel handshake failed: {}", e)))?;
+        Ok(SecureStream::from_transport(stream, noise))
+    }
+
+    /// Runs the server side of the handshake, rejecting any client whose static
+    /// key is not in `authorized_clients`.
+    pub fn accept(mut stream : TcpStream, keys : &ChannelKeys, authorized_clients : &[Vec<u8>]) -> Result<SecureStream> {
+        let mut handshake = Builder::new(noise_params()?)
+            .local_private_key(&keys.private[..])
+            .build_responder()
+            .map_err(|e| Error::Crypto(format!("Could not start the channel handshake: {}", e)))?;
+        let mut buf = vec![0u8; MAX_NOISE_MSG];
+
+        let msg = recv_noise_msg(&mut stream)?
+            .ok_or(Error::Protocol("The client closed the connection during the channel handshake".to_string()))?;
+        handshake.read_message(&msg[..], &mut buf).map_err(|e| Error::Protocol(format!("Channel handshake failed: {}", e)))?;
+        let authorized = match handshake.get_remote_static() {
+            Some(client_public) => authorized_clients.iter().any(|key| &key[..] == client_public),
+            None => false
+        };
+        if !authorized {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(Error::Crypto("The client's key is not in the list of authorized clients".to_string()));
+        }
+
+        let len = handshake.write_message(&[], &mut buf).map_err(|e| Error::Protocol(format!("Channel handshake failed: {}", e)))?;
+        send_noise_msg(&mut stream, &buf[..len])?;
+
+        let noise = handshake.into_transport_mode().map_err(|e| Error::Protocol(format!("Channel handshake failed: {}", e)))?;
+        Ok(SecureStream::from_transport(stream, noise))
+    }
+
+    fn from_transport(stream : TcpStream, noise : TransportState) -> SecureStream {
+        SecureStream {
+            stream,
+            noise,
+            read_buf : Vec::new(),
+            read_pos : 0
+        }
+    }
+
+    /// The static key the other end authenticated with.
+    pub fn remote_key(&self) -> Option<&[u8]> {
+        self.noise.get_remote_static()
+    }
+
+    pub fn shutdown(&self) -> Result<()> {
+        self.stream.shutdown(Shutdown::Both).map_err(Error::from)
+    }
+}
+
+impl Read for SecureStream {
+    fn read(&mut self, out : &mut [u8]) -> std::io::Result<usize> {
+        if self.read_pos == self.read_buf.len() {
+            let msg = match recv_noise_msg(&mut self.stream) {
+                Ok(Some(msg)) => msg,
+                Ok(None) => return Ok(0),
+                Err(e) => return Err(std::io::Error::other(e.to_string()))
+            };
+            let mut plain = vec![0u8; msg.len()];
+            let len = self.noise.read_message(&msg[..], &mut plain)
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "A channel message failed authentication"))?;
+            plain.truncate(len);
+            self.read_buf = plain;
+            self.read_pos = 0;
+        }
+        let n = std::cmp::min(out.len(), self.read_buf.len() - self.read_pos);
+        out[..n].copy_from_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for SecureStream {
+    fn write(&mut self, data : &[u8]) -> std::io::Result<usize> {
+        let chunk = &data[..std::cmp::min(data.len(), MAX_NOISE_MSG - TAG_LEN)];
+        let mut msg = vec![0u8; chunk.len() + TAG_LEN];
+        let len = self.noise.write_message(chunk, &mut msg)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        send_noise_msg(&mut self.stream, &msg[..len])
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        Ok(chunk.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path : &str, contents : &[u8]) -> std::io::Result<()> {
+    use std::os::unix::fs::{ OpenOptionsExt, PermissionsExt };
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    // `mode` only applies when the file is created, so tighten a key file left by an older version
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path : &str, contents : &[u8]) -> std::io::Result<()> {
+    OpenOptions::new().write(true).create(true).truncate(true).open(path)?.write_all(contents)
+}
+
+fn noise_params() -> Result<snow::params::NoiseParams> {
+    NOISE_PARAMS.parse().map_err(|_| Error::Crypto("Unsupported noise parameters".to_string()))
+}
+
+// Noise messages are sent with a two byte big endian length in front of them
+fn send_noise_msg(stream : &mut TcpStream, msg : &[u8]) -> Result<()> {
+    let mut bytes = (msg.len() as u16).to_be_bytes().to_vec();
+    bytes.extend_from_slice(msg);
+    stream.write_all(&bytes[..]).map_err(|e| Error::Io(format!("Error writing to the channel: {}", e)))
+}
+
+fn recv_noise_msg(stream : &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut len_bytes = [0u8; 2];
+    match stream.read_exact(&mut len_bytes) {
+        Ok(_) => {},
+        Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(Error::Io(format!("Error reading from the channel: {}", e)))
+    }
+    let mut msg = vec![0u8; u16::from_be_bytes(len_bytes) as usize];
+    stream.read_exact(&mut msg[..]).map_err(|e| Error::Io(format!("Error reading from the channel: {}", e)))?;
+    Ok(Some(msg))
+}
+
+pub fn to_hex(bytes : &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s : &str) -> Option<Vec<u8>> {
+    if !s.is_ascii() || s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Accepts one connection on a loopback port and connects to it with `client_keys`
+    fn connect_pair(client_keys : &ChannelKeys, authorized : Vec<Vec<u8>>) -> (Result<SecureStream>, Result<SecureStream>) {
+        let server_keys = ChannelKeys::generate().unwrap();
+        let server_public = server_keys.public.clone();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            SecureStream::accept(stream, &server_keys, &authorized[..])
+        });
+        let client = SecureStream::connect(TcpStream::connect(addr).unwrap(), client_keys, &server_public[..]);
+        (client, server.join().unwrap())
+    }
+
+    #[test]
+    fn authorized_client_round_trips_frames() {
+        let client_keys = ChannelKeys::generate().unwrap();
+        let (client, server) = connect_pair(&client_keys, vec![client_keys.public.clone()]);
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        assert_eq!(server.remote_key(), Some(&client_keys.public[..]));
+
+        client.write_all(b"hello server").unwrap();
+        let mut buf = [0u8; 12];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello server");
+
+        // Larger than one noise message, so it is split over several
+        let big = vec![7u8; 3 * MAX_NOISE_MSG];
+        let sent = big.clone();
+        let writer = thread::spawn(move || server.write_all(&sent[..]));
+        let mut received = vec![0u8; big.len()];
+        client.read_exact(&mut received[..]).unwrap();
+        writer.join().unwrap().unwrap();
+        assert_eq!(received, big);
+    }
+
+    #[test]
+    fn unauthorized_client_is_rejected() {
+        let client_keys = ChannelKeys::generate().unwrap();
+        let other_keys = ChannelKeys::generate().unwrap();
+        let (client, server) = connect_pair(&client_keys, vec![other_keys.public.clone()]);
+        assert!(server.is_err());
+        assert!(client.is_err());
+    }
+
+    #[test]
+    fn tampered_frame_is_rejected() {
+        let client_keys = ChannelKeys::generate().unwrap();
+        let (client, server) = connect_pair(&client_keys, vec![client_keys.public.clone()]);
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        let mut msg = vec![0u8; 5 + TAG_LEN];
+        let len = client.noise.write_message(b"hello", &mut msg).unwrap();
+        msg[0] ^= 1;
+        send_noise_msg(&mut client.stream, &msg[..len]).unwrap();
+
+        let mut buf = [0u8; 5];
+        let err = server.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_key_file_is_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("channel-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let keys = ChannelKeys::generate().unwrap();
+        keys.save(path).unwrap();
+        let mode = fs::metadata(format!("{}.key", path)).unwrap().permissions().mode();
+        let loaded = ChannelKeys::load(path).unwrap();
+        fs::remove_file(format!("{}.key", path)).unwrap();
+        fs::remove_file(format!("{}.pub", path)).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(loaded.private, keys.private);
+        assert_eq!(loaded.public, keys.public);
+    }
+}
@@ -1,87 +1,299 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
 use serde::{Deserialize, Serialize};
-use crate::crypto_helper::{ prf, fixed_encrypt, fixed_decrypt, gen_key };
+use crate::crypto_helper::{ prf, rand_encrypt, rand_decrypt, derive_key, derive_seed, KeyRng, LabelledCiphertext };
+use crate::ore::{ OreKey, OreCiphertext };
+use crate::paillier::PaillierKey;
 use crate::token::{ HybToken, HybQuery };
-use crate::db_structs::{ DB, Relation };
-use crate::sti::{ STIClient, STIServer };
-
-// pub struct STE{
-//     client: STEClient,
-//     server: STEServer,
-// }
-// 
-// impl STE{
-//     pub fn new_ste() -> STE {
-//         STE {
-//             client : STEClient::new_ste_client(),
-//             server : STEServer::new_ste_server()
-//         }
-//     }
-// 
-//     pub fn setup_ste(&mut self, db : &mut DB){
-//         self.server = self.client.setup_ste(db);
-//     }
-// 
-//     pub fn search_ste(&mut self, qry : HybQuery){
-//         let tk = self.client.tokenize_ste(qry);
-//         let ciphertexts = self.server.eval_ste(tk);
-//         let rel = self.client.decrypt_ste(ciphertexts);
-//         rel.print_rel();
-//     }
-// }
+use crate::db_structs::{ DB, Relation, rows_digest };
+use crate::value::{ Value, ValueType };
+use crate::sti::{ STIClient, STIServer, STIUpdate, STIRewrite, get_tk };
+use crate::keystore::KeyStore;
+use crate::error::{ Error, Result };
 
 pub struct STEClient {
+    master_key : [u8; 32],
     enc_key: [u8; 16],
     sti_client : STIClient,
+    // made on first use, since finding its primes takes a while
+    paillier_key : Option<PaillierKey>,
     last_query : Option<HybQuery>,
+    // a hash of each relation's rows as the edb holds them, and the one the
+    // relation will have once the last update or rewrite is confirmed
+    digests : HashMap<String, [u8; 32]>,
+    pending_digest : Option<(String, [u8; 32])>,
 }
 
 impl STEClient {
     pub fn new_ste_client()->STEClient {
+        STEClient::with_rng(KeyRng::from_entropy())
+    }
+    
+    /// Builds a client whose master key is drawn from `rng`. Pass `KeyRng::seeded`
+    /// here to get the same keys on every run, e.g. for benchmarks.
+    pub fn with_rng(mut rng : KeyRng)->STEClient {
+        STEClient::from_master_key(rng.gen_master_key())
+    }
+    
+    /// Rebuilds a client from its 32 byte master key. Every other key is derived
+    /// from it, so this is all a client needs to keep to read its edb again.
+    pub fn from_master_key(master_key : [u8; 32])->STEClient {
         STEClient {
-            enc_key : [0u8; 16],
-            sti_client : STIClient::new_sti_client(),
-            last_query : None
+            master_key,
+            enc_key : derive_key(&master_key, "ste/cells"),
+            sti_client : STIClient::with_emm_key(derive_key(&master_key, "sti/emm")),
+            paillier_key : None,
+            last_query : None,
+            digests : HashMap::new(),
+            pending_digest : None
+        }
+    }
+    
+    pub fn master_key(&self) -> [u8; 32] {
+        self.master_key
+    }
+    
+    pub fn to_keystore(&self) -> KeyStore {
+        KeyStore {
+            master_key : self.master_key,
+            schema : self.sti_client.get_schema(),
+            annotations : self.sti_client.get_annotations(),
+            conjunctions : self.sti_client.get_conjunctions(),
+            ordered : self.sti_client.get_ordered(),
+            ore : self.sti_client.get_ore(),
+            summable : self.sti_client.get_summable(),
+            grouped : self.sti_client.get_grouped(),
+            types : self.sti_client.get_types(),
+            emm_state : self.sti_client.get_emm_state(),
+            digests : self.digests.clone()
         }
     }
     
-    pub fn setup_ste(&mut self, db : &mut DB) -> STEServer {
-        self.enc_key = gen_key();
-        let label_key = gen_key();
-        let mut data : HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
-        let mut tk_map : HashMap<Vec<u8>, [u8; 32]> = HashMap::new();
+    /// Rebuilds the client that wrote `keystore`, ready to query its edb without a new setup.
+    pub fn from_keystore(keystore : KeyStore) -> STEClient {
+        let mut client = STEClient::from_master_key(keystore.master_key);
+        client.sti_client.restore(keystore.schema, keystore.annotations, keystore.conjunctions, keystore.ordered, keystore.ore, keystore.summable, keystore.grouped, keystore.types);
+        client.sti_client.restore_emm_state(keystore.emm_state);
+        client.digests = keystore.digests;
+        client
+    }
+    
+    pub fn setup_ste(&mut self, db : &mut DB) -> Result<STEServer> {
+        let mut cells = EncryptedCells::new();
+        if !db.summable.is_empty() {
+            self.paillier_key();
+        }
         
         let ids = db.ids();
         let new_ids : Vec<String> = ids.iter().map(|s| s.to_string()).collect();
+        let mut digests = HashMap::new();
         for id in new_ids{
             let rel = db.get_rel(&id.to_string()).unwrap();
-            for row in &rel.table{
-                for (i, at) in rel.get_ats().iter().enumerate(){
-                    let label = (&id, &at, &row[0].to_string());
-                    let label_bytes = &bincode::serialize(&label).unwrap()[..];
-                    let label_vec = label_bytes.iter().cloned().collect();
-                    let tk = prf(label_bytes, &label_key[..]);
-                    tk_map.insert(label_vec, tk);
-                    
-                    let row_bytes = &bincode::serialize(&row[i]).unwrap()[..];
-                    let enc_row = fixed_encrypt(row_bytes, &self.enc_key[..]);
-                    data.insert(tk.to_vec(), enc_row);
-                }
-            }
+            self.encrypt_rows(&id, rel, &rel.table, &db.ore, &db.summable, &mut cells)?;
+            digests.insert(id, rows_digest(&rel.table));
         }
         
-        let sti_server = self.sti_client.setup_sti(db, &tk_map);
+        let sum_public_key = self.paillier_key.as_ref().map(|paillier_key| paillier_key.public_key());
+        let sti_server = self.sti_client.setup_sti(db, &cells.tk_map, cells.ore_data, cells.sum_data, sum_public_key)?;
+        
+        self.digests = digests;
+        self.pending_digest = None;
+        Ok(STEServer{
+            sti_server,
+            data: cells.data
+        })
+    }
+    
+    /// Encrypts rows about to be inserted into the relation `id` of the edb this
+    /// client set up. `db` holds the database as it was before the insert, and the
+    /// rows only belong in it once the server has stored the update and
+    /// `confirm_update` has been called.
+    pub fn update_ste(&mut self, db : &DB, id : &String, rows : &Vec<Vec<Value>>) -> Result<STEUpdate> {
+        let rel = self.set_up_relation(db, id, "insert into")?;
+        let summable = self.sti_client.get_summable();
+        if rel.get_ats().iter().any(|at| summable.contains(at)) {
+            self.paillier_key();
+        }
+        let mut cells = EncryptedCells::new();
+        self.encrypt_rows(id, rel, rows, &self.sti_client.get_ore(), &summable, &mut cells)?;
+        
+        // the new rows are joined to the rows of annotated relations by the
+        // first cell of each, so those are labelled as well
+        self.label_partners(db, id, &mut cells.tk_map);
+        
+        let sti_update = self.sti_client.update_sti(db, id, rows, &cells.tk_map, cells.ore_data, cells.sum_data)?;
+        let mut rows_after = rel.table.clone();
+        rows_after.extend(rows.iter().cloned());
+        self.pending_digest = Some((id.to_string(), rows_digest(&rows_after)));
+        Ok(STEUpdate{
+            sti_update,
+            data: cells.data
+        })
+    }
+    
+    /// Takes the `removed` rows out of the relation `id` of the edb this client set
+    /// up and puts the `added` rows in. Deleting a row only removes it, and
+    /// updating one removes the old version and adds the new one. `db` holds the
+    /// database as it was before, and the change only belongs in it once the
+    /// server has applied the rewrite and `confirm_update` has been called.
+    ///
+    /// Every list a removed or added row is filed under is written again whole,
+    /// and the lists of the relation's rows and of each of its columns hold every
+    /// row, so deleting a row costs work and traffic in proportion to the whole
+    /// relation rather than to the rows removed. The new lists are built from
+    /// `db`, so it is refused unless it holds the rows the edb was last changed with.
+    pub fn rewrite_ste(&mut self, db : &DB, id : &String, removed : &Vec<Vec<Value>>, added : &Vec<Vec<Value>>) -> Result<STERewrite> {
+        let rel = self.set_up_relation(db, id, "change")?;
+        let summable = self.sti_client.get_summable();
+        if rel.get_ats().iter().any(|at| summable.contains(at)) {
+            self.paillier_key();
+        }
+        let mut cells = EncryptedCells::new();
+        self.encrypt_rows(id, rel, added, &self.sti_client.get_ore(), &summable, &mut cells)?;
         
-        STEServer{
-            sti_server: sti_server,
-            data: data
+        // the lists being rewritten may hold any cell of the relation, and the
+        // join indexes the first cell of any row of an annotated relation
+        let mut rows_after = rel.without_rows(removed);
+        rows_after.extend(added.iter().cloned());
+        self.label_rows(id, rel, &rows_after, &mut cells.tk_map);
+        self.label_rows(id, rel, removed, &mut cells.tk_map);
+        self.label_partners(db, id, &mut cells.tk_map);
+        
+        let mut removed_cells = Vec::new();
+        for row in removed{
+            for at in rel.get_ats(){
+                removed_cells.push(get_tk(&cells.tk_map, id, &at, &rel.row_key(row))?.to_vec());
+            }
+        }
+        
+        let sti_rewrite = self.sti_client.rewrite_sti(db, id, removed, added, &cells.tk_map, cells.ore_data, cells.sum_data)?;
+        self.pending_digest = Some((id.to_string(), rows_digest(&rows_after)));
+        Ok(STERewrite{
+            sti_rewrite,
+            removed: removed_cells,
+            data: cells.data
+        })
+    }
+    
+    /// Records that the server stored the last update or rewrite, so the next one carries on after it.
+    pub fn confirm_update(&mut self){
+        self.sti_client.confirm_update();
+        if let Some((id, digest)) = self.pending_digest.take() {
+            self.digests.insert(id, digest);
+        }
+    }
+    
+    // The relation `id` of `db`, checked to be the one the edb was set up with
+    fn set_up_relation<'a>(&self, db : &'a DB, id : &String, action : &str) -> Result<&'a Relation> {
+        let rel = match db.get_rel(id) {
+            Some(rel) => rel,
+            None => return Err(Error::Schema(format!("Could not {} {} because there is no such relation", action, id)))
+        };
+        if self.sti_client.get_schema().is_empty() {
+            return Err(Error::Schema("An edb has to be set up before its rows can be changed".to_string()));
+        }
+        if self.sti_client.get_schema().get(id) != Some(&rel.get_ats()) {
+            return Err(Error::Schema(format!("The loaded relation {} is not the one the edb was set up with", id)));
+        }
+        // the lists the change writes are built from these rows, so they must
+        // be the ones the edb holds
+        if self.digests.get(id) != Some(&rows_digest(&rel.table)) {
+            return Err(Error::Schema(format!("The loaded rows of {} are not the ones the edb holds. Load the database as it was at the last change", id)));
+        }
+        Ok(rel)
+    }
+    
+    // Labels every cell of the rows of the relation `id` without encrypting them
+    fn label_rows(&self, id : &String, rel : &Relation, rows : &Vec<Vec<Value>>, tk_map : &mut HashMap<Vec<u8>, [u8; 32]>) {
+        let label_key = derive_key(&self.master_key, &format!("ste/labels/{}", id));
+        for row in rows{
+            let row_key = rel.row_key(row);
+            for at in rel.get_ats(){
+                let label = (id, &at, &row_key);
+                let label_bytes = &bincode::serialize(&label).unwrap()[..];
+                tk_map.insert(label_bytes.to_vec(), prf(label_bytes, &label_key[..]));
+            }
+        }
+    }
+    
+    // Labels the first cell of every row of the relations annotated to join `id`
+    fn label_partners(&self, db : &DB, id : &String, tk_map : &mut HashMap<Vec<u8>, [u8; 32]>) {
+        for (ats1, ats2) in self.sti_client.get_annotations(){
+            let (id1, id2) = match (db.get_id_from_ats(&ats1), db.get_id_from_ats(&ats2)) {
+                (Some(id1), Some(id2)) => (id1, id2),
+                _ => continue
+            };
+            let other_id = if &id1 == id { id2 } else if &id2 == id { id1 } else { continue };
+            let other = match db.get_rel(&other_id) {
+                Some(other) => other,
+                None => continue
+            };
+            let label_key = derive_key(&self.master_key, &format!("ste/labels/{}", other_id));
+            let uk = &other.get_ats()[0];
+            for row in &other.table{
+                let label = (&other_id, uk, &other.row_key(row));
+                let label_bytes = &bincode::serialize(&label).unwrap()[..];
+                tk_map.insert(label_bytes.to_vec(), prf(label_bytes, &label_key[..]));
+            }
+        }
+    }
+    
+    // Labels and encrypts every cell of the rows, along with their ORE and
+    // Paillier ciphertexts, for the relation `id`
+    fn encrypt_rows(&self, id : &String, rel : &Relation, rows : &Vec<Vec<Value>>, ore : &HashSet<String>,
+                    summable : &HashSet<String>, cells : &mut EncryptedCells) -> Result<()> {
+        let label_key = derive_key(&self.master_key, &format!("ste/labels/{}", id));
+        // every ORE column gets its own key so values of different columns never compare
+        let ore_keys : Vec<Option<OreKey>> = rel.get_ats().iter().map(|at| {
+            if ore.contains(at) {
+                Some(OreKey::new(derive_key(&self.master_key, &format!("ste/ore/{}/prf", at)),
+                                 derive_key(&self.master_key, &format!("ste/ore/{}/prp", at))))
+            } else {
+                None
+            }
+        }).collect();
+        if rel.get_key().is_empty() {
+            return Err(Error::Schema(format!("Relation {} has no primary key to label its rows with", id)));
+        }
+        let sum_key = self.paillier_key.as_ref();
+        for row in rows{
+            let row_key = rel.row_key(row);
+            for (i, at) in rel.get_ats().iter().enumerate(){
+                let label = (&id, &at, &row_key);
+                let label_bytes = &bincode::serialize(&label).unwrap()[..];
+                let label_vec = label_bytes.to_vec();
+                let tk = prf(label_bytes, &label_key[..]);
+                if cells.tk_map.insert(label_vec, tk).is_some() {
+                    return Err(Error::Schema(format!("Two rows of {} share the primary key ({})", id, rel.get_key().join(", "))));
+                }
+                
+                // bound to its label, so the server cannot pass it off as another cell
+                let row_bytes = &bincode::serialize(&row[i]).unwrap()[..];
+                let enc_row = rand_encrypt(row_bytes, &self.enc_key[..], &tk[..]);
+                cells.data.insert(tk.to_vec(), enc_row);
+                
+                // NULL has no order and adds nothing to a sum, so it gets neither ciphertext
+                if row[i].is_null() {
+                    continue;
+                }
+                if let Some(ore_key) = &ore_keys[i] {
+                    let key = row[i].order_key().map_err(|_| Error::Schema(format!("Could not store {} with ORE because {} is neither a number nor a date", &at, &row[i])))?;
+                    cells.ore_data.insert(tk.to_vec(), ore_key.encrypt(key));
+                }
+                
+                if let (Some(paillier_key), true) = (sum_key, summable.contains(at)) {
+                    cells.sum_data.insert(tk.to_vec(), paillier_key.encrypt(&row[i].to_string())?);
+                }
+            }
         }
+        Ok(())
     }
     
-    pub fn tokenize_ste(&mut self, qry : HybQuery) -> Result<HybToken, String> {
+    pub fn tokenize_ste(&mut self, qry : HybQuery) -> Result<HybToken> {
         if let HybQuery::BadQuery(s) = qry{
-            Err(s)
+            Err(Error::Parse(s))
         } else {
+            let qry = self.sti_client.bind_query(&qry)?;
             let tk_wrap = self.sti_client.tokenize_sti(&qry);
             match tk_wrap {
                 Ok(tk) => {
@@ -93,25 +305,96 @@ impl STEClient {
         }
     }
     
-    pub fn decrypt_ste(&mut self, ciphertexts : Vec<Vec<Vec<u8>>>) -> Relation {
+    pub fn decrypt_ste(&mut self, ciphertexts : Vec<Vec<LabelledCiphertext>>) -> Result<Relation> {
         let mut plaintexts : Vec<Vec<Vec<u8>>> = Vec::new();
         
         for ciphertext in ciphertexts{
             let mut p_vec = Vec::new();
-            for c_table in ciphertext{
-                p_vec.push(fixed_decrypt(&c_table[..], &self.enc_key[..]));
+            for (tk, c_table) in ciphertext{
+                p_vec.push(rand_decrypt(&c_table[..], &self.enc_key[..], &tk[..])?);
             }
             plaintexts.push(p_vec);
         }
         
-        let rel = self.sti_client.fin_sti(&self.last_query, plaintexts);
-        
-        rel
+        self.sti_client.fin_sti(&self.last_query, plaintexts)
+    }
+    
+    /// Decrypts the total and the count the server sent back for the last query, a SUM or AVG.
+    pub fn decrypt_sum(&mut self, ciphertext : Vec<u8>, enc_count : Vec<u8>) -> Result<Relation> {
+        let (attrib, avg) = match &self.last_query {
+            Some(HybQuery::Sum(attrib, avg, _)) => (attrib.to_string(), *avg),
+            _ => return Err(Error::Protocol("Received a sum for a query that asked for none".to_string()))
+        };
+        let name = if avg { format!("AVG({})", attrib) } else { format!("SUM({})", attrib) };
+        let ty = if avg { ValueType::Float } else { self.sti_client.get_type(&attrib) };
+        let count = self.sti_client.fin_count(&enc_count)?;
+        let mut rel = Relation::new_rel(vec!(name), vec!(ty));
+        // like SQL, the sum and average of no values are NULL
+        if count > 0 {
+            let total = self.paillier_key().decrypt_sum(&ciphertext, count, avg)?;
+            rel.add_row(vec!(Value::parse(&total, &ty)?))?;
+        } else {
+            rel.add_row(vec!(Value::Null))?;
+        }
+        Ok(rel)
+    }
+    
+    /// Decrypts the group counts the server sent back for the last query.
+    pub fn decrypt_group(&mut self, enc_counts : Vec<LabelledCiphertext>) -> Result<Relation> {
+        self.sti_client.fin_group(&self.last_query, enc_counts)
+    }
+    
+    /// Decrypts the count the server sent back for a COUNT(*) query into a single row.
+    pub fn decrypt_count(&self, enc_count : Vec<u8>) -> Result<Relation> {
+        let count = self.sti_client.fin_count(&enc_count)?;
+        let mut rel = Relation::new_rel(vec!(String::from("COUNT(*)")), vec!(ValueType::Int));
+        rel.add_row(vec!(Value::Int(count as i64)))?;
+        Ok(rel)
     }
     
     pub fn print_schema(&self){
         self.sti_client.print_schema();
     }
+    
+    fn paillier_key(&mut self) -> &PaillierKey {
+        let master_key = self.master_key;
+        self.paillier_key.get_or_insert_with(|| PaillierKey::from_seed(derive_seed(&master_key, "ste/paillier")))
+    }
+}
+
+// The cells of some rows as they are sent to the server, with the token of each
+struct EncryptedCells {
+    data : HashMap<Vec<u8>, Vec<u8>>,
+    tk_map : HashMap<Vec<u8>, [u8; 32]>,
+    ore_data : HashMap<Vec<u8>, OreCiphertext>,
+    sum_data : HashMap<Vec<u8>, Vec<u8>>
+}
+
+impl EncryptedCells {
+    fn new() -> EncryptedCells {
+        EncryptedCells {
+            data : HashMap::new(),
+            tk_map : HashMap::new(),
+            ore_data : HashMap::new(),
+            sum_data : HashMap::new()
+        }
+    }
+}
+
+/// Rows inserted after setup, encrypted for the server to add to its edb.
+#[derive(Serialize, Deserialize)]
+pub struct STEUpdate {
+    sti_update: STIUpdate,
+    data: HashMap<Vec<u8>, Vec<u8>>
+}
+
+/// Rows deleted or changed after setup: the cells the server drops and the
+/// encrypted cells it adds in their place.
+#[derive(Serialize, Deserialize)]
+pub struct STERewrite {
+    sti_rewrite: STIRewrite,
+    removed: Vec<Vec<u8>>,
+    data: HashMap<Vec<u8>, Vec<u8>>
 }
 
 #[derive(Serialize, Deserialize)]
@@ -129,32 +412,505 @@ impl STEServer {
         }
     }
     
-    pub fn eval_ste(&mut self, tk : HybToken) -> Vec<Vec<Vec<u8>>> {
-        let mut result : Vec<Vec<Vec<u8>>> = Vec::new();
-        let ref_tables = self.sti_server.eval_sti(tk);
+    /// Adds inserted rows to the edb. Nothing is added if any cell would replace one already there.
+    pub fn apply_update(&mut self, update : STEUpdate) -> Result<()> {
+        if update.data.keys().any(|tk| self.data.contains_key(tk)) {
+            return Err(Error::ServerState("An update tried to overwrite cells of the edb".to_string()));
+        }
+        self.sti_server.apply_update(update.sti_update)?;
+        self.data.extend(update.data);
+        Ok(())
+    }
+    
+    /// Drops the cells of deleted or changed rows and adds the new cells. Nothing
+    /// changes if a new cell would replace one that is kept.
+    pub fn apply_rewrite(&mut self, rewrite : STERewrite) -> Result<()> {
+        let removed : HashSet<&Vec<u8>> = rewrite.removed.iter().collect();
+        if rewrite.data.keys().any(|tk| self.data.contains_key(tk) && !removed.contains(tk)) {
+            return Err(Error::ServerState("A rewrite tried to overwrite cells of the edb".to_string()));
+        }
+        self.sti_server.apply_rewrite(rewrite.sti_rewrite, &rewrite.removed)?;
+        for tk in &rewrite.removed{
+            self.data.remove(tk);
+        }
+        self.data.extend(rewrite.data);
+        Ok(())
+    }
+    
+    /// The cells a search finds, each with its label so the client can check that
+    /// it is the cell stored under that label.
+    pub fn eval_ste(&self, tk : HybToken) -> Result<Vec<Vec<LabelledCiphertext>>> {
+        let mut result : Vec<Vec<LabelledCiphertext>> = Vec::new();
+        let ref_tables = self.sti_server.eval_sti(tk)?;
         let mut pointers = Vec::new();
         let mut table_pointers = Vec::new();
         for ref_table in ref_tables{
-            // println!("Number ref rows in id: {}", ref_table.refs.len());
             for ref_row in ref_table.refs{
-                // println!("Number ref cells in row: {}", &ref_row.cells.len());
                 table_pointers.extend(ref_row.cells);
             }
             pointers.push(table_pointers);
             table_pointers = Vec::new()
         }
         
-        // println!("Number pointers {}", pointers.len());
         for pointer in pointers{
-            // println!("Number cell pointers {}", pointer.len());
             let mut res_vec = Vec::new();
             for cell_token in pointer{
-                res_vec.push(self.data.get(&cell_token).unwrap().clone());
+                match self.data.get(&cell_token) {
+                    Some(cell) => res_vec.push((cell_token, cell.clone())),
+                    None => return Err(Error::ServerState("The edb holds no cell for a reference in the search".to_string()))
+                }
             }
             result.push(res_vec);
         }
         
-        result
+        Ok(result)
+    }
+    
+    pub fn count_ste(&self, tk : HybToken) -> Result<Vec<u8>> {
+        self.sti_server.count_sti(tk)
+    }
+    
+    pub fn sum_ste(&self, tk : HybToken) -> Result<(Vec<u8>, Vec<u8>)> {
+        self.sti_server.sum_sti(tk)
+    }
+    
+    pub fn group_ste(&self, tk : HybToken) -> Result<Vec<LabelledCiphertext>> {
+        self.sti_server.group_sti(tk)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::BoolQuery;
+
+    fn rel(id : &str, ats : &[&str], rows : &[&[&str]]) -> Relation {
+        let ats = ats.iter().map(|at| at.to_string()).collect::<Vec<String>>();
+        let declared = vec!(None; ats.len());
+        let rows = rows.iter().map(|row| row.iter().map(|val| val.to_string()).collect()).collect();
+        let mut rel = Relation::from_strings(ats, declared, rows).unwrap();
+        rel.default_key(&id.to_string());
+        rel
+    }
+
+    fn fixture() -> DB {
+        let mut db = DB::new_db();
+        db.add_rel(&"emp".to_string(), rel("emp", &["eid", "name", "dept", "salary", "hired"], &[
+            &["1", "ann", "10", "100", "2020-01-05"],
+            &["2", "bob", "10", "200", "2021-03-01"],
+            &["3", "cat", "20", "300", "2019-07-15"],
+            &["4", "dan", "30", "300", "2022-11-30"],
+        ]));
+        db.add_rel(&"dept".to_string(), rel("dept", &["did", "dname"], &[
+            &["10", "sales"],
+            &["20", "eng"],
+            &["30", "hr"],
+        ]));
+        db
+    }
+
+    fn set_up(db : &mut DB) -> (STEClient, STEServer) {
+        let mut client = STEClient::with_rng(KeyRng::seeded(7));
+        let server = client.setup_ste(db).unwrap();
+        (client, server)
+    }
+
+    // runs a query against the edb the way the client does without a server
+    fn search(client : &mut STEClient, server : &STEServer, qry : HybQuery) -> Result<Relation> {
+        let tk = client.tokenize_ste(qry)?;
+        match tk {
+            HybToken::Count(_, _) => client.decrypt_count(server.count_ste(tk)?),
+            HybToken::Sum(_, _, _, _) => {
+                let (total, enc_count) = server.sum_ste(tk)?;
+                client.decrypt_sum(total, enc_count)
+            },
+            HybToken::Group(_) => {
+                let enc_counts = server.group_ste(tk)?;
+                client.decrypt_group(enc_counts)
+            },
+            _ => {
+                let ciphertexts = server.eval_ste(tk)?;
+                client.decrypt_ste(ciphertexts)
+            }
+        }
+    }
+
+    // the rows of a relation with each cell named by its column, in an order
+    // that does not depend on how the relation was built
+    fn rows(rel : &Relation) -> Vec<Vec<(String, String)>> {
+        let mut rows : Vec<Vec<(String, String)>> = rel.table.iter().map(|row| {
+            let mut cells : Vec<(String, String)> = rel.get_ats().into_iter().zip(row.iter().map(|value| value.to_string())).collect();
+            cells.sort();
+            cells
+        }).collect();
+        rows.sort();
+        rows
+    }
+
+    fn select(id : &str, b_query : BoolQuery) -> HybQuery {
+        HybQuery::Select(b_query, Box::new(HybQuery::Id(id.to_string(), None)))
+    }
+
+    fn eq(attrib : &str, val : &str) -> BoolQuery {
+        BoolQuery::Eq(attrib.to_string(), val.to_string())
+    }
+
+    fn and(left : BoolQuery, right : BoolQuery) -> BoolQuery {
+        BoolQuery::And(Box::new(left), Box::new(right))
+    }
+
+    fn or(left : BoolQuery, right : BoolQuery) -> BoolQuery {
+        BoolQuery::Or(Box::new(left), Box::new(right))
+    }
+
+    fn not(sub : BoolQuery) -> BoolQuery {
+        BoolQuery::Not(Box::new(sub))
+    }
+
+    fn is_in(attrib : &str, vals : &[&str]) -> BoolQuery {
+        BoolQuery::In(attrib.to_string(), vals.iter().map(|val| val.to_string()).collect())
+    }
+
+    fn range(attrib : &str, lower : Option<(&str, bool)>, upper : Option<(&str, bool)>) -> BoolQuery {
+        let bound = |bound : Option<(&str, bool)>| bound.map(|(val, inclusive)| (val.to_string(), inclusive));
+        BoolQuery::Range(attrib.to_string(), bound(lower), bound(upper))
+    }
+
+    fn plain_select(db : &DB, id : &str, b_query : &BoolQuery) -> Relation {
+        let rel = db.get_rel(&id.to_string()).unwrap();
+        let table = rel.table.iter().filter(|row| rel.eval_bool(row, b_query).unwrap() == Some(true)).cloned().collect();
+        rel.with_rows(table)
+    }
+
+    fn insert(client : &mut STEClient, server : &mut STEServer, db : &mut DB, id : &str, values : &[&str]) {
+        let id = id.to_string();
+        let values = values.iter().map(|val| val.to_string()).collect::<Vec<String>>();
+        let rows = vec!(db.get_rel(&id).unwrap().row_from_strings(&values).unwrap());
+        server.apply_update(client.update_ste(db, &id, &rows).unwrap()).unwrap();
+        client.confirm_update();
+        for row in rows {
+            db.get_rel_mut(&id).unwrap().add_row(row).unwrap();
+        }
+    }
+
+    fn fp_join(ats1 : &[&str], ats2 : &[&str], left : HybQuery, right : HybQuery) -> HybQuery {
+        let to_strings = |ats : &[&str]| ats.iter().map(|at| at.to_string()).collect();
+        HybQuery::FPJoin(to_strings(ats1), to_strings(ats2), Box::new(left), Box::new(right))
+    }
+
+    fn id(id : &str) -> HybQuery {
+        HybQuery::Id(id.to_string(), None)
+    }
+
+    // each value of `attrib` with how many rows hold it
+    fn groups(rel : &Relation, attrib : &str) -> Vec<(String, String)> {
+        let ats = rel.get_ats();
+        let i = ats.iter().position(|at| at == attrib).unwrap();
+        let mut groups : Vec<(String, String)> = match ats.iter().position(|at| at == "COUNT(*)") {
+            Some(c) => rel.table.iter().map(|row| (row[i].to_string(), row[c].to_string())).collect(),
+            None => {
+                let mut counts : HashMap<String, usize> = HashMap::new();
+                for row in &rel.table {
+                    *counts.entry(row[i].to_string()).or_insert(0) += 1;
+                }
+                counts.into_iter().map(|(value, count)| (value, count.to_string())).collect()
+            }
+        };
+        groups.sort();
+        groups
+    }
+
+    // deletes the rows `b_query` selects, or sets `assignments` in them, the way the client does
+    fn change(client : &mut STEClient, server : &mut STEServer, db : &mut DB, id : &str, b_query : &BoolQuery, assignments : &[(&str, &str)]) {
+        let id = id.to_string();
+        let rel = db.get_rel(&id).unwrap();
+        let removed = plain_select(db, &id, b_query).table;
+        let added = match assignments {
+            [] => Vec::new(),
+            _ => rel.updated_rows(&removed, &assignments.iter().map(|(at, val)| (at.to_string(), val.to_string())).collect()).unwrap()
+        };
+        server.apply_rewrite(client.rewrite_ste(db, &id, &removed, &added).unwrap()).unwrap();
+        client.confirm_update();
+        let rel = db.get_rel_mut(&id).unwrap();
+        rel.table = rel.without_rows(&removed);
+        for row in added {
+            rel.add_row(row).unwrap();
+        }
+    }
+
+    fn count(rel : &Relation) -> String {
+        assert_eq!(rel.table.len(), 1);
+        rel.table[0][0].to_string()
+    }
+
+    #[test]
+    fn count_matches_the_plaintext_count() {
+        let mut db = fixture();
+        let (mut client, server) = set_up(&mut db);
+        for (attrib, val) in &[("dept", "10"), ("salary", "300"), ("name", "eve")] {
+            let qry = HybQuery::Count(Box::new(select("emp", eq(attrib, val))));
+            let expected = plain_select(&db, "emp", &eq(attrib, val)).table.len();
+            assert_eq!(count(&search(&mut client, &server, qry).unwrap()), expected.to_string());
+        }
+    }
+
+    #[test]
+    fn count_is_encrypted_under_the_token_key() {
+        let mut db = fixture();
+        let (mut client, server) = set_up(&mut db);
+        let qry = HybQuery::Count(Box::new(select("emp", eq("dept", "10"))));
+        let enc_count = server.count_ste(client.tokenize_ste(qry.clone()).unwrap()).unwrap();
+        assert_eq!(enc_count.len(), crate::sti::COUNT_CIPHERTEXT_BYTES);
+        assert!(!enc_count.windows(8).any(|bytes| bytes == 2u64.to_be_bytes() || bytes == 2u64.to_le_bytes()));
+        // a count answered under an earlier token cannot be read with the next one
+        client.tokenize_ste(qry).unwrap();
+        assert!(client.decrypt_count(enc_count).is_err());
+    }
+
+    #[test]
+    fn conjunctions_match_the_plaintext_rows() {
+        let mut db = fixture();
+        db.add_conjunction(&"dept".to_string(), &"salary".to_string());
+        let (mut client, server) = set_up(&mut db);
+        // the first two are answered from the conjunctive index, the others conjunct by conjunct
+        for b_query in [and(eq("dept", "10"), eq("salary", "200")), and(eq("salary", "300"), eq("dept", "30")),
+                        and(eq("name", "cat"), eq("dept", "10")), and(and(eq("dept", "10"), eq("salary", "100")), eq("name", "ann"))] {
+            let found = search(&mut client, &server, select("emp", b_query.clone())).unwrap();
+            assert_eq!(rows(&found), rows(&plain_select(&db, "emp", &b_query)));
+        }
+    }
+
+    #[test]
+    fn disjunctions_negations_and_in_lists_match_the_plaintext_rows() {
+        let mut db = fixture();
+        let (mut client, server) = set_up(&mut db);
+        for b_query in [or(eq("dept", "20"), eq("salary", "100")), not(eq("dept", "10")), is_in("name", &["ann", "cat", "eve"]),
+                        not(is_in("salary", &["100", "300"])), and(or(eq("dept", "10"), eq("dept", "30")), not(eq("name", "bob"))),
+                        or(not(eq("dept", "10")), eq("eid", "1"))] {
+            let found = search(&mut client, &server, select("emp", b_query.clone())).unwrap();
+            assert_eq!(rows(&found), rows(&plain_select(&db, "emp", &b_query)));
+        }
+    }
+
+    #[test]
+    fn ranges_match_the_plaintext_rows() {
+        let mut db = fixture();
+        db.add_ordered(&"salary".to_string());
+        db.add_ordered(&"hired".to_string());
+        let (mut client, server) = set_up(&mut db);
+        for b_query in [range("salary", Some(("150", false)), None), range("salary", None, Some(("200", true))),
+                        range("salary", Some(("100", true)), Some(("300", false))), range("salary", Some(("300", false)), None),
+                        range("hired", Some(("2020-01-05", true)), Some(("2021-12-31", true))), range("hired", None, Some(("2020-01-05", false))),
+                        and(range("salary", Some(("200", true)), None), eq("dept", "20"))] {
+            let found = search(&mut client, &server, select("emp", b_query.clone())).unwrap();
+            assert_eq!(rows(&found), rows(&plain_select(&db, "emp", &b_query)));
+        }
+    }
+
+    #[test]
+    fn group_counts_match_the_plaintext_counts() {
+        let mut db = fixture();
+        db.add_annotation(&["dept".to_string()], &["did".to_string()]);
+        db.add_grouped(&"dept".to_string());
+        db.add_grouped(&"dname".to_string());
+        let (mut client, server) = set_up(&mut db);
+        let emp = db.get_rel(&"emp".to_string()).unwrap();
+        let found = search(&mut client, &server, HybQuery::Group("dept".to_string(), Box::new(id("emp")))).unwrap();
+        assert_eq!(groups(&found, "dept"), groups(emp, "dept"));
+        let joined = emp.join(db.get_rel(&"dept".to_string()).unwrap(), &["dept".to_string()], &["did".to_string()]);
+        let qry = HybQuery::Group("dname".to_string(), Box::new(fp_join(&["did"], &["dept"], id("dept"), id("emp"))));
+        assert_eq!(groups(&search(&mut client, &server, qry).unwrap(), "dname"), groups(&joined, "dname"));
+    }
+
+    #[test]
+    fn values_are_compared_by_their_type() {
+        let mut db = fixture();
+        // a leading zero keeps a column text, where 01 and 1 differ
+        db.add_rel(&"zip".to_string(), rel("zip", &["code"], &[&["01"], &["1"]]));
+        let (mut client, server) = set_up(&mut db);
+        for (id, b_query, expected) in [("emp", eq("dept", "010"), 2), ("emp", eq("eid", "+1"), 1), ("emp", eq("hired", "2019-07-15"), 1), ("zip", eq("code", "01"), 1)] {
+            let found = search(&mut client, &server, select(id, b_query.clone())).unwrap();
+            assert_eq!(found.table.len(), expected);
+            assert_eq!(rows(&found), rows(&plain_select(&db, id, &b_query)));
+        }
+        let found = search(&mut client, &server, select("emp", eq("name", "ann"))).unwrap();
+        assert_eq!(found.get_types(), db.get_rel(&"emp".to_string()).unwrap().get_types());
+        assert!(matches!(found.table[0][..], [Value::Int(1), Value::Text(_), Value::Int(10), Value::Int(100), Value::Date(_)]));
+    }
+
+    #[test]
+    fn nulls_are_selected_only_by_is_null_and_join_nothing() {
+        let mut db = fixture();
+        db.add_rel(&"proj".to_string(), rel("proj", &["pid", "lead", "budget"], &[&["1", "1", "50"], &["2", "NULL", "70"], &["3", "3", ""]]));
+        db.add_annotation(&["lead".to_string()], &["eid".to_string()]);
+        db.add_ordered(&"budget".to_string());
+        let (mut client, server) = set_up(&mut db);
+        let is_null = |attrib : &str| BoolQuery::IsNull(attrib.to_string());
+        for b_query in [is_null("lead"), not(is_null("budget")), eq("lead", "1"), not(eq("lead", "1")), range("budget", Some(("0", true)), None),
+                        or(is_null("lead"), eq("lead", "3"))] {
+            let found = search(&mut client, &server, select("proj", b_query.clone())).unwrap();
+            assert_eq!(rows(&found), rows(&plain_select(&db, "proj", &b_query)));
+        }
+        let proj = db.get_rel(&"proj".to_string()).unwrap();
+        let joined = proj.join(db.get_rel(&"emp".to_string()).unwrap(), &["lead".to_string()], &["eid".to_string()]);
+        assert_eq!(joined.table.len(), 2);
+        let found = search(&mut client, &server, fp_join(&["lead"], &["eid"], id("proj"), id("emp"))).unwrap();
+        assert_eq!(rows(&found), rows(&joined));
+    }
+
+    #[test]
+    fn rows_sharing_a_first_column_keep_their_own_cells() {
+        let mut db = fixture();
+        let mut stock = rel("stock", &["store", "film", "qty"], &[&["1", "1", "5"], &["1", "2", "3"], &["2", "1", "7"]]);
+        stock.set_key(&vec!("store".to_string(), "film".to_string())).unwrap();
+        db.add_rel(&"stock".to_string(), stock);
+        // nothing is unique here, so the rows are numbered
+        db.add_rel(&"tag".to_string(), rel("tag", &["t"], &[&["x"], &["x"], &["y"]]));
+        assert_eq!(db.get_rel(&"tag".to_string()).unwrap().get_key(), vec!("tag.rowid".to_string()));
+        let (mut client, server) = set_up(&mut db);
+        for (id, b_query) in [("stock", eq("film", "1")), ("stock", eq("store", "1")), ("stock", not(eq("qty", "0"))), ("tag", eq("t", "x"))] {
+            let found = search(&mut client, &server, select(id, b_query.clone())).unwrap();
+            assert_eq!(rows(&found), rows(&plain_select(&db, id, &b_query)));
+        }
+        assert_eq!(search(&mut client, &server, id("stock")).unwrap().table.len(), 3);
+    }
+
+    #[test]
+    fn inserts_are_found_across_epochs() {
+        let mut db = fixture();
+        db.add_annotation(&["dept".to_string()], &["did".to_string()]);
+        db.add_conjunction(&"dept".to_string(), &"salary".to_string());
+        db.add_ordered(&"salary".to_string());
+        db.add_grouped(&"dept".to_string());
+        let (mut client, mut server) = set_up(&mut db);
+        let new_rows = [["5", "eve", "10", "500", "2023-02-01"], ["6", "fay", "40", "200", "2023-03-01"], ["7", "gus", "10", "200", "2023-04-01"]];
+        // searching between inserts starts a new epoch for the lists searched,
+        // and inserting twice in a row carries on in the same one
+        for (i, values) in new_rows.iter().enumerate() {
+            insert(&mut client, &mut server, &mut db, "emp", values);
+            if i == 1 {
+                continue;
+            }
+            for b_query in [eq("dept", "10"), and(eq("dept", "10"), eq("salary", "200")), range("salary", Some(("200", true)), None)] {
+                let found = search(&mut client, &server, select("emp", b_query.clone())).unwrap();
+                assert_eq!(rows(&found), rows(&plain_select(&db, "emp", &b_query)));
+            }
+            let emp = db.get_rel(&"emp".to_string()).unwrap();
+            assert_eq!(rows(&search(&mut client, &server, id("emp")).unwrap()), rows(emp));
+            let joined = emp.join(db.get_rel(&"dept".to_string()).unwrap(), &["dept".to_string()], &["did".to_string()]);
+            assert_eq!(rows(&search(&mut client, &server, fp_join(&["dept"], &["did"], id("emp"), id("dept"))).unwrap()), rows(&joined));
+            let found = search(&mut client, &server, HybQuery::Group("dept".to_string(), Box::new(id("emp")))).unwrap();
+            assert_eq!(groups(&found, "dept"), groups(emp, "dept"));
+        }
+        insert(&mut client, &mut server, &mut db, "dept", &["40", "ops"]);
+        let joined = db.get_rel(&"emp".to_string()).unwrap().join(db.get_rel(&"dept".to_string()).unwrap(), &["dept".to_string()], &["did".to_string()]);
+        assert_eq!(rows(&search(&mut client, &server, fp_join(&["dept"], &["did"], id("emp"), id("dept"))).unwrap()), rows(&joined));
+    }
+
+    #[test]
+    fn deleted_and_updated_rows_are_searched_as_they_are_now() {
+        let mut db = fixture();
+        db.add_annotation(&["dept".to_string()], &["did".to_string()]);
+        db.add_conjunction(&"dept".to_string(), &"salary".to_string());
+        db.add_ordered(&"salary".to_string());
+        db.add_grouped(&"dept".to_string());
+        let (mut client, mut server) = set_up(&mut db);
+        let old_tk = client.tokenize_ste(select("emp", eq("dept", "10"))).unwrap();
+        change(&mut client, &mut server, &mut db, "emp", &eq("eid", "1"), &[]);
+        // a token issued before the delete finds nothing of the rewritten list
+        assert!(server.eval_ste(old_tk).unwrap().concat().is_empty());
+        change(&mut client, &mut server, &mut db, "emp", &eq("name", "cat"), &[("dept", "10"), ("salary", "250")]);
+        change(&mut client, &mut server, &mut db, "dept", &eq("did", "30"), &[]);
+        for b_query in [eq("dept", "10"), eq("dept", "20"), eq("eid", "1"), and(eq("dept", "10"), eq("salary", "250")), range("salary", Some(("150", true)), Some(("260", true)))] {
+            let found = search(&mut client, &server, select("emp", b_query.clone())).unwrap();
+            assert_eq!(rows(&found), rows(&plain_select(&db, "emp", &b_query)));
+        }
+        let emp = db.get_rel(&"emp".to_string()).unwrap();
+        assert_eq!(rows(&search(&mut client, &server, id("emp")).unwrap()), rows(emp));
+        let joined = emp.join(db.get_rel(&"dept".to_string()).unwrap(), &["dept".to_string()], &["did".to_string()]);
+        assert_eq!(rows(&search(&mut client, &server, fp_join(&["dept"], &["did"], id("emp"), id("dept"))).unwrap()), rows(&joined));
+        let found = search(&mut client, &server, HybQuery::Group("dept".to_string(), Box::new(id("emp")))).unwrap();
+        assert_eq!(groups(&found, "dept"), groups(emp, "dept"));
+        let qry = HybQuery::Count(Box::new(select("emp", eq("dept", "10"))));
+        assert_eq!(count(&search(&mut client, &server, qry).unwrap()), "2");
+    }
+
+    #[test]
+    fn self_joins_match_the_plaintext_join() {
+        let mut db = fixture();
+        db.add_rel(&"staff".to_string(), rel("staff", &["sid", "name", "manager"], &[&["1", "ann", "NULL"], &["2", "bob", "1"], &["3", "cat", "1"], &["4", "dan", "2"]]));
+        db.add_annotation(&["manager".to_string()], &["sid".to_string()]);
+        let (mut client, server) = set_up(&mut db);
+        let staff = db.get_rel(&"staff".to_string()).unwrap();
+        let instance = |alias : &str| {
+            let mut rel = staff.with_rows(staff.table.clone());
+            rel.set_ats(staff.get_ats().iter().map(|at| format!("{}.{}", alias, at)).collect());
+            rel
+        };
+        let joined = instance("e").join(&instance("m"), &["e.manager".to_string()], &["m.sid".to_string()]);
+        let aliased = |alias : &str| HybQuery::Id("staff".to_string(), Some(alias.to_string()));
+        let qry = fp_join(&["e.manager"], &["m.sid"], aliased("e"), aliased("m"));
+        assert_eq!(rows(&search(&mut client, &server, qry.clone()).unwrap()), rows(&joined));
+        let found = search(&mut client, &server, HybQuery::Select(eq("m.name", "ann"), Box::new(qry))).unwrap();
+        let b_query = eq("m.name", "ann");
+        let expected = joined.with_rows(joined.table.iter().filter(|row| joined.eval_bool(row, &b_query).unwrap() == Some(true)).cloned().collect());
+        assert_eq!(expected.table.len(), 2);
+        assert_eq!(rows(&found), rows(&expected));
+    }
+
+    #[test]
+    fn composite_joins_match_the_plaintext_join() {
+        let mut db = fixture();
+        let mut stock = rel("stock", &["store", "film", "qty"], &[&["1", "1", "5"], &["1", "2", "3"], &["2", "1", "7"]]);
+        stock.set_key(&vec!("store".to_string(), "film".to_string())).unwrap();
+        db.add_rel(&"stock".to_string(), stock);
+        db.add_rel(&"rental".to_string(), rel("rental", &["rid", "rstore", "rfilm"], &[&["1", "1", "1"], &["2", "1", "1"], &["3", "2", "1"], &["4", "2", "2"], &["5", "1", "NULL"]]));
+        db.add_annotation(&["rstore".to_string(), "rfilm".to_string()], &["store".to_string(), "film".to_string()]);
+        let (mut client, mut server) = set_up(&mut db);
+        for step in 0..2 {
+            let rental = db.get_rel(&"rental".to_string()).unwrap();
+            let joined = rental.join(db.get_rel(&"stock".to_string()).unwrap(), &["rstore".to_string(), "rfilm".to_string()], &["store".to_string(), "film".to_string()]);
+            assert_eq!(joined.table.len(), 3 + step);
+            let (ats1, ats2) = (vec!("rfilm".to_string(), "rstore".to_string()), vec!("film".to_string(), "store".to_string()));
+            let qry = HybQuery::FPJoin(ats1.clone(), ats2.clone(), Box::new(id("rental")), Box::new(id("stock")));
+            assert_eq!(rows(&search(&mut client, &server, qry).unwrap()), rows(&joined));
+            let qry = HybQuery::PPJoin(ats1, ats2, Box::new(id("rental")), Box::new(id("stock")));
+            assert_eq!(rows(&search(&mut client, &server, qry).unwrap()), rows(&joined));
+            insert(&mut client, &mut server, &mut db, "rental", &[&(6 + step).to_string(), "1", "2"]);
+        }
+    }
+
+    #[test]
+    fn tokens_issued_after_the_keys_were_saved_miss_later_inserts() {
+        let mut db = fixture();
+        let (mut client, mut server) = set_up(&mut db);
+        let keystore = client.to_keystore();
+        let old_tks : Vec<HybToken> = vec!(select("emp", eq("dept", "10")), select("emp", eq("name", "eve"))).into_iter()
+            .map(|qry| client.tokenize_ste(qry).unwrap())
+            .collect();
+        let mut client = STEClient::from_keystore(keystore);
+        insert(&mut client, &mut server, &mut db, "emp", &["5", "eve", "10", "500", "2023-02-01"]);
+        // each row of emp comes back as its five cells
+        let found : Vec<usize> = old_tks.into_iter().map(|tk| server.eval_ste(tk).unwrap().concat().len() / 5).collect();
+        assert_eq!(found, vec!(2, 0));
+        let qry = select("emp", eq("dept", "10"));
+        assert_eq!(search(&mut client, &server, qry).unwrap().table.len(), 3);
+    }
+
+    #[test]
+    fn changes_are_refused_unless_the_rows_match_the_edb() {
+        let mut db = fixture();
+        let (mut client, mut server) = set_up(&mut db);
+        insert(&mut client, &mut server, &mut db, "emp", &["5", "eve", "10", "500", "2023-02-01"]);
+        let keystore = client.to_keystore();
+        let id = "emp".to_string();
+        let row = db.get_rel(&id).unwrap().row_from_strings(&["6", "fay", "20", "600", "2023-05-01"].map(String::from)).unwrap();
+        // the database as it was loaded before the insert
+        let stale = fixture();
+        let mut client = STEClient::from_keystore(keystore);
+        assert!(client.update_ste(&stale, &id, &vec!(row.clone())).is_err());
+        assert!(client.rewrite_ste(&stale, &id, &vec!(stale.get_rel(&id).unwrap().table[0].clone()), &Vec::new()).is_err());
+        assert!(client.update_ste(&db, &id, &vec!(row)).is_ok());
+    }
+}
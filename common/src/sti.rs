@@ -3,25 +3,133 @@ use std::collections::HashSet;
 use std::cmp::{ min, max };
 use serde::{Deserialize, Serialize};
 use crate::mm::MM;
-use crate::emm::{ EMMClient, EMMServer };
-use crate::token::{ HybToken, HybQuery, BoolQuery };
-use crate::db_structs::{DB, Relation};
-use crate::eval_references::{ RefTable, RefRow };
-// use crate::crypto_helper::{ prf, fixed_encrypt, fixed_decrypt, gen_key };
+use crate::crypto_helper::{ rand_encrypt, rand_decrypt, KeyRng, LabelledCiphertext, NONCE_LEN };
+use crate::range::{ dyadic_nodes, range_cover, key_bounds };
+use crate::ore::{ OreCiphertext, compare };
+use crate::paillier::PaillierPublicKey;
+use crate::emm::{ EMMClient, EMMServer, EMMUpdate, EMMRewrite, EMMState };
+use crate::token::{ HybToken, HybQuery, BoolQuery, BoolToken };
+use crate::db_structs::{DB, Relation, join_key};
+use crate::value::{ Value, ValueType };
+use crate::eval_references::{ RefTable, RefRow, RowFilter };
+use crate::error::{ Error, Result };
 
-fn get_tk(tk_map : &HashMap<Vec<u8>, [u8; 32]>, id : &String, at : &String, val : &String) -> [u8; 32]{
-    let label = (id, at, val);
-    let label_bytes = &bincode::serialize(&label).unwrap()[..];
-    let label_vec : Vec<u8> = label_bytes.iter().cloned().collect();
-    let tk = tk_map.get(&label_vec).unwrap();
-    *tk
+// The conjuncts of a selection by relation: its plain equalities, which can use a
+// conjunctive index, and the rest
+pub(crate) type ConjunctGroups = Vec<(String, Vec<(String, String)>, Vec<BoolQuery>)>;
+// The rows of a relation by their values of the join attributes
+type JoinGroups = HashMap<Vec<Value>, Vec<Vec<Value>>>;
+
+pub(crate) fn get_tk(tk_map : &HashMap<Vec<u8>, [u8; 32]>, id : &String, at : &String, row_key : &Vec<Value>) -> Result<[u8; 32]>{
+    let label = (id, at, row_key);
+    let label_bytes = bincode::serialize(&label).map_err(|e| Error::Crypto(format!("Could not serialize the label of a cell of {}: {}", id, e)))?;
+    match tk_map.get(&label_bytes) {
+        Some(tk) => Ok(*tk),
+        None => Err(Error::Schema(format!("No cell of {} in {} has the label of the row being indexed", at, id)))
+    }
+}
+
+fn get_rel<'a>(db : &'a DB, id : &String) -> Result<&'a Relation>{
+    db.get_rel(id).ok_or(Error::Schema(format!("Could not find a relation named {} in the schema", id)))
+}
+
+// The keyword of the group counts of a join annotation by the attribute `at`
+// of the relation on `side` of it
+fn group_join_qry(ats1 : &Vec<String>, ats2 : &Vec<String>, side : u8, at : &String) -> Vec<u8>{
+    bincode::serialize(&("gj", ats1, ats2, side, at)).unwrap()
+}
+
+// The ORDER BY a query ends with, looking through its projections
+fn get_top_order(qry : &HybQuery) -> Option<(String, bool, Option<usize>)>{
+    match qry {
+        HybQuery::Project(_, sub_query) => get_top_order(sub_query),
+        HybQuery::Order(attrib, desc, limit, _) => Some((attrib.to_string(), *desc, *limit)),
+        _ => None
+    }
+}
+
+// The relations a query reads, with the aliases they were given
+fn collect_ids(qry : &HybQuery, ids : &mut Vec<(String, Option<String>)>){
+    match qry {
+        HybQuery::FPJoin(_, _, sub_query1, sub_query2) | HybQuery::PPJoin(_, _, sub_query1, sub_query2) => {
+            collect_ids(sub_query1, ids);
+            collect_ids(sub_query2, ids);
+        },
+        HybQuery::Select(_, sub_query) | HybQuery::Project(_, sub_query) | HybQuery::Order(_, _, _, sub_query)
+            | HybQuery::Count(sub_query) | HybQuery::Sum(_, _, sub_query) | HybQuery::Group(_, sub_query) => collect_ids(sub_query, ids),
+        HybQuery::Id(id, alias) => ids.push((id.to_string(), alias.clone())),
+        HybQuery::BadQuery(_) => ()
+    }
+}
+
+// The attributes of the two sides of a join paired up, in an order that does
+// not depend on how the pairs were written
+fn sorted_pairs<'a>(ats1 : &'a [String], ats2 : &'a [String]) -> Vec<(&'a String, &'a String)>{
+    let mut pairs : Vec<(&String, &String)> = ats1.iter().zip(ats2.iter()).collect();
+    pairs.sort();
+    pairs
+}
+
+/// Groups the equalities of a conjunction into emm lookups. Two equalities whose
+/// attributes have a conjunctive index become one lookup; every other equality is
+/// looked up on its own.
+pub fn pair_conjuncts(conjuncts : &[(String, String)], conjunctions : &HashSet<(String, String)>)
+                    -> Vec<Vec<(String, String)>>{
+    let mut used = vec![false; conjuncts.len()];
+    let mut lookups = Vec::new();
+    for i in 0..conjuncts.len(){
+        if used[i] {
+            continue;
+        }
+        used[i] = true;
+        let (attrib1, val1) = &conjuncts[i];
+        let mut lookup = vec!((attrib1.to_string(), val1.to_string()));
+        for j in (i + 1)..conjuncts.len(){
+            let (attrib2, val2) = &conjuncts[j];
+            if used[j] {
+                continue;
+            }
+            if conjunctions.contains(&(attrib1.to_string(), attrib2.to_string())){
+                lookup.push((attrib2.to_string(), val2.to_string()));
+            } else if conjunctions.contains(&(attrib2.to_string(), attrib1.to_string())){
+                lookup.insert(0, (attrib2.to_string(), val2.to_string()));
+            } else {
+                continue;
+            }
+            used[j] = true;
+            break;
+        }
+        lookups.push(lookup);
+    }
+    lookups
 }
 
 pub struct STIClient {
     emm_client : EMMClient,
     schema: HashMap<String, Vec<String>>,
-    annotations : HashSet<(String, String)>
-    // label_key: [u8; 16],
+    annotations : HashSet<(Vec<String>, Vec<String>)>,
+    conjunctions : HashSet<(String, String)>,
+    ordered : HashSet<String>,
+    ore : HashSet<String>,
+    summable : HashSet<String>,
+    grouped : HashSet<String>,
+    types : HashMap<String, ValueType>,
+    // the relation of each instance in the last bound query
+    instances : HashMap<String, String>,
+    // the instance and attribute of each column of the last bound query
+    columns : HashMap<String, (String, String)>,
+    // the key the server encrypts the count of the last COUNT, SUM or AVG under
+    count_key : Option<[u8; 16]>,
+}
+
+// The server sends a count back encrypted under a key the client draws for that
+// one query and puts in the token
+const COUNT_AAD : &[u8] = b"count";
+/// The size of an encrypted count: a nonce, the count and the GCM tag.
+pub const COUNT_CIPHERTEXT_BYTES : usize = NONCE_LEN + 8 + 16;
+
+fn encrypt_count(count : u64, key : &[u8; 16]) -> Vec<u8> {
+    rand_encrypt(&count.to_be_bytes(), &key[..], COUNT_AAD)
 }
 
 impl STIClient {
@@ -30,201 +138,737 @@ impl STIClient {
             emm_client : EMMClient::new_emm_client(),
             schema : HashMap::new(),
             annotations : HashSet::new(),
-            // label_key: [0u8; 16],
+            conjunctions : HashSet::new(),
+            ordered : HashSet::new(),
+            ore : HashSet::new(),
+            summable : HashSet::new(),
+            grouped : HashSet::new(),
+            types : HashMap::new(),
+            instances : HashMap::new(),
+            columns : HashMap::new(),
+            count_key : None,
         }
     }
     
-    pub fn setup_sti(&mut self, db : &mut DB, tk_map : &HashMap<Vec<u8>, [u8; 32]>) -> STIServer{
+    pub fn with_emm_key(emm_key : [u8; 16]) -> STIClient {
+        STIClient{
+            emm_client : EMMClient::with_key(emm_key),
+            schema : HashMap::new(),
+            annotations : HashSet::new(),
+            conjunctions : HashSet::new(),
+            ordered : HashSet::new(),
+            ore : HashSet::new(),
+            summable : HashSet::new(),
+            grouped : HashSet::new(),
+            types : HashMap::new(),
+            instances : HashMap::new(),
+            columns : HashMap::new(),
+            count_key : None,
+        }
+    }
+    
+    pub fn setup_sti(&mut self, db : &mut DB, tk_map : &HashMap<Vec<u8>, [u8; 32]>,
+                     ore_data : HashMap<Vec<u8>, OreCiphertext>, sum_data : HashMap<Vec<u8>, Vec<u8>>,
+                     sum_key : Option<PaillierPublicKey>) -> Result<STIServer>{
         let mut mm = MM::new_mm();
-        let mut emm_client = EMMClient::new_emm_client();
-        // self.label_key = gen_key();
         self.schema = db.get_schema();
         self.annotations = db.annotations.clone();
-        // let mut set = HashSet::new();
-        // let mut data : HashMap<[u8; 32], Vec<u8>> = HashMap::new();
+        self.conjunctions = db.conjunctions.clone();
+        self.ordered = db.ordered.clone();
+        self.ore = db.ore.clone();
+        self.summable = db.summable.clone();
+        self.grouped = db.grouped.clone();
+        self.types = db.get_types();
         
     
+        for (attrib1, attrib2) in &db.conjunctions{
+            match (db.get_id_from_at(attrib1), db.get_id_from_at(attrib2)) {
+                (Some(id1), Some(id2)) if id1 == id2 => (),
+                _ => return Err(Error::Annotation(format!("Could not index ({0}, {1}) together because they are not attributes of the same relation", &attrib1, &attrib2)))
+            }
+        }
+        for attrib in &db.grouped{
+            if db.get_id_from_at(attrib).is_none() {
+                return Err(Error::Schema(format!("Could not precompute group counts on {} because it does not belong to any relations", &attrib)));
+            }
+        }
+        for attrib in &db.ordered{
+            if db.get_id_from_at(attrib).is_none() {
+                return Err(Error::Schema(format!("Could not build a range index on {} because it does not belong to any relations", &attrib)));
+            }
+        }
+        
         let ids = db.ids();
         let new_ids : Vec<String> = ids.iter().map(|s| s.to_string()).collect();
         for id in new_ids{
-            let id_qry = ("i", id.to_string());
-            let id_qry_bytes = &bincode::serialize(&id_qry).unwrap()[..];
-            let rel = db.get_rel(&id.to_string()).unwrap();
-            for row in &rel.table{
-                for (i, at) in rel.get_ats().iter().enumerate(){
-                    // add id to leaf multimap
-                    let tk = get_tk(&tk_map, &id, &at, &row[0].to_string());
-                    mm.add_mm(tk[..].to_vec(), id_qry_bytes.to_vec());
-                    
-                    
-                    // add to project multimap
-                    let proj_qry = ("p", &at);
-                    let proj_qry_bytes = &bincode::serialize(&proj_qry).unwrap()[..];
-                    mm.add_mm(tk[..].to_vec(), proj_qry_bytes.to_vec());
-                    
-                    // add to select multimap
-                    let sel_qry = ("s", &at, &row[i]);
-                    let sel_qry_bytes = &bincode::serialize(&sel_qry).unwrap()[..];
-                    // Note: can get rid of this for loop but it means you can't select on an attribute
-                    // once it's been projected away. This is logical but my current parser
-                    // projects before it selects when it sees a SELECT a FROM b WHERE c = d.
-                    // Can remove once I update the parser
-                    // for sub_at in rel.get_ats(){
-                    let tk = get_tk(&tk_map, &id, &at, &row[0].to_string());
-                    mm.add_mm(tk[..].to_vec(), sel_qry_bytes.to_vec());
-                        
-                        // opx set for inner selects
-                        // let set_label = prf(self.label_key, label_bytes);
-                        // 
-                        // set.insert();
-                }
-            }
+            let rel = get_rel(db, &id)?;
+            self.index_rows(&mut mm, &id, rel, &rel.table, tk_map)?;
         }
         
-        for (attrib1, attrib2) in &db.annotations{
+        for (ats1, ats2) in &db.annotations{
             // prepare join preliminaries
-            // println!("Annotated {} {}", attrib1, attrib2);
-            let id1 = &db.get_id_from_at(&attrib1);
-            let id2 = &db.get_id_from_at(&attrib2);
-            if let None = id1{
-                println!("Note: Could not annotate joining on ({0}, {1}) because the attribute {0} does not belong to any relations", &attrib1, &attrib2);
+            if ats1.is_empty() || ats1.len() != ats2.len() {
+                return Err(Error::Annotation(format!("Could not annotate joining ({}) with ({}) because they do not pair up", ats1.join(", "), ats2.join(", "))));
+            }
+            let id1 = match db.get_id_from_ats(ats1) {
+                Some(id1) => id1,
+                None => return Err(Error::Annotation(format!("Could not annotate joining ({0}) with ({1}) because ({0}) are not attributes of one relation", ats1.join(", "), ats2.join(", "))))
+            };
+            let id2 = match db.get_id_from_ats(ats2) {
+                Some(id2) => id2,
+                None => return Err(Error::Annotation(format!("Could not annotate joining ({0}) with ({1}) because ({1}) are not attributes of one relation", ats1.join(", "), ats2.join(", "))))
+            };
+            let rel1 = get_rel(db, &id1)?;
+            let rel2 = get_rel(db, &id2)?;
+            self.index_join(&mut mm, ats1, ats2, (&id1, rel1), (&id2, rel2), tk_map)?;
+            self.index_join_groups(&mut mm, ats1, ats2, &self.join_group_counts(ats1, ats2, rel1, rel2));
+        }
+        
+        let emm_server = self.emm_client.setup_emm(&mut mm);
+        
+        Ok(STIServer{
+            emm_server,
+            ore_data,
+            sum_data,
+            sum_key,
+        })
+    }
+    
+    /// Indexes rows about to be inserted into the relation `id`. `db` still holds
+    /// the relation as it was before the insert. A new row joins every row of the
+    /// annotated relation with the same values, so its pairs are added to the full
+    /// join index, and it enters the partial join list on its side. The partner
+    /// rows enter the list on their side too if no row of `id` held the values yet.
+    pub fn update_sti(&mut self, db : &DB, id : &String, rows : &Vec<Vec<Value>>, tk_map : &HashMap<Vec<u8>, [u8; 32]>,
+                      ore_data : HashMap<Vec<u8>, OreCiphertext>, sum_data : HashMap<Vec<u8>, Vec<u8>>) -> Result<STIUpdate>{
+        let mut mm = MM::new_mm();
+        let rel = match db.get_rel(id) {
+            Some(rel) => rel,
+            None => return Err(Error::Schema(format!("Could not insert into {} because there is no such relation", id)))
+        };
+        self.index_rows(&mut mm, id, rel, rows, tk_map)?;
+        
+        for (ats1, ats2) in &self.annotations{
+            let (id1, id2) = match (db.get_id_from_ats(ats1), db.get_id_from_ats(ats2)) {
+                (Some(id1), Some(id2)) => (id1, id2),
+                _ => continue
+            };
+            if &id1 != id && &id2 != id {
                 continue;
             }
-            if let None = id2{
-                println!("Note: Could not annotate joining on ({0}, {1}) because the attribute {1} does not belong to any relations", &attrib1, &attrib2);
+            // the pairs the new rows enter add to the group counts of the join,
+            // filed as further counts for the client to sum
+            let rel1 = if &id1 == id { rel } else { get_rel(db, &id1)? };
+            let rel2 = if &id2 == id { rel } else { get_rel(db, &id2)? };
+            let before = self.join_group_counts(ats1, ats2, rel1, rel2);
+            if !before.is_empty() {
+                let rel_after = rel.with_rows(rel.table.iter().chain(rows.iter()).cloned().collect());
+                let after = self.join_group_counts(ats1, ats2, if &id1 == id { &rel_after } else { rel1 },
+                                                   if &id2 == id { &rel_after } else { rel2 });
+                let mut increases = HashMap::new();
+                for (group, counts) in after{
+                    let old_counts = before.get(&group);
+                    let increase : HashMap<Value, usize> = counts.into_iter().filter_map(|(value, count)| {
+                        let old_count = old_counts.and_then(|old_counts| old_counts.get(&value)).cloned().unwrap_or(0);
+                        if count > old_count { Some((value, count - old_count)) } else { None }
+                    }).collect();
+                    increases.insert(group, increase);
+                }
+                self.index_join_groups(&mut mm, ats1, ats2, &increases);
+            }
+            if &id1 == id && &id2 == id {
+                // the relation joins itself, so the new rows may join the old rows
+                // and each other on either side. The join is indexed as it was
+                // before and after the insert, and what the insert adds is kept
+                let mut rows_after = rel.table.clone();
+                rows_after.extend(rows.iter().cloned());
+                let rel_after = rel.with_rows(rows_after);
+                let mut before = MM::new_mm();
+                self.index_join(&mut before, ats1, ats2, (id, rel), (id, rel), tk_map)?;
+                let mut after = MM::new_mm();
+                self.index_join(&mut after, ats1, ats2, (id, &rel_after), (id, &rel_after), tk_map)?;
+                for (keyword, entries) in after.data{
+                    let old_entries : HashSet<&Vec<u8>> = match before.data.get(&keyword) {
+                        Some(old_entries) => old_entries.iter().collect(),
+                        None => HashSet::new()
+                    };
+                    for entry in entries.iter().filter(|entry| !old_entries.contains(entry)){
+                        mm.add_mm(entry.clone(), keyword.clone());
+                    }
+                }
                 continue;
             }
-            let id1 = id1.as_ref().unwrap();
-            let id2 = id2.as_ref().unwrap();
-            if id1 == id2{
-                println!("Note: Could not annotate joining on ({0}, {1}) because they both belong to the relation {2}", &attrib1, &attrib2, &id1);
+            let new_first = &id1 == id;
+            if !new_first && &id2 != id {
                 continue;
             }
-            let rel1 = &db.get_rel(&id1).unwrap();
-            let rel2 = &db.get_rel(&id2).unwrap();
-            let uk1 = &rel1.get_ats()[0];
-            let uk2 = &rel2.get_ats()[0];
-            let (h1, h2) = self.prepare_join(&rel1, &rel2, &attrib1, &attrib2);
-            let rel1_values: HashSet<String> = h1.keys().cloned().collect();
-            let rel2_values: HashSet<String> = h2.keys().cloned().collect();
-            let intersect_values : HashSet<&String> = rel1_values.intersection(&rel2_values).collect();
+            let (new_ats, old_ats, old_id) = if new_first { (ats1, ats2, &id2) } else { (ats2, ats1, &id1) };
+            let (new_side, old_side) = if new_first { (1, 2) } else { (2, 1) };
+            let other = get_rel(db, old_id)?;
+            let (cols_new, cols_old) = match (rel.positions(new_ats), other.positions(old_ats)) {
+                (Some(cols_new), Some(cols_old)) => (cols_new, cols_old),
+                _ => continue
+            };
+            let uk_new = &rel.get_ats()[0];
+            let uk_old = &other.get_ats()[0];
+            
+            // NULL equals nothing, so a row with a NULL join value never joins
+            let existing : HashSet<Vec<Value>> = rel.table.iter().filter_map(|row| join_key(row, &cols_new)).collect();
+            let mut partners : HashMap<Vec<Value>, Vec<Vec<Value>>> = HashMap::new();
+            for row in &other.table{
+                if let Some(value) = join_key(row, &cols_old) {
+                    partners.entry(value).or_default().push(other.row_key(row));
+                }
+            }
             
-            // add to fp join multimap
-            let fp_join_qry = ("fpj", &attrib1, &attrib2);
+            let fp_join_qry = ("fpj", &ats1, &ats2);
             let fp_join_qry_bytes = &bincode::serialize(&fp_join_qry).unwrap()[..];
-            for value in &intersect_values{
-                for ref1 in h1.get(*value).unwrap(){
-                    for ref2 in h2.get(*value).unwrap(){
-                        let tk1 = get_tk(&tk_map, &id1, &uk1, &ref1.to_string());
-                        let tk2 = get_tk(&tk_map, &id2, &uk2, &ref2.to_string());
-                        
-                        let tk_pair = (tk1, tk2);
-                        let tk_bytes = &bincode::serialize(&tk_pair).unwrap()[..];
-                        mm.add_mm(tk_bytes[..].to_vec(), fp_join_qry_bytes.to_vec());
+            let pp_join_qry_new = ("ppj", &ats1, &ats2, new_side);
+            let pp_join_qry_new_bytes = &bincode::serialize(&pp_join_qry_new).unwrap()[..];
+            let pp_join_qry_old = ("ppj", &ats1, &ats2, old_side);
+            let pp_join_qry_old_bytes = &bincode::serialize(&pp_join_qry_old).unwrap()[..];
+            let mut listed : HashSet<Vec<Value>> = HashSet::new();
+            for row in rows{
+                let value = match join_key(row, &cols_new) {
+                    Some(value) => value,
+                    None => continue
+                };
+                let refs = match partners.get(&value) {
+                    Some(refs) => refs,
+                    None => continue
+                };
+                let tk_new = get_tk(tk_map, id, uk_new, &rel.row_key(row))?;
+                for partner in refs{
+                    let tk_old = get_tk(tk_map, old_id, uk_old, partner)?;
+                    // the pair keeps the order of the annotation
+                    let tk_pair = if new_first { (tk_new, tk_old) } else { (tk_old, tk_new) };
+                    let tk_bytes = &bincode::serialize(&tk_pair).unwrap()[..];
+                    mm.add_mm(tk_bytes[..].to_vec(), fp_join_qry_bytes.to_vec());
+                }
+                mm.add_mm(tk_new[..].to_vec(), pp_join_qry_new_bytes.to_vec());
+                if !existing.contains(&value) && listed.insert(value) {
+                    for partner in refs{
+                        let tk_old = get_tk(tk_map, old_id, uk_old, partner)?;
+                        mm.add_mm(tk_old[..].to_vec(), pp_join_qry_old_bytes.to_vec());
                     }
                 }
             }
-            
-            // add to pp join multimap
-            let pp_join_qry1 = ("ppj", &attrib1, &attrib2, 1);
-            let pp_join_qry_bytes1 = &bincode::serialize(&pp_join_qry1).unwrap()[..];
-            let pp_join_qry2 = ("ppj", &attrib1, &attrib2, 2);
-            let pp_join_qry_bytes2 = &bincode::serialize(&pp_join_qry2).unwrap()[..];
-            
-            for value in &intersect_values{
-                for ref1 in h1.get(*value).unwrap(){
-                    let tk1 = get_tk(&tk_map, &id1, &uk1, &ref1.to_string());
-                    mm.add_mm(tk1[..].to_vec(), pp_join_qry_bytes1.to_vec());
+        }
+        
+        Ok(STIUpdate{
+            emm_update: self.emm_client.update_emm(&mm)?,
+            ore_data,
+            sum_data,
+        })
+    }
+    
+    /// Indexes the relation `id` again after rows were deleted or changed. `db`
+    /// still holds the relation as it was before, `removed` are the rows taken out
+    /// of it and `added` the rows put in, so a changed row is removed and added
+    /// again. Only the lists whose entries change are rewritten, each of them
+    /// whole, from the rows the relation is left with. A join index is rewritten
+    /// when a changed row held values of the annotated attributes.
+    #[allow(clippy::too_many_arguments)]
+    pub fn rewrite_sti(&mut self, db : &DB, id : &String, removed : &Vec<Vec<Value>>, added : &Vec<Vec<Value>>,
+                       tk_map : &HashMap<Vec<u8>, [u8; 32]>, ore_data : HashMap<Vec<u8>, OreCiphertext>,
+                       sum_data : HashMap<Vec<u8>, Vec<u8>>) -> Result<STIRewrite>{
+        let rel = match db.get_rel(id) {
+            Some(rel) => rel,
+            None => return Err(Error::Schema(format!("Could not change {} because there is no such relation", id)))
+        };
+        let mut rows_after = rel.without_rows(removed);
+        rows_after.extend(added.iter().cloned());
+        let rel_after = rel.with_rows(rows_after);
+        
+        // a list changes when the removed rows file other entries under it than the added ones
+        let mut old_mm = MM::new_mm();
+        self.index_rows(&mut old_mm, id, rel, removed, tk_map)?;
+        let mut new_mm = MM::new_mm();
+        self.index_rows(&mut new_mm, id, rel, added, tk_map)?;
+        let mut keywords = HashSet::new();
+        for keyword in old_mm.data.keys().chain(new_mm.data.keys()){
+            let mut old_entries = old_mm.data.get(keyword).cloned().unwrap_or(Vec::new());
+            let mut new_entries = new_mm.data.get(keyword).cloned().unwrap_or(Vec::new());
+            old_entries.sort();
+            new_entries.sort();
+            if old_entries != new_entries {
+                keywords.insert(keyword.clone());
+            }
+        }
+        
+        let mut mm = MM::new_mm();
+        self.index_rows(&mut mm, id, &rel_after, &rel_after.table, tk_map)?;
+        for (ats1, ats2) in &self.annotations{
+            let (id1, id2) = match (db.get_id_from_ats(ats1), db.get_id_from_ats(ats2)) {
+                (Some(id1), Some(id2)) => (id1, id2),
+                _ => continue
+            };
+            // a relation that joins itself is on both sides
+            let mut sides = Vec::new();
+            if &id1 == id {
+                sides.extend(rel.positions(ats1));
+            }
+            if &id2 == id {
+                sides.extend(rel.positions(ats2));
+            }
+            if sides.is_empty() {
+                continue;
+            }
+            // a group count of the join changes when a changed row joined, or
+            // held a value of a grouped attribute
+            let rel1 = if &id1 == id { &rel_after } else { get_rel(db, &id1)? };
+            let rel2 = if &id2 == id { &rel_after } else { get_rel(db, &id2)? };
+            let before = self.join_group_counts(ats1, ats2, if &id1 == id { rel } else { rel1 }, if &id2 == id { rel } else { rel2 });
+            let mut changed = self.join_group_counts(ats1, ats2, rel1, rel2);
+            changed.retain(|group, counts| before.get(group) != Some(counts));
+            for (side, at) in changed.keys(){
+                keywords.insert(group_join_qry(ats1, ats2, *side, at));
+            }
+            self.index_join_groups(&mut mm, ats1, ats2, &changed);
+            // NULL never joins, so only rows holding values can change the join
+            let join_values = |rows : &Vec<Vec<Value>>| {
+                let mut values : Vec<(usize, Vec<Value>, Vec<Value>)> = Vec::new();
+                for (side, cols) in sides.iter().enumerate(){
+                    values.extend(rows.iter().filter_map(|row| join_key(row, cols).map(|value| (side, rel.row_key(row), value))));
                 }
-            
+                values.sort();
+                values
+            };
+            if join_values(removed) == join_values(added) {
+                continue;
+            }
+            keywords.insert(bincode::serialize(&("fpj", &ats1, &ats2)).unwrap());
+            keywords.insert(bincode::serialize(&("ppj", &ats1, &ats2, 1)).unwrap());
+            keywords.insert(bincode::serialize(&("ppj", &ats1, &ats2, 2)).unwrap());
+            self.index_join(&mut mm, ats1, ats2, (&id1, rel1), (&id2, rel2), tk_map)?;
+        }
+        
+        Ok(STIRewrite{
+            emm_rewrite: self.emm_client.rewrite_emm(&keywords, &mm)?,
+            ore_data,
+            sum_data,
+        })
+    }
+    
+    // A key for the server to encrypt the count of this query under
+    fn new_count_key(&mut self) -> [u8; 16] {
+        let count_key = KeyRng::from_entropy().gen_key();
+        self.count_key = Some(count_key);
+        count_key
+    }
+    
+    /// Decrypts the count the server sent back for the last COUNT, SUM or AVG.
+    pub fn fin_count(&self, enc_count : &[u8]) -> Result<u64> {
+        let count_key = match &self.count_key {
+            Some(count_key) => count_key,
+            None => return Err(Error::Protocol("Received a count for a query that asked for none".to_string()))
+        };
+        let count_bytes = rand_decrypt(enc_count, &count_key[..], COUNT_AAD)?;
+        let mut be_bytes = [0u8; 8];
+        if count_bytes.len() != be_bytes.len() {
+            return Err(Error::Protocol("The server sent back a count that is not one number".to_string()));
+        }
+        be_bytes.copy_from_slice(&count_bytes);
+        Ok(u64::from_be_bytes(be_bytes))
+    }
+    
+    /// Records that the server stored the last update or rewrite.
+    pub fn confirm_update(&mut self){
+        self.emm_client.confirm_update();
+    }
+    
+    pub fn get_emm_state(&self) -> Option<EMMState>{
+        self.emm_client.get_state()
+    }
+    
+    pub fn restore_emm_state(&mut self, emm_state : Option<EMMState>){
+        self.emm_client.restore_state(emm_state);
+    }
+    
+    // Adds every pair of rows of the two relations that join on the annotation
+    // to the full join index, and each row that joins anything to the partial
+    // join list on its side
+    fn index_join(&self, mm : &mut MM, ats1 : &Vec<String>, ats2 : &Vec<String>, (id1, rel1) : (&String, &Relation),
+                  (id2, rel2) : (&String, &Relation), tk_map : &HashMap<Vec<u8>, [u8; 32]>) -> Result<()>{
+        // the server holds on to a row by the cell of its first column, which
+        // is unique to the row since every label carries the row's key
+        let uk1 = &rel1.get_ats()[0];
+        let uk2 = &rel2.get_ats()[0];
+        let (h1, h2) = self.prepare_join(rel1, rel2, ats1, ats2);
+        let rel1_values: HashSet<Vec<Value>> = h1.keys().cloned().collect();
+        let rel2_values: HashSet<Vec<Value>> = h2.keys().cloned().collect();
+        let intersect_values : HashSet<&Vec<Value>> = rel1_values.intersection(&rel2_values).collect();
+        
+        // add to fp join multimap
+        let fp_join_qry = ("fpj", &ats1, &ats2);
+        let fp_join_qry_bytes = &bincode::serialize(&fp_join_qry).unwrap()[..];
+        for value in &intersect_values{
+            for ref1 in h1.get(*value).unwrap(){
                 for ref2 in h2.get(*value).unwrap(){
-                    let tk2 = get_tk(&tk_map, &id2, &uk2, &ref2.to_string());
-                    mm.add_mm(tk2[..].to_vec(), pp_join_qry_bytes2.to_vec());
+                    let tk1 = get_tk(tk_map, id1, uk1, ref1)?;
+                    let tk2 = get_tk(tk_map, id2, uk2, ref2)?;
+                    
+                    let tk_pair = (tk1, tk2);
+                    let tk_bytes = &bincode::serialize(&tk_pair).unwrap()[..];
+                    mm.add_mm(tk_bytes[..].to_vec(), fp_join_qry_bytes.to_vec());
                 }
             }
         }
         
-        let emm_server = emm_client.setup_emm(&mut mm);
-        self.emm_client = emm_client;
+        // add to pp join multimap
+        let pp_join_qry1 = ("ppj", &ats1, &ats2, 1);
+        let pp_join_qry_bytes1 = &bincode::serialize(&pp_join_qry1).unwrap()[..];
+        let pp_join_qry2 = ("ppj", &ats1, &ats2, 2);
+        let pp_join_qry_bytes2 = &bincode::serialize(&pp_join_qry2).unwrap()[..];
         
-        STIServer{
-            emm_server: emm_server,
-            // set: set,
-            // data: data
+        for value in &intersect_values{
+            for ref1 in h1.get(*value).unwrap(){
+                let tk1 = get_tk(tk_map, id1, uk1, ref1)?;
+                mm.add_mm(tk1[..].to_vec(), pp_join_qry_bytes1.to_vec());
+            }
+        
+            for ref2 in h2.get(*value).unwrap(){
+                let tk2 = get_tk(tk_map, id2, uk2, ref2)?;
+                mm.add_mm(tk2[..].to_vec(), pp_join_qry_bytes2.to_vec());
+            }
         }
+        Ok(())
     }
     
-    fn prepare_join(&self, rel1 : &Relation, rel2 : &Relation, at1 : &String, at2 : &String)
-                    -> (HashMap<String, Vec<String>>, HashMap<String, Vec<String>>){        
-        let mut i1_opt = None;
-        let mut i2_opt = None;
-        let mut h1 : HashMap<String, Vec<String>> = HashMap::new();
-        let mut h2 : HashMap<String, Vec<String>> = HashMap::new();
-        for (i, at) in rel1.get_ats().iter().enumerate(){
-            if at == at1{
-                i1_opt = Some(i);
+    // Adds the rows of one relation to every index that is not a join index: the
+    // id, projection and selection lists, and the conjunctive, range and group
+    // count indexes of its attributes. The rows need not be in the relation yet
+    fn index_rows(&self, mm : &mut MM, id : &String, rel : &Relation, rows : &Vec<Vec<Value>>,
+                  tk_map : &HashMap<Vec<u8>, [u8; 32]>) -> Result<()>{
+        let id_qry = ("i", id.to_string());
+        let id_qry_bytes = &bincode::serialize(&id_qry).unwrap()[..];
+        let ats = rel.get_ats();
+        for row in rows{
+            for (i, at) in ats.iter().enumerate(){
+                // add id to leaf multimap
+                let tk = get_tk(tk_map, id, at, &rel.row_key(row))?;
+                mm.add_mm(tk[..].to_vec(), id_qry_bytes.to_vec());
+                
+                
+                // add to project multimap
+                let proj_qry = ("p", &at);
+                let proj_qry_bytes = &bincode::serialize(&proj_qry).unwrap()[..];
+                mm.add_mm(tk[..].to_vec(), proj_qry_bytes.to_vec());
+                
+                // add to select multimap
+                let sel_qry = ("s", &at, &row[i]);
+                let sel_qry_bytes = &bincode::serialize(&sel_qry).unwrap()[..];
+                // Note: can get rid of this for loop but it means you can't select on an attribute
+                // once it's been projected away. This is logical but my current parser
+                // projects before it selects when it sees a SELECT a FROM b WHERE c = d.
+                // Can remove once I update the parser
+                mm.add_mm(tk[..].to_vec(), sel_qry_bytes.to_vec());
+            }
+        }
+        
+        for (attrib1, attrib2) in &self.conjunctions{
+            // add to conjunctive select multimap. Rows are referenced by their
+            // first cell since projection never removes it
+            let (i1, i2) = match (ats.iter().position(|at| at == attrib1), ats.iter().position(|at| at == attrib2)) {
+                (Some(i1), Some(i2)) => (i1, i2),
+                _ => continue
+            };
+            for row in rows.iter().filter(|row| !row[i1].is_null() && !row[i2].is_null()){
+                let conj_qry = ("s2", &attrib1, &row[i1], &attrib2, &row[i2]);
+                let conj_qry_bytes = &bincode::serialize(&conj_qry).unwrap()[..];
+                let tk = get_tk(tk_map, id, &ats[0], &rel.row_key(row))?;
+                mm.add_mm(tk[..].to_vec(), conj_qry_bytes.to_vec());
+            }
+        }
+        
+        for attrib in &self.ordered{
+            // add to range multimap. Each row is filed under every dyadic interval
+            // holding its value, so a range is answered by the intervals covering it
+            let i = match ats.iter().position(|at| at == attrib) {
+                Some(i) => i,
+                None => continue
+            };
+            // NULL is outside every range
+            for row in rows.iter().filter(|row| !row[i].is_null()){
+                let key = row[i].order_key().map_err(|_| Error::Schema(format!("Could not build a range index on {} because {} is neither a number nor a date", &attrib, &row[i])))?;
+                let tk = get_tk(tk_map, id, attrib, &rel.row_key(row))?;
+                for (level, node) in dyadic_nodes(key){
+                    let range_qry = ("r", &attrib, level, node);
+                    let range_qry_bytes = &bincode::serialize(&range_qry).unwrap()[..];
+                    mm.add_mm(tk[..].to_vec(), range_qry_bytes.to_vec());
+                }
             }
         }
-        for (i, at) in rel2.get_ats().iter().enumerate(){
-            if at == at2{
-                i2_opt = Some(i);
+        
+        for attrib in &self.grouped{
+            // add to group count multimap. Each value of the attribute is filed
+            // with its count under one keyword, returned still encrypted. Rows
+            // added later file their own counts, which the client sums
+            let i = match ats.iter().position(|at| at == attrib) {
+                Some(i) => i,
+                None => continue
+            };
+            let mut counts : HashMap<&Value, usize> = HashMap::new();
+            for row in rows{
+                *counts.entry(&row[i]).or_insert(0) += 1;
+            }
+            let group_qry = ("g", &attrib);
+            let group_qry_bytes = &bincode::serialize(&group_qry).unwrap()[..];
+            for (value, count) in counts{
+                let count_bytes = &bincode::serialize(&(value, count)).unwrap()[..];
+                mm.add_mm(count_bytes.to_vec(), group_qry_bytes.to_vec());
             }
         }
-        if let Some(i1) = i1_opt {
-            if let Some(i2) = i2_opt {
-                for row in &rel1.table {
-                    h1.entry(row[i1].clone()).or_insert(Vec::new()).push(row[0].clone())
+        Ok(())
+    }
+    
+    // Groups the keys of the rows of each relation by their values of the join attributes
+    fn prepare_join(&self, rel1 : &Relation, rel2 : &Relation, ats1 : &[String], ats2 : &[String])
+                    -> (JoinGroups, JoinGroups){        
+        let mut h1 : JoinGroups = HashMap::new();
+        let mut h2 : JoinGroups = HashMap::new();
+        if let (Some(cols1), Some(cols2)) = (rel1.positions(ats1), rel2.positions(ats2)) {
+            for row in &rel1.table {
+                if let Some(value) = join_key(row, &cols1) {
+                    h1.entry(value).or_default().push(rel1.row_key(row))
                 }
-                for row in &rel2.table {
-                    h2.entry(row[i2].clone()).or_insert(Vec::new()).push(row[0].clone())
+            }
+            for row in &rel2.table {
+                if let Some(value) = join_key(row, &cols2) {
+                    h2.entry(value).or_default().push(rel2.row_key(row))
                 }
             }
         }
         (h1, h2)
     }
     
-    pub fn tokenize_sti(&mut self, qry : &HybQuery) -> Result<HybToken, String> {
+    // Counts the pairs of rows joined on the annotation by their value of each
+    // GROUPED attribute of either relation, keyed by the side of the annotation
+    // the attribute is on. Each grouped attribute has an entry, if only an empty one
+    fn join_group_counts(&self, ats1 : &[String], ats2 : &[String], rel1 : &Relation, rel2 : &Relation)
+                         -> HashMap<(u8, String), HashMap<Value, usize>>{
+        let mut counts = HashMap::new();
+        let (cols1, cols2) = match (rel1.positions(ats1), rel2.positions(ats2)) {
+            (Some(cols1), Some(cols2)) => (cols1, cols2),
+            _ => return counts
+        };
+        for &(side, rel, cols, other, other_cols) in &[(1u8, rel1, &cols1, rel2, &cols2), (2u8, rel2, &cols2, rel1, &cols1)]{
+            let ats = rel.get_ats();
+            let grouped : Vec<(usize, &String)> = ats.iter().enumerate().filter(|(_, at)| self.grouped.contains(*at)).collect();
+            if grouped.is_empty() {
+                continue;
+            }
+            for (_, at) in &grouped{
+                counts.insert((side, at.to_string()), HashMap::new());
+            }
+            // each row is in as many pairs as the other relation has rows with its join value
+            let mut partners : HashMap<Vec<Value>, usize> = HashMap::new();
+            for row in &other.table{
+                if let Some(value) = join_key(row, other_cols) {
+                    *partners.entry(value).or_insert(0) += 1;
+                }
+            }
+            for row in &rel.table{
+                let pairs = match join_key(row, cols).and_then(|value| partners.get(&value)) {
+                    Some(pairs) => *pairs,
+                    None => continue
+                };
+                for (i, at) in &grouped{
+                    let group_counts = counts.entry((side, at.to_string())).or_insert_with(HashMap::new);
+                    *group_counts.entry(row[*i].clone()).or_insert(0) += pairs;
+                }
+            }
+        }
+        counts
+    }
+    
+    // Files the group counts of a join, each value with its count under the
+    // keyword of its attribute, as the group count index does for a relation
+    fn index_join_groups(&self, mm : &mut MM, ats1 : &Vec<String>, ats2 : &Vec<String>,
+                         counts : &HashMap<(u8, String), HashMap<Value, usize>>){
+        for ((side, at), group_counts) in counts{
+            let group_qry_bytes = group_join_qry(ats1, ats2, *side, at);
+            for (value, count) in group_counts{
+                let count_bytes = &bincode::serialize(&(value, count)).unwrap()[..];
+                mm.add_mm(count_bytes.to_vec(), group_qry_bytes.clone());
+            }
+        }
+    }
+    
+    /// Resolves every attribute of a query to a column of one instance of a
+    /// relation, so that a relation can appear in a query several times under
+    /// different aliases. The columns of an aliased instance are named
+    /// alias.attribute, and an attribute may be written without its alias when
+    /// only one instance in the query has it. The query should be tokenized and
+    /// its results finished in the terms of the query this returns.
+    pub fn bind_query(&mut self, qry : &HybQuery) -> Result<HybQuery>{
+        let mut ids = Vec::new();
+        collect_ids(qry, &mut ids);
+        self.instances = HashMap::new();
+        self.columns = HashMap::new();
+        for (id, alias) in ids{
+            let instance = alias.clone().unwrap_or(id.to_string());
+            if !self.schema.contains_key(&id) {
+                return Err(Error::Schema(format!("Could not find a relation named {} in the schema", id)));
+            }
+            if self.instances.insert(instance.to_string(), id.to_string()).is_some() {
+                return Err(Error::Parse(format!("{} appears more than once in the query, so each must be given its own alias", instance)));
+            }
+            for (column, at) in self.column_names(&id, &alias).into_iter().zip(self.schema[&id].clone()){
+                self.columns.insert(column, (instance.to_string(), at));
+            }
+        }
+        self.bind_helper(qry)
+    }
+    
+    fn bind_helper(&self, qry : &HybQuery) -> Result<HybQuery>{
         match qry {
-            HybQuery::Id(id) => {
+            HybQuery::Select(b_query, sub_query) => Ok(HybQuery::Select(self.bind_bool(b_query)?, Box::new(self.bind_helper(sub_query)?))),
+            HybQuery::FPJoin(ats1, ats2, sub_query1, sub_query2) => {
+                Ok(HybQuery::FPJoin(self.bind_ats(ats1)?, self.bind_ats(ats2)?, Box::new(self.bind_helper(sub_query1)?), Box::new(self.bind_helper(sub_query2)?)))
+            },
+            HybQuery::PPJoin(ats1, ats2, sub_query1, sub_query2) => {
+                Ok(HybQuery::PPJoin(self.bind_ats(ats1)?, self.bind_ats(ats2)?, Box::new(self.bind_helper(sub_query1)?), Box::new(self.bind_helper(sub_query2)?)))
+            },
+            HybQuery::Project(cols, sub_query) => Ok(HybQuery::Project(self.bind_ats(cols)?, Box::new(self.bind_helper(sub_query)?))),
+            HybQuery::Order(attrib, desc, limit, sub_query) => Ok(HybQuery::Order(self.bind_at(attrib)?, *desc, *limit, Box::new(self.bind_helper(sub_query)?))),
+            HybQuery::Count(sub_query) => Ok(HybQuery::Count(Box::new(self.bind_helper(sub_query)?))),
+            HybQuery::Sum(attrib, avg, sub_query) => Ok(HybQuery::Sum(self.bind_at(attrib)?, *avg, Box::new(self.bind_helper(sub_query)?))),
+            HybQuery::Group(attrib, sub_query) => Ok(HybQuery::Group(self.bind_at(attrib)?, Box::new(self.bind_helper(sub_query)?))),
+            HybQuery::Id(_, _) | HybQuery::BadQuery(_) => Ok(qry.clone())
+        }
+    }
+    
+    fn bind_bool(&self, b_query : &BoolQuery) -> Result<BoolQuery>{
+        match b_query {
+            BoolQuery::Eq(attrib, val) => Ok(BoolQuery::Eq(self.bind_at(attrib)?, val.to_string())),
+            BoolQuery::In(attrib, vals) => Ok(BoolQuery::In(self.bind_at(attrib)?, vals.clone())),
+            BoolQuery::Range(attrib, lower, upper) => Ok(BoolQuery::Range(self.bind_at(attrib)?, lower.clone(), upper.clone())),
+            BoolQuery::IsNull(attrib) => Ok(BoolQuery::IsNull(self.bind_at(attrib)?)),
+            BoolQuery::Not(sub) => Ok(BoolQuery::Not(Box::new(self.bind_bool(sub)?))),
+            BoolQuery::And(left, right) => Ok(BoolQuery::And(Box::new(self.bind_bool(left)?), Box::new(self.bind_bool(right)?))),
+            BoolQuery::Or(left, right) => Ok(BoolQuery::Or(Box::new(self.bind_bool(left)?), Box::new(self.bind_bool(right)?))),
+            BoolQuery::BadBool(s) => Err(Error::Parse(s.to_string()))
+        }
+    }
+    
+    // The column of the bound query an attribute written in the query refers to
+    fn bind_at(&self, attrib : &String) -> Result<String>{
+        if self.columns.contains_key(attrib) {
+            return Ok(attrib.to_string());
+        }
+        // a relation without an alias may still qualify its attributes
+        if let Some((prefix, at)) = attrib.split_once('.') {
+            if self.instances.get(prefix).map(|id| id == prefix) == Some(true)
+                && self.columns.get(at) == Some(&(prefix.to_string(), at.to_string())) {
+                return Ok(at.to_string());
+            }
+        }
+        let mut found : Vec<&String> = self.columns.iter().filter(|(_, (_, at))| at == attrib).map(|(column, _)| column).collect();
+        found.sort();
+        match found.len() {
+            0 => Err(Error::Schema(format!("Could not find a column named {} in the relations of the query", attrib))),
+            1 => Ok(found[0].to_string()),
+            _ => Err(Error::Parse(format!("{} is ambiguous since its relation appears more than once in the query, so it must be written as one of {}", attrib,
+                found.iter().map(|s| s.as_str()).collect::<Vec<&str>>().join(", "))))
+        }
+    }
+    
+    fn bind_ats(&self, ats : &[String]) -> Result<Vec<String>>{
+        ats.iter().map(|at| self.bind_at(at)).collect()
+    }
+    
+    // The columns of an instance of the relation `id`, qualified by its alias if it has one
+    fn column_names(&self, id : &String, alias : &Option<String>) -> Vec<String>{
+        let ats = self.schema.get(id).cloned().unwrap_or(Vec::new());
+        match alias {
+            Some(alias) => ats.iter().map(|at| format!("{}.{}", alias, at)).collect(),
+            None => ats
+        }
+    }
+    
+    // The attribute a column of the last bound query names
+    fn base_at(&self, column : &String) -> String{
+        match self.columns.get(column) {
+            Some((_, at)) => at.to_string(),
+            None => column.to_string()
+        }
+    }
+    
+    pub fn tokenize_sti(&mut self, qry : &HybQuery) -> Result<HybToken> {
+        match qry {
+            HybQuery::Id(id, alias) => {
                 let id_qry = ("i", id.to_string());
                 let id_qry_bytes = &bincode::serialize(&id_qry).unwrap()[..];
                 match self.schema.get(id) {
                     Some(ats) => {
                         let length = ats.len();
                         Ok(HybToken::Id(self.emm_client.tokenize_emm(&id_qry_bytes.to_vec()),
-                            id.to_string(),
+                            alias.clone().unwrap_or(id.to_string()),
                             length)
                         )
                     },
-                    None => Err(format!("Could not find a relation named {} in the schema", id))
+                    None => Err(Error::Schema(format!("Could not find a relation named {} in the schema", id)))
                 }
             },
-            HybQuery::Select(BoolQuery::Eq(attrib, val), sub_query) => {
-                let sub_tk_wrap = self.tokenize_sti(sub_query);
-                match sub_tk_wrap {
-                    Ok(sub_tk) => {
-                        match &self.get_id_from_at(&attrib) {
-                            Some((id, _)) => {
-                                let sel_qry = ("s", &attrib, &val);
-                                let sel_qry_bytes = &bincode::serialize(&sel_qry).unwrap()[..];
-                                Ok(HybToken::Select(
-                                    self.emm_client.tokenize_emm(&sel_qry_bytes.to_vec()),
-                                    Box::new(sub_tk),
-                                    id.to_string()
-                                ))
-                            },
-                            None => Err(format!("Could not find a column named {} in the schema", attrib))
+            HybQuery::Select(b_query, sub_query) => {
+                let mut tk = self.tokenize_sti(sub_query)?;
+                // Predicates on different relations filter different tables, so
+                // each relation gets its own select token. Only plain equalities
+                // can use a conjunctive index
+                let mut groups : ConjunctGroups = Vec::new();
+                for conjunct in b_query.conjuncts()? {
+                    let id = self.get_id_from_bool(&conjunct)?;
+                    let group_index = match groups.iter().position(|(group_id, _, _)| *group_id == id) {
+                        Some(i) => i,
+                        None => {
+                            groups.push((id, Vec::new(), Vec::new()));
+                            groups.len() - 1
                         }
+                    };
+                    match conjunct {
+                        BoolQuery::Eq(attrib, val) => groups[group_index].1.push((attrib, val)),
+                        conjunct => groups[group_index].2.push(conjunct)
                     }
-                    Err(s) => Err(s)
                 }
+                for (id, eqs, others) in groups {
+                    // the indexes are keyed by attribute, whichever instance it is of
+                    let eqs : Vec<(String, String)> = eqs.iter().map(|(attrib, val)| (self.base_at(attrib), val.to_string())).collect();
+                    let mut match_tks = Vec::new();
+                    for lookup in pair_conjuncts(&eqs, &self.conjunctions) {
+                        let sel_qry_bytes = if lookup.len() == 2 {
+                            let val1 = self.parse_literal(&lookup[0].0, &lookup[0].1)?;
+                            let val2 = self.parse_literal(&lookup[1].0, &lookup[1].1)?;
+                            bincode::serialize(&("s2", &lookup[0].0, &val1, &lookup[1].0, &val2)).unwrap()
+                        } else {
+                            let val = self.parse_literal(&lookup[0].0, &lookup[0].1)?;
+                            bincode::serialize(&("s", &lookup[0].0, &val)).unwrap()
+                        };
+                        match_tks.push(BoolToken::Match(self.emm_client.tokenize_emm(&sel_qry_bytes)));
+                    }
+                    for other in &others {
+                        match_tks.push(self.tokenize_bool(other)?);
+                    }
+                    let bool_tk = match_tks.into_iter().reduce(|prev_tk, match_tk| BoolToken::And(Box::new(prev_tk), Box::new(match_tk)));
+                    if let Some(bool_tk) = bool_tk {
+                        tk = HybToken::Select(bool_tk, Box::new(tk), id);
+                    }
+                }
+                Ok(tk)
             },
             HybQuery::Project(cols, sub_query) => {
                 let sub_tk_wrap = self.tokenize_sti(sub_query);
                 match sub_tk_wrap {
                     Ok(sub_tk) => {
                         let mut tk_vec = Vec::new();
-                        for attrib in cols{
+                        let mut ats = Vec::new();
+                        for col in cols{
+                            let at = self.base_at(col);
+                            if !ats.contains(&at) {
+                                ats.push(at);
+                            }
+                        }
+                        for attrib in ats{
                             let proj_qry = ("p", &attrib);
                             let proj_qry_bytes = &bincode::serialize(&proj_qry).unwrap()[..];
                             tk_vec.push(self.emm_client.tokenize_emm(&proj_qry_bytes.to_vec()));
@@ -234,99 +878,142 @@ impl STIClient {
                     Err(s) => Err(s)
                 }
             },
-            HybQuery::FPJoin(attrib1, attrib2, sub_query1, sub_query2) => {
+            HybQuery::Count(sub_query) => {
+                // the server can only count rows once every join is done on its side
+                if !self.get_remaining_joins(sub_query).is_empty() {
+                    return Err(Error::Parse("COUNT(*) can only count queries whose joins are all fully precomputed (JOINF)".to_string()));
+                }
+                let sub_tk = self.tokenize_sti(sub_query)?;
+                Ok(HybToken::Count(Box::new(sub_tk), self.new_count_key()))
+            },
+            HybQuery::Sum(attrib, _, sub_query) => {
+                // the client only divides for AVG, so the server sees SUM and AVG alike
+                if !self.get_remaining_joins(sub_query).is_empty() {
+                    return Err(Error::Parse("SUM and AVG can only total queries whose joins are all fully precomputed (JOINF)".to_string()));
+                }
+                let at = self.base_at(attrib);
+                if !self.summable.contains(&at) {
+                    return Err(Error::Schema(format!("{} was not declared SUMMABLE so the server cannot total it", attrib)));
+                }
+                let id = match self.get_id_from_at(attrib) {
+                    Some((id, _)) => id,
+                    None => return Err(Error::Schema(format!("Could not find a column named {} in the schema", attrib)))
+                };
+                let sub_tk = self.tokenize_sti(sub_query)?;
+                let proj_qry = ("p", &at);
+                let proj_qry_bytes = &bincode::serialize(&proj_qry).unwrap()[..];
+                Ok(HybToken::Sum(self.emm_client.tokenize_emm(&proj_qry_bytes.to_vec()), id, Box::new(sub_tk), self.new_count_key()))
+            },
+            HybQuery::Group(attrib, sub_query) => {
+                let at = self.base_at(attrib);
+                if !self.grouped.contains(&at) {
+                    return Err(Error::Schema(format!("{} was not declared GROUPED so it has no precomputed counts", attrib)));
+                }
+                let not_precomputed = || Error::Parse(format!("GROUP BY {} only reads precomputed counts, so it must be over its whole relation or one annotated join of it, without WHERE", attrib));
+                let at_id = match self.get_id_from_at(attrib) {
+                    Some((at_id, _)) => at_id,
+                    None => return Err(Error::Schema(format!("Could not find a column named {} in the schema", attrib)))
+                };
+                let group_qry_bytes = match &**sub_query {
+                    HybQuery::Id(id, alias) if *alias.as_ref().unwrap_or(id) == at_id => bincode::serialize(&("g", &at)).unwrap(),
+                    HybQuery::FPJoin(ats1, ats2, sub_query1, sub_query2) | HybQuery::PPJoin(ats1, ats2, sub_query1, sub_query2) => {
+                        match (&**sub_query1, &**sub_query2) {
+                            (HybQuery::Id(_, _), HybQuery::Id(_, _)) => {},
+                            _ => return Err(not_precomputed())
+                        }
+                        let (id1, _) = self.get_id_from_ats(ats1)?;
+                        let (id2, _) = self.get_id_from_ats(ats2)?;
+                        let (ann1, ann2, swapped) = self.find_annotation(ats1, ats2)?;
+                        // the side of the annotation, rather than of the query, the attribute is on
+                        let side = match (at_id == id1, at_id == id2) {
+                            (true, _) => if swapped { 2 } else { 1 },
+                            (_, true) => if swapped { 1 } else { 2 },
+                            _ => return Err(not_precomputed())
+                        };
+                        group_join_qry(&ann1, &ann2, side, &at)
+                    },
+                    _ => return Err(not_precomputed())
+                };
+                Ok(HybToken::Group(self.emm_client.tokenize_emm_rh(&group_qry_bytes)))
+            },
+            HybQuery::Order(attrib, desc, limit, sub_query) => {
+                let sub_tk = self.tokenize_sti(sub_query)?;
+                let at = self.base_at(attrib);
+                if !self.ore.contains(&at) {
+                    return Err(Error::Schema(format!("{} was not declared ORE so the server cannot order on it", attrib)));
+                }
+                let id = match self.get_id_from_at(attrib) {
+                    Some((id, _)) => id,
+                    None => return Err(Error::Schema(format!("Could not find a column named {} in the schema", attrib)))
+                };
+                // the projection index tells the server which cell of each row to compare
+                let proj_qry = ("p", &at);
+                let proj_qry_bytes = &bincode::serialize(&proj_qry).unwrap()[..];
+                Ok(HybToken::Order(self.emm_client.tokenize_emm(&proj_qry_bytes.to_vec()), id, *desc, *limit, Box::new(sub_tk)))
+            },
+            HybQuery::FPJoin(ats1, ats2, sub_query1, sub_query2) => {
                 let sub_tk1_wrap = self.tokenize_sti(sub_query1);
                 let sub_tk2_wrap = self.tokenize_sti(sub_query2);
                 match (sub_tk1_wrap, sub_tk2_wrap) {
                     (Ok(sub_tk1), Ok(sub_tk2)) => {
-                        let mut fp_join_qry = ("fpj", &attrib1, &attrib2);
-                        let id1_wrap = &self.get_id_from_at(&attrib1);
-                        let id2_wrap = &self.get_id_from_at(&attrib2);
+                        let (id1, _) = self.get_id_from_ats(ats1)?;
+                        let (id2, _) = self.get_id_from_ats(ats2)?;
+                        let (ann1, ann2, swapped) = self.find_annotation(ats1, ats2)?;
+                        let fp_join_qry = ("fpj", &ann1, &ann2);
+                        let fp_join_qry_bytes = &bincode::serialize(&fp_join_qry).unwrap()[..];
                         
-                        match (id1_wrap, id2_wrap) {
-                            (Some((id1, _)), Some((id2, _))) => {
-                                let mut swapped = false;
-                                if !self.annotations.contains(&(attrib1.to_string(), attrib2.to_string()))
-                                    && !self.annotations.contains(&(attrib2.to_string(), attrib1.to_string())){
-                                    Err(format!("Could not find ({}, {}) in the database annotations", attrib1, attrib2))
-                                } else {
-                                    if !self.annotations.contains(&(attrib1.to_string(), attrib2.to_string()))
-                                        && self.annotations.contains(&(attrib2.to_string(), attrib1.to_string())){
-                                        swapped = true;
-                                        fp_join_qry = ("fpj", &attrib2, &attrib1);
-                                    }
-                                    let fp_join_qry_bytes = &bincode::serialize(&fp_join_qry).unwrap()[..];
-                                
-                                    Ok(HybToken::FPJoin(
-                                        self.emm_client.tokenize_emm(&fp_join_qry_bytes.to_vec()),
-                                        Box::new(sub_tk1),
-                                        Box::new(sub_tk2),
-                                        id1.to_string(),
-                                        id2.to_string(),
-                                        swapped
-                                    ))
-                                }
-                            },
-                            (None, _) => Err(format!("Could not find a column named {} in the schema for a join", attrib1)),
-                            (_, None) => Err(format!("Could not find a column named {} in the schema for a join", attrib2)),
-                        }
+                        Ok(HybToken::FPJoin(
+                            self.emm_client.tokenize_emm(&fp_join_qry_bytes.to_vec()),
+                            Box::new(sub_tk1),
+                            Box::new(sub_tk2),
+                            id1.to_string(),
+                            id2.to_string(),
+                            swapped
+                        ))
                     },
                     (Err(s), _) => Err(s),
                     (_, Err(s)) => Err(s)
                 }
             },
-            HybQuery::PPJoin(attrib1, attrib2, sub_query1, sub_query2) => {
+            HybQuery::PPJoin(ats1, ats2, sub_query1, sub_query2) => {
                 let sub_tk1_wrap = self.tokenize_sti(sub_query1);
                 let sub_tk2_wrap = self.tokenize_sti(sub_query2);
                 match (sub_tk1_wrap, sub_tk2_wrap) {
                     (Ok(sub_tk1), Ok(sub_tk2)) => {
-                        let mut pp_join_qry1 = ("ppj", &attrib1, &attrib2, 1);
-                        let mut pp_join_qry2 = ("ppj", &attrib1, &attrib2, 2);
-                        let id1_wrap = &self.get_id_from_at(&attrib1);
-                        let id2_wrap = &self.get_id_from_at(&attrib2);
+                        let (id1, save_cols1) = self.get_id_from_ats(ats1)?;
+                        let (id2, save_cols2) = self.get_id_from_ats(ats2)?;
+                        let (ann1, ann2, swapped) = self.find_annotation(ats1, ats2)?;
+                        let pp_join_qry1 = ("ppj", &ann1, &ann2, 1);
+                        let pp_join_qry2 = ("ppj", &ann1, &ann2, 2);
+                        let pp_join_qry_bytes1 = &bincode::serialize(&pp_join_qry1).unwrap()[..];
+                        let pp_join_qry_bytes2 = &bincode::serialize(&pp_join_qry2).unwrap()[..];
                         
-                        match (id1_wrap, id2_wrap) {
-                            (Some((id1, save_col1)), Some((id2, save_col2))) => {
-                                let mut swapped = false;
-                                if !self.annotations.contains(&(attrib1.to_string(), attrib2.to_string()))
-                                    && !self.annotations.contains(&(attrib2.to_string(), attrib1.to_string())){
-                                    Err(format!("Could not find ({}, {}) in the database annotations", attrib1, attrib2))
-                                } else {
-                                    if !self.annotations.contains(&(attrib1.to_string(), attrib2.to_string()))
-                                        && self.annotations.contains(&(attrib2.to_string(), attrib1.to_string())){
-                                        swapped = true;
-                                        pp_join_qry1 = ("ppj", &attrib2, &attrib1, 1);
-                                        pp_join_qry2 = ("ppj", &attrib2, &attrib1, 2);
-                                    }
-                                    let pp_join_qry_bytes1 = &bincode::serialize(&pp_join_qry1).unwrap()[..];
-                                    let pp_join_qry_bytes2 = &bincode::serialize(&pp_join_qry2).unwrap()[..];
-                                    
-                                    Ok(HybToken::PPJoin(
-                                        self.emm_client.tokenize_emm(&pp_join_qry_bytes1.to_vec()),
-                                        self.emm_client.tokenize_emm(&pp_join_qry_bytes2.to_vec()),
-                                        Box::new(sub_tk1),
-                                        Box::new(sub_tk2),
-                                        id1.to_string(),
-                                        id2.to_string(),
-                                        *save_col1,
-                                        *save_col2,
-                                        swapped
-                                    ))
-                                }
-                            },
-                            (None, _) => Err(format!("Could not find a column named {} in the schema for a join", attrib1)),
-                            (_, None) => Err(format!("Could not find a column named {} in the schema for a join", attrib2)),
-                        }
+                        Ok(HybToken::PPJoin(
+                            self.emm_client.tokenize_emm(&pp_join_qry_bytes1.to_vec()),
+                            self.emm_client.tokenize_emm(&pp_join_qry_bytes2.to_vec()),
+                            Box::new(sub_tk1),
+                            Box::new(sub_tk2),
+                            id1.to_string(),
+                            id2.to_string(),
+                            save_cols1,
+                            save_cols2,
+                            swapped
+                        ))
                     },
                     (Err(s), _) => Err(s),
                     (_, Err(s)) => Err(s)
                 }
             },
-            _ => Ok(HybToken::BadToken)
+            HybQuery::BadQuery(s) => Err(Error::Parse(s.to_string()))
         }
     }
     
     fn get_id_from_at(&self, at_target : &String) -> Option<(String, usize)>{
+        // a column of the bound query belongs to its instance
+        if let Some((instance, at)) = self.columns.get(at_target) {
+            let ats = self.schema.get(&self.instances[instance])?;
+            return ats.iter().position(|a| a == at).map(|i| (instance.to_string(), i));
+        }
         let mut res = None;
         for (id, ats) in self.schema.iter(){
             for (i, at) in ats.iter().enumerate(){
@@ -338,60 +1025,206 @@ impl STIClient {
         res
     }
     
-    fn get_ats_from_qry(&self, qry: &HybQuery)-> (Vec<Vec<String>>, Vec<String>){
+    // The instance the attributes of one side of a join belong to, and where they are in it
+    fn get_id_from_ats(&self, ats : &Vec<String>) -> Result<(String, Vec<usize>)>{
+        let mut res : Option<String> = None;
+        let mut cols = Vec::new();
+        for at in ats{
+            let (id, col) = match self.get_id_from_at(at) {
+                Some(found) => found,
+                None => return Err(Error::Schema(format!("Could not find a column named {} in the schema for a join", at)))
+            };
+            match res {
+                Some(ref prev_id) if *prev_id != id => {
+                    return Err(Error::Schema(format!("The attributes on one side of a join must belong to one relation, but {} and {} were mixed", prev_id, id)))
+                },
+                _ => res = Some(id)
+            }
+            cols.push(col);
+        }
+        res.map(|id| (id, cols)).ok_or(Error::Parse("A join must be on at least one pair of attributes".to_string()))
+    }
+    
+    // The annotation a join of the query was built from, as it was declared, and
+    // whether the query has its sides the other way around. The pairs of
+    // attributes of a composite join may be written in any order
+    fn find_annotation(&self, ats1 : &[String], ats2 : &[String]) -> Result<(Vec<String>, Vec<String>, bool)>{
+        let ats1 : Vec<String> = ats1.iter().map(|at| self.base_at(at)).collect();
+        let ats2 : Vec<String> = ats2.iter().map(|at| self.base_at(at)).collect();
+        let pairs = sorted_pairs(&ats1, &ats2);
+        let swapped_pairs = sorted_pairs(&ats2, &ats1);
+        for (swapped, wanted) in [(false, &pairs), (true, &swapped_pairs)]{
+            for (ann1, ann2) in &self.annotations{
+                if sorted_pairs(ann1, ann2) == *wanted {
+                    return Ok((ann1.clone(), ann2.clone(), swapped));
+                }
+            }
+        }
+        Err(Error::Annotation(format!("Could not find ({}) = ({}) in the database annotations", ats1.join(", "), ats2.join(", "))))
+    }
+    
+    /// The relation a predicate filters. `OR` and `NOT` are evaluated row by row,
+    /// so every attribute below them must belong to the same relation.
+    fn get_id_from_bool(&self, b_query : &BoolQuery) -> Result<String>{
+        let mut res : Option<String> = None;
+        for attrib in b_query.attributes() {
+            let id = match self.get_id_from_at(&attrib) {
+                Some((id, _)) => id,
+                None => return Err(Error::Schema(format!("Could not find a column named {} in the schema", attrib)))
+            };
+            match res {
+                Some(ref prev_id) if *prev_id != id => {
+                    return Err(Error::Schema(format!("OR and NOT can only combine predicates on one relation, but {} and {} were mixed", prev_id, id)))
+                },
+                _ => res = Some(id)
+            }
+        }
+        res.ok_or(Error::Parse("A selection predicate must mention an attribute".to_string()))
+    }
+    
+    /// Normalizes a value written in a query the same way setup normalized the
+    /// column, so it lands on the same label.
+    fn parse_literal(&self, attrib : &String, val : &str) -> Result<Value>{
+        match Value::parse(val, &self.get_type(attrib))? {
+            Value::Null => Err(Error::Parse(format!("Nothing equals NULL, so use {} IS NULL to select the rows where it is NULL", attrib))),
+            value => Ok(value)
+        }
+    }
+    
+    fn tokenize_bool(&mut self, b_query : &BoolQuery) -> Result<BoolToken>{
+        match b_query {
+            BoolQuery::Eq(attrib, val) => {
+                let sel_qry = ("s", self.base_at(attrib), self.parse_literal(attrib, val)?);
+                let sel_qry_bytes = &bincode::serialize(&sel_qry).unwrap()[..];
+                Ok(BoolToken::Match(self.emm_client.tokenize_emm(&sel_qry_bytes.to_vec())))
+            },
+            BoolQuery::In(attrib, vals) => {
+                let mut match_tks = Vec::new();
+                for val in vals {
+                    match_tks.push(self.tokenize_bool(&BoolQuery::Eq(attrib.to_string(), val.to_string()))?);
+                }
+                match_tks.into_iter().reduce(|prev_tk, match_tk| BoolToken::Or(Box::new(prev_tk), Box::new(match_tk)))
+                    .ok_or(Error::Parse(format!("The IN list for {} is empty", attrib)))
+            },
+            BoolQuery::Range(attrib, lower, upper) => {
+                let at = self.base_at(attrib);
+                if !self.ordered.contains(&at) {
+                    return Err(Error::Schema(format!("{} was not declared ORDERED so it has no range index", attrib)));
+                }
+                let cover = match key_bounds(lower, upper)? {
+                    Some((lo, hi)) => range_cover(lo, hi),
+                    None => Vec::new()
+                };
+                let mut tks = Vec::new();
+                for (level, node) in cover {
+                    let range_qry = ("r", &at, level, node);
+                    let range_qry_bytes = &bincode::serialize(&range_qry).unwrap()[..];
+                    tks.push(self.emm_client.tokenize_emm(&range_qry_bytes.to_vec()));
+                }
+                Ok(BoolToken::Cover(tks))
+            },
+            BoolQuery::IsNull(attrib) => {
+                let sel_qry = ("s", self.base_at(attrib), Value::Null);
+                let sel_qry_bytes = &bincode::serialize(&sel_qry).unwrap()[..];
+                Ok(BoolToken::Match(self.emm_client.tokenize_emm(&sel_qry_bytes.to_vec())))
+            },
+            BoolQuery::Not(sub) => self.tokenize_false(sub),
+            BoolQuery::And(left, right) => {
+                Ok(BoolToken::And(Box::new(self.tokenize_bool(left)?), Box::new(self.tokenize_bool(right)?)))
+            },
+            BoolQuery::Or(left, right) => {
+                Ok(BoolToken::Or(Box::new(self.tokenize_bool(left)?), Box::new(self.tokenize_bool(right)?)))
+            },
+            BoolQuery::BadBool(s) => Err(Error::Parse(s.to_string()))
+        }
+    }
+    
+    // The rows where a predicate is false. A comparison with NULL is neither true
+    // nor false, so the rows where the attribute is NULL are taken out as well
+    fn tokenize_false(&mut self, b_query : &BoolQuery) -> Result<BoolToken>{
+        match b_query {
+            BoolQuery::Eq(attrib, _) | BoolQuery::In(attrib, _) | BoolQuery::Range(attrib, _, _) => {
+                let true_tk = self.tokenize_bool(b_query)?;
+                let null_tk = self.tokenize_bool(&BoolQuery::IsNull(attrib.to_string()))?;
+                Ok(BoolToken::And(Box::new(BoolToken::Not(Box::new(true_tk))), Box::new(BoolToken::Not(Box::new(null_tk)))))
+            },
+            BoolQuery::IsNull(_) => Ok(BoolToken::Not(Box::new(self.tokenize_bool(b_query)?))),
+            BoolQuery::Not(sub) => self.tokenize_bool(sub),
+            BoolQuery::And(left, right) => {
+                Ok(BoolToken::Or(Box::new(self.tokenize_false(left)?), Box::new(self.tokenize_false(right)?)))
+            },
+            BoolQuery::Or(left, right) => {
+                Ok(BoolToken::And(Box::new(self.tokenize_false(left)?), Box::new(self.tokenize_false(right)?)))
+            },
+            BoolQuery::BadBool(s) => Err(Error::Parse(s.to_string()))
+        }
+    }
+    
+    fn get_ats_from_qry(&self, qry: &HybQuery)-> Result<(Vec<Vec<String>>, Vec<String>)>{
         let ats_res : (Vec<Vec<String>>, Vec<String>);
         let mut ats_all : Vec<Vec<String>> = Vec::new();
         match qry{
-            HybQuery::Id(id)=> {
-                let ats = self.schema.get(id).unwrap().clone();
-                let primary_key = &ats[0].clone();
-                ats_res = (vec!(ats), vec!(primary_key.to_string()));
+            HybQuery::Id(id, alias)=> {
+                let ats = match self.schema.get(id) {
+                    Some(ats) if !ats.is_empty() => self.column_names(id, alias),
+                    _ => return Err(Error::Schema(format!("Could not find a relation named {} in the schema", id)))
+                };
+                // the server never projects away the first cell of a row since it
+                // holds on to the row by it. It need not be the primary key
+                let handle = &ats[0].clone();
+                ats_res = (vec!(ats), vec!(handle.to_string()));
             },
-            HybQuery::Select(_, sub_query)=>{
-                ats_res = self.get_ats_from_qry(sub_query);
+            HybQuery::Select(_, sub_query) | HybQuery::Order(_, _, _, sub_query) | HybQuery::Count(sub_query) | HybQuery::Sum(_, _, sub_query) | HybQuery::Group(_, sub_query)=>{
+                ats_res = self.get_ats_from_qry(sub_query)?;
             },
-            HybQuery::FPJoin(attrib1, attrib2, sub_query1, sub_query2)=>{
-                let (mut sub_ats1, mut save_ats1) = self.get_ats_from_qry(sub_query1);
-                let (mut sub_ats2, mut save_ats2) = self.get_ats_from_qry(sub_query2);
+            HybQuery::FPJoin(ats1, ats2, sub_query1, sub_query2)=>{
+                let (mut sub_ats1, mut save_ats1) = self.get_ats_from_qry(sub_query1)?;
+                let (mut sub_ats2, mut save_ats2) = self.get_ats_from_qry(sub_query2)?;
                 
+                // every attribute of a side belongs to the same relation, so the first finds it
                 let mut index1 = None;
                 let mut index2 = None;
                 for (i, sub_at) in sub_ats1.iter().enumerate(){
-                    if sub_at.iter().any(|at| at == attrib1){
+                    if sub_at.iter().any(|at| *at == ats1[0]){
                         index1 = Some(i);
                         break;
                     }
                 }
                 for (i, sub_at) in sub_ats2.iter().enumerate(){
-                    if sub_at.iter().any(|at| at == attrib2){
+                    if sub_at.iter().any(|at| *at == ats2[0]){
                         index2 = Some(i);
                         break;
                     }
                 }
-                let mut merged = sub_ats1[index1.unwrap()].clone();
-                merged.append(&mut sub_ats2[index2.unwrap()]);
-                sub_ats1.remove(index1.unwrap());
-                sub_ats2.remove(index2.unwrap());
+                let (index1, index2) = match (index1, index2) {
+                    (Some(index1), Some(index2)) => (index1, index2),
+                    _ => return Err(Error::Schema(format!("Could not find the columns {} and {} in the tables being joined", ats1.join(", "), ats2.join(", "))))
+                };
+                let mut merged = sub_ats1[index1].clone();
+                merged.append(&mut sub_ats2[index2]);
+                sub_ats1.remove(index1);
+                sub_ats2.remove(index2);
                 sub_ats1.append(&mut sub_ats2);
                 sub_ats1.push(merged);
                 save_ats1.append(&mut save_ats2);
                 
                 ats_res = (sub_ats1, save_ats1);
             },
-            HybQuery::PPJoin(attrib1, attrib2, sub_query1, sub_query2)=>{
-                let (mut sub_ats1, mut save_ats1) = self.get_ats_from_qry(sub_query1);
-                let (mut sub_ats2, mut save_ats2) = self.get_ats_from_qry(sub_query2);
+            HybQuery::PPJoin(ats1, ats2, sub_query1, sub_query2)=>{
+                let (mut sub_ats1, mut save_ats1) = self.get_ats_from_qry(sub_query1)?;
+                let (mut sub_ats2, mut save_ats2) = self.get_ats_from_qry(sub_query2)?;
                 sub_ats1.append(&mut sub_ats2);
                 save_ats1.append(&mut save_ats2);
-                save_ats1.push(attrib1.to_string());
-                save_ats1.push(attrib2.to_string());
+                save_ats1.extend(ats1.iter().cloned());
+                save_ats1.extend(ats2.iter().cloned());
                 ats_res = (sub_ats1, save_ats1);
             },
             HybQuery::Project(cols, sub_query)=>{
-                let (sub_ats, save_ats) = self.get_ats_from_qry(sub_query);
+                let (sub_ats, save_ats) = self.get_ats_from_qry(sub_query)?;
                 for t_sub_ats in sub_ats{
+                    // the server keeps the column of every instance of a projected attribute
                     let filter_ats = t_sub_ats.into_iter()
-                        .filter(|at| cols.iter().any(|proj_at| proj_at==at)
+                        .filter(|at| cols.iter().any(|proj_at| self.base_at(proj_at) == self.base_at(at))
                         || save_ats.iter().any(|save_at| save_at==at))
                         .collect();
                     ats_all.push(filter_ats);
@@ -400,25 +1233,29 @@ impl STIClient {
             },
             _ => { ats_res = (Vec::new(), Vec::new()) }
         }
-        ats_res
+        Ok(ats_res)
     }
     
-    fn get_remaining_joins(&self, qry: &HybQuery)->Vec<(String, String)>{
+    fn get_remaining_joins(&self, qry: &HybQuery)->Vec<(Vec<String>, Vec<String>)>{
         match qry {
-            HybQuery::Id(_)=> Vec::new(),
+            HybQuery::Id(_, _)=> Vec::new(),
             HybQuery::Select(_, sub_query)=> self.get_remaining_joins(sub_query),
             HybQuery::Project(_, sub_query)=> self.get_remaining_joins(sub_query),
+            HybQuery::Order(_, _, _, sub_query)=> self.get_remaining_joins(sub_query),
+            HybQuery::Count(sub_query)=> self.get_remaining_joins(sub_query),
+            HybQuery::Sum(_, _, sub_query)=> self.get_remaining_joins(sub_query),
+            HybQuery::Group(_, _)=> Vec::new(),
             HybQuery::FPJoin(_, _, sub_query1, sub_query2)=>{
                 let mut sub_remaining1 = self.get_remaining_joins(sub_query1);
                 let mut sub_remaining2 = self.get_remaining_joins(sub_query2);
                 sub_remaining1.append(&mut sub_remaining2);
                 sub_remaining1
             },
-            HybQuery::PPJoin(attrib1, attrib2, sub_query1, sub_query2)=>{
+            HybQuery::PPJoin(ats1, ats2, sub_query1, sub_query2)=>{
                 let mut sub_remaining1 = self.get_remaining_joins(sub_query1);
                 let mut sub_remaining2 = self.get_remaining_joins(sub_query2);
                 sub_remaining1.append(&mut sub_remaining2);
-                sub_remaining1.push((attrib1.to_string(), attrib2.to_string()));
+                sub_remaining1.push((ats1.clone(), ats2.clone()));
                 sub_remaining1
             },
             _ => Vec::new()
@@ -427,17 +1264,17 @@ impl STIClient {
     
     fn get_true_projection(&self, qry: &HybQuery)->HashSet<String>{
         match qry {
-            HybQuery::Id(id)=> {
-                let ats = self.schema.get(id).unwrap().clone();
-                let hash_cols : HashSet<String> = ats.iter().map(|s| s.to_string()).collect();
-                hash_cols
-            },
+            HybQuery::Id(id, alias)=> self.column_names(id, alias).into_iter().collect(),
             HybQuery::Select(_, sub_query)=> self.get_true_projection(sub_query),
+            HybQuery::Order(_, _, _, sub_query)=> self.get_true_projection(sub_query),
+            HybQuery::Count(sub_query)=> self.get_true_projection(sub_query),
+            HybQuery::Sum(_, _, sub_query)=> self.get_true_projection(sub_query),
+            HybQuery::Group(_, sub_query)=> self.get_true_projection(sub_query),
             HybQuery::Project(cols, sub_query)=>{
                 let sub_p = self.get_true_projection(sub_query);
                 let hash_cols : HashSet<String> = cols.iter().map(|s| s.to_string()).collect();
-                let res : HashSet<String>;
-                res = sub_p.intersection(&hash_cols).map(|s| s.to_string()).collect();
+                
+                let res : HashSet<String> = sub_p.intersection(&hash_cols).map(|s| s.to_string()).collect();
                 res
             },
             HybQuery::FPJoin(_, _, sub_query1, sub_query2)=>{
@@ -456,142 +1293,262 @@ impl STIClient {
         }
     }
     
-    pub fn fin_sti(&mut self, qry_opt: &Option<HybQuery>, plaintexts : Vec<Vec<Vec<u8>>>) -> Relation{
+    pub fn fin_sti(&mut self, qry_opt: &Option<HybQuery>, plaintexts : Vec<Vec<Vec<u8>>>) -> Result<Relation>{
         if let Some(qry) = qry_opt {
             let mut rels : Vec<Relation> = Vec::new();
-            let (ats_all, _) = self.get_ats_from_qry(qry);
-            // println!("SAVE ATTRIBUTES");
-            // for save_at in save_ats{
-            //     println!("{}", save_at);
-            // }
+            let (ats_all, _) = self.get_ats_from_qry(qry)?;
+            if plaintexts.len() != ats_all.len() {
+                return Err(Error::Protocol(format!("The server sent back {} tables for a query over {}", plaintexts.len(), ats_all.len())));
+            }
             let remaining_joins = self.get_remaining_joins(qry);
             let true_projection = self.get_true_projection(qry);
-            // println!("TRUE PROJECTION");
-            // for at in &true_projection{
-            //     println!("{}", at);
-            // }
             for (i, ats) in ats_all.iter().enumerate(){
-                let mut rel = Relation::new_rel(ats.to_vec());
+                let types = ats.iter().map(|at| self.get_type(at)).collect();
+                let mut rel = Relation::new_rel(ats.to_vec(), types);
                 let mut row = Vec::new();
                 for (j, cell) in plaintexts[i].iter().enumerate(){
-                    if let Ok(cell_str) = bincode::deserialize::<String>(&cell){
-                        row.push(cell_str);
-                    } 
+                    match bincode::deserialize::<Value>(cell) {
+                        Ok(cell_str) => row.push(cell_str),
+                        Err(_) => return Err(Error::Protocol("The server sent back a cell that does not hold a value".to_string()))
+                    }
                     if (j + 1) % ats.len() == 0 {
-                        rel.add_row(row.clone());
+                        rel.add_row(row.clone())?;
                         row = Vec::new();
                     }
                 }
                 rels.push(rel);
             }
-            for (attrib1, attrib2) in &remaining_joins{
+            for (ats1, ats2) in &remaining_joins{
                 let mut rel1 = &Relation::empty_rel();
                 let mut rel2 = &Relation::empty_rel();
                 let mut index1 = None;
                 let mut index2 = None;
                 for (i, rel) in rels.iter().enumerate(){
-                    // println!("Number attributes: {}", rel.get_ats().len());
-                    if rel.get_ats().iter().any(|at| attrib1 == at) {
+                    if rel.positions(ats1).is_some() {
                         rel1 = rel;
                         index1 = Some(i)
                     }
-                    if rel.get_ats().iter().any(|at| attrib2 == at) {
+                    if rel.positions(ats2).is_some() {
                         rel2 = rel;
                         index2 = Some(i)
                     }
                 }
-                let merged = rel1.join(rel2, attrib1, attrib2);
-                let first_remove = max(index1.unwrap(), index2.unwrap());
-                let second_remove = min(index1.unwrap(), index2.unwrap());
+                let (index1, index2) = match (index1, index2) {
+                    (Some(index1), Some(index2)) if index1 != index2 => (index1, index2),
+                    _ => return Err(Error::Protocol(format!("The server's response does not hold the tables joined on {} and {}", ats1.join(", "), ats2.join(", "))))
+                };
+                let merged = rel1.join(rel2, ats1, ats2);
+                let first_remove = max(index1, index2);
+                let second_remove = min(index1, index2);
                 rels.remove(first_remove);
                 rels.remove(second_remove);
                 rels.push(merged);
             }
             if rels.len() == 1{
+                // the server could only cut the rows down if nothing was left to join
+                if let Some((attrib, desc, limit)) = get_top_order(qry) {
+                    rels[0].order_by(&attrib, desc, limit)?;
+                }
                 if !true_projection.is_empty(){
                     rels[0].projection(true_projection);
                 }
-                rels.pop().unwrap()
+                Ok(rels.remove(0))
             } else {
-                panic!("Multiple tables in join. Something went wrong");
+                Err(Error::Protocol(format!("The server's response left {} tables unjoined", rels.len())))
             }
         } else {
-            Relation::empty_rel()
+            Ok(Relation::empty_rel())
+        }
+    }
+    
+    /// Decrypts the group counts for the last query into one row per value.
+    pub fn fin_group(&mut self, qry_opt: &Option<HybQuery>, enc_counts : Vec<LabelledCiphertext>) -> Result<Relation>{
+        let attrib = match qry_opt {
+            Some(HybQuery::Group(attrib, _)) => attrib,
+            _ => return Err(Error::Protocol("Received group counts for a query that asked for none".to_string()))
+        };
+        // rows inserted after setup add their own counts, so one value may have several
+        let mut totals : HashMap<Value, usize> = HashMap::new();
+        for count_bytes in self.emm_client.decrypt_emm(enc_counts)?{
+            match bincode::deserialize::<(Value, usize)>(&count_bytes) {
+                Ok((value, count)) => *totals.entry(value).or_insert(0) += count,
+                Err(_) => return Err(Error::Protocol("The server sent back a group count that does not hold a value".to_string()))
+            }
         }
+        let mut counts : Vec<(Value, usize)> = totals.into_iter().collect();
+        counts.sort();
+        let mut rel = Relation::new_rel(vec!(attrib.to_string(), String::from("COUNT(*)")), vec!(self.get_type(attrib), ValueType::Int));
+        for (value, count) in counts{
+            rel.add_row(vec!(value, Value::Int(count as i64)))?;
+        }
+        Ok(rel)
+    }
+    
+    pub fn get_schema(&self) -> HashMap<String, Vec<String>>{
+        self.schema.clone()
+    }
+    
+    pub fn get_annotations(&self) -> HashSet<(Vec<String>, Vec<String>)>{
+        self.annotations.clone()
+    }
+    
+    pub fn get_conjunctions(&self) -> HashSet<(String, String)>{
+        self.conjunctions.clone()
+    }
+    
+    pub fn get_ordered(&self) -> HashSet<String>{
+        self.ordered.clone()
+    }
+    
+    pub fn get_ore(&self) -> HashSet<String>{
+        self.ore.clone()
+    }
+    
+    pub fn get_summable(&self) -> HashSet<String>{
+        self.summable.clone()
+    }
+    
+    pub fn get_grouped(&self) -> HashSet<String>{
+        self.grouped.clone()
+    }
+    
+    pub fn get_types(&self) -> HashMap<String, ValueType>{
+        self.types.clone()
+    }
+    
+    /// The type of an attribute, or text for one the schema does not know.
+    pub fn get_type(&self, attrib : &String) -> ValueType{
+        self.types.get(&self.base_at(attrib)).copied().unwrap_or(ValueType::Text)
+    }
+    
+    #[allow(clippy::too_many_arguments)]
+    pub fn restore(&mut self, schema : HashMap<String, Vec<String>>, annotations : HashSet<(Vec<String>, Vec<String>)>,
+                   conjunctions : HashSet<(String, String)>, ordered : HashSet<String>, ore : HashSet<String>,
+                   summable : HashSet<String>, grouped : HashSet<String>, types : HashMap<String, ValueType>){
+        self.schema = schema;
+        self.annotations = annotations;
+        self.conjunctions = conjunctions;
+        self.ordered = ordered;
+        self.ore = ore;
+        self.summable = summable;
+        self.grouped = grouped;
+        self.types = types;
     }
     
     pub fn print_schema(&self){
         println!("Printing edb schema:");
         for (id, ats) in &self.schema{
             println!("Relation: {}", id);
-            let ats_str = ats.join(", ");
-            println!("    {}", ats_str);
+            let ats_str : Vec<String> = ats.iter().map(|at| format!("{} {}", at, self.get_type(at))).collect();
+            println!("    {}", ats_str.join(", "));
         }
     }
 }
 
+/// What the server adds to its indexes when rows are inserted.
+#[derive(Serialize, Deserialize)]
+pub struct STIUpdate {
+    emm_update: EMMUpdate,
+    ore_data: HashMap<Vec<u8>, OreCiphertext>,
+    sum_data: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+/// What the server changes in its indexes when rows are deleted or changed.
+#[derive(Serialize, Deserialize)]
+pub struct STIRewrite {
+    emm_rewrite: EMMRewrite,
+    ore_data: HashMap<Vec<u8>, OreCiphertext>,
+    sum_data: HashMap<Vec<u8>, Vec<u8>>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[derive(Clone)]
 pub struct STIServer {
     emm_server: EMMServer,
-    // set : HashSet<[u8; 32]>,
-    // data : HashMap<[u8; 32], Vec<u8>>,
+    ore_data: HashMap<Vec<u8>, OreCiphertext>,
+    sum_data: HashMap<Vec<u8>, Vec<u8>>,
+    sum_key: Option<PaillierPublicKey>,
 }
 
 impl STIServer {
     pub fn new_sti_server() -> STIServer {
         STIServer{
             emm_server : EMMServer::new_emm_server(),
-            // set: HashSet::new(),
-            // data: HashMap::new(),
+            ore_data : HashMap::new(),
+            sum_data : HashMap::new(),
+            sum_key : None,
+        }
+    }
+    
+    pub fn apply_update(&mut self, update : STIUpdate) -> Result<()>{
+        self.emm_server.add_entries(update.emm_update)?;
+        self.ore_data.extend(update.ore_data);
+        self.sum_data.extend(update.sum_data);
+        Ok(())
+    }
+    
+    /// Rewrites the indexes and swaps the ORE and Paillier ciphertexts of the
+    /// `removed` cells for the new ones.
+    pub fn apply_rewrite(&mut self, rewrite : STIRewrite, removed : &Vec<Vec<u8>>) -> Result<()>{
+        self.emm_server.rewrite_entries(rewrite.emm_rewrite)?;
+        for tk in removed{
+            self.ore_data.remove(tk);
+            self.sum_data.remove(tk);
         }
+        self.ore_data.extend(rewrite.ore_data);
+        self.sum_data.extend(rewrite.sum_data);
+        Ok(())
     }
     
-    pub fn eval_sti(&mut self, tk : HybToken)-> Vec<RefTable>{
+    pub fn eval_sti(&self, tk : HybToken)-> Result<Vec<RefTable>>{
         match tk{
-            HybToken::Id((key1, key2), id, length) => {
+            HybToken::Id(emm_tk, id, length) => {
+                if length == 0 {
+                    return Err(Error::ServerState(format!("The token for relation {} has no columns", id)));
+                }
                 let mut new_ref_cells = Vec::new();
                 let mut ref_rows = Vec::new();
-                let mut i = 0;
-                let matches = self.emm_server.eval_emm_rr(&key1, &key2);
-                for m in matches {
+                let matches = self.emm_server.eval_emm_rr(&emm_tk);
+                for (i, m) in matches.into_iter().enumerate() {
                     new_ref_cells.push(m);
                     if (i + 1) % length == 0 {
+                        if new_ref_cells[0].len() != 32 {
+                            return Err(Error::ServerState(format!("The index for relation {} holds a malformed row", id)));
+                        }
                         let mut uk = [0u8; 32];
                         uk.copy_from_slice(&new_ref_cells[0]);
-                        ref_rows.push(RefRow::from_uk(new_ref_cells.clone(), uk));
+                        ref_rows.push(RefRow::from_uk(new_ref_cells.clone(), uk, &id));
                         new_ref_cells = Vec::new();
                     }
-                    i = i + 1;
                 } 
-                let ref_table = RefTable::from_id(ref_rows, id);
-                vec!(ref_table)
+                let ref_table = RefTable::from_id(ref_rows, id, length);
+                Ok(vec!(ref_table))
             }
-            HybToken::Select((key1, key2), sub_tk, id) => {
-                let mut sub_ref_tables = self.eval_sti(*sub_tk);
-                let matches = self.emm_server.eval_emm_rr(&key1, &key2);
+            HybToken::Select(bool_tk, sub_tk, id) => {
+                let mut sub_ref_tables = self.eval_sti(*sub_tk)?;
+                let filter = self.eval_bool(bool_tk);
                 for sub_ref_table in &mut sub_ref_tables{
                     if sub_ref_table.has_id(&id){
-                        (*sub_ref_table).filter_rows(&matches);
+                        (*sub_ref_table).filter_rows_by(&filter, &id);
                     }
                 }
-                sub_ref_tables
+                Ok(sub_ref_tables)
             },
             HybToken::Project(tk_vec, sub_tk) => {
-                let mut sub_ref_tables = self.eval_sti(*sub_tk);
+                let mut sub_ref_tables = self.eval_sti(*sub_tk)?;
                 let mut matches = Vec::new();
-                for (key1, key2) in tk_vec{
-                    matches.extend(self.emm_server.eval_emm_rr(&key1, &key2));
+                for emm_tk in tk_vec{
+                    matches.extend(self.emm_server.eval_emm_rr(&emm_tk));
                 }
                 for sub_ref_table in &mut sub_ref_tables{
                     (*sub_ref_table).filter_refs(&matches);
                 }
-                sub_ref_tables
+                Ok(sub_ref_tables)
             },
-            HybToken::FPJoin((key1, key2), sub_tk1, sub_tk2, id1, id2, swapped) => {
-                let mut sub_ref_tables1 = self.eval_sti(*sub_tk1);
-                let mut sub_ref_tables2 = self.eval_sti(*sub_tk2);
-                let matches_tuple = self.emm_server.eval_emm_rr(&key1, &key2);
+            HybToken::FPJoin(emm_tk, sub_tk1, sub_tk2, id1, id2, swapped) => {
+                let mut sub_ref_tables1 = self.eval_sti(*sub_tk1)?;
+                let mut sub_ref_tables2 = self.eval_sti(*sub_tk2)?;
+                let matches_tuple = self.emm_server.eval_emm_rr(&emm_tk);
                 let mut ref_pairs = HashSet::new();
                 
                 let mut index1 = None;
@@ -619,42 +1576,168 @@ impl STIServer {
                         }
                     }
                 }
-                let merged = sub_ref_tables1[index1.unwrap()].concat_table(&sub_ref_tables2[index2.unwrap()], &ref_pairs);
-                sub_ref_tables1.remove(index1.unwrap());
-                sub_ref_tables2.remove(index2.unwrap());
+                let (index1, index2) = match (index1, index2) {
+                    (Some(index1), Some(index2)) => (index1, index2),
+                    _ => return Err(Error::ServerState(format!("The join token refers to relations {} and {} which its sub-queries do not return", id1, id2)))
+                };
+                let merged = sub_ref_tables1[index1].concat_table(&sub_ref_tables2[index2], &ref_pairs, &id1, &id2);
+                sub_ref_tables1.remove(index1);
+                sub_ref_tables2.remove(index2);
                 sub_ref_tables1.append(&mut sub_ref_tables2);
                 sub_ref_tables1.push(merged);
-                sub_ref_tables1
+                Ok(sub_ref_tables1)
             },
-            HybToken::PPJoin((key11, key21), (key12, key22), sub_tk1, sub_tk2, id1, id2, save_col1, save_col2, swapped) => {
-                let mut sub_ref_tables1 = self.eval_sti(*sub_tk1);
-                let mut sub_ref_tables2 = self.eval_sti(*sub_tk2);
+            HybToken::PPJoin(emm_tk1, emm_tk2, sub_tk1, sub_tk2, id1, id2, save_cols1, save_cols2, swapped) => {
+                let mut sub_ref_tables1 = self.eval_sti(*sub_tk1)?;
+                let mut sub_ref_tables2 = self.eval_sti(*sub_tk2)?;
                 
                 let matches1;
                 let matches2;
                 if !swapped {
-                    matches1 = self.emm_server.eval_emm_rr(&key11, &key21);
-                    matches2 = self.emm_server.eval_emm_rr(&key12, &key22);
+                    matches1 = self.emm_server.eval_emm_rr(&emm_tk1);
+                    matches2 = self.emm_server.eval_emm_rr(&emm_tk2);
                 } else {
-                    matches1 = self.emm_server.eval_emm_rr(&key12, &key22);
-                    matches2 = self.emm_server.eval_emm_rr(&key11, &key21);
+                    matches1 = self.emm_server.eval_emm_rr(&emm_tk2);
+                    matches2 = self.emm_server.eval_emm_rr(&emm_tk1);
                 }
                 for sub_ref_table in &mut sub_ref_tables1{
                     if sub_ref_table.has_id(&id1){
-                        (*sub_ref_table).filter_rows(&matches1);
-                        (*sub_ref_table).add_save_col(save_col1);
+                        (*sub_ref_table).filter_rows(&matches1, &id1);
+                        for save_col in &save_cols1{
+                            (*sub_ref_table).add_save_col(*save_col);
+                        }
                     }
                 }
                 for sub_ref_table in &mut sub_ref_tables2{
                     if sub_ref_table.has_id(&id2){
-                        (*sub_ref_table).filter_rows(&matches2);
-                        (*sub_ref_table).add_save_col(save_col2);
+                        (*sub_ref_table).filter_rows(&matches2, &id2);
+                        for save_col in &save_cols2{
+                            (*sub_ref_table).add_save_col(*save_col);
+                        }
                     }
                 }
                 sub_ref_tables1.append(&mut sub_ref_tables2);
-                sub_ref_tables1
+                Ok(sub_ref_tables1)
+            }
+            HybToken::Order(emm_tk, id, desc, limit, sub_tk) => {
+                let mut sub_ref_tables = self.eval_sti(*sub_tk)?;
+                let column : HashSet<Vec<u8>> = self.emm_server.eval_emm_rr(&emm_tk).into_iter().collect();
+                // rows may only be cut once no joins are left for the client
+                let can_limit = sub_ref_tables.len() == 1;
+                for sub_ref_table in &mut sub_ref_tables{
+                    if sub_ref_table.has_id(&id){
+                        let cols = sub_ref_table.cells_of(&id);
+                        let mut keyed_rows = Vec::new();
+                        // NULL cells have no ORE ciphertext and go after every value
+                        let mut null_rows = Vec::new();
+                        for ref_row in sub_ref_table.refs.drain(..){
+                            match cols.iter().map(|i| &ref_row.cells[*i]).find(|cell| column.contains(*cell)) {
+                                Some(cell) => match self.ore_data.get(cell) {
+                                    Some(ore_ct) => keyed_rows.push((ore_ct, ref_row)),
+                                    None => null_rows.push(ref_row)
+                                },
+                                None => return Err(Error::ServerState(format!("A row of {} has no cell to order it by", id)))
+                            }
+                        }
+                        keyed_rows.sort_by(|(ore_ct1, _), (ore_ct2, _)| {
+                            if desc { compare(ore_ct2, ore_ct1) } else { compare(ore_ct1, ore_ct2) }
+                        });
+                        let mut ordered_rows : Vec<RefRow> = keyed_rows.into_iter().map(|(_, ref_row)| ref_row).collect();
+                        ordered_rows.append(&mut null_rows);
+                        if let (true, Some(limit)) = (can_limit, limit) {
+                            ordered_rows.truncate(limit);
+                        }
+                        sub_ref_table.refs = ordered_rows;
+                    }
+                }
+                Ok(sub_ref_tables)
+            },
+            HybToken::Count(_, _) => Err(Error::Protocol("A count can only be asked for as a whole query".to_string())),
+            HybToken::Sum(_, _, _, _) => Err(Error::Protocol("A sum can only be asked for as a whole query".to_string())),
+            HybToken::Group(_) => Err(Error::Protocol("Group counts can only be asked for as a whole query".to_string())),
+            HybToken::BadToken => Err(Error::Protocol("Received a token that does not describe a query".to_string()))
+        }
+    }
+    
+    /// Evaluates the query under a count token and returns only how many rows it
+    /// selects, encrypted under the key in the token. The server counts the rows
+    /// itself, so it still learns the count, as it learns every volume.
+    pub fn count_sti(&self, tk : HybToken) -> Result<Vec<u8>>{
+        match tk {
+            HybToken::Count(sub_tk, count_key) => {
+                let ref_tables = self.eval_sti(*sub_tk)?;
+                if ref_tables.len() != 1 {
+                    return Err(Error::ServerState(format!("Cannot count a query that leaves {} tables to join", ref_tables.len())));
+                }
+                Ok(encrypt_count(ref_tables[0].refs.len() as u64, &count_key))
+            },
+            _ => Err(Error::Protocol("Received a count request without a count token".to_string()))
+        }
+    }
+    
+    /// Evaluates the query under a sum token and multiplies together the Paillier
+    /// ciphertexts of the summed column in the rows it selects. Returns the
+    /// encrypted total and the number of rows, which the client needs for AVG,
+    /// encrypted like a count.
+    pub fn sum_sti(&self, tk : HybToken) -> Result<(Vec<u8>, Vec<u8>)>{
+        match tk {
+            HybToken::Sum(emm_tk, id, sub_tk, count_key) => {
+                let sum_key = match &self.sum_key {
+                    Some(sum_key) => sum_key,
+                    None => return Err(Error::ServerState("The edb holds no summable columns".to_string()))
+                };
+                let ref_tables = self.eval_sti(*sub_tk)?;
+                if ref_tables.len() != 1 {
+                    return Err(Error::ServerState(format!("Cannot total a query that leaves {} tables to join", ref_tables.len())));
+                }
+                let column : HashSet<Vec<u8>> = self.emm_server.eval_emm_rr(&emm_tk).into_iter().collect();
+                let cols = ref_tables[0].cells_of(&id);
+                let mut total = sum_key.zero();
+                let mut count = 0;
+                for ref_row in &ref_tables[0].refs{
+                    match cols.iter().map(|i| &ref_row.cells[*i]).find(|cell| column.contains(*cell)) {
+                        // NULL cells have no Paillier ciphertext and are left out, as in SQL
+                        Some(cell) => if let Some(sum_ct) = self.sum_data.get(cell) {
+                            total = sum_key.add(&total, sum_ct);
+                            count += 1;
+                        },
+                        None => return Err(Error::ServerState("A row has no cell to total".to_string()))
+                    }
+                }
+                Ok((total, encrypt_count(count, &count_key)))
+            },
+            _ => Err(Error::Protocol("Received a sum request without a sum token".to_string()))
+        }
+    }
+    
+    /// Looks up the precomputed counts under a group token. They stay encrypted,
+    /// so the server learns only how many groups there are.
+    pub fn group_sti(&self, tk : HybToken) -> Result<Vec<LabelledCiphertext>>{
+        match tk {
+            HybToken::Group(emm_tk) => Ok(self.emm_server.eval_emm_rh(&emm_tk)),
+            _ => Err(Error::Protocol("Received a group request without a group token".to_string()))
+        }
+    }
+    
+    fn eval_bool(&self, bool_tk : BoolToken) -> RowFilter {
+        match bool_tk {
+            BoolToken::Match(emm_tk) => {
+                RowFilter::Match(self.emm_server.eval_emm_rr(&emm_tk).into_iter().collect())
+            },
+            BoolToken::And(left, right) => {
+                RowFilter::And(Box::new(self.eval_bool(*left)), Box::new(self.eval_bool(*right)))
+            },
+            BoolToken::Or(left, right) => {
+                RowFilter::Or(Box::new(self.eval_bool(*left)), Box::new(self.eval_bool(*right)))
+            },
+            BoolToken::Not(sub) => RowFilter::Not(Box::new(self.eval_bool(*sub))),
+            BoolToken::Cover(tks) => {
+                let mut matches = HashSet::new();
+                for emm_tk in tks {
+                    matches.extend(self.emm_server.eval_emm_rr(&emm_tk));
+                }
+                RowFilter::Match(matches)
             }
-            _ => { Vec::new() }
         }
     }
 }
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use num_bigint::BigUint;
+use num_traits::{ Zero, One };
+use rand_chacha::ChaCha20Rng;
+use rand_core::{ RngCore, SeedableRng };
+use crate::paillier::gen_prime;
+use crate::error::{ Error, Result };
+
+// RSA used as a trapdoor permutation. Anyone holding the public key can step a
+// state back with x^e, but only the client can step it forward with x^d.
+const PUBLIC_EXPONENT : u32 = 65537;
+
+pub struct TdpKey {
+    p : BigUint,
+    q : BigUint,
+    // d reduced modulo p - 1 and q - 1, and q inverted modulo p, for the CRT
+    d_p : BigUint,
+    d_q : BigUint,
+    q_inv : BigUint,
+    n : BigUint,
+}
+
+/// The half of the permutation the server can evaluate.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+pub struct TdpPublicKey {
+    n : Vec<u8>,
+}
+
+impl TdpKey {
+    /// Derives the key from a seed, so a client that keeps only its master key
+    /// can step states forward again.
+    pub fn from_seed(seed : [u8; 32]) -> TdpKey {
+        let mut rng = ChaCha20Rng::from_seed(seed);
+        let e = BigUint::from(PUBLIC_EXPONENT);
+        let one = BigUint::one();
+        // e has to be invertible modulo p - 1 and q - 1
+        let mut gen = || loop {
+            let prime = gen_prime(&mut rng);
+            if !((&prime - &one) % &e).is_zero() {
+                break prime;
+            }
+        };
+        let p = gen();
+        let mut q = gen();
+        while q == p {
+            q = gen();
+        }
+        let d_p = e.modinv(&(&p - &one)).expect("e is coprime to p - 1");
+        let d_q = e.modinv(&(&q - &one)).expect("e is coprime to q - 1");
+        let q_inv = q.modinv(&p).expect("distinct primes are coprime");
+        TdpKey {
+            n : &p * &q,
+            p,
+            q,
+            d_p,
+            d_q,
+            q_inv,
+        }
+    }
+
+    pub fn public_key(&self) -> TdpPublicKey {
+        TdpPublicKey {
+            n : self.n.to_bytes_be()
+        }
+    }
+
+    /// A state drawn from a seed, the first of a chain.
+    pub fn sample(&self, seed : [u8; 32]) -> Vec<u8> {
+        let mut rng = ChaCha20Rng::from_seed(seed);
+        let mut bytes = vec![0u8; self.n.to_bytes_be().len()];
+        rng.fill_bytes(&mut bytes);
+        (BigUint::from_bytes_be(&bytes) % &self.n).to_bytes_be()
+    }
+
+    /// The state after `state`, which the public key maps back to `state`.
+    pub fn invert(&self, state : &[u8]) -> Vec<u8> {
+        let x = BigUint::from_bytes_be(state) % &self.n;
+        let x_p = x.modpow(&self.d_p, &self.p);
+        let x_q = x.modpow(&self.d_q, &self.q);
+        let diff = (&x_p + &self.p - &x_q % &self.p) % &self.p;
+        (x_q + &self.q * (diff * &self.q_inv % &self.p)).to_bytes_be()
+    }
+}
+
+impl TdpPublicKey {
+    /// The state before `state`.
+    pub fn apply(&self, state : &[u8]) -> Result<Vec<u8>> {
+        let n = BigUint::from_bytes_be(&self.n);
+        let x = BigUint::from_bytes_be(state);
+        if x >= n {
+            return Err(Error::Crypto("A search state is not a number modulo the trapdoor key".to_string()));
+        }
+        Ok(x.modpow(&BigUint::from(PUBLIC_EXPONENT), &n).to_bytes_be())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+
+    // Making a key takes a while, so every test shares one
+    fn key() -> &'static TdpKey {
+        static KEY : OnceLock<TdpKey> = OnceLock::new();
+        KEY.get_or_init(|| TdpKey::from_seed([3u8; 32]))
+    }
+
+    #[test]
+    fn apply_steps_back_through_every_epoch() {
+        let public = key().public_key();
+        let mut states = vec![key().sample([9u8; 32])];
+        for _ in 0..8 {
+            let next = key().invert(states.last().unwrap());
+            assert!(!states.contains(&next));
+            states.push(next);
+        }
+        for epoch in (1..states.len()).rev() {
+            assert_eq!(public.apply(&states[epoch]).unwrap(), states[epoch - 1]);
+        }
+        // going forward again from any epoch reaches the same states
+        assert_eq!(key().invert(&public.apply(&states[5]).unwrap()), states[5]);
+    }
+
+    #[test]
+    fn states_come_from_their_seed() {
+        assert_eq!(key().sample([1u8; 32]), key().sample([1u8; 32]));
+        assert_ne!(key().sample([1u8; 32]), key().sample([2u8; 32]));
+        assert!(BigUint::from_bytes_be(&key().sample([1u8; 32])) < key().n);
+    }
+
+    #[test]
+    fn keys_come_from_their_seed() {
+        assert_eq!(TdpKey::from_seed([3u8; 32]).public_key().n, key().public_key().n);
+        assert_ne!(TdpKey::from_seed([4u8; 32]).public_key().n, key().public_key().n);
+    }
+
+    #[test]
+    fn states_outside_the_key_are_rejected() {
+        let public = key().public_key();
+        assert!(public.apply(&key().n.to_bytes_be()).is_err());
+        assert!(public.apply(&[0xffu8; 300]).is_err());
+        assert_eq!(public.apply(&[1u8]).unwrap(), vec![1u8]);
+    }
+}
@@ -1,51 +1,175 @@
-type KeyPair = ([u8; 16], [u8; 16]);
 use serde::{Deserialize, Serialize};
-use crate::ste::STEServer;
+use crate::ste::{ STEServer, STEUpdate, STERewrite };
+use crate::emm::EMMToken;
+use crate::crypto_helper::LabelledCiphertext;
+use crate::error::{ Error, Result };
 
 #[derive(Debug)]
+#[derive(Clone)]
 pub enum BoolQuery {
     Eq(String, String),
-    // Not(Box<BoolQuery>),
-    // And(Box<BoolQuery>, Box<BoolQuery>),
-    // Or(Box<BoolQuery>, Box<BoolQuery>),
+    In(String, Vec<String>),
+    // attribute, then the lower and upper bounds with whether each is inclusive
+    Range(String, Option<(String, bool)>, Option<(String, bool)>),
+    IsNull(String),
+    Not(Box<BoolQuery>),
+    And(Box<BoolQuery>, Box<BoolQuery>),
+    Or(Box<BoolQuery>, Box<BoolQuery>),
     BadBool(String)
 }
 
+impl BoolQuery {
+    /// Flattens the top level `And`s of a predicate. Anything below an `Or` or a
+    /// `Not` stays in one piece.
+    pub fn conjuncts(&self) -> Result<Vec<BoolQuery>> {
+        match self {
+            BoolQuery::And(left, right) => {
+                let mut conjuncts = left.conjuncts()?;
+                conjuncts.extend(right.conjuncts()?);
+                Ok(conjuncts)
+            },
+            BoolQuery::BadBool(s) => Err(Error::Parse(s.to_string())),
+            b_query => Ok(vec!(b_query.clone()))
+        }
+    }
+    
+    /// Every attribute the predicate mentions.
+    pub fn attributes(&self) -> Vec<String> {
+        match self {
+            BoolQuery::Eq(attrib, _) | BoolQuery::In(attrib, _) | BoolQuery::Range(attrib, _, _) | BoolQuery::IsNull(attrib) => vec!(attrib.to_string()),
+            BoolQuery::Not(sub) => sub.attributes(),
+            BoolQuery::And(left, right) | BoolQuery::Or(left, right) => {
+                let mut ats = left.attributes();
+                ats.extend(right.attributes());
+                ats
+            },
+            BoolQuery::BadBool(_) => Vec::new()
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum SPJQuery {
     Select(BoolQuery, Box<SPJQuery>),
-    Join(String, String, Box<SPJQuery>, Box<SPJQuery>),
+    // the attributes of each side, where the i-th of one must equal the i-th of the other
+    Join(Vec<String>, Vec<String>, Box<SPJQuery>, Box<SPJQuery>),
     Project(Vec<String>, Box<SPJQuery>),
-    Id(String),
+    // attribute, whether descending, and how many rows to keep
+    Order(String, bool, Option<usize>, Box<SPJQuery>),
+    Count(Box<SPJQuery>),
+    // the column to total, and true to average it instead
+    Sum(String, bool, Box<SPJQuery>),
+    // COUNT(*) per value of the column, over the whole relation below it
+    Group(String, Box<SPJQuery>),
+    // a relation, and the alias it goes by in the query if it was given one
+    Id(String, Option<String>),
     BadQuery(String)
 }
 
 #[derive(Debug)]
+#[derive(Clone)]
 // #[derive(Serialize, Deserialize)]
 pub enum HybQuery {
     Select(BoolQuery, Box<HybQuery>),
-    FPJoin(String, String, Box<HybQuery>, Box<HybQuery>),
-    PPJoin(String, String, Box<HybQuery>, Box<HybQuery>),
+    FPJoin(Vec<String>, Vec<String>, Box<HybQuery>, Box<HybQuery>),
+    PPJoin(Vec<String>, Vec<String>, Box<HybQuery>, Box<HybQuery>),
     Project(Vec<String>, Box<HybQuery>),
-    Id(String),
+    Order(String, bool, Option<usize>, Box<HybQuery>),
+    Count(Box<HybQuery>),
+    Sum(String, bool, Box<HybQuery>),
+    Group(String, Box<HybQuery>),
+    Id(String, Option<String>),
     BadQuery(String)
 }
 
+/// A statement that changes the rows of a relation. Without a predicate it
+/// applies to every row.
+#[derive(Debug)]
+pub enum ModQuery {
+    Delete(String, Option<BoolQuery>),
+    // the relation, the columns set with their new values, and the rows to change
+    Update(String, Vec<(String, String)>, Option<BoolQuery>),
+    BadMod(String)
+}
+
+/// A selection predicate over one relation. Each `Match` is one emm lookup, so the
+/// server learns how many rows each lookup returns as well as which rows survive.
+/// `And` keeps the rows every side matches. A pair of equalities covered by a
+/// conjunctive index is sent as a single `Match`, which hides the volumes of the
+/// two equalities on their own.
+///
+/// `Or` keeps the rows either side matches. The server still sees the rows each
+/// side matches, so it learns how the sides overlap and not just their union; an
+/// `IN` list is sent as an `Or` of its values and also reveals how many values
+/// were listed. `Not` keeps the rows its operand does not match, which leaks the
+/// volume of the operand as well as of its complement.
+///
+/// `Cover` is a range predicate split into the dyadic intervals that cover it. It
+/// keeps the rows any interval matches, and the server learns how many intervals
+/// there are and how many rows fall in each.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+pub enum BoolToken {
+    Match(EMMToken),
+    And(Box<BoolToken>, Box<BoolToken>),
+    Or(Box<BoolToken>, Box<BoolToken>),
+    Not(Box<BoolToken>),
+    Cover(Vec<EMMToken>),
+}
+
+/// The `String`s name instances of relations: the alias a relation goes by in the
+/// query, or the relation itself if it has none. They let the server keep apart
+/// the tables of a relation that is joined with itself.
 #[derive(Serialize, Deserialize)]
 #[derive(Clone)]
 pub enum HybToken {
-    Id(KeyPair, String, usize),
-    Select(KeyPair, Box<HybToken>, String),
-    Project(Vec<KeyPair>, Box<HybToken>),
-    FPJoin(KeyPair, Box<HybToken>, Box<HybToken>, String, String, bool),
-    PPJoin(KeyPair, KeyPair, Box<HybToken>, Box<HybToken>, String, String, usize, usize, bool),
+    Id(EMMToken, String, usize),
+    Select(BoolToken, Box<HybToken>, String),
+    Project(Vec<EMMToken>, Box<HybToken>),
+    FPJoin(EMMToken, Box<HybToken>, Box<HybToken>, String, String, bool),
+    // the positions of the joined attributes in each relation are kept from projection
+    PPJoin(EMMToken, EMMToken, Box<HybToken>, Box<HybToken>, String, String, Vec<usize>, Vec<usize>, bool),
+    // the projection token of the attribute sorted on, its relation, whether
+    // descending, and how many rows to keep
+    Order(EMMToken, String, bool, Option<usize>, Box<HybToken>),
+    // only ever outermost. The server answers with the number of rows instead of
+    // the rows, encrypted under the key, which is drawn afresh for every query
+    Count(Box<HybToken>, [u8; 16]),
+    // only ever outermost. The projection token for the column the server totals
+    // homomorphically, the instance the column is taken from, and a key for the
+    // count as for `Count`
+    Sum(EMMToken, String, Box<HybToken>, [u8; 16]),
+    // only ever a whole query. A response-hiding token for the group count list, so
+    // the server can find the encrypted counts but not read them
+    Group(EMMToken),
     BadToken,
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum ServerCommand {
+    Hello(u16),
     Setup(STEServer),
     Search(HybToken),
-    SearchResponse(Vec<Vec<Vec<u8>>>),
-    SearchFailure(String)
+    // every cell with its label, for each relation instance of the result
+    SearchResponse(Vec<Vec<LabelledCiphertext>>),
+    SearchFailure(Error),
+    SaveEDB(String),
+    LoadEDB(String),
+    DeleteEDB(String),
+    ListEDBs,
+    ListResponse(Vec<String>),
+    CommandSuccess(String),
+    CommandFailure(Error),
+    Count(HybToken),
+    // the count encrypted under the key in the count token
+    CountResponse(Vec<u8>),
+    Sum(HybToken),
+    // the encrypted total and how many rows went into it, encrypted as for a count
+    SumResponse(Vec<u8>, Vec<u8>),
+    Group(HybToken),
+    GroupResponse(Vec<LabelledCiphertext>),
+    // rows inserted since setup
+    Update(STEUpdate),
+    // rows deleted or changed since setup
+    Rewrite(STERewrite)
 }
\ No newline at end of file
@@ -0,0 +1,235 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{ Hash, Hasher };
+use serde::{Deserialize, Serialize};
+use crate::range::{ float_key, order_key, parse_date };
+use crate::error::{ Error, Result };
+
+/// The type of a column. Every value of the column is of this type or NULL.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ValueType {
+    Int,
+    Float,
+    Date,
+    Bool,
+    Text,
+}
+
+/// One cell of a relation. Values are normalized when they are read, so `01`
+/// and `1` in an INT column are the same value everywhere, including in the
+/// labels of the edb.
+#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug)]
+pub enum Value {
+    Null,
+    Int(i64),
+    Float(f64),
+    // seconds since 1970-01-01
+    Date(i64),
+    Bool(bool),
+    Text(String),
+}
+
+impl ValueType {
+    /// Reads a type as written in a CREATE TABLE line.
+    pub fn from_name(name : &str) -> Option<ValueType> {
+        match name.to_lowercase().as_str() {
+            "int" | "integer" | "bigint" => Some(ValueType::Int),
+            "float" | "double" | "real" | "decimal" | "numeric" => Some(ValueType::Float),
+            "date" | "datetime" | "timestamp" => Some(ValueType::Date),
+            "bool" | "boolean" => Some(ValueType::Bool),
+            "text" | "string" | "varchar" | "char" => Some(ValueType::Text),
+            _ => None
+        }
+    }
+
+    /// The narrowest type that every raw value fits without changing how it is
+    /// written, so numbers with leading zeros such as postal codes stay text.
+    /// NULLs fit every type.
+    pub fn infer(raw_values : &[&str]) -> ValueType {
+        let values : Vec<&str> = raw_values.iter().map(|raw| raw.trim()).filter(|raw| !is_null(raw)).collect();
+        if values.is_empty() {
+            ValueType::Text
+        } else if values.iter().all(|raw| is_plain_int(raw)) {
+            ValueType::Int
+        } else if values.iter().all(|raw| is_plain_int(raw) || is_plain_decimal(raw)) {
+            ValueType::Float
+        } else if values.iter().all(|raw| parse_date(raw).is_some()) {
+            ValueType::Date
+        } else if values.iter().all(|raw| raw.eq_ignore_ascii_case("true") || raw.eq_ignore_ascii_case("false")) {
+            ValueType::Bool
+        } else {
+            ValueType::Text
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ValueType::Int => "int",
+            ValueType::Float => "float",
+            ValueType::Date => "date",
+            ValueType::Bool => "bool",
+            ValueType::Text => "text",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Value {
+    /// Reads a raw value into a column of type `ty`. `NULL` and an empty value,
+    /// such as an empty csv field, are NULL in any column.
+    pub fn parse(raw : &str, ty : &ValueType) -> Result<Value> {
+        let trimmed = raw.trim();
+        if is_null(trimmed) {
+            return Ok(Value::Null);
+        }
+        let bad_value = || Error::Schema(format!("{} is not a valid {} value", raw, ty));
+        match ty {
+            ValueType::Int => trimmed.parse::<i64>().map(Value::Int).map_err(|_| bad_value()),
+            ValueType::Float => match trimmed.parse::<f64>() {
+                // -0.0 and 0.0 are the same value
+                Ok(num) if num.is_finite() => Ok(Value::Float(num + 0.0)),
+                _ => Err(bad_value())
+            },
+            ValueType::Date => parse_date(trimmed).map(Value::Date).ok_or_else(bad_value),
+            ValueType::Bool => match trimmed.to_lowercase().as_str() {
+                "true" | "t" | "yes" | "1" => Ok(Value::Bool(true)),
+                "false" | "f" | "no" | "0" => Ok(Value::Bool(false)),
+                _ => Err(bad_value())
+            },
+            ValueType::Text => Ok(Value::Text(raw.to_string()))
+        }
+    }
+
+    /// Whether the value may be stored in a column of type `ty`.
+    pub fn fits(&self, ty : &ValueType) -> bool {
+        matches!((self, ty), (Value::Null, _) | (Value::Int(_), ValueType::Int) | (Value::Float(_), ValueType::Float)
+            | (Value::Date(_), ValueType::Date) | (Value::Bool(_), ValueType::Bool) | (Value::Text(_), ValueType::Text))
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// Maps the value to a u64 in the same order, as `range::order_key` does for
+    /// the values written in queries.
+    pub fn order_key(&self) -> Result<u64> {
+        match self {
+            Value::Int(num) => Ok(float_key(*num as f64)),
+            Value::Float(num) => Ok(float_key(*num)),
+            Value::Date(seconds) => Ok(float_key(*seconds as f64)),
+            Value::Bool(b) => Ok(float_key(if *b { 1.0 } else { 0.0 })),
+            Value::Text(s) => order_key(s),
+            Value::Null => Err(Error::Parse("NULL cannot be compared".to_string()))
+        }
+    }
+
+    // Position of the variant, to order values of different types
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Int(_) => 1,
+            Value::Float(_) => 2,
+            Value::Date(_) => 3,
+            Value::Bool(_) => 4,
+            Value::Text(_) => 5,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Int(num) => write!(f, "{}", num),
+            Value::Float(num) => write!(f, "{}", num),
+            Value::Date(seconds) => write!(f, "{}", format_date(*seconds)),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Text(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other : &Value) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H : Hasher>(&self, state : &mut H) {
+        self.rank().hash(state);
+        match self {
+            Value::Null => {},
+            Value::Int(num) => num.hash(state),
+            Value::Float(num) => num.to_bits().hash(state),
+            Value::Date(seconds) => seconds.hash(state),
+            Value::Bool(b) => b.hash(state),
+            Value::Text(s) => s.hash(state),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other : &Value) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other : &Value) -> Ordering {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a.cmp(b),
+            (Value::Float(a), Value::Float(b)) => a.total_cmp(b),
+            (Value::Date(a), Value::Date(b)) => a.cmp(b),
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank())
+        }
+    }
+}
+
+fn is_null(raw : &str) -> bool {
+    raw.is_empty() || raw.eq_ignore_ascii_case("NULL")
+}
+
+// An integer written the way it prints: no plus sign and no leading zeros
+fn is_plain_int(raw : &str) -> bool {
+    let digits = raw.strip_prefix('-').unwrap_or(raw);
+    !digits.is_empty() && digits.chars().all(|ch| ch.is_ascii_digit())
+        && (digits == "0" || !digits.starts_with('0')) && raw.parse::<i64>().is_ok()
+}
+
+// A decimal such as 2.99 or -0.5, with a plain integer part
+fn is_plain_decimal(raw : &str) -> bool {
+    match raw.split_once('.') {
+        Some((whole, frac)) => (is_plain_int(whole) || whole == "-0") && !frac.is_empty() && frac.chars().all(|ch| ch.is_ascii_digit()),
+        None => false
+    }
+}
+
+// `YYYY-MM-DD`, with ` HH:MM:SS` when the time is not midnight
+fn format_date(seconds : i64) -> String {
+    let days = seconds.div_euclid(86400);
+    let time = seconds.rem_euclid(86400);
+    // civil from days, counting years from March so leap days fall last
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    if time == 0 {
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    } else {
+        format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, time / 3600, (time % 3600) / 60, time % 60)
+    }
+}
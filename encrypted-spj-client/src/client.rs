@@ -0,0 +1,353 @@
+use common::token::{ ServerCommand, HybQuery, HybToken, ModQuery };
+use common::db_structs::{ DB, Relation };
+use common::ste::{ STEClient, STEServer };
+use common::crypto_helper::KeyRng;
+use common::keystore::KeyStore;
+use common::leakage_query_planner::{ Statistics, Estimate };
+use common::error::{ Error, Result };
+use crate::connection::ServerConnection;
+use crate::parse::{ parse, parse_hyb, parse_mod, annotate_query, load_db_from_txt, load_rel_from_csv, annotate_from_txt };
+
+/// Programmatic client for an encrypted-spj database. Holds the plaintext
+/// database being prepared, the keys for the edb, and either a connection to a
+/// server or a local in-process edb when no server is connected.
+pub struct EncryptedSpjClient {
+    db : DB,
+    edb_client : STEClient,
+    edb_server_local : STEServer,
+    conn : Option<ServerConnection>,
+    stats : Option<Statistics>,
+}
+
+impl Default for EncryptedSpjClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EncryptedSpjClient {
+    pub fn new() -> EncryptedSpjClient {
+        EncryptedSpjClient::with_ste_client(STEClient::new_ste_client())
+    }
+
+    pub fn with_ste_client(edb_client : STEClient) -> EncryptedSpjClient {
+        EncryptedSpjClient {
+            db : DB::new_db(),
+            edb_client,
+            edb_server_local : STEServer::new_ste_server(),
+            conn : None,
+            stats : None
+        }
+    }
+
+    /// Connects to the server at `addr`. Until this is called, or after
+    /// `disconnect`, `setup` and `query` work against a local edb.
+    pub fn connect(&mut self, addr : &str) -> Result<()> {
+        self.conn = Some(ServerConnection::connect(addr)?);
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self) -> Result<()> {
+        match self.conn.take() {
+            Some(conn) => conn.shutdown(),
+            None => Ok(())
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.conn.is_some()
+    }
+
+    /// Loads `csvs/{name}.csv` into the database as the relation `name` and
+    /// returns how many rows it had.
+    pub fn load_csv(&mut self, name : &str) -> Result<usize> {
+        let rel = load_rel_from_csv(&name.to_string())?;
+        let num_rows = rel.table.len();
+        self.db.add_rel(&name.to_string(), rel);
+        Ok(num_rows)
+    }
+
+    /// Replaces the database with the one described in `txts/{name}.txt`.
+    pub fn load_db_txt(&mut self, name : &str) -> Result<()> {
+        self.db = load_db_from_txt(&name.to_string())?;
+        Ok(())
+    }
+
+    /// Precomputes the join on each attribute of `ats1` equalling the one of
+    /// `ats2` in the same position at the next setup.
+    pub fn annotate(&mut self, ats1 : &[&str], ats2 : &[&str]) {
+        let to_strings = |ats : &[&str]| ats.iter().map(|at| at.to_string()).collect::<Vec<String>>();
+        self.db.add_annotation(&to_strings(ats1), &to_strings(ats2));
+    }
+
+    /// Builds a conjunctive index on two attributes of the same relation at the next
+    /// setup, so `attrib1 = x AND attrib2 = y` costs the server one lookup.
+    pub fn add_conjunction(&mut self, attrib1 : &str, attrib2 : &str) {
+        self.db.add_conjunction(&attrib1.to_string(), &attrib2.to_string());
+    }
+
+    /// Builds a range index on a numeric or date attribute at the next setup.
+    pub fn add_ordered(&mut self, attrib : &str) {
+        self.db.add_ordered(&attrib.to_string());
+    }
+
+    /// Stores order-revealing ciphertexts for a numeric or date attribute at the
+    /// next setup, so the server can answer ORDER BY, MIN and MAX on it.
+    pub fn add_ore(&mut self, attrib : &str) {
+        self.db.add_ore(&attrib.to_string());
+    }
+
+    /// Stores Paillier ciphertexts for a numeric attribute at the next setup, so
+    /// the server can answer SUM and AVG on it.
+    pub fn add_summable(&mut self, attrib : &str) {
+        self.db.add_summable(&attrib.to_string());
+    }
+
+    /// Precomputes how many rows hold each value of an attribute at the next
+    /// setup, so the server can answer GROUP BY on it with COUNT(*).
+    pub fn add_grouped(&mut self, attrib : &str) {
+        self.db.add_grouped(&attrib.to_string());
+    }
+
+    /// Adds every annotation, conjunctive index, range index, ORE, summable and grouped column listed in `txts/{name}.txt`.
+    pub fn annotate_from_txt(&mut self, name : &str) -> Result<()> {
+        annotate_from_txt(&name.to_string(), &mut self.db)
+    }
+
+    /// Encrypts the database and hands the edb to the server, or keeps it locally
+    /// when not connected. Returns the size in bytes of the edb.
+    pub fn setup(&mut self) -> Result<usize> {
+        let edb_server = self.edb_client.setup_ste(&mut self.db)?;
+        let edb_bytes = bincode::serialize(&edb_server).map_err(|e| Error::Protocol(e.to_string()))?.len();
+        if let Some(ref mut conn) = self.conn {
+            match conn.request(&ServerCommand::Setup(edb_server))? {
+                ServerCommand::CommandSuccess(_) => Ok(edb_bytes),
+                ServerCommand::CommandFailure(e) => Err(e),
+                _ => Err(unexpected_reply())
+            }
+        } else {
+            self.edb_server_local = edb_server;
+            Ok(edb_bytes)
+        }
+    }
+
+    /// Inserts a row, written as its values in column order, into the relation
+    /// `id` of the edb set up by this client, and into the database it came from.
+    /// A generated row number is filled in rather than given.
+    pub fn insert(&mut self, id : &str, values : Vec<String>) -> Result<()> {
+        let id = id.to_string();
+        let row = match self.db.get_rel(&id) {
+            Some(rel) => rel.row_from_strings(&values)?,
+            None => return Err(Error::Schema(format!("Could not insert into {} because there is no such relation", id)))
+        };
+        let rows = vec!(row);
+        let update = self.edb_client.update_ste(&self.db, &id, &rows)?;
+        if let Some(ref mut conn) = self.conn {
+            match conn.request(&ServerCommand::Update(update))? {
+                ServerCommand::CommandSuccess(_) => (),
+                ServerCommand::CommandFailure(e) => return Err(e),
+                _ => return Err(unexpected_reply())
+            }
+        } else {
+            self.edb_server_local.apply_update(update)?;
+        }
+        self.edb_client.confirm_update();
+        let rel = self.db.get_rel_mut(&id).ok_or(Error::Schema(format!("Could not insert into {} because there is no such relation", id)))?;
+        for row in rows{
+            rel.add_row(row)?;
+        }
+        Ok(())
+    }
+
+    /// Runs a DELETE or UPDATE statement on the edb set up by this client and on the
+    /// database it came from, returning how many rows it changed. The rows are
+    /// picked from the plaintext database, which the client holds, and the edb's
+    /// lists are written again from it, so it must be the database the edb was
+    /// last changed from. A delete rewrites the lists of every row of the
+    /// relation, so it costs about as much as encrypting the relation again.
+    pub fn modify(&mut self, statement : &str) -> Result<usize> {
+        let (id, assignments, b_query) = match parse_mod(statement) {
+            ModQuery::Delete(id, b_query) => (id, None, b_query),
+            ModQuery::Update(id, assignments, b_query) => (id, Some(assignments), b_query),
+            ModQuery::BadMod(s) => return Err(Error::Parse(s))
+        };
+        let rel = match self.db.get_rel(&id) {
+            Some(rel) => rel,
+            None => return Err(Error::Schema(format!("Could not change {} because there is no such relation", id)))
+        };
+        let mut removed = Vec::new();
+        for row in &rel.table{
+            let selected = match &b_query {
+                Some(b_query) => rel.eval_bool(row, b_query)? == Some(true),
+                None => true
+            };
+            if selected {
+                removed.push(row.clone());
+            }
+        }
+        let added = match &assignments {
+            Some(assignments) => rel.updated_rows(&removed, assignments)?,
+            None => Vec::new()
+        };
+        if removed.is_empty() {
+            return Ok(0);
+        }
+        let rewrite = self.edb_client.rewrite_ste(&self.db, &id, &removed, &added)?;
+        if let Some(ref mut conn) = self.conn {
+            match conn.request(&ServerCommand::Rewrite(rewrite))? {
+                ServerCommand::CommandSuccess(_) => (),
+                ServerCommand::CommandFailure(e) => return Err(e),
+                _ => return Err(unexpected_reply())
+            }
+        } else {
+            self.edb_server_local.apply_rewrite(rewrite)?;
+        }
+        self.edb_client.confirm_update();
+        let rel = self.db.get_rel_mut(&id).ok_or(Error::Schema(format!("Could not change {} because there is no such relation", id)))?;
+        rel.table = rel.without_rows(&removed);
+        for row in added{
+            rel.add_row(row)?;
+        }
+        Ok(removed.len())
+    }
+
+    /// Runs an SQL query, evaluating every join as a fully precomputed join.
+    pub fn query(&mut self, query : &str) -> Result<Relation> {
+        self.search(annotate_query(parse(query)))
+    }
+
+    /// Runs a query written with JOINF and JOINP to pick the join type of each join.
+    pub fn query_hyb(&mut self, query : &str) -> Result<Relation> {
+        self.search(parse_hyb(query))
+    }
+
+    pub fn search(&mut self, qry : HybQuery) -> Result<Relation> {
+        let tk = self.edb_client.tokenize_ste(qry)?;
+        if let HybToken::Count(_, _) = tk {
+            // only the encrypted number of rows comes back, never their cells
+            let enc_count = if let Some(ref mut conn) = self.conn {
+                match conn.request(&ServerCommand::Count(tk))? {
+                    ServerCommand::CountResponse(enc_count) => enc_count,
+                    ServerCommand::SearchFailure(e) => return Err(e),
+                    _ => return Err(unexpected_reply())
+                }
+            } else {
+                self.edb_server_local.count_ste(tk)?
+            };
+            return self.edb_client.decrypt_count(enc_count);
+        }
+        if let HybToken::Sum(_, _, _, _) = tk {
+            let (total, enc_count) = if let Some(ref mut conn) = self.conn {
+                match conn.request(&ServerCommand::Sum(tk))? {
+                    ServerCommand::SumResponse(total, enc_count) => (total, enc_count),
+                    ServerCommand::SearchFailure(e) => return Err(e),
+                    _ => return Err(unexpected_reply())
+                }
+            } else {
+                self.edb_server_local.sum_ste(tk)?
+            };
+            return self.edb_client.decrypt_sum(total, enc_count);
+        }
+        if let HybToken::Group(_) = tk {
+            let enc_counts = if let Some(ref mut conn) = self.conn {
+                match conn.request(&ServerCommand::Group(tk))? {
+                    ServerCommand::GroupResponse(enc_counts) => enc_counts,
+                    ServerCommand::SearchFailure(e) => return Err(e),
+                    _ => return Err(unexpected_reply())
+                }
+            } else {
+                self.edb_server_local.group_ste(tk)?
+            };
+            return self.edb_client.decrypt_group(enc_counts);
+        }
+        let ciphertexts = if let Some(ref mut conn) = self.conn {
+            match conn.request(&ServerCommand::Search(tk))? {
+                ServerCommand::SearchResponse(ciphertexts) => ciphertexts,
+                ServerCommand::SearchFailure(e) => return Err(e),
+                _ => return Err(unexpected_reply())
+            }
+        } else {
+            self.edb_server_local.eval_ste(tk)?
+        };
+        self.edb_client.decrypt_ste(ciphertexts)
+    }
+
+    /// Builds the client-side statistics that `estimate` uses from the current database.
+    pub fn setup_stats(&mut self) {
+        self.stats = Some(Statistics::from_database(&mut self.db));
+    }
+
+    /// Estimates the bandwidth and leakage of a query written with JOINF and JOINP.
+    pub fn estimate(&self, query : &str) -> Result<Estimate> {
+        match self.stats {
+            Some(ref stats) => stats.estimate(parse_hyb(query)),
+            None => Err(Error::Schema("Statistics have not been set up yet".to_string()))
+        }
+    }
+
+    /// Makes later setups derive their keys from `seed`. Only for benchmarks.
+    pub fn seed_keys(&mut self, seed : u64) {
+        self.edb_client = STEClient::with_rng(KeyRng::seeded(seed));
+    }
+
+    pub fn save_keys(&self, path : &str, passphrase : &str) -> Result<()> {
+        self.edb_client.to_keystore().save(path, passphrase)
+    }
+
+    pub fn load_keys(&mut self, path : &str, passphrase : &str) -> Result<()> {
+        let keystore = KeyStore::load(path, passphrase)?;
+        self.edb_client = STEClient::from_keystore(keystore);
+        Ok(())
+    }
+
+    pub fn save_edb(&mut self, name : &str) -> Result<String> {
+        self.manage_edb(ServerCommand::SaveEDB(name.to_string()))
+    }
+
+    pub fn load_edb(&mut self, name : &str) -> Result<String> {
+        self.manage_edb(ServerCommand::LoadEDB(name.to_string()))
+    }
+
+    pub fn delete_edb(&mut self, name : &str) -> Result<String> {
+        self.manage_edb(ServerCommand::DeleteEDB(name.to_string()))
+    }
+
+    pub fn list_edbs(&mut self) -> Result<Vec<String>> {
+        match self.request(&ServerCommand::ListEDBs)? {
+            ServerCommand::ListResponse(names) => Ok(names),
+            ServerCommand::CommandFailure(e) => Err(e),
+            _ => Err(unexpected_reply())
+        }
+    }
+
+    fn manage_edb(&mut self, message : ServerCommand) -> Result<String> {
+        match self.request(&message)? {
+            ServerCommand::CommandSuccess(s) => Ok(s),
+            ServerCommand::CommandFailure(e) => Err(e),
+            _ => Err(unexpected_reply())
+        }
+    }
+
+    fn request(&mut self, message : &ServerCommand) -> Result<ServerCommand> {
+        match self.conn {
+            Some(ref mut conn) => conn.request(message),
+            None => Err(Error::ServerState("You must connect to a server to manage stored edbs".to_string()))
+        }
+    }
+
+    pub fn db(&self) -> &DB {
+        &self.db
+    }
+
+    pub fn print_schema(&self) {
+        self.db.print_schema();
+    }
+
+    pub fn print_schema_edb(&self) {
+        self.edb_client.print_schema();
+    }
+}
+
+fn unexpected_reply() -> Error {
+    Error::Protocol("The server sent back an unexpected reply".to_string())
+}
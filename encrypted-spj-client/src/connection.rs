@@ -0,0 +1,63 @@
+use std::net::TcpStream;
+use common::token::ServerCommand;
+use common::protocol::{ read_frame, write_frame, PROTOCOL_VERSION };
+use common::secure_channel::{ ChannelKeys, SecureStream, load_key };
+use common::error::{ Error, Result };
+
+pub const CLIENT_KEYS : &str = "channel/client";
+pub const SERVER_PUBLIC_KEY : &str = "channel/server.pub";
+
+/// A connection to an encrypted-spj server over an authenticated channel that
+/// has completed the version handshake.
+pub struct ServerConnection {
+    stream : SecureStream,
+    next_request_id : u64,
+}
+
+impl ServerConnection {
+    /// Connects to `addr`, authenticating with the client keys in `channel/client.key`
+    /// and accepting only the server whose key is pinned in `channel/server.pub`.
+    pub fn connect(addr : &str) -> Result<ServerConnection> {
+        let keys = ChannelKeys::load(CLIENT_KEYS).map_err(|e| match e {
+            Error::Io(s) => Error::Io(format!("{}. Run gen-channel-keys first", s)),
+            e => e
+        })?;
+        let server_public = load_key(SERVER_PUBLIC_KEY)?;
+        let tcp_stream = TcpStream::connect(addr).map_err(|e| Error::Io(format!("Could not connect to {}: {}", addr, e)))?;
+        let stream = SecureStream::connect(tcp_stream, &keys, &server_public[..])?;
+        let mut conn = ServerConnection {
+            stream,
+            next_request_id : 0
+        };
+        match conn.request(&ServerCommand::Hello(PROTOCOL_VERSION))? {
+            ServerCommand::Hello(version) if version == PROTOCOL_VERSION => Ok(conn),
+            ServerCommand::Hello(version) => Err(Error::Protocol(format!("The server speaks protocol version {} but the client speaks version {}", version, PROTOCOL_VERSION))),
+            ServerCommand::CommandFailure(e) => Err(e),
+            _ => Err(Error::Protocol("The server did not answer the handshake".to_string()))
+        }
+    }
+
+    /// Sends one command and waits for the server's reply to it.
+    pub fn request(&mut self, message : &ServerCommand) -> Result<ServerCommand> {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        write_frame(&mut self.stream, request_id, message)?;
+        match read_frame(&mut self.stream)? {
+            Some(frame) => {
+                // The server answers in order, so anything else means the stream is out of sync
+                if frame.request_id != request_id {
+                    if let ServerCommand::CommandFailure(e) = frame.command {
+                        return Err(e);
+                    }
+                    return Err(Error::Protocol(format!("Expected the reply to request {} but got one for {}", request_id, frame.request_id)));
+                }
+                Ok(frame.command)
+            },
+            None => Err(Error::Protocol("The server closed the connection".to_string()))
+        }
+    }
+
+    pub fn shutdown(&self) -> Result<()> {
+        self.stream.shutdown()
+    }
+}
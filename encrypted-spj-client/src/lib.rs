@@ -0,0 +1,5 @@
+pub mod parse;
+pub mod connection;
+mod client;
+
+pub use client::EncryptedSpjClient;
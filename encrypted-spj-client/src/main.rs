@@ -1,18 +1,12 @@
-mod parse;
-
-use rustyline;
-use serde_json;
-use csv::Reader;
-use std::net::{Shutdown, TcpStream};
-use std::io::{ Write, BufRead, BufReader };
 use std::time::Instant;
-use common::token::{ ServerCommand, HybQuery };
-use common::db_structs::{ DB, Relation};
 use common::mm::MM;
 use common::emm::EMM;
-use common::ste::{ STEClient, STEServer };
-use common::leakage_query_planner::Statistics;
-use crate::parse::{ parse, parse_hyb, annotate_query, load_db_from_txt, annotate_from_txt };
+use common::db_structs::Relation;
+use common::error::Result;
+use common::secure_channel::ChannelKeys;
+use encrypted_spj_client::EncryptedSpjClient;
+use encrypted_spj_client::connection::CLIENT_KEYS;
+use encrypted_spj_client::parse::{ parse, parse_hyb };
 
 enum Command {
     Help,
@@ -23,6 +17,16 @@ enum Command {
     LoadCSV(String),
     LoadDB(String),
     SetupEDB,
+    InsertEDB(String, Vec<String>),
+    ModifyEDB(String),
+    SeedKeys(String),
+    GenChannelKeys,
+    SaveEDB(String),
+    LoadEDB(String),
+    DeleteEDB(String),
+    ListEDBs,
+    SaveKeys(String),
+    LoadKeys(String),
     SetupStatistics,
     EvalBandwidth(String),
     SearchEDB(String),
@@ -39,7 +43,7 @@ enum Command {
     PrintSchema,
     PrintSchemaEDB,
     ToggleFull,
-    CommandError(String)
+    Invalid(String)
 }
 
 fn check_num_args(first_word : &str, num_words : usize) -> Option<String> {
@@ -54,6 +58,8 @@ fn check_num_args(first_word : &str, num_words : usize) -> Option<String> {
         || first_word == "print-schema"
         || first_word == "print-schema-edb"
         || first_word == "toggle-full"
+        || first_word == "list-edbs"
+        || first_word == "gen-channel-keys"
         || first_word == "connect-server"
         || first_word == "disconnect-server"{
         if num_words != 1 {
@@ -65,27 +71,36 @@ fn check_num_args(first_word : &str, num_words : usize) -> Option<String> {
         || first_word == "load-db-txt"
         || first_word == "load-csv"
         || first_word == "annotate-db"
+        || first_word == "seed-keys"
+        || first_word == "save-edb"
+        || first_word == "load-edb"
+        || first_word == "delete-edb"
+        || first_word == "save-keys"
+        || first_word == "load-keys"
         || first_word == "search-mm" {
         if num_words != 2 {
             option = Some(String::from(first_word) + " should take one argument")
         }
-    } else if first_word == "add-mm" {
-        if num_words != 3 {
+    } else if first_word == "insert-edb" {
+        if num_words < 3 {
+            option = Some(String::from(first_word) + " should take a relation and the values of a row");
+        }
+    } else if first_word == "add-mm"
+        && num_words != 3 {
             option = Some(String::from(first_word) + " should take two arguments");
         }
-    }
     option
 }
 
 fn input_to_command(input: &str) -> Command {
     let words = input.split_whitespace();
     let words: Vec<&str> = words.collect();
-    if words.len() <= 0 {
-        Command::CommandError(String::from("Your command must include at least one word"))
+    if words.is_empty() {
+        Command::Invalid(String::from("Your command must include at least one word"))
     } else {
         let arg_check = check_num_args(words[0], words.len());
         if let Some(err) = arg_check {
-            Command::CommandError(String::from(err))
+            Command::Invalid(err)
         } else {
             if words[0] == "help" {
                 Command::Help
@@ -103,6 +118,24 @@ fn input_to_command(input: &str) -> Command {
                 Command::AnnotateDB(String::from(words[1]))
             } else if words[0] == "setup-edb" {
                 Command::SetupEDB
+            } else if words[0] == "insert-edb" {
+                Command::InsertEDB(String::from(words[1]), words[2..].iter().map(|word| String::from(*word)).collect())
+            } else if words[0] == "seed-keys" {
+                Command::SeedKeys(String::from(words[1]))
+            } else if words[0] == "save-edb" {
+                Command::SaveEDB(String::from(words[1]))
+            } else if words[0] == "load-edb" {
+                Command::LoadEDB(String::from(words[1]))
+            } else if words[0] == "delete-edb" {
+                Command::DeleteEDB(String::from(words[1]))
+            } else if words[0] == "gen-channel-keys" {
+                Command::GenChannelKeys
+            } else if words[0] == "list-edbs" {
+                Command::ListEDBs
+            } else if words[0] == "save-keys" {
+                Command::SaveKeys(String::from(words[1]))
+            } else if words[0] == "load-keys" {
+                Command::LoadKeys(String::from(words[1]))
             } else if words[0] == "setup-stats" {
                 Command::SetupStatistics
             } else if words[0] == "reset-mm" {
@@ -126,96 +159,44 @@ fn input_to_command(input: &str) -> Command {
             } else if words[0] == "parse" {
                 let mut words_parse = input.split_whitespace();
                 words_parse.next().unwrap();
-                let query = String::from(words_parse.collect::<Vec<&str>>().join(" "));
+                let query = words_parse.collect::<Vec<&str>>().join(" ");
                 Command::Parse(query)
             } else if words[0] == "parse-hyb" {
                 let mut words_parse = input.split_whitespace();
                 words_parse.next().unwrap();
-                let query = String::from(words_parse.collect::<Vec<&str>>().join(" "));
+                let query = words_parse.collect::<Vec<&str>>().join(" ");
                 Command::ParseHyb(query)
             } else if words[0] == "search-edb" {
                 let mut words_search = input.split_whitespace();
                 words_search.next().unwrap();
-                let query = String::from(words_search.collect::<Vec<&str>>().join(" "));
+                let query = words_search.collect::<Vec<&str>>().join(" ");
                 Command::SearchEDB(query)
+            } else if words[0] == "modify-edb" {
+                let mut words_modify = input.split_whitespace();
+                words_modify.next().unwrap();
+                let statement = words_modify.collect::<Vec<&str>>().join(" ");
+                Command::ModifyEDB(statement)
             } else if words[0] == "search-edb-hyb" {
                 let mut words_search = input.split_whitespace();
                 words_search.next().unwrap();
-                let query = String::from(words_search.collect::<Vec<&str>>().join(" "));
+                let query = words_search.collect::<Vec<&str>>().join(" ");
                 Command::SearchEDBHyb(query)
             } else if words[0] == "eval-stats" {
                 let mut words_search = input.split_whitespace();
                 words_search.next().unwrap();
-                let query = String::from(words_search.collect::<Vec<&str>>().join(" "));
+                let query = words_search.collect::<Vec<&str>>().join(" ");
                 Command::EvalBandwidth(query)
             } else if words[0] == "quit" {
                 Command::Quit
             } else {
-                Command::CommandError(String::from("That is not a recognized command"))
+                Command::Invalid(String::from("That is not a recognized command"))
             }
         }
     }
 }
 
 
-fn connect_server() -> Option<TcpStream>{
-    let host = String::from("127.0.0.1");
-    let port = String::from("4000");
-    
-    let mut bind_addr = host.clone();
-    bind_addr.push_str(":");
-    bind_addr.push_str(&port);
-
-    match TcpStream::connect(bind_addr) {
-        Ok(stream) => {
-            Some(stream)
-        },
-        Err(_) => {
-            None
-        }
-    }
-}
-
-fn load_rel_from_csv(filename : &String) -> Result<Relation, String>{
-    if let Ok(mut rdr) = Reader::from_path(format!("csvs/{}.csv", filename.to_string())){
-        let mut inserted_records = 0;
-        
-        // let mut cur_attribs = db.get_all_ats();
-        
-        let headers : &Vec<String> = &rdr.headers()
-            .unwrap()
-            .iter()
-            .map(|s| {
-                // let s = String::from(s);
-                // if cur_attribs.contains(&s){
-                    String::from(format!("{}.{}", filename, s))
-                // } else {
-                //     s
-                // }
-            })
-            .collect();
-        let mut rel = Relation::new_rel(headers.clone());
-        
-        
-        for result in rdr.records() {
-            match result {
-                Ok(rec) => {
-                    let row : Vec<String> = rec.iter().map(|s| String::from(s)).collect();
-                    rel.add_row(row);
-                    inserted_records = inserted_records + 1;
-                }
-                _ => {
-                    panic!("Could not read row from CSV");
-                }
-            }
-        }
-        println!("Loaded {} records from {}", inserted_records, filename);
-        Ok(rel)
-    } else {
-        // Ok(Relation::empty_rel())
-        Err("Could not read a file from that path".to_string())
-    }
-}
+const SERVER_ADDR : &str = "127.0.0.1:4000";
 
 fn main() {
     println!("Testing implementation SQL over encrypted data with structured encryption");
@@ -223,16 +204,12 @@ fn main() {
     
     let mut mm = MM::new_mm();
     let mut emm = EMM::new_emm();
-    let mut db = DB::new_db();
-    let mut edb_client = STEClient::new_ste_client();
-    let mut edb_server_local = STEServer::new_ste_server();
-    let mut stream : Option<TcpStream> = None;
-    let mut stats = Statistics::new();
+    let mut client = EncryptedSpjClient::new();
     
     let mut full = true;
     
     let mut rl = rustyline::Editor::<()>::new();
-    if let Err(_) = rl.load_history("command-line-history.txt"){
+    if rl.load_history("command-line-history.txt").is_err(){
         println!("No command line history");
     }
     
@@ -259,48 +236,130 @@ fn main() {
                 match cmd {
                     Command::Help => print_help(),
                     Command::HelpEMM => print_help_emm(),
-                    Command::SetupStatistics => stats = Statistics::from_database(&mut db),
-                    Command::LoadDB(filename) => db = load_db_from_txt(&filename),
+                    Command::SetupStatistics => client.setup_stats(),
+                    Command::LoadDB(filename) => {
+                        if let Err(e) = client.load_db_txt(&filename) {
+                            println!("Error loading the db: {}", e);
+                        }
+                    },
                     Command::LoadCSV(filename) => {
-                        match load_rel_from_csv(&filename){
-                            Ok(rel) => db.add_rel(&filename, rel),
+                        match client.load_csv(&filename){
+                            Ok(num_rows) => println!("Loaded {} records from {}", num_rows, filename),
                             Err(e) => println!("Error loading csv: {}", e)
                         }
                     },
-                    Command::AnnotateDB(filename) => annotate_from_txt(&filename, &mut db),
+                    Command::AnnotateDB(filename) => {
+                        if let Err(e) = client.annotate_from_txt(&filename) {
+                            println!("Error annotating the db: {}", e);
+                        }
+                    },
+                    Command::InsertEDB(id, values) => {
+                        match client.insert(&id, values) {
+                            Ok(_) => println!("Inserted a row into {}", id),
+                            Err(e) => println!("Error inserting into the edb: {}", e)
+                        }
+                    },
+                    Command::ModifyEDB(statement) => {
+                        match client.modify(&statement) {
+                            Ok(changed) => println!("Changed {} rows", changed),
+                            Err(e) => println!("Error changing the edb: {}", e)
+                        }
+                    },
                     Command::SetupEDB => {
-                        if let Some(ref mut stream_found) = stream {
-                            let start = Instant::now();
-                            let edb_server = edb_client.setup_ste(&mut db);
-                            let message = ServerCommand::Setup(edb_server);
-                            let message_bytes = &bincode::serialize(&message).unwrap()[..];
-                            let db_bytes = &bincode::serialize(&db).unwrap()[..];
-                            println!("The server must store {} bytes for this database", message_bytes.len());
-                            println!("The unencrypted database stores {} bytes", db_bytes.len());
-                            let mut message_json = serde_json::to_string(&message_bytes).unwrap();
-                            message_json.push_str("\n");
-                            match stream_found.write_all(&message_json.as_bytes()) {
-                                Ok(_) => {
-                                    let duration = start.elapsed();
-                                    println!("Setting up the edb took {:?}", duration);
-                                },
-                                Err(e) => println!("Error writing edb to server: {}", e),
-                            }
-                        } else {
-                            edb_server_local = edb_client.setup_ste(&mut db);
+                        let start = Instant::now();
+                        let db_bytes = bincode::serialize(client.db()).unwrap().len();
+                        match client.setup() {
+                            Ok(edb_bytes) => {
+                                if client.is_connected() {
+                                    println!("The server must store {} bytes for this database", edb_bytes);
+                                    println!("The unencrypted database stores {} bytes", db_bytes);
+                                    println!("Setting up the edb took {:?}", start.elapsed());
+                                }
+                            },
+                            Err(e) => println!("Error setting up the edb: {}", e)
+                        }
+                    },
+                    Command::SeedKeys(seed) => {
+                        match seed.parse::<u64>() {
+                            Ok(seed) => {
+                                client.seed_keys(seed);
+                                println!("Keys for the next setup-edb will be derived from seed {}. Only use this for benchmarks", seed);
+                            },
+                            Err(_) => println!("The seed must be a non-negative integer")
+                        }
+                    },
+                    Command::GenChannelKeys => {
+                        let saved = ChannelKeys::generate().and_then(|keys| {
+                            std::fs::create_dir_all("channel")?;
+                            keys.save(CLIENT_KEYS)
+                        });
+                        match saved {
+                            Ok(_) => println!("Saved client channel keys. Add {}.pub to the server's authorized clients", CLIENT_KEYS),
+                            Err(e) => println!("Error generating channel keys: {}", e)
+                        }
+                    },
+                    Command::SaveEDB(name) => print_outcome(client.save_edb(&name)),
+                    Command::LoadEDB(name) => print_outcome(client.load_edb(&name)),
+                    Command::DeleteEDB(name) => print_outcome(client.delete_edb(&name)),
+                    Command::ListEDBs => {
+                        match client.list_edbs() {
+                            Ok(names) => {
+                                if names.is_empty() {
+                                    println!("No edbs are stored on the server");
+                                } else {
+                                    println!("Edbs stored on the server:");
+                                    for name in names {
+                                        println!("    {}", name);
+                                    }
+                                }
+                            },
+                            Err(e) => println!("{}", e)
+                        }
+                    },
+                    Command::SaveKeys(path) => {
+                        match rpassword::prompt_password("Passphrase: ") {
+                            Ok(passphrase) => {
+                                match client.save_keys(&path, &passphrase) {
+                                    Ok(_) => println!("Saved the edb keys to {}", path),
+                                    Err(e) => println!("Error saving keys: {}", e)
+                                }
+                            },
+                            Err(e) => println!("Error reading passphrase: {}", e)
+                        }
+                    },
+                    Command::LoadKeys(path) => {
+                        match rpassword::prompt_password("Passphrase: ") {
+                            Ok(passphrase) => {
+                                match client.load_keys(&path, &passphrase) {
+                                    Ok(_) => println!("Loaded the edb keys from {}", path),
+                                    Err(e) => println!("Error loading keys: {}", e)
+                                }
+                            },
+                            Err(e) => println!("Error reading passphrase: {}", e)
                         }
                     },
                     Command::SearchEDB(query) => {
-                        let qry = annotate_query(parse(&query));
-                        search_edb(qry, &mut stream, &mut edb_client, &mut edb_server_local, full);
+                        let start = Instant::now();
+                        print_search(client.query(&query), start, full);
                     },
                     Command::SearchEDBHyb(query) => {
-                        let qry = parse_hyb(&query);
-                        search_edb(qry, &mut stream, &mut edb_client, &mut edb_server_local, full);
+                        let start = Instant::now();
+                        print_search(client.query_hyb(&query), start, full);
                     },
                     Command::EvalBandwidth(query) => {
-                        let qry = parse_hyb(&query);
-                        stats.estimate_query(qry);
+                        match client.estimate(&query) {
+                            Ok(estimate) => {
+                                println!("Estimated bandwidth: {} rows with a size of {} bytes will be sent from the server to the client", estimate.rows, estimate.bytes);
+                                println!("Estimated volumes leaked: {} volumes will be leaked to the server", estimate.volumes);
+                                for (attrib, rows) in &estimate.order_leakage {
+                                    println!("Estimated order leaked: the server will learn the order of {} rows of {}", rows, attrib);
+                                }
+                                if let Some(count) = estimate.count_leakage {
+                                    println!("Estimated count leaked: the server will count {} rows, though the count it sends back is encrypted", count);
+                                }
+                            },
+                            Err(e) => println!("{}", e)
+                        }
                     },
                     Command::ResetMM => mm = MM::new_mm(),
                     Command::AddMM(identifier, keyword) => {
@@ -315,89 +374,55 @@ fn main() {
                         let ast = parse_hyb(&query);
                         println!("{:?}", ast);
                     },
-                    Command::PrintSchema => db.print_schema(),
-                    Command::PrintSchemaEDB => edb_client.print_schema(),
+                    Command::PrintSchema => client.print_schema(),
+                    Command::PrintSchemaEDB => client.print_schema_edb(),
                     Command::ToggleFull => {
                         println!("Setting printing full relations to: {}", !full);
                         full = !full;
                     },
-                    Command::CommandError(err) => println!("{}", err),
+                    Command::Invalid(err) => println!("{}", err),
                     Command::SetupEMM => emm.setup_emm(&mut mm),
                     Command::SearchEMMRR(keyword) => emm.search_emm_rr(&keyword.into_bytes()),
                     Command::SearchEMMRH(keyword) => emm.search_emm_rh(&keyword.into_bytes()),
                     Command::ConnectServer => {
-                        stream = connect_server();
-                        if let None = stream { println!("Could not connect"); }
-                    },
-                    Command::DisconnectServer => {
-                        if let Some(ref stream_found) = stream {
-                            match stream_found.shutdown(Shutdown::Both) {
-                                Ok(_) => println!("Shutting down connection to server"),
-                                Err(e) => println!("Error disconnecting from server: {}", e)
-                            }
+                        if let Err(e) = client.connect(SERVER_ADDR) {
+                            println!("{}", e);
+                            println!("Could not connect");
                         }
                     },
+                    Command::DisconnectServer => disconnect(&mut client),
                     _ => println!("TODO"),
                 }
             }
         }
     }
-    if let Some(stream_found) = stream {
-        match stream_found.shutdown(Shutdown::Both) {
+    disconnect(&mut client);
+}
+
+fn disconnect(client : &mut EncryptedSpjClient){
+    if client.is_connected() {
+        match client.disconnect() {
             Ok(_) => println!("Shutting down connection to server"),
             Err(e) => println!("Error disconnecting from server: {}", e)
         }
     }
 }
 
+fn print_outcome(outcome : Result<String>){
+    match outcome {
+        Ok(s) => println!("{}", s),
+        Err(e) => println!("{}", e)
+    }
+}
 
-fn search_edb(qry : HybQuery,
-    stream : &mut Option<TcpStream>,
-    edb_client : &mut STEClient,
-    edb_server_local : &mut STEServer,
-    full : bool){
-    let start = Instant::now();
-    let tk_wrap = edb_client.tokenize_ste(qry);
-    if let Err(s) = &tk_wrap {
-        println!("Error: {}", s);
-    } else {
-        let tk = tk_wrap.unwrap();
-        if let Some(ref mut stream_found) = stream {
-            let message = ServerCommand::Search(tk);
-            let message_bytes = &bincode::serialize(&message).unwrap()[..];
-            let mut message_json = serde_json::to_string(&message_bytes).unwrap();
-            message_json.push_str("\n");
-            match stream_found.write_all(&message_json.as_bytes()) {
-                Ok(_) => { },
-                Err(e) => println!("Error writing edb to server: {}", e),
-            }
-            let mut data = String::new();
-            let mut buf_stream = BufReader::new(stream_found.try_clone().expect("Failed to clone stream"));
-            
-            if let Ok(_) = buf_stream.read_line(&mut data) {
-                let res : serde_json::Result<Vec<u8>>;
-                res = serde_json::from_str(&data);
-                match res{
-                    Ok(bincode_command) => {
-                        println!("The server sent back {} bytes", bincode_command.len());
-                        if let Ok(ServerCommand::SearchResponse(ciphertexts)) = &bincode::deserialize(&bincode_command[..]){
-                            let rel = edb_client.decrypt_ste(ciphertexts.to_vec());
-                            let duration = start.elapsed();
-                            println!("The search took: {:?}", duration);
-                            rel.print_rel(full);
-                        }
-                        if let Ok(ServerCommand::SearchFailure(e)) = &bincode::deserialize(&bincode_command[..]){
-                            println!("Search failure: {}", e);
-                        }
-                    },
-                    Err(e) => println!("Error getting from json: {}", e)
-                }
-            }
-        } else {
-            let ciphertexts = edb_server_local.eval_ste(tk);
-            let rel = edb_client.decrypt_ste(ciphertexts);
+fn print_search(result : Result<Relation>, start : Instant, full : bool){
+    match result {
+        Ok(rel) => {
+            let duration = start.elapsed();
+            println!("The search took: {:?}", duration);
             rel.print_rel(full);
-        }
+        },
+        Err(e) => println!("Error: {}", e)
     }
 }
 
@@ -406,15 +431,24 @@ fn print_help() {
     println!("Commands:");
     println!("    help                  - Prints the program\'s commands and instructions");
     println!("    help-emm              - Prints commands specific to testing the emm primitive");
-    println!("    connect-server        - Attempts to connect to an encrypted-spj server if one is running");
+    println!("    gen-channel-keys      - Generates the client key pair used to authenticate to the server");
+    println!("    connect-server        - Connects to the server pinned in channel/server.pub if one is running");
     println!("    disconnect-server     - Disconnects from the server if a connection has been made");
     println!("    load-db-txt path      - Loads a database from a file");
     println!("    load-csv path         - Loads a relation into the current database from a csv");
-    println!("    annotate-db path      - Loads all the annotations from a text file to the current database");
+    println!("    annotate-db path      - Loads all the annotations, primary keys, conjunctive, range, ORE, SUM and GROUP BY indexes from a text file to the current database");
     println!("    setup-stats           - Prepares client-side statistics for bandwidth/leakage estimation");
     println!("    eval-stats            - Evaluates a hybrid query for how many rows/bytes will be returned and how many volumes will be leaked");
-    println!("    load-edb filename     - Loads an encrypted database which is saved on the server under that filename");
+    println!("    save-edb name         - Stores the edb currently on the server under a name on the server's disk");
+    println!("    load-edb name         - Loads an encrypted database which is saved on the server under that name");
+    println!("    list-edbs             - Lists the encrypted databases saved on the server");
+    println!("    delete-edb name       - Deletes an encrypted database saved on the server");
     println!("    setup-edb             - Encrypts the currently loaded database");
+    println!("    insert-edb rel values - Adds a row to a relation of the edb set up in this session");
+    println!("    modify-edb statement  - Runs a DELETE or UPDATE statement on the edb set up in this session");
+    println!("    save-keys path        - Saves the edb keys, schema and annotations to a passphrase protected file");
+    println!("    load-keys path        - Loads edb keys saved by save-keys so an existing edb can be queried");
+    println!("    seed-keys seed        - Makes the next setup-edb use reproducible keys from a seed (benchmarks only)");
     println!("    search-edb query      - Queries the edb if one has been setup");
     println!("    search-edb-hyb query  - Queries the edb with an annotated query");
     println!("    parse query           - Parses a query into selection, projection, and joins");
@@ -1,8 +1,16 @@
 use std::fs;
 use std::str::SplitWhitespace;
+use csv::Reader;
 // use std::collections::HashSet;
-use common::token::{ SPJQuery, HybQuery, BoolQuery };
+use common::token::{ SPJQuery, HybQuery, BoolQuery, ModQuery };
 use common::db_structs::{ DB, Relation};
+use common::value::ValueType;
+use common::error::{ Error, Result };
+
+// The attribute sorted on, whether descending, and how many rows to keep
+type OrderClause = (String, bool, Option<usize>);
+// A relation as written in a database file: its name, attributes, declared types and rows
+type RawTable = (String, Vec<String>, Vec<Option<ValueType>>, Vec<Vec<String>>);
 
 pub fn annotate_query(qry : SPJQuery)-> HybQuery{
     match qry {
@@ -11,19 +19,24 @@ pub fn annotate_query(qry : SPJQuery)-> HybQuery{
             HybQuery::FPJoin(at1, at2, Box::new(annotate_query(*sub_query1)), Box::new(annotate_query(*sub_query2)))
         },
         SPJQuery::Project(cols, sub_query) => HybQuery::Project(cols, Box::new(annotate_query(*sub_query))),
-        SPJQuery::Id(s) => HybQuery::Id(s),
+        SPJQuery::Order(attrib, desc, limit, sub_query) => HybQuery::Order(attrib, desc, limit, Box::new(annotate_query(*sub_query))),
+        SPJQuery::Count(sub_query) => HybQuery::Count(Box::new(annotate_query(*sub_query))),
+        SPJQuery::Sum(attrib, avg, sub_query) => HybQuery::Sum(attrib, avg, Box::new(annotate_query(*sub_query))),
+        SPJQuery::Group(attrib, sub_query) => HybQuery::Group(attrib, Box::new(annotate_query(*sub_query))),
+        SPJQuery::Id(s, alias) => HybQuery::Id(s, alias),
         SPJQuery::BadQuery(s) => HybQuery::BadQuery(s)
     }
 }
 
-pub fn parse(query : &String)-> SPJQuery{
+pub fn parse(query : &str)-> SPJQuery{
     let clean_query = query.replace("=", " = ");
     let clean_query = clean_query.replace(",", ", ");
     let clean_query = clean_query.replace("\"", " \" ");
+    let clean_query = clean_query.replace("(", " ( ").replace(")", " ) ").replace("<", " < ").replace(">", " > ");
     let mut query_iter = clean_query.split_whitespace();
     let (ast, spoiler) = parse_helper(&mut query_iter);
-    if let None = query_iter.next(){
-        if let None = spoiler{
+    if query_iter.next().is_none(){
+        if spoiler.is_none(){
             ast
         } else {
             SPJQuery::BadQuery(String::from("Last word of query left dangling"))
@@ -33,9 +46,87 @@ pub fn parse(query : &String)-> SPJQuery{
     }
 }
 
+/// Parses `DELETE FROM rel [WHERE ...]` or `UPDATE rel SET a = v, ... [WHERE ...]`.
+pub fn parse_mod(query : &str)-> ModQuery{
+    let clean_query = query.replace("=", " = ");
+    let clean_query = clean_query.replace(",", ", ");
+    let clean_query = clean_query.replace("\"", " \" ");
+    let clean_query = clean_query.replace("(", " ( ").replace(")", " ) ").replace("<", " < ").replace(">", " > ");
+    let mut query_iter = clean_query.split_whitespace();
+    match query_iter.next().map(|w| w.to_uppercase()) {
+        Some(ref first_word) if first_word == "DELETE" => {
+            match (query_iter.next().map(|w| w.to_uppercase()), query_iter.next()) {
+                (Some(ref from_word), Some(id)) if from_word == "FROM" => {
+                    match parse_mod_where(&mut query_iter) {
+                        Ok(b_query) => ModQuery::Delete(String::from(id), b_query),
+                        Err(s) => ModQuery::BadMod(s)
+                    }
+                },
+                _ => ModQuery::BadMod(String::from("DELETE must be followed by FROM and a relation"))
+            }
+        },
+        Some(ref first_word) if first_word == "UPDATE" => {
+            let id = match (query_iter.next(), query_iter.next().map(|w| w.to_uppercase())) {
+                (Some(id), Some(ref set_word)) if set_word == "SET" => String::from(id),
+                _ => return ModQuery::BadMod(String::from("UPDATE must be followed by a relation and SET"))
+            };
+            let mut assignments = Vec::new();
+            loop {
+                let attrib = match (query_iter.next(), query_iter.next()) {
+                    (Some(attrib), Some("=")) => String::from(attrib),
+                    _ => return ModQuery::BadMod(String::from("SET must be followed by columns and their values, as in a = v, b = w"))
+                };
+                let mut more = false;
+                match parse_value(&mut query_iter) {
+                    Some(mut val) => {
+                        if val.ends_with(',') {
+                            val.pop();
+                            more = true;
+                        }
+                        assignments.push((attrib, val));
+                    },
+                    None => return ModQuery::BadMod(format!("Could not parse the value {} is set to", attrib))
+                }
+                if !more && query_iter.clone().next() == Some(",") {
+                    query_iter.next();
+                    more = true;
+                }
+                if !more {
+                    break;
+                }
+            }
+            match parse_mod_where(&mut query_iter) {
+                Ok(b_query) => ModQuery::Update(id, assignments, b_query),
+                Err(s) => ModQuery::BadMod(s)
+            }
+        },
+        _ => ModQuery::BadMod(String::from("A change must be a DELETE or an UPDATE statement"))
+    }
+}
+
+// The WHERE clause that ends a DELETE or UPDATE, if it has one
+fn parse_mod_where(query_iter : &mut SplitWhitespace)-> std::result::Result<Option<BoolQuery>, String>{
+    match query_iter.next() {
+        None => Ok(None),
+        Some(where_word) if where_word.to_uppercase() == "WHERE" => {
+            match parse_bool(query_iter) {
+                (BoolQuery::BadBool(s), _) => Err(s),
+                (b_query, None) => Ok(Some(b_query)),
+                (_, Some(_)) => Err(String::from("Last word of statement left dangling"))
+            }
+        },
+        Some(_) => Err(String::from("Only a WHERE clause may follow the relation that is changed"))
+    }
+}
+
 fn parse_helper(query_iter : &mut SplitWhitespace)-> (SPJQuery, Option<String>){
     let mut ast = SPJQuery::BadQuery(String::from("Empty query"));
     let mut whereable = false;
+    let mut orderable = false;
+    let mut aggregate : Option<(String, bool)> = None;
+    let mut counted = false;
+    let mut summed : Option<(String, bool)> = None;
+    let mut grouped : Option<String> = None;
     let mut spoiler : Option<String> = None;
     
     let mut skip_post_loop = false;
@@ -50,7 +141,27 @@ fn parse_helper(query_iter : &mut SplitWhitespace)-> (SPJQuery, Option<String>){
                 if let Some(project_word) = query_iter.next(){
                     if project_word == "*" && projection.is_empty(){
                         break;
-                    } else if project_word.chars().last().unwrap() == ',' {
+                    } else if (project_word.to_uppercase() == "MIN" || project_word.to_uppercase() == "MAX") && projection.is_empty(){
+                        // MIN and MAX are the first row of an ORDER BY ... LIMIT 1
+                        match (query_iter.next(), query_iter.next(), query_iter.next()) {
+                            (Some("("), Some(attrib), Some(")")) => aggregate = Some((String::from(attrib), project_word.to_uppercase() == "MAX")),
+                            _ => loop_error = true
+                        }
+                        break;
+                    } else if project_word.to_uppercase() == "COUNT" {
+                        // columns may only come before COUNT(*) when they are grouped on
+                        match (query_iter.next(), query_iter.next(), query_iter.next()) {
+                            (Some("("), Some("*"), Some(")")) => counted = true,
+                            _ => loop_error = true
+                        }
+                        break;
+                    } else if (project_word.to_uppercase() == "SUM" || project_word.to_uppercase() == "AVG") && projection.is_empty(){
+                        match (query_iter.next(), query_iter.next(), query_iter.next()) {
+                            (Some("("), Some(attrib), Some(")")) => summed = Some((String::from(attrib), project_word.to_uppercase() == "AVG")),
+                            _ => loop_error = true
+                        }
+                        break;
+                    } else if project_word.ends_with(',') {
                         let mut new_project_word = String::from(project_word);
                         new_project_word.pop();
                         projection.push(new_project_word);
@@ -64,7 +175,7 @@ fn parse_helper(query_iter : &mut SplitWhitespace)-> (SPJQuery, Option<String>){
                 }
             }
             if loop_error {
-                ast = SPJQuery::BadQuery(String::from("You must list columns for projection or *"));
+                ast = SPJQuery::BadQuery(String::from("You must list columns for projection, *, COUNT(*), or MIN, MAX, SUM or AVG of one attribute"));
             } else {
                 if let Some(from_word) = query_iter.next(){
                     if from_word.to_uppercase() == "FROM" {
@@ -81,6 +192,7 @@ fn parse_helper(query_iter : &mut SplitWhitespace)-> (SPJQuery, Option<String>){
                             }
                             ast = sub_query;
                             whereable = true;
+                        orderable = true;
                         }
                     } else {
                         ast = SPJQuery::BadQuery(String::from("FROM must come after the projected columns of SELECT"));
@@ -90,112 +202,395 @@ fn parse_helper(query_iter : &mut SplitWhitespace)-> (SPJQuery, Option<String>){
                 }
             }
         } else {
-            ast = SPJQuery::Id(String::from(first_word));
+            ast = match parse_alias(query_iter) {
+                Ok(alias) => SPJQuery::Id(String::from(first_word), alias),
+                Err(s) => SPJQuery::BadQuery(s)
+            };
         }
     }
-    while !skip_post_loop {
-        let mut next_word : Option<String> = None;
-        match spoiler{
-            Some(ref suffix_word) => next_word = Some(suffix_word.to_string()),
-            None => {
-                match query_iter.next(){
-                    Some(suffix_word) => next_word = Some(String::from(suffix_word)),
-                    None => {}
+    if !skip_post_loop {
+        loop {
+            let mut next_word : Option<String> = None;
+            match spoiler{
+                Some(ref suffix_word) => next_word = Some(suffix_word.to_string()),
+                None => {
+                    if let Some(suffix_word) = query_iter.next() { next_word = Some(String::from(suffix_word)) }
                 }
             }
-        }
-        if let Some(suffix_word) = next_word{
-            if suffix_word.to_uppercase() == "WHERE" && whereable{
-                whereable = false;
-                spoiler = None;
-                let b_query = parse_bool(query_iter);
-                if let BoolQuery::BadBool(s) = b_query{
-                    ast = SPJQuery::BadQuery(s);
-                    spoiler = None;
-                    break;
-                }
-                if will_apply_project {
-                    ast = SPJQuery::Project(projection_cols_save.clone(), Box::new(
-                        SPJQuery::Select(b_query, Box::new(ast))
-                    ));
-                } else {
-                    ast = SPJQuery::Select(b_query, Box::new(ast));
-                }
-            } else if suffix_word.to_uppercase() == "JOIN" {
-                let (sub_query, spoiler_inner) = parse_helper(query_iter);
-                if let SPJQuery::BadQuery(s) = sub_query{
-                    ast = SPJQuery::BadQuery(s);
+            if let Some(suffix_word) = next_word{
+                if suffix_word.to_uppercase() == "WHERE" && whereable{
+                    whereable = false;
+                    let (b_query, spoiler_inner) = parse_bool(query_iter);
+                    if let BoolQuery::BadBool(s) = b_query{
+                        ast = SPJQuery::BadQuery(s);
+                        spoiler = None;
+                        break;
+                    }
+                    spoiler = spoiler_inner;
+                    if will_apply_project {
+                        ast = SPJQuery::Project(projection_cols_save.clone(), Box::new(
+                            SPJQuery::Select(b_query, Box::new(ast))
+                        ));
+                    } else {
+                        ast = SPJQuery::Select(b_query, Box::new(ast));
+                    }
+                } else if suffix_word.to_uppercase() == "ORDER" && orderable {
+                    whereable = false;
+                    orderable = false;
+                    let (order, spoiler_inner) = parse_order(query_iter);
+                    let (attrib, desc, limit) = match order {
+                        Ok(order) if aggregate.is_none() && !counted && summed.is_none() => order,
+                        Ok(_) => {
+                            ast = SPJQuery::BadQuery(String::from("MIN, MAX, SUM, AVG and COUNT(*) cannot be combined with ORDER BY"));
+                            spoiler = None;
+                            break;
+                        },
+                        Err(s) => {
+                            ast = SPJQuery::BadQuery(s);
+                            spoiler = None;
+                            break;
+                        }
+                    };
+                    spoiler = spoiler_inner;
+                    // order before projecting so the column sorted on is still there
+                    ast = match ast {
+                        SPJQuery::Project(cols, sub_query) => SPJQuery::Project(cols, Box::new(SPJQuery::Order(attrib, desc, limit, sub_query))),
+                        ast if will_apply_project => SPJQuery::Project(projection_cols_save.clone(), Box::new(SPJQuery::Order(attrib, desc, limit, Box::new(ast)))),
+                        ast => SPJQuery::Order(attrib, desc, limit, Box::new(ast))
+                    };
+                } else if suffix_word.to_uppercase() == "GROUP" && orderable {
+                    whereable = false;
+                    orderable = false;
                     spoiler = None;
-                    break;
-                }
-                spoiler = spoiler_inner;
-                let mut on_word_opt : Option<String> = None;
-                match spoiler{
-                    Some(ref pos_on_word) => on_word_opt = Some(pos_on_word.to_string()),
-                    None => {
-                        match query_iter.next(){
-                            Some(pos_on_word) => on_word_opt = Some(String::from(pos_on_word)),
-                            None => {}
+                    match (query_iter.next().map(|w| w.to_uppercase()), query_iter.next()) {
+                        (Some(ref by_word), Some(attrib)) if by_word == "BY" => grouped = Some(String::from(attrib)),
+                        _ => {
+                            ast = SPJQuery::BadQuery(String::from("GROUP must be followed by BY and an attribute"));
+                            spoiler = None;
+                            break;
                         }
                     }
-                }
-                spoiler = None;
-                if let Some(on_word) = on_word_opt{
-                    if on_word.to_uppercase() == "ON" {
-                        let mut error_in_on = true;
-                        if let Some(attrib1) = query_iter.next(){
-                            if let Some(eq_word) = query_iter.next(){
-                                if eq_word == "=" {
-                                    if let Some(attrib2) = query_iter.next(){
-                                        error_in_on = false;
-                                        ast = SPJQuery::Join(
-                                            String::from(attrib1),
-                                            String::from(attrib2),
-                                            Box::new(ast),
-                                            Box::new(sub_query)
-                                        );
-                                    }
-                                }
-                            }
+                } else if suffix_word.to_uppercase() == "JOIN" {
+                    let (sub_query, spoiler_inner) = parse_helper(query_iter);
+                    if let SPJQuery::BadQuery(s) = sub_query{
+                        ast = SPJQuery::BadQuery(s);
+                        spoiler = None;
+                        break;
+                    }
+                    spoiler = spoiler_inner;
+                    let mut on_word_opt : Option<String> = None;
+                    match spoiler{
+                        Some(ref pos_on_word) => on_word_opt = Some(pos_on_word.to_string()),
+                        None => {
+                            if let Some(pos_on_word) = query_iter.next() { on_word_opt = Some(String::from(pos_on_word)) }
                         }
-                        if error_in_on {
-                            ast = SPJQuery::BadQuery(String::from("You must join on attrib1 = attrib2"));
+                    }
+                    spoiler = None;
+                    if let Some(on_word) = on_word_opt{
+                        if on_word.to_uppercase() == "ON" {
+                            match parse_on(query_iter) {
+                                Ok((ats1, ats2)) => {
+                                    ast = SPJQuery::Join(
+                                        ats1,
+                                        ats2,
+                                        Box::new(ast),
+                                        Box::new(sub_query)
+                                    );
+                                },
+                                Err(s) => ast = SPJQuery::BadQuery(s)
+                            }
+                        } else {
+                            ast = SPJQuery::BadQuery(String::from("A word other than ON followed a JOIN. Remember, no natural joins"));
                         }
                     } else {
-                        ast = SPJQuery::BadQuery(String::from("A word other than ON followed a JOIN. Remember, no natural joins"));
+                        ast = SPJQuery::BadQuery(String::from("You must specify what attributes to join on. Sorry - no natural joins"));
                     }
                 } else {
-                    ast = SPJQuery::BadQuery(String::from("You must specify what attributes to join on. Sorry - no natural joins"));
+                    spoiler = Some(suffix_word);
+                    break;
                 }
             } else {
-                spoiler = Some(String::from(suffix_word));
                 break;
             }
+        }
+    }
+    if let Some((attrib, desc)) = aggregate {
+        if let SPJQuery::BadQuery(_) = ast {} else {
+            ast = SPJQuery::Project(vec!(attrib.to_string()), Box::new(SPJQuery::Order(attrib, desc, Some(1), Box::new(ast))));
+        }
+    }
+    if let Some(attrib) = grouped {
+        // the counts are precomputed over whole relations and annotated joins, so nothing may come between
+        ast = match ast {
+            SPJQuery::BadQuery(s) => SPJQuery::BadQuery(s),
+            _ if !counted => SPJQuery::BadQuery(String::from("GROUP BY can only be used with COUNT(*)")),
+            _ if projection_cols_save.iter().any(|col| *col != attrib) => SPJQuery::BadQuery(String::from("Only the grouped attribute can be selected next to COUNT(*)")),
+            ast if is_groupable(&ast) => SPJQuery::Group(attrib, Box::new(ast)),
+            _ => SPJQuery::BadQuery(String::from(NOT_GROUPABLE))
+        };
+    } else if counted {
+        if let SPJQuery::BadQuery(_) = ast {} else if !projection_cols_save.is_empty() {
+            ast = SPJQuery::BadQuery(String::from("Columns can only be selected next to COUNT(*) with GROUP BY"));
         } else {
-            break;
+            ast = SPJQuery::Count(Box::new(ast));
+        }
+    }
+    if let Some((attrib, avg)) = summed {
+        if let SPJQuery::BadQuery(_) = ast {} else {
+            ast = SPJQuery::Sum(attrib, avg, Box::new(ast));
         }
     }
     (ast, spoiler)
 }
 
-fn parse_bool(query_iter : &mut SplitWhitespace)-> BoolQuery{
-    if let Some(attrib) = query_iter.next(){
-        if let Some(eq_word) = query_iter.next(){
+const NOT_GROUPABLE : &str = "GROUP BY reads precomputed counts, so it can only be over one relation or one join of two relations, without WHERE";
+
+// Whether a query has group counts precomputed for it: a relation, or two
+// relations joined
+fn is_groupable(qry : &SPJQuery) -> bool {
+    match qry {
+        SPJQuery::Id(_, _) => true,
+        SPJQuery::Join(_, _, sub_query1, sub_query2) => matches!((&**sub_query1, &**sub_query2), (SPJQuery::Id(_, _), SPJQuery::Id(_, _))),
+        _ => false
+    }
+}
+
+fn is_groupable_hyb(qry : &HybQuery) -> bool {
+    match qry {
+        HybQuery::Id(_, _) => true,
+        HybQuery::FPJoin(_, _, sub_query1, sub_query2) | HybQuery::PPJoin(_, _, sub_query1, sub_query2) => {
+            matches!((&**sub_query1, &**sub_query2), (HybQuery::Id(_, _), HybQuery::Id(_, _)))
+        },
+        _ => false
+    }
+}
+
+// Parses the equalities after ON, `attrib1 = attrib2` or several of them joined
+// by AND for a composite join, into the attributes of each side
+fn parse_on(query_iter : &mut SplitWhitespace)-> std::result::Result<(Vec<String>, Vec<String>), String>{
+    let mut ats1 = Vec::new();
+    let mut ats2 = Vec::new();
+    loop {
+        match (query_iter.next(), query_iter.next(), query_iter.next()) {
+            (Some(attrib1), Some("="), Some(attrib2)) => {
+                ats1.push(String::from(attrib1));
+                ats2.push(String::from(attrib2));
+            },
+            _ => return Err(String::from("You must join on attrib1 = attrib2, or on several of these joined by AND"))
+        }
+        match query_iter.clone().next() {
+            Some(and_word) if and_word.to_uppercase() == "AND" => {
+                query_iter.next();
+            },
+            _ => return Ok((ats1, ats2))
+        }
+    }
+}
+
+// Parses the alias a relation may be given, either after AS or as the next word
+// when that word does not start another clause
+fn parse_alias(query_iter : &mut SplitWhitespace)-> std::result::Result<Option<String>, String>{
+    let alias = match query_iter.clone().next() {
+        Some(as_word) if as_word.to_uppercase() == "AS" => {
+            query_iter.next();
+            match query_iter.next() {
+                Some(alias) => alias,
+                None => return Err(String::from("AS must be followed by an alias"))
+            }
+        },
+        Some(word) if !["WHERE", "ORDER", "GROUP", "JOIN", "JOINF", "JOINP", "ON"].contains(&word.to_uppercase().as_str()) => {
+            query_iter.next();
+            word
+        },
+        _ => return Ok(None)
+    };
+    if alias.contains('.') || ["WHERE", "ORDER", "GROUP", "JOIN", "JOINF", "JOINP", "ON"].contains(&alias.to_uppercase().as_str()) {
+        Err(format!("{} cannot be used as an alias", alias))
+    } else {
+        Ok(Some(String::from(alias)))
+    }
+}
+
+// Parses what follows ORDER: BY attrib, then optionally ASC or DESC, then optionally LIMIT k
+fn parse_order(query_iter : &mut SplitWhitespace)-> (std::result::Result<OrderClause, String>, Option<String>){
+    if query_iter.next().map(|w| w.to_uppercase()) != Some(String::from("BY")) {
+        return (Err(String::from("ORDER must be followed by BY")), None);
+    }
+    let attrib = match query_iter.next() {
+        Some(attrib) => String::from(attrib),
+        None => return (Err(String::from("ORDER BY must be followed by an attribute")), None)
+    };
+    let mut desc = false;
+    let mut next_word = query_iter.next();
+    if let Some(dir_word) = next_word {
+        if dir_word.to_uppercase() == "ASC" || dir_word.to_uppercase() == "DESC" {
+            desc = dir_word.to_uppercase() == "DESC";
+            next_word = query_iter.next();
+        }
+    }
+    let mut limit = None;
+    if let Some(limit_word) = next_word {
+        if limit_word.to_uppercase() == "LIMIT" {
+            match query_iter.next().and_then(|num| num.parse::<usize>().ok()) {
+                Some(num) => limit = Some(num),
+                None => return (Err(String::from("LIMIT must be followed by a number of rows")), None)
+            }
+            next_word = query_iter.next();
+        }
+    }
+    (Ok((attrib, desc, limit)), next_word.map(String::from))
+}
+
+// OR binds loosest, then AND, then NOT. Parentheses group as usual
+fn parse_bool(query_iter : &mut SplitWhitespace)-> (BoolQuery, Option<String>){
+    let (b_query, spoiler) = parse_conj(query_iter);
+    if let BoolQuery::BadBool(_) = b_query{
+        return (b_query, None);
+    }
+    match spoiler{
+        Some(next_word) if next_word.to_uppercase() == "OR" => {
+            match parse_bool(query_iter) {
+                (BoolQuery::BadBool(s), _) => (BoolQuery::BadBool(s), None),
+                (rest, spoiler) => (BoolQuery::Or(Box::new(b_query), Box::new(rest)), spoiler)
+            }
+        },
+        spoiler => (b_query, spoiler)
+    }
+}
+
+fn parse_conj(query_iter : &mut SplitWhitespace)-> (BoolQuery, Option<String>){
+    let (b_query, spoiler) = parse_atom(query_iter);
+    if let BoolQuery::BadBool(_) = b_query{
+        return (b_query, None);
+    }
+    match spoiler{
+        Some(next_word) if next_word.to_uppercase() == "AND" => {
+            match parse_conj(query_iter) {
+                (BoolQuery::BadBool(s), _) => (BoolQuery::BadBool(s), None),
+                (rest, spoiler) => (BoolQuery::And(Box::new(b_query), Box::new(rest)), spoiler)
+            }
+        },
+        spoiler => (b_query, spoiler)
+    }
+}
+
+fn parse_atom(query_iter : &mut SplitWhitespace)-> (BoolQuery, Option<String>){
+    let b_query = if let Some(attrib) = query_iter.next(){
+        if attrib.to_uppercase() == "NOT" {
+            return match parse_atom(query_iter) {
+                (BoolQuery::BadBool(s), _) => (BoolQuery::BadBool(s), None),
+                (sub, spoiler) => (BoolQuery::Not(Box::new(sub)), spoiler)
+            };
+        } else if attrib == "(" {
+            match parse_bool(query_iter) {
+                (BoolQuery::BadBool(s), _) => BoolQuery::BadBool(s),
+                (sub, Some(ref close_word)) if close_word == ")" => sub,
+                _ => BoolQuery::BadBool(String::from("A \"(\" in a selection predicate was never closed"))
+            }
+        } else if let Some(eq_word) = query_iter.next(){
             if eq_word == "=" {
                 if let Some(val) = parse_value(query_iter){
-                    BoolQuery::Eq(String::from(attrib), String::from(val))
+                    BoolQuery::Eq(String::from(attrib), val)
                 } else{
-                    BoolQuery::BadBool(String::from(format!("Could not parse the value that {} is supposed to equal", attrib)))
+                    BoolQuery::BadBool(format!("Could not parse the value that {} is supposed to equal", attrib))
+                }
+            } else if eq_word == "<" || eq_word == ">" {
+                // "<=" and ">=" arrive split in two since "=" is spaced out
+                let mut peek_iter = query_iter.clone();
+                let inclusive = peek_iter.next() == Some("=");
+                if inclusive {
+                    query_iter.next();
+                }
+                if let Some(val) = parse_value(query_iter){
+                    if eq_word == "<" {
+                        BoolQuery::Range(String::from(attrib), None, Some((val, inclusive)))
+                    } else {
+                        BoolQuery::Range(String::from(attrib), Some((val, inclusive)), None)
+                    }
+                } else {
+                    BoolQuery::BadBool(format!("Could not parse the value that {} is compared to", attrib))
+                }
+            } else if eq_word.to_uppercase() == "IN" {
+                parse_in_list(attrib, query_iter)
+            } else if eq_word.to_uppercase() == "BETWEEN" {
+                parse_between(attrib, query_iter)
+            } else if eq_word.to_uppercase() == "IS" {
+                let negated = {
+                    let mut peek_iter = query_iter.clone();
+                    peek_iter.next().map(|w| w.to_uppercase()) == Some(String::from("NOT"))
+                };
+                if negated {
+                    query_iter.next();
+                }
+                match query_iter.next().map(|w| w.to_uppercase()) {
+                    Some(ref null_word) if null_word == "NULL" && negated => BoolQuery::Not(Box::new(BoolQuery::IsNull(String::from(attrib)))),
+                    Some(ref null_word) if null_word == "NULL" => BoolQuery::IsNull(String::from(attrib)),
+                    _ => BoolQuery::BadBool(format!("{} IS must be followed by NULL or NOT NULL", attrib))
+                }
+            } else if eq_word.to_uppercase() == "NOT" {
+                let sub = match query_iter.next().map(|w| w.to_uppercase()) {
+                    Some(ref op_word) if op_word == "IN" => parse_in_list(attrib, query_iter),
+                    Some(ref op_word) if op_word == "BETWEEN" => parse_between(attrib, query_iter),
+                    _ => BoolQuery::BadBool(String::from("NOT after an attribute must be followed by IN or BETWEEN"))
+                };
+                match sub {
+                    BoolQuery::BadBool(s) => BoolQuery::BadBool(s),
+                    sub => BoolQuery::Not(Box::new(sub))
                 }
             } else {
-                BoolQuery::BadBool(String::from("Error in parsing a select condition. \"=\" must come after an attribute in a selection predicate"))
+                BoolQuery::BadBool(String::from("Error in parsing a select condition. \"=\", a comparison, IN, BETWEEN or IS must come after an attribute in a selection predicate"))
             }
         } else {
-            BoolQuery::BadBool(String::from("Error in parsing a select condition. \"=\" must come after an attribute in a selection predicate"))
+            BoolQuery::BadBool(String::from("Error in parsing a select condition. \"=\", a comparison, IN, BETWEEN or IS must come after an attribute in a selection predicate"))
         }
     } else {
         BoolQuery::BadBool(String::from("WHERE must be followed by a selection predicate"))
+    };
+    if let BoolQuery::BadBool(_) = b_query{
+        return (b_query, None);
     }
+    (b_query, query_iter.next().map(String::from))
+}
+
+fn parse_between(attrib : &str, query_iter : &mut SplitWhitespace)-> BoolQuery{
+    let lower = parse_value(query_iter);
+    let and_word = query_iter.next().map(|w| w.to_uppercase());
+    let upper = parse_value(query_iter);
+    match (lower, and_word, upper) {
+        (Some(lower), Some(ref and_word), Some(upper)) if and_word == "AND" => {
+            BoolQuery::Range(String::from(attrib), Some((lower, true)), Some((upper, true)))
+        },
+        _ => BoolQuery::BadBool(format!("{} BETWEEN must be followed by low AND high", attrib))
+    }
+}
+
+fn parse_in_list(attrib : &str, query_iter : &mut SplitWhitespace)-> BoolQuery{
+    if query_iter.next() != Some("(") {
+        return BoolQuery::BadBool(format!("IN must be followed by a parenthesized list of the values {} may equal", attrib));
+    }
+    let mut vals = Vec::new();
+    loop {
+        match parse_value(query_iter) {
+            Some(ref val) if val == ")" && vals.is_empty() => {
+                return BoolQuery::BadBool(format!("The IN list for {} is empty", attrib));
+            },
+            Some(mut val) => {
+                if val.ends_with(',') {
+                    val.pop();
+                    vals.push(val);
+                    continue;
+                }
+                vals.push(val);
+            },
+            None => return BoolQuery::BadBool(format!("Could not parse the values that {} may equal", attrib))
+        }
+        match query_iter.next() {
+            Some(",") => {},
+            Some(")") => break,
+            _ => return BoolQuery::BadBool(format!("The IN list for {} must be closed with \")\"", attrib))
+        }
+    }
+    BoolQuery::In(String::from(attrib), vals)
 }
 
 fn parse_value(query_iter : &mut SplitWhitespace)-> Option<String>{
@@ -210,14 +605,14 @@ fn parse_value(query_iter : &mut SplitWhitespace)-> Option<String>{
                         break
                     }
                     res.push_str(next_word);
-                    res.push_str(" ");
+                    res.push(' ');
                 } else {
                     good_string = false;
                     break;
                 }
             }
             if good_string {
-                if res.len() >= 1{
+                if !res.is_empty(){
                     res = res[0..(res.len() - 1)].to_string();
                 }
                 val = Some(res);
@@ -231,88 +626,219 @@ fn parse_value(query_iter : &mut SplitWhitespace)-> Option<String>{
 }
 
 
-pub fn load_db_from_txt(filename : &String) -> DB{
+pub fn load_db_from_txt(filename : &String) -> Result<DB>{
     let mut db = DB::new_db();
-    if let Ok(contents) = fs::read_to_string(format!("txts/{}.txt", filename)){
-        let lines = contents.lines();
-        for line in lines{
-            let mut words = line.split_whitespace();
-            if let Some(first_word) = words.next(){
-                if first_word == "CREATE"{
-                    if let Some(second_word) = words.next(){
-                        if second_word == "TABLE"{
-                            if let Some(rel_id) = words.next(){
-                                let ats : Vec<String> = words.map(|s| String::from(s)).collect();
-                                let rel = Relation::new_rel(ats);
-                                db.add_rel(&String::from(rel_id), rel);
-                            } else {
-                                println!("You need to supply a name for the table");
+    let contents = fs::read_to_string(format!("txts/{}.txt", filename))
+        .map_err(|e| Error::Io(format!("Could not read txts/{}.txt: {}", filename, e)))?;
+    // rows are kept as written until the whole file is read, so a column with
+    // no declared type can be inferred from all of its values
+    let mut tables : Vec<RawTable> = Vec::new();
+    let mut keys : Vec<(String, Vec<String>)> = Vec::new();
+    for (line_num, line) in contents.lines().enumerate(){
+        let mut words = line.split_whitespace();
+        let line_error = |msg : &str| Error::Parse(format!("{}.txt line {}: {}", filename, line_num + 1, msg));
+        if let Some(first_word) = words.next(){
+            if first_word == "CREATE"{
+                if words.next() != Some("TABLE"){
+                    return Err(line_error("Expected TABLE"));
+                }
+                if let Some(rel_id) = words.next(){
+                    let mut ats = Vec::new();
+                    let mut declared = Vec::new();
+                    while let Some(word) = words.next() {
+                        if word == "PRIMARY" {
+                            if words.next() != Some("KEY"){
+                                return Err(line_error("Expected KEY after PRIMARY"));
                             }
-                        } else {
-                            println!("Expected TABLE");
+                            keys.retain(|(id, _)| id != rel_id);
+                            keys.push((String::from(rel_id), words.by_ref().map(String::from).collect()));
+                            break;
                         }
-                    } else {
-                        println!("Expected TABLE");
-                    }
-                } else if first_word == "INSERT" {
-                    if let Some(second_word) = words.next(){
-                        if second_word == "INTO"{
-                            if let Some(rel_id) = words.next(){
-                                let row : Vec<String> = words.map(|s| String::from(s)).collect();
-                                if let Some(rel) = db.get_rel_mut(&String::from(rel_id)){
-                                    rel.add_row(row);
-                                } else {
-                                    println!("Could not find table with that id in the database");
-                                }
-                            } else {
-                                println!("You need to supply a name for the table");
+                        match word.split_once(':') {
+                            Some((at, ty_name)) => match ValueType::from_name(ty_name) {
+                                Some(ty) => {
+                                    ats.push(String::from(at));
+                                    declared.push(Some(ty));
+                                },
+                                None => return Err(line_error(&format!("{} is not a column type", ty_name)))
+                            },
+                            None => {
+                                ats.push(String::from(word));
+                                declared.push(None);
                             }
-                        } else {
-                            println!("Expected INTO");
                         }
-                    }else {
-                        println!("Expected INTO");
                     }
-                } else if first_word == "ANNOTATE" {
-                    if let Some(attrib1) = words.next(){
-                        if let Some(attrib2) = words.next(){
-                            &db.add_annotation(&String::from(attrib1), &String::from(attrib2));
+                    if ats.is_empty() {
+                        return Err(line_error("A table needs at least one column"));
+                    }
+                    tables.retain(|(id, _, _, _)| id != rel_id);
+                    tables.push((String::from(rel_id), ats, declared, Vec::new()));
+                } else {
+                    return Err(line_error("You need to supply a name for the table"));
+                }
+            } else if first_word == "INSERT" {
+                if words.next() != Some("INTO"){
+                    return Err(line_error("Expected INTO"));
+                }
+                if let Some(rel_id) = words.next(){
+                    let row : Vec<String> = words.map(String::from).collect();
+                    if let Some((_, ats, _, rows)) = tables.iter_mut().find(|(id, _, _, _)| id == rel_id){
+                        if row.len() != ats.len() {
+                            return Err(Error::Schema(format!("{}.txt line {}: A row with {} values doesn\'t fit into {} which has {} columns", filename, line_num + 1, row.len(), rel_id, ats.len())));
                         }
+                        rows.push(row);
+                    } else {
+                        return Err(Error::Schema(format!("{}.txt line {}: Could not find table {} in the database", filename, line_num + 1, rel_id)));
                     }
                 } else {
-                    println!("Unrecognized command on this line");
+                    return Err(line_error("You need to supply a name for the table"));
+                }
+            } else if first_word == "PRIMARY" {
+                if words.next() != Some("KEY"){
+                    return Err(line_error("Expected KEY"));
+                }
+                match words.next() {
+                    Some(rel_id) => {
+                        keys.retain(|(id, _)| id != rel_id);
+                        keys.push((String::from(rel_id), words.map(String::from).collect()));
+                    },
+                    None => return Err(line_error("PRIMARY KEY needs a table and its key columns"))
+                }
+            } else if first_word == "ANNOTATE" {
+                match parse_annotation(&mut words) {
+                    Ok((ats1, ats2)) => db.add_annotation(&ats1, &ats2),
+                    Err(s) => return Err(line_error(&s))
+                }
+            } else if first_word == "CONJUNCT" {
+                match (words.next(), words.next()) {
+                    (Some(attrib1), Some(attrib2)) => db.add_conjunction(&String::from(attrib1), &String::from(attrib2)),
+                    _ => return Err(line_error("CONJUNCT needs two attributes"))
+                }
+            } else if first_word == "ORDERED" {
+                match words.next() {
+                    Some(attrib) => db.add_ordered(&String::from(attrib)),
+                    None => return Err(line_error("ORDERED needs an attribute"))
+                }
+            } else if first_word == "ORE" {
+                match words.next() {
+                    Some(attrib) => db.add_ore(&String::from(attrib)),
+                    None => return Err(line_error("ORE needs an attribute"))
                 }
+            } else if first_word == "SUMMABLE" {
+                match words.next() {
+                    Some(attrib) => db.add_summable(&String::from(attrib)),
+                    None => return Err(line_error("SUMMABLE needs an attribute"))
+                }
+            } else if first_word == "GROUPED" {
+                match words.next() {
+                    Some(attrib) => db.add_grouped(&String::from(attrib)),
+                    None => return Err(line_error("GROUPED needs an attribute"))
+                }
+            } else {
+                return Err(line_error("Unrecognized command on this line"));
             }
         }
-        db
-    } else {
-        println!("Something went wrong while loading the db. Reverting to an empty database");
-        db
     }
+    for (rel_id, ats, declared, rows) in tables {
+        let mut rel = Relation::from_strings(ats, declared, rows)?;
+        match keys.iter().find(|(id, _)| *id == rel_id) {
+            Some((_, key_ats)) => rel.set_key(key_ats)?,
+            None => rel.default_key(&rel_id)
+        }
+        db.add_rel(&rel_id, rel);
+    }
+    if let Some((rel_id, _)) = keys.iter().find(|(id, _)| db.get_rel(id).is_none()) {
+        return Err(Error::Schema(format!("{}.txt: Could not find table {} to give it a primary key", filename, rel_id)));
+    }
+    Ok(db)
 }
 
-pub fn annotate_from_txt(filename : &String, db : &mut DB){
-    if let Ok(contents) = fs::read_to_string(format!("txts/{}.txt", filename)){
-        let lines = contents.lines();
-        for line in lines{
-            let mut words = line.split_whitespace();
-            if let Some(first_word) = words.next(){
-                if first_word == "ANNOTATE" {
-                    if let Some(attrib1) = words.next(){
-                        if let Some(attrib2) = words.next(){
-                            db.add_annotation(&String::from(attrib1), &String::from(attrib2));
-                        }
-                    }
-                } else {
-                    println!("Unrecognized command on this line");
+pub fn load_rel_from_csv(filename : &String) -> Result<Relation>{
+    let mut rdr = Reader::from_path(format!("csvs/{}.csv", filename))
+        .map_err(|e| Error::Io(format!("Could not read csvs/{}.csv: {}", filename, e)))?;
+    let headers : Vec<String> = match rdr.headers() {
+        Ok(headers) => headers.iter().map(|s| format!("{}.{}", filename, s)).collect(),
+        Err(e) => return Err(Error::Parse(format!("Could not read the header of the csv: {}", e)))
+    };
+    let mut rows = Vec::new();
+    
+    for result in rdr.records() {
+        match result {
+            Ok(rec) => {
+                let row : Vec<String> = rec.iter().map(String::from).collect();
+                rows.push(row);
+            }
+            Err(e) => {
+                return Err(Error::Parse(format!("Could not read row from CSV: {}", e)));
+            }
+        }
+    }
+    // csvs carry no types, so every column is inferred from its values
+    let declared = vec![None; headers.len()];
+    let mut rel = Relation::from_strings(headers, declared, rows)?;
+    rel.default_key(filename);
+    Ok(rel)
+}
+
+// Parses the pairs of attributes of an ANNOTATE line, `at1 at2` for one pair and
+// `at1 at2 AND at3 at4 ...` for a composite join, into the attributes of each side
+fn parse_annotation(words : &mut SplitWhitespace)-> std::result::Result<(Vec<String>, Vec<String>), String>{
+    let mut ats1 = Vec::new();
+    let mut ats2 = Vec::new();
+    loop {
+        match (words.next(), words.next()) {
+            (Some(attrib1), Some(attrib2)) => {
+                ats1.push(String::from(attrib1));
+                ats2.push(String::from(attrib2));
+            },
+            _ => return Err(String::from("ANNOTATE needs two attributes, or pairs of them joined by AND"))
+        }
+        match words.next() {
+            Some(and_word) if and_word.to_uppercase() == "AND" => (),
+            Some(_) => return Err(String::from("The pairs of attributes of ANNOTATE must be joined by AND")),
+            None => return Ok((ats1, ats2))
+        }
+    }
+}
+
+pub fn annotate_from_txt(filename : &String, db : &mut DB) -> Result<()>{
+    let contents = fs::read_to_string(format!("txts/{}.txt", filename))
+        .map_err(|e| Error::Io(format!("Could not read txts/{}.txt so could not annotate: {}", filename, e)))?;
+    for (line_num, line) in contents.lines().enumerate(){
+        let mut words = line.split_whitespace();
+        if let Some(first_word) = words.next(){
+            if first_word == "PRIMARY" {
+                if words.next() != Some("KEY"){
+                    return Err(Error::Parse(format!("{}.txt line {}: Expected KEY", filename, line_num + 1)));
                 }
-            } else {
-                println!("Unrecognized command on this line");
+                match words.next() {
+                    Some(rel_id) => db.set_primary_key(&String::from(rel_id), &words.map(String::from).collect())?,
+                    None => return Err(Error::Parse(format!("{}.txt line {}: PRIMARY KEY needs a table and its key columns", filename, line_num + 1)))
+                }
+                continue;
+            }
+            if first_word != "ANNOTATE" && first_word != "CONJUNCT" && first_word != "ORDERED" && first_word != "ORE" && first_word != "SUMMABLE" && first_word != "GROUPED" {
+                return Err(Error::Parse(format!("{}.txt line {}: Unrecognized command on this line", filename, line_num + 1)));
+            }
+            if first_word == "ANNOTATE" {
+                match parse_annotation(&mut words) {
+                    Ok((ats1, ats2)) => db.add_annotation(&ats1, &ats2),
+                    Err(s) => return Err(Error::Parse(format!("{}.txt line {}: {}", filename, line_num + 1, s)))
+                }
+                continue;
+            }
+            match (words.next(), words.next()) {
+                (Some(attrib), None) if first_word == "ORDERED" => db.add_ordered(&String::from(attrib)),
+                (Some(attrib), None) if first_word == "ORE" => db.add_ore(&String::from(attrib)),
+                (Some(attrib), None) if first_word == "SUMMABLE" => db.add_summable(&String::from(attrib)),
+                (Some(attrib), None) if first_word == "GROUPED" => db.add_grouped(&String::from(attrib)),
+                _ if first_word == "ORDERED" || first_word == "ORE" || first_word == "SUMMABLE" || first_word == "GROUPED" => return Err(Error::Parse(format!("{}.txt line {}: {} needs an attribute", filename, line_num + 1, first_word))),
+                (Some(attrib1), Some(attrib2)) => db.add_conjunction(&String::from(attrib1), &String::from(attrib2)),
+                _ => return Err(Error::Parse(format!("{}.txt line {}: {} needs two attributes", filename, line_num + 1, first_word)))
             }
         }
-    } else {
-        println!("Could not find that file so could not annotate");
     }
+    Ok(())
 }
 
 
@@ -333,13 +859,15 @@ pub fn annotate_from_txt(filename : &String, db : &mut DB){
 
 // There are way better ways to do this than just copying and pasting, but this is the lazy solution for now
 
-pub fn parse_hyb(query : &String)-> HybQuery{
+pub fn parse_hyb(query : &str)-> HybQuery{
     let clean_query = query.replace("=", " = ");
     let clean_query = clean_query.replace(",", ", ");
+    let clean_query = clean_query.replace("\"", " \" ");
+    let clean_query = clean_query.replace("(", " ( ").replace(")", " ) ").replace("<", " < ").replace(">", " > ");
     let mut query_iter = clean_query.split_whitespace();
     let (ast, spoiler) = parse_helper_hyb(&mut query_iter);
-    if let None = query_iter.next(){
-        if let None = spoiler{
+    if query_iter.next().is_none(){
+        if spoiler.is_none(){
             ast
         } else {
             HybQuery::BadQuery(String::from("Last word of query left dangling"))
@@ -352,6 +880,11 @@ pub fn parse_hyb(query : &String)-> HybQuery{
 fn parse_helper_hyb(query_iter : &mut SplitWhitespace)-> (HybQuery, Option<String>){
     let mut ast = HybQuery::BadQuery(String::from("Empty query"));
     let mut whereable = false;
+    let mut orderable = false;
+    let mut aggregate : Option<(String, bool)> = None;
+    let mut counted = false;
+    let mut summed : Option<(String, bool)> = None;
+    let mut grouped : Option<String> = None;
     let mut spoiler : Option<String> = None;
     
     if let Some(first_word) = query_iter.next(){
@@ -362,7 +895,27 @@ fn parse_helper_hyb(query_iter : &mut SplitWhitespace)-> (HybQuery, Option<Strin
                 if let Some(project_word) = query_iter.next(){
                     if project_word == "*" && projection.is_empty(){
                         break;
-                    } else if project_word.chars().last().unwrap() == ',' {
+                    } else if (project_word.to_uppercase() == "MIN" || project_word.to_uppercase() == "MAX") && projection.is_empty(){
+                        // MIN and MAX are the first row of an ORDER BY ... LIMIT 1
+                        match (query_iter.next(), query_iter.next(), query_iter.next()) {
+                            (Some("("), Some(attrib), Some(")")) => aggregate = Some((String::from(attrib), project_word.to_uppercase() == "MAX")),
+                            _ => loop_error = true
+                        }
+                        break;
+                    } else if project_word.to_uppercase() == "COUNT" {
+                        // columns may only come before COUNT(*) when they are grouped on
+                        match (query_iter.next(), query_iter.next(), query_iter.next()) {
+                            (Some("("), Some("*"), Some(")")) => counted = true,
+                            _ => loop_error = true
+                        }
+                        break;
+                    } else if (project_word.to_uppercase() == "SUM" || project_word.to_uppercase() == "AVG") && projection.is_empty(){
+                        match (query_iter.next(), query_iter.next(), query_iter.next()) {
+                            (Some("("), Some(attrib), Some(")")) => summed = Some((String::from(attrib), project_word.to_uppercase() == "AVG")),
+                            _ => loop_error = true
+                        }
+                        break;
+                    } else if project_word.ends_with(',') {
                         let mut new_project_word = String::from(project_word);
                         new_project_word.pop();
                         projection.push(new_project_word);
@@ -376,7 +929,7 @@ fn parse_helper_hyb(query_iter : &mut SplitWhitespace)-> (HybQuery, Option<Strin
                 }
             }
             if loop_error {
-                ast = HybQuery::BadQuery(String::from("You must list columns for projection or *"));
+                ast = HybQuery::BadQuery(String::from("You must list columns for projection, *, COUNT(*), or MIN, MAX, SUM or AVG of one attribute"));
             } else {
                 if let Some(from_word) = query_iter.next(){
                     if from_word.to_uppercase() == "FROM" {
@@ -388,6 +941,7 @@ fn parse_helper_hyb(query_iter : &mut SplitWhitespace)-> (HybQuery, Option<Strin
                             ast = sub_query;
                         }
                         whereable = true;
+                        orderable = true;
                     } else {
                         ast = HybQuery::BadQuery(String::from("FROM must come after the projected columns of SELECT"));
                     }
@@ -396,7 +950,10 @@ fn parse_helper_hyb(query_iter : &mut SplitWhitespace)-> (HybQuery, Option<Strin
                 }
             }
         } else {
-            ast = HybQuery::Id(String::from(first_word));
+            ast = match parse_alias(query_iter) {
+                Ok(alias) => HybQuery::Id(String::from(first_word), alias),
+                Err(s) => HybQuery::BadQuery(s)
+            };
         }
     }
     loop{
@@ -405,66 +962,86 @@ fn parse_helper_hyb(query_iter : &mut SplitWhitespace)-> (HybQuery, Option<Strin
         match spoiler{
             Some(ref suffix_word) => next_word = Some(suffix_word.to_string()),
             None => {
-                match query_iter.next(){
-                    Some(suffix_word) => next_word = Some(String::from(suffix_word)),
-                    None => {}
-                }
+                if let Some(suffix_word) = query_iter.next() { next_word = Some(String::from(suffix_word)) }
             }
         }
         if let Some(suffix_word) = next_word{
             if suffix_word.to_uppercase() == "WHERE" && whereable{
                 whereable = false;
+                let (b_query, spoiler_inner) = parse_bool(query_iter);
+                spoiler = spoiler_inner;
+                // select before projecting so the selected columns are still there
+                ast = match ast {
+                    HybQuery::Project(cols, sub_query) => HybQuery::Project(cols, Box::new(HybQuery::Select(b_query, sub_query))),
+                    ast => HybQuery::Select(b_query, Box::new(ast))
+                };
+            } else if suffix_word.to_uppercase() == "ORDER" && orderable {
+                whereable = false;
+                orderable = false;
+                let (order, spoiler_inner) = parse_order(query_iter);
+                let (attrib, desc, limit) = match order {
+                    Ok(order) if aggregate.is_none() && !counted && summed.is_none() => order,
+                    Ok(_) => {
+                        ast = HybQuery::BadQuery(String::from("MIN, MAX, SUM, AVG and COUNT(*) cannot be combined with ORDER BY"));
+                        spoiler = None;
+                        break;
+                    },
+                    Err(s) => {
+                        ast = HybQuery::BadQuery(s);
+                        spoiler = None;
+                        break;
+                    }
+                };
+                spoiler = spoiler_inner;
+                // order before projecting so the column sorted on is still there
+                ast = match ast {
+                    HybQuery::Project(cols, sub_query) => HybQuery::Project(cols, Box::new(HybQuery::Order(attrib, desc, limit, sub_query))),
+                    ast => HybQuery::Order(attrib, desc, limit, Box::new(ast))
+                };
+            } else if suffix_word.to_uppercase() == "GROUP" && orderable {
+                whereable = false;
+                orderable = false;
                 spoiler = None;
-                let b_query = parse_bool(query_iter);
-                ast = HybQuery::Select(b_query, Box::new(ast));
-            } else if suffix_word.to_uppercase() == "JOINF" || suffix_word.to_uppercase() == "JOINP" {
-                if suffix_word.to_uppercase() == "JOINF"{
-                    join_type_fpj = true;
-                } else {
-                    join_type_fpj = false
+                match (query_iter.next().map(|w| w.to_uppercase()), query_iter.next()) {
+                    (Some(ref by_word), Some(attrib)) if by_word == "BY" => grouped = Some(String::from(attrib)),
+                    _ => {
+                        ast = HybQuery::BadQuery(String::from("GROUP must be followed by BY and an attribute"));
+                        spoiler = None;
+                        break;
+                    }
                 }
+            } else if suffix_word.to_uppercase() == "JOINF" || suffix_word.to_uppercase() == "JOINP" {
+                join_type_fpj = suffix_word.to_uppercase() == "JOINF";
                 let (sub_query, spoiler_inner) = parse_helper_hyb(query_iter);
                 spoiler = spoiler_inner;
                 let mut on_word_opt : Option<String> = None;
                 match spoiler{
                     Some(ref pos_on_word) => on_word_opt = Some(pos_on_word.to_string()),
                     None => {
-                        match query_iter.next(){
-                            Some(pos_on_word) => on_word_opt = Some(String::from(pos_on_word)),
-                            None => {}
-                        }
+                        if let Some(pos_on_word) = query_iter.next() { on_word_opt = Some(String::from(pos_on_word)) }
                     }
                 }
                 spoiler = None;
                 if let Some(on_word) = on_word_opt{
                     if on_word.to_uppercase() == "ON" {
-                        let mut error_in_on = true;
-                        if let Some(attrib1) = query_iter.next(){
-                            if let Some(eq_word) = query_iter.next(){
-                                if eq_word == "=" {
-                                    if let Some(attrib2) = query_iter.next(){
-                                        error_in_on = false;
-                                        if join_type_fpj {
-                                            ast = HybQuery::FPJoin(
-                                                String::from(attrib1),
-                                                String::from(attrib2),
-                                                Box::new(ast),
-                                                Box::new(sub_query)
-                                            );
-                                        } else {
-                                            ast = HybQuery::PPJoin(
-                                                String::from(attrib1),
-                                                String::from(attrib2),
-                                                Box::new(ast),
-                                                Box::new(sub_query)
-                                            );
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        if error_in_on {
-                            ast = HybQuery::BadQuery(String::from("You must join on attrib1 = attrib2"));
+                        match parse_on(query_iter) {
+                            Ok((ats1, ats2)) if join_type_fpj => {
+                                ast = HybQuery::FPJoin(
+                                    ats1,
+                                    ats2,
+                                    Box::new(ast),
+                                    Box::new(sub_query)
+                                );
+                            },
+                            Ok((ats1, ats2)) => {
+                                ast = HybQuery::PPJoin(
+                                    ats1,
+                                    ats2,
+                                    Box::new(ast),
+                                    Box::new(sub_query)
+                                );
+                            },
+                            Err(s) => ast = HybQuery::BadQuery(s)
                         }
                     } else {
                         ast = HybQuery::BadQuery(String::from("A word other than ON followed a JOIN. Remember, no natural joins"));
@@ -473,13 +1050,46 @@ fn parse_helper_hyb(query_iter : &mut SplitWhitespace)-> (HybQuery, Option<Strin
                     ast = HybQuery::BadQuery(String::from("You must specify what attributes to join on. Sorry - no natural joins"));
                 }
             } else {
-                spoiler = Some(String::from(suffix_word));
+                spoiler = Some(suffix_word);
                 break;
             }
         } else {
             break;
         }
     }
+    if let Some((attrib, desc)) = aggregate {
+        if let HybQuery::BadQuery(_) = ast {} else {
+            ast = HybQuery::Project(vec!(attrib.to_string()), Box::new(HybQuery::Order(attrib, desc, Some(1), Box::new(ast))));
+        }
+    }
+    if let Some(attrib) = grouped {
+        // the counts are precomputed over whole relations and annotated joins, so nothing may come between
+        ast = match ast {
+            HybQuery::BadQuery(s) => HybQuery::BadQuery(s),
+            _ if !counted => HybQuery::BadQuery(String::from("GROUP BY can only be used with COUNT(*)")),
+            HybQuery::Project(cols, sub_query) if cols.iter().all(|col| *col == attrib) => {
+                if is_groupable_hyb(&sub_query) {
+                    HybQuery::Group(attrib, sub_query)
+                } else {
+                    HybQuery::BadQuery(String::from(NOT_GROUPABLE))
+                }
+            },
+            HybQuery::Project(_, _) => HybQuery::BadQuery(String::from("Only the grouped attribute can be selected next to COUNT(*)")),
+            ast if is_groupable_hyb(&ast) => HybQuery::Group(attrib, Box::new(ast)),
+            _ => HybQuery::BadQuery(String::from(NOT_GROUPABLE))
+        };
+    } else if counted {
+        if let HybQuery::BadQuery(_) = ast {} else if let HybQuery::Project(_, _) = ast {
+            ast = HybQuery::BadQuery(String::from("Columns can only be selected next to COUNT(*) with GROUP BY"));
+        } else {
+            ast = HybQuery::Count(Box::new(ast));
+        }
+    }
+    if let Some((attrib, avg)) = summed {
+        if let HybQuery::BadQuery(_) = ast {} else {
+            ast = HybQuery::Sum(attrib, avg, Box::new(ast));
+        }
+    }
     (ast, spoiler)
 }
 
@@ -1,65 +1,222 @@
-use serde_json::Result;
-use std::io::{BufRead, BufReader, Write};
+use std::io::Write;
 
-use std::net::{Shutdown, TcpStream};
-use common::ste::STEServer;
+use std::sync::Arc;
 use common::token::ServerCommand;
+use common::error::{ Error, Result };
+use common::protocol::{ read_frame, write_frame, PROTOCOL_VERSION };
+use common::secure_channel::SecureStream;
+use crate::registry::{ EDBRegistry, SharedEDB, default_edb, read_edb };
 
 /// Waits for user commands and dispatches the commands.
 ///
 /// # Arguments
 ///
-/// * `stream` - TCP stream containing user inputs.
-pub fn handle_client_request(mut stream: TcpStream) {
-    let mut data = String::new();
-    let mut buf_stream = BufReader::new(stream.try_clone().expect("Failed to clone stream"));
-    let mut edb_server : Option<STEServer> = None;
+/// * `stream` - Authenticated channel containing user inputs.
+/// * `registry` - Edbs shared with every other connection.
+pub fn handle_client_request(mut stream: SecureStream, registry: Arc<EDBRegistry>) {
+    // Name of the edb in the registry that this connection is working with
+    let mut edb_name : Option<String> = None;
+    let default_name = match stream.remote_key() {
+        Some(client_key) => default_edb(client_key),
+        None => {
+            println!("Rejected a client without a static key");
+            let _ = stream.shutdown();
+            return;
+        }
+    };
     
-    while match buf_stream.read_line(&mut data) {
-        Ok(size) => {
-            if size == 0 {
-                false
-            } else {
-                // buf_stream.consume(size);
-                let res : Result<Vec<u8>>;
-                res = serde_json::from_str(&data);
-                match res{
-                    Ok(bincode_command) => {
-                        if let Ok(ServerCommand::Setup(edb)) = &bincode::deserialize(&bincode_command[..]){
-                            edb_server = Some((*edb).clone());
-                            println!("Server received an encrypted database");
-                        } else if let Ok(ServerCommand::Search(tk)) = &bincode::deserialize(&bincode_command[..]){
-                            println!("Server received a search");
-                            if let Some(ref mut edb_server_found) = edb_server {
-                                let ciphertexts = edb_server_found.eval_ste((*tk).clone());
-                                println!("Number ciphertexts: {}", ciphertexts.len());
-                                let message = ServerCommand::SearchResponse(ciphertexts);
-                                let message_bytes = &bincode::serialize(&message).unwrap()[..];
-                                let mut message_json = serde_json::to_string(&message_bytes).unwrap();
-                                message_json.push_str("\n");
-                                stream.write_all(&message_json.as_bytes()).unwrap();
-                            } else {
-                                let message = ServerCommand::SearchFailure("No edb has been set up on the server yet".to_string());
-                                let message_bytes = &bincode::serialize(&message).unwrap()[..];
-                                let mut message_json = serde_json::to_string(&message_bytes).unwrap();
-                                message_json.push_str("\n");
-                                stream.write_all(&message_json.as_bytes()).unwrap();
-                            }
-                        } else {
-                            println!("Server could not process a message");
-                        }
-                    },
-                    Err(e) => println!("Error getting from json: {}", e)
-                }
-                data = String::new();
-                true
+    if let Err(e) = handshake(&mut stream) {
+        println!("Rejected a client: {}", e);
+        let _ = stream.shutdown();
+        return;
+    }
+    
+    loop {
+        match read_frame(&mut stream) {
+            Ok(Some(frame)) => {
+                let reply = dispatch(frame.command, &registry, &mut edb_name, &default_name);
+                send_command(&mut stream, frame.request_id, &reply);
+            },
+            Ok(None) => break,
+            Err(e) => {
+                println!("Closing connection: {}", e);
+                let _ = stream.shutdown();
+                break;
             }
         }
+    }
+    println!("Ending connection");
+}
+
+/// The first frame from a client must be a `Hello` with the same protocol
+/// version as the server. Anything else is answered with a failure and the
+/// connection is dropped.
+fn handshake(stream : &mut SecureStream) -> Result<()> {
+    let result = match read_frame(stream) {
+        Ok(Some(frame)) => {
+            match frame.command {
+                ServerCommand::Hello(version) if version == PROTOCOL_VERSION => Ok(frame.request_id),
+                ServerCommand::Hello(version) => Err(Error::Protocol(format!("Client speaks protocol version {} but the server speaks version {}", version, PROTOCOL_VERSION))),
+                _ => Err(Error::Protocol("Client did not start with a handshake".to_string()))
+            }
+        },
+        Ok(None) => Err(Error::Protocol("Client closed the connection during the handshake".to_string())),
+        Err(e) => Err(e)
+    };
+    match result {
+        Ok(request_id) => {
+            send_command(stream, request_id, &ServerCommand::Hello(PROTOCOL_VERSION));
+            Ok(())
+        },
         Err(e) => {
-            stream.shutdown(Shutdown::Both).unwrap();
-            println!("Shutting down due to write error: {}", e);
-            std::process::exit(0);
+            send_command(stream, 0, &ServerCommand::CommandFailure(e.clone()));
+            Err(e)
         }
-    } {}
-    println!("Ending connection");
+    }
+}
+
+fn dispatch(command : ServerCommand, registry : &EDBRegistry, edb_name : &mut Option<String>, default_name : &str) -> ServerCommand {
+    match command {
+        ServerCommand::Setup(edb) => {
+            let name = edb_name.clone().unwrap_or_else(|| default_name.to_string());
+            match registry.put(&name, edb) {
+                Ok(_) => {
+                    println!("Server received an encrypted database for {}", name);
+                    *edb_name = Some(name.to_string());
+                    ServerCommand::CommandSuccess(format!("The server stored the edb as {}", name))
+                },
+                Err(e) => ServerCommand::CommandFailure(e)
+            }
+        },
+        ServerCommand::Search(tk) => {
+            println!("Server received a search");
+            match lookup(registry, edb_name).and_then(|edb| read_edb(&edb)?.eval_ste(tk)) {
+                Ok(ciphertexts) => {
+                    println!("Number ciphertexts: {}", ciphertexts.len());
+                    ServerCommand::SearchResponse(ciphertexts)
+                },
+                Err(e) => {
+                    println!("Search failed: {}", e);
+                    ServerCommand::SearchFailure(e)
+                }
+            }
+        },
+        ServerCommand::Count(tk) => {
+            println!("Server received a count");
+            match lookup(registry, edb_name).and_then(|edb| read_edb(&edb)?.count_ste(tk)) {
+                Ok(count) => ServerCommand::CountResponse(count),
+                Err(e) => {
+                    println!("Count failed: {}", e);
+                    ServerCommand::SearchFailure(e)
+                }
+            }
+        },
+        ServerCommand::Sum(tk) => {
+            println!("Server received a sum");
+            match lookup(registry, edb_name).and_then(|edb| read_edb(&edb)?.sum_ste(tk)) {
+                Ok((total, count)) => ServerCommand::SumResponse(total, count),
+                Err(e) => {
+                    println!("Sum failed: {}", e);
+                    ServerCommand::SearchFailure(e)
+                }
+            }
+        },
+        ServerCommand::Group(tk) => {
+            println!("Server received a group count");
+            match lookup(registry, edb_name).and_then(|edb| read_edb(&edb)?.group_ste(tk)) {
+                Ok(enc_counts) => ServerCommand::GroupResponse(enc_counts),
+                Err(e) => {
+                    println!("Group count failed: {}", e);
+                    ServerCommand::SearchFailure(e)
+                }
+            }
+        },
+        ServerCommand::Update(update) => {
+            println!("Server received an update");
+            // an update is applied whole or not at all, so a failed one can be sent again
+            match current(edb_name).and_then(|name| registry.change(name, |edb| edb.apply_update(update))) {
+                Ok(_) => ServerCommand::CommandSuccess("The server added the rows to the edb".to_string()),
+                Err(e) => {
+                    println!("Update failed: {}", e);
+                    ServerCommand::CommandFailure(e)
+                }
+            }
+        },
+        ServerCommand::Rewrite(rewrite) => {
+            println!("Server received a rewrite");
+            // like an update, a rewrite is applied whole or not at all
+            match current(edb_name).and_then(|name| registry.change(name, |edb| edb.apply_rewrite(rewrite))) {
+                Ok(_) => ServerCommand::CommandSuccess("The server changed the rows of the edb".to_string()),
+                Err(e) => {
+                    println!("Rewrite failed: {}", e);
+                    ServerCommand::CommandFailure(e)
+                }
+            }
+        },
+        ServerCommand::SaveEDB(name) => {
+            if let Some(ref from) = edb_name {
+                match registry.save(from, &name) {
+                    Ok(_) => {
+                        println!("Server stored an edb as {}", name);
+                        *edb_name = Some(name.to_string());
+                        ServerCommand::CommandSuccess(format!("Stored the edb on the server as {}", name))
+                    },
+                    Err(e) => ServerCommand::CommandFailure(e)
+                }
+            } else {
+                ServerCommand::CommandFailure(Error::ServerState("No edb has been set up on the server yet".to_string()))
+            }
+        },
+        ServerCommand::LoadEDB(name) => {
+            match registry.get(&name) {
+                Ok(_) => {
+                    println!("Server loaded the edb {}", name);
+                    *edb_name = Some(name.to_string());
+                    ServerCommand::CommandSuccess(format!("Loaded the edb {}", name))
+                },
+                Err(e) => ServerCommand::CommandFailure(e)
+            }
+        },
+        ServerCommand::DeleteEDB(name) => {
+            match registry.delete(&name) {
+                Ok(_) => {
+                    println!("Server deleted the edb {}", name);
+                    if edb_name.as_ref() == Some(&name) {
+                        *edb_name = None;
+                    }
+                    ServerCommand::CommandSuccess(format!("Deleted the edb {}", name))
+                },
+                Err(e) => ServerCommand::CommandFailure(e)
+            }
+        },
+        ServerCommand::ListEDBs => {
+            match registry.names() {
+                Ok(names) => ServerCommand::ListResponse(names),
+                Err(e) => ServerCommand::CommandFailure(e)
+            }
+        },
+        _ => {
+            println!("Server could not process a message");
+            ServerCommand::CommandFailure(Error::Protocol("The server does not accept that message".to_string()))
+        }
+    }
+}
+
+/// The edb this connection is working with.
+fn lookup(registry : &EDBRegistry, edb_name : &Option<String>) -> Result<SharedEDB> {
+    registry.get(current(edb_name)?)
+}
+
+/// The name of the edb this connection is working with.
+fn current(edb_name : &Option<String>) -> Result<&str> {
+    match edb_name {
+        Some(name) => Ok(name),
+        None => Err(Error::ServerState("No edb has been set up on the server yet".to_string()))
+    }
+}
+
+fn send_command<W : Write>(stream : &mut W, request_id : u64, message : &ServerCommand) {
+    if let Err(e) = write_frame(stream, request_id, message) {
+        println!("Error writing to client: {}", e);
+    }
 }
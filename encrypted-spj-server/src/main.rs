@@ -1,7 +1,15 @@
 mod handler;
+mod storage;
+mod registry;
 
 use std::net::TcpListener;
+use std::sync::Arc;
 use std::thread;
+use common::secure_channel::{ ChannelKeys, SecureStream, load_key_list };
+use crate::registry::EDBRegistry;
+
+const SERVER_KEYS : &str = "channel/server";
+const AUTHORIZED_CLIENTS : &str = "channel/authorized_clients.txt";
 
 /// Entry point for server.
 ///
@@ -11,20 +19,39 @@ fn main() {
     let port = String::from("4000");
     
     let mut bind_addr = host.clone();
-    bind_addr.push_str(":");
+    bind_addr.push(':');
     bind_addr.push_str(&port);
     let listener = TcpListener::bind(bind_addr).unwrap();
+    
+    let keys = Arc::new(load_server_keys());
+    let authorized_clients = match load_key_list(AUTHORIZED_CLIENTS) {
+        Ok(clients) => clients,
+        Err(e) => {
+            println!("{}", e);
+            Vec::new()
+        }
+    };
+    if authorized_clients.is_empty() {
+        println!("No authorized clients. Add client public keys to {} and restart the server", AUTHORIZED_CLIENTS);
+    }
+    let authorized_clients = Arc::new(authorized_clients);
 
     // Accept connections and process them on independent threads.
     println!("Encrypted SPJ server listening on with host {} on port {}", &host, &port);
+    let server_state = Arc::new(EDBRegistry::new());
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
                 println!("New connection: {}", stream.peer_addr().unwrap());
-                // let server_state = Arc::clone(&server_state);
+                let server_state = Arc::clone(&server_state);
+                let keys = Arc::clone(&keys);
+                let authorized_clients = Arc::clone(&authorized_clients);
                 let _handler = thread::spawn(move || {
                     // Connection succeeded.
-                    handler::handle_client_request(stream); // server_state
+                    match SecureStream::accept(stream, &keys, &authorized_clients) {
+                        Ok(secure_stream) => handler::handle_client_request(secure_stream, server_state),
+                        Err(e) => println!("Rejected a connection: {}", e)
+                    }
                 });
             }
             Err(e) => {
@@ -36,3 +63,16 @@ fn main() {
     // Close the socket server.
     drop(listener);
 }
+
+/// Loads the server's static channel keys, generating them on first start.
+/// Clients pin the public half, which is written next to the private key.
+fn load_server_keys() -> ChannelKeys {
+    if let Ok(keys) = ChannelKeys::load(SERVER_KEYS) {
+        return keys;
+    }
+    let keys = ChannelKeys::generate().unwrap();
+    std::fs::create_dir_all("channel").unwrap();
+    keys.save(SERVER_KEYS).unwrap();
+    println!("Generated new server channel keys. Give {}.pub to clients so they can pin it", SERVER_KEYS);
+    keys
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::sync::{ Arc, RwLock, RwLockReadGuard, RwLockWriteGuard };
+use common::ste::STEServer;
+use common::error::{ Error, Result };
+use common::secure_channel::to_hex;
+use crate::storage::{ save_edb, is_saved, load_edb, delete_edb, list_edbs };
+
+/// Name an edb is registered under when a client sets one up without naming it.
+/// It is taken from the client's channel key, so clients with different keys
+/// never write into each other's edb.
+pub fn default_edb(client_key : &[u8]) -> String {
+    format!("default-{}", to_hex(&client_key[..8]))
+}
+
+pub type SharedEDB = Arc<RwLock<STEServer>>;
+
+/// Locks an edb for searching. A connection that panicked while changing the
+/// edb leaves its lock poisoned, which fails every later request on that edb
+/// instead of panicking the connections that make them.
+pub fn read_edb(edb : &SharedEDB) -> Result<RwLockReadGuard<'_, STEServer>> {
+    edb.read().map_err(|_| poisoned())
+}
+
+/// Locks an edb for changing it, failing the same way as `read_edb`.
+pub fn write_edb(edb : &SharedEDB) -> Result<RwLockWriteGuard<'_, STEServer>> {
+    edb.write().map_err(|_| poisoned())
+}
+
+fn poisoned() -> Error {
+    Error::ServerState("A connection failed while changing this edb, so the server will not use it".to_string())
+}
+
+fn registry_poisoned() -> Error {
+    Error::ServerState("A connection failed while registering an edb, so the server cannot look up edbs".to_string())
+}
+
+/// Edbs held in memory and shared by every connection. Each edb sits behind its
+/// own read-write lock, so any number of searches can run on it at once and only
+/// a write (a new setup under the same name, an insert or a delete) waits for them to finish.
+pub struct EDBRegistry {
+    edbs : RwLock<HashMap<String, SharedEDB>>,
+}
+
+impl EDBRegistry {
+    pub fn new() -> EDBRegistry {
+        EDBRegistry {
+            edbs : RwLock::new(HashMap::new())
+        }
+    }
+
+    /// Returns the edb registered under `name`, reading it from disk the first
+    /// time any connection asks for it.
+    pub fn get(&self, name : &str) -> Result<SharedEDB> {
+        if let Some(edb) = self.edbs.read().map_err(|_| registry_poisoned())?.get(name) {
+            return Ok(Arc::clone(edb));
+        }
+        let loaded = load_edb(name)?;
+        let mut edbs = self.edbs.write().map_err(|_| registry_poisoned())?;
+        // Another connection may have loaded it while this one was reading the disk
+        let edb = edbs.entry(name.to_string()).or_insert_with(|| Arc::new(RwLock::new(loaded)));
+        Ok(Arc::clone(edb))
+    }
+
+    /// Registers `edb` under `name`, swapping it into an existing entry so that
+    /// connections already using that name see the new edb. The map lock is let go
+    /// before waiting on the entry, so lookups of other edbs don't wait for the
+    /// searches running on this one.
+    pub fn put(&self, name : &str, edb : STEServer) -> Result<()> {
+        let existing = {
+            let mut edbs = self.edbs.write().map_err(|_| registry_poisoned())?;
+            match edbs.get(name) {
+                Some(existing) => Arc::clone(existing),
+                None => {
+                    edbs.insert(name.to_string(), Arc::new(RwLock::new(edb)));
+                    return Ok(());
+                }
+            }
+        };
+        *write_edb(&existing)? = edb;
+        Ok(())
+    }
+
+    /// Changes the edb registered under `name` with `change`, such as an insert.
+    /// An edb that is saved on disk is written back while its lock is still held,
+    /// so the saved copy never falls behind the one being searched. The change is
+    /// then made on a copy that replaces the edb only once it is saved, so a
+    /// failed change or save leaves the edb as it was in memory and on disk.
+    pub fn change<F>(&self, name : &str, change : F) -> Result<()> where F : FnOnce(&mut STEServer) -> Result<()> {
+        let edb = self.get(name)?;
+        let mut edb = write_edb(&edb)?;
+        if !is_saved(name) {
+            return change(&mut edb);
+        }
+        let mut changed = edb.clone();
+        change(&mut changed)?;
+        save_edb(name, &changed)?;
+        *edb = changed;
+        Ok(())
+    }
+
+    /// Persists the edb registered under `from` to disk as `name` and registers it under `name` too.
+    pub fn save(&self, from : &str, name : &str) -> Result<()> {
+        let edb = self.get(from)?;
+        // Copy the edb out first, since `put` takes the map lock and must not be
+        // called while holding the lock of an edb
+        let copy = {
+            let edb = read_edb(&edb)?;
+            save_edb(name, &edb)?;
+            if from == name {
+                return Ok(());
+            }
+            edb.clone()
+        };
+        self.put(name, copy)
+    }
+
+    pub fn delete(&self, name : &str) -> Result<()> {
+        let in_memory = self.edbs.write().map_err(|_| registry_poisoned())?.remove(name).is_some();
+        match delete_edb(name) {
+            Ok(_) => Ok(()),
+            Err(_) if in_memory => Ok(()),
+            Err(e) => Err(e)
+        }
+    }
+
+    pub fn names(&self) -> Result<Vec<String>> {
+        let mut names = list_edbs()?;
+        for name in self.edbs.read().map_err(|_| registry_poisoned())?.keys() {
+            if !names.contains(name) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use common::crypto_helper::KeyRng;
+    use common::db_structs::{ DB, Relation };
+    use common::ste::STEClient;
+
+    // An edb holding one small relation, which serializes to more bytes than an empty one
+    fn set_up_edb() -> STEServer {
+        let mut db = DB::new_db();
+        let mut rel = Relation::from_strings(vec!("eid".to_string()), vec!(None), vec!(vec!("1".to_string()), vec!("2".to_string()))).unwrap();
+        rel.default_key(&"emp".to_string());
+        db.add_rel(&"emp".to_string(), rel);
+        STEClient::with_rng(KeyRng::seeded(1)).setup_ste(&mut db).unwrap()
+    }
+
+    fn edb_len(edb : &SharedEDB) -> usize {
+        bincode::serialize(&*read_edb(edb).unwrap()).unwrap().len()
+    }
+
+    #[test]
+    fn clients_get_their_own_default_edb() {
+        assert_ne!(default_edb(&[1u8; 32]), default_edb(&[2u8; 32]));
+        assert_eq!(default_edb(&[1u8; 32]), default_edb(&[1u8; 32]));
+    }
+
+    #[test]
+    fn put_swaps_the_edb_for_connections_already_using_it() {
+        let registry = EDBRegistry::new();
+        registry.put("swap", STEServer::new_ste_server()).unwrap();
+        let held = registry.get("swap").unwrap();
+        let empty_len = edb_len(&held);
+        registry.put("swap", set_up_edb()).unwrap();
+        assert!(Arc::ptr_eq(&held, &registry.get("swap").unwrap()));
+        assert!(edb_len(&held) > empty_len);
+        assert!(registry.names().unwrap().contains(&"swap".to_string()));
+    }
+
+    #[test]
+    fn unknown_edbs_are_reported() {
+        let registry = EDBRegistry::new();
+        assert!(matches!(registry.get(&format!("registry-test-missing-{}", std::process::id())), Err(Error::ServerState(_))));
+    }
+
+    #[test]
+    fn a_poisoned_edb_fails_requests_instead_of_panicking() {
+        let registry = EDBRegistry::new();
+        registry.put("poisoned", STEServer::new_ste_server()).unwrap();
+        registry.put("healthy", STEServer::new_ste_server()).unwrap();
+        let edb = registry.get("poisoned").unwrap();
+        let writer = Arc::clone(&edb);
+        let panicked = thread::spawn(move || {
+            let _guard = writer.write().unwrap();
+            panic!("a connection failed while changing the edb");
+        }).join();
+        assert!(panicked.is_err());
+        assert!(matches!(read_edb(&edb), Err(Error::ServerState(_))));
+        assert!(matches!(write_edb(&edb), Err(Error::ServerState(_))));
+        assert!(matches!(registry.put("poisoned", STEServer::new_ste_server()), Err(Error::ServerState(_))));
+        // the other edbs and the registry itself carry on
+        assert!(read_edb(&registry.get("healthy").unwrap()).is_ok());
+        assert!(registry.names().is_ok());
+    }
+
+    #[test]
+    fn changes_to_a_saved_edb_are_written_back() {
+        let registry = EDBRegistry::new();
+        let (saved, unsaved) = (format!("registry-test-saved-{}", std::process::id()), format!("registry-test-unsaved-{}", std::process::id()));
+        registry.put(&saved, STEServer::new_ste_server()).unwrap();
+        registry.save(&saved, &saved).unwrap();
+        registry.put(&unsaved, STEServer::new_ste_server()).unwrap();
+        let empty_len = edb_len(&registry.get(&saved).unwrap());
+        // a change that fails part way leaves the edb as it was
+        let failed = registry.change(&saved, |edb| {
+            *edb = set_up_edb();
+            Err(Error::ServerState("the change failed".to_string()))
+        });
+        assert!(failed.is_err());
+        assert_eq!(edb_len(&registry.get(&saved).unwrap()), empty_len);
+        assert_eq!(bincode::serialize(&load_edb(&saved).unwrap()).unwrap().len(), empty_len);
+        for name in &[&saved, &unsaved] {
+            registry.change(name, |edb| {
+                *edb = set_up_edb();
+                Ok(())
+            }).unwrap();
+            assert!(edb_len(&registry.get(name).unwrap()) > empty_len);
+        }
+        let on_disk = load_edb(&saved).map(|edb| bincode::serialize(&edb).unwrap().len());
+        delete_edb(&saved).unwrap();
+        assert!(on_disk.unwrap() > empty_len);
+        assert!(!is_saved(&unsaved));
+    }
+}
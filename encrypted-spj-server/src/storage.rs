@@ -0,0 +1,109 @@
+use std::fs;
+use std::path::PathBuf;
+use common::ste::STEServer;
+use common::error::{ Error, Result };
+
+const EDB_DIR : &str = "edbs";
+const EDB_EXT : &str = "edb";
+
+/// Names become file names, so only allow ones that cannot escape the edb directory.
+fn edb_path(name : &str) -> Result<PathBuf> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(Error::Parse(format!("\"{}\" is not a valid edb name. Use letters, digits, _ and -", name)));
+    }
+    let mut path = PathBuf::from(EDB_DIR);
+    path.push(format!("{}.{}", name, EDB_EXT));
+    Ok(path)
+}
+
+/// Writes an edb to the server's disk under `name`, replacing any edb with that name.
+pub fn save_edb(name : &str, edb : &STEServer) -> Result<()> {
+    let path = edb_path(name)?;
+    fs::create_dir_all(EDB_DIR).map_err(|e| Error::Io(format!("Could not create the edb directory: {}", e)))?;
+    let edb_bytes = bincode::serialize(edb).map_err(|e| Error::Io(format!("Could not serialize the edb: {}", e)))?;
+    fs::write(&path, edb_bytes).map_err(|e| Error::Io(format!("Could not write edb {}: {}", name, e)))
+}
+
+/// Whether an edb named `name` is on the server's disk.
+pub fn is_saved(name : &str) -> bool {
+    edb_path(name).map(|path| path.exists()).unwrap_or(false)
+}
+
+pub fn load_edb(name : &str) -> Result<STEServer> {
+    let path = edb_path(name)?;
+    let edb_bytes = fs::read(&path).map_err(|_| Error::ServerState(format!("No edb named {} is stored on the server", name)))?;
+    bincode::deserialize(&edb_bytes[..]).map_err(|e| Error::ServerState(format!("Stored edb {} is corrupt: {}", name, e)))
+}
+
+pub fn delete_edb(name : &str) -> Result<()> {
+    let path = edb_path(name)?;
+    fs::remove_file(&path).map_err(|_| Error::ServerState(format!("No edb named {} is stored on the server", name)))
+}
+
+pub fn list_edbs() -> Result<Vec<String>> {
+    let entries = match fs::read_dir(EDB_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(Vec::new())
+    };
+    let mut names = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == EDB_EXT) {
+            if let Some(stem) = path.file_stem() {
+                names.push(stem.to_string_lossy().to_string());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests run side by side in one directory, so each uses its own edb name
+    fn name(test : &str) -> String {
+        format!("storage-test-{}-{}", test, std::process::id())
+    }
+
+    #[test]
+    fn names_that_leave_the_edb_directory_are_refused() {
+        let edb = STEServer::new_ste_server();
+        for bad in &["", "../escape", "a/b", "a b", "name.edb", "..", "C:\\x"] {
+            assert!(matches!(save_edb(bad, &edb), Err(Error::Parse(_))), "{}", bad);
+            assert!(matches!(load_edb(bad), Err(Error::Parse(_))), "{}", bad);
+            assert!(matches!(delete_edb(bad), Err(Error::Parse(_))), "{}", bad);
+            assert!(!is_saved(bad));
+        }
+    }
+
+    #[test]
+    fn saved_edbs_load_list_and_delete() {
+        let name = name("round-trip");
+        let edb = STEServer::new_ste_server();
+        save_edb(&name, &edb).unwrap();
+        assert!(is_saved(&name));
+        assert!(list_edbs().unwrap().contains(&name));
+        let loaded = load_edb(&name).unwrap();
+        assert_eq!(bincode::serialize(&loaded).unwrap(), bincode::serialize(&edb).unwrap());
+        delete_edb(&name).unwrap();
+        assert!(!is_saved(&name));
+        assert!(!list_edbs().unwrap().contains(&name));
+        assert!(matches!(load_edb(&name), Err(Error::ServerState(_))));
+        assert!(matches!(delete_edb(&name), Err(Error::ServerState(_))));
+    }
+
+    #[test]
+    fn corrupt_edbs_are_reported() {
+        let name = name("corrupt");
+        save_edb(&name, &STEServer::new_ste_server()).unwrap();
+        fs::write(edb_path(&name).unwrap(), b"not an edb").unwrap();
+        let loaded = load_edb(&name);
+        delete_edb(&name).unwrap();
+        match loaded {
+            Err(Error::ServerState(msg)) => assert!(msg.contains("corrupt")),
+            _ => panic!("a corrupt edb was loaded")
+        }
+    }
+}